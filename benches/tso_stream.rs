@@ -0,0 +1,56 @@
+//! Compares the timestamp throughput of unary `GetTimestamp` calls with that
+//! of pipelined requests on one `AllocateStream` call, against a PD serving
+//! on a local socket. Run with:
+//!
+//!     cargo bench --bench tso_stream
+
+use std::time::{Duration, Instant};
+use tokio_stream::StreamExt;
+
+use featherpd::config::{ClientConfig, Config};
+use featherpd::proto::placement_driver::TsoRequest;
+use featherpd::server::FeatherPD;
+use featherpd::transport::{connect, spawn_serve, Address};
+
+/// The number of timestamp requests sent each way.
+const REQUESTS: usize = 20_000;
+
+#[tokio::main]
+async fn main() {
+    let dir = std::env::temp_dir().join(format!("featherpd-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let pd = FeatherPD::from_config(&Config::default()).unwrap();
+    pd.recover().await.unwrap();
+    let addr = Address::Unix(dir.join("pd.sock"));
+    let server = spawn_serve(pd, &addr, std::future::pending()).unwrap();
+    let mut client = connect(&addr, &ClientConfig::default()).await.unwrap();
+    let request = TsoRequest { count: 1, ..Default::default() };
+
+    // Each unary call waits for its reply before the next one is sent.
+    let start = Instant::now();
+    for _ in 0..REQUESTS {
+        client.get_timestamp(request.clone()).await.unwrap();
+    }
+    report("GetTimestamp", start.elapsed());
+
+    // The stream keeps requests in flight while earlier ones are served.
+    let start = Instant::now();
+    let requests = tokio_stream::iter(vec![request; REQUESTS]);
+    let mut replies = client.allocate_stream(requests).await.unwrap().into_inner();
+    let mut served = 0;
+    while let Some(reply) = replies.next().await {
+        reply.unwrap();
+        served += 1;
+    }
+    assert_eq!(served, REQUESTS);
+    report("AllocateStream", start.elapsed());
+
+    server.abort();
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// Prints the throughput of `REQUESTS` requests served in `elapsed`.
+fn report(name: &str, elapsed: Duration) {
+    let rate = REQUESTS as f64 / elapsed.as_secs_f64();
+    println!("{:<16} {:>10.0} requests/s ({} requests in {:?})", name, rate, REQUESTS, elapsed);
+}
@@ -1,7 +1,12 @@
+use std::path::PathBuf;
+
 fn main() {
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
     tonic_build::configure()
         .build_server(true)
         .build_client(true)
+        // Served by gRPC server reflection, with the reflection feature.
+        .file_descriptor_set_path(out_dir.join("placement_driver_descriptor.bin"))
         .compile(&["src/proto/pd.proto"], &["proto"])
         .unwrap();
-}
\ No newline at end of file
+}
@@ -0,0 +1,16 @@
+//! Feeds arbitrary status messages through the `tonic::Status` to `Error`
+//! parser, which handles untrusted wire data. Run with:
+//!
+//!     cargo fuzz run status_parser
+#![no_main]
+
+use featherpd::error::Error;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let message = String::from_utf8_lossy(data);
+    let error = Error::from(tonic::Status::unknown(message.as_ref()));
+    // Whatever the message, it parses to an error that survives a round trip
+    // back through a status unchanged.
+    assert_eq!(Error::from(tonic::Status::from(error.clone())), error);
+});
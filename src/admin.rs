@@ -0,0 +1,111 @@
+use log::warn;
+use std::collections::{HashSet, VecDeque};
+use tonic::Request;
+
+use crate::error::{Error, Result};
+use crate::peer::client_addr;
+
+/// The gRPC metadata key carrying the admin token.
+pub const ADMIN_TOKEN_KEY: &str = "x-featherpd-admin-token";
+
+/// The gRPC metadata key carrying the one-time admin request nonce.
+pub const ADMIN_NONCE_KEY: &str = "x-featherpd-admin-nonce";
+
+/// The default number of recent admin nonces remembered.
+pub const DEFAULT_NONCE_CAPACITY: usize = 10_000;
+
+/// Checks that a request carries the configured admin token. If no token is
+/// configured, admin RPCs are open to everyone, which is only suitable for
+/// development and tests.
+pub fn check_admin_token<T>(request: &Request<T>, token: Option<&str>) -> Result<()> {
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(()),
+    };
+    let err = match request.metadata().get(ADMIN_TOKEN_KEY).map(|value| value.to_str()) {
+        Some(Ok(given)) if given == token => return Ok(()),
+        Some(_) => Error::PermissionDenied("Invalid admin token".into()),
+        None => Error::PermissionDenied("Missing admin token".into()),
+    };
+    warn!("Rejected admin request from {:?}: {}", client_addr(request), err);
+    Err(err)
+}
+
+/// A bounded set of recently seen admin request nonces, used to reject
+/// replayed admin requests, e.g. from a retrying client. Once full, the
+/// oldest nonces are forgotten first.
+pub struct NonceCache {
+    capacity: usize,
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl NonceCache {
+    /// Creates a nonce cache remembering up to `capacity` nonces.
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, seen: HashSet::new(), order: VecDeque::new() }
+    }
+
+    /// Checks the nonce of an admin request, if it carries one, and records
+    /// it. A nonce that was seen before is rejected with `Error::Abort`.
+    /// Requests without a nonce aren't protected against replays.
+    pub fn check<T>(&mut self, request: &Request<T>) -> Result<()> {
+        let nonce = match request.metadata().get(ADMIN_NONCE_KEY) {
+            Some(value) => value
+                .to_str()
+                .map_err(|_| Error::Value("Admin nonce must be printable ASCII".into()))?
+                .to_string(),
+            None => return Ok(()),
+        };
+        if self.seen.contains(&nonce) {
+            warn!("Rejected replayed admin request from {:?} with nonce {}", client_addr(request), nonce);
+            return Err(Error::Abort);
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(nonce.clone());
+        self.order.push_back(nonce);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a request carrying the given admin metadata.
+    fn request(token: Option<&str>, nonce: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(token) = token {
+            request.metadata_mut().insert(ADMIN_TOKEN_KEY, token.parse().unwrap());
+        }
+        if let Some(nonce) = nonce {
+            request.metadata_mut().insert(ADMIN_NONCE_KEY, nonce.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn replayed_nonces_are_rejected() {
+        let mut nonces = NonceCache::new(10);
+        assert_eq!(nonces.check(&request(None, Some("a"))), Ok(()));
+        assert_eq!(nonces.check(&request(None, Some("a"))), Err(Error::Abort));
+        assert_eq!(nonces.check(&request(None, Some("b"))), Ok(()));
+        // Requests without a nonce aren't tracked.
+        assert_eq!(nonces.check(&request(None, None)), Ok(()));
+        assert_eq!(nonces.check(&request(None, None)), Ok(()));
+    }
+
+    #[test]
+    fn the_oldest_nonces_are_forgotten_first() {
+        let mut nonces = NonceCache::new(2);
+        for nonce in ["a", "b", "c"] {
+            nonces.check(&request(None, Some(nonce))).unwrap();
+        }
+        assert_eq!(nonces.check(&request(None, Some("c"))), Err(Error::Abort));
+        assert_eq!(nonces.check(&request(None, Some("a"))), Ok(()));
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+use crate::error::Result;
+
+/// The priority of a request, which only matters under contention.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// The default priority, e.g. for bulk timestamp allocations.
+    Normal,
+    /// Served before normal requests under contention, e.g. for GC safe point
+    /// updates.
+    High,
+}
+
+/// The virtual time a keyspace with weight 1 is charged per admitted
+/// request. Heavier keyspaces are charged proportionally less.
+const STRIDE: u64 = 1 << 20;
+
+/// Limits the number of requests in flight, queueing the excess. While there
+/// is spare capacity, requests are admitted right away and the queue is
+/// bypassed entirely. Once saturated, queued high-priority requests are
+/// admitted before any normal ones. Within a level, keyspaces share the
+/// released slots in proportion to their weights, each in FIFO order.
+///
+/// A weight is a minimum share, not a cap: a keyspace with weight 2 is
+/// admitted at least twice as often as a waiting keyspace with weight 1,
+/// but is free to use all slots while no other keyspace is waiting.
+pub struct AdmissionQueue {
+    state: Arc<Mutex<QueueState>>,
+}
+
+struct QueueState {
+    /// The number of requests that may still be admitted right away.
+    available: usize,
+    /// The keyspace weights. Keyspaces not listed have weight 1.
+    weights: HashMap<u32, u32>,
+    /// The waiting high-priority requests.
+    high: FairQueue,
+    /// The waiting normal-priority requests.
+    normal: FairQueue,
+}
+
+impl QueueState {
+    /// Hands a released slot to the next waiter, or returns it to the pool.
+    /// Waiters that gave up in the meantime are skipped.
+    fn release(&mut self) {
+        while let Some(waiter) = self.high.pop(&self.weights).or_else(|| self.normal.pop(&self.weights)) {
+            if waiter.send(()).is_ok() {
+                return;
+            }
+        }
+        self.available += 1;
+    }
+}
+
+/// The waiting requests of one priority level, queued per keyspace and
+/// served by stride scheduling: each keyspace has a pass, advanced by
+/// `STRIDE / weight` whenever one of its requests is admitted, and the
+/// waiting keyspace with the lowest pass goes next.
+#[derive(Default)]
+struct FairQueue {
+    /// The waiting requests, by keyspace. Keyspaces without waiters are
+    /// removed.
+    queues: BTreeMap<u32, VecDeque<oneshot::Sender<()>>>,
+    /// The pass of each keyspace that has been admitted from.
+    passes: HashMap<u32, u64>,
+    /// The pass of the last admitted request. A keyspace that starts waiting
+    /// again is brought up to it, so it can't bank credit while idle.
+    virtual_time: u64,
+}
+
+impl FairQueue {
+    /// Queues a waiter of the given keyspace.
+    fn push(&mut self, keyspace_id: u32, waiter: oneshot::Sender<()>) {
+        if !self.queues.contains_key(&keyspace_id) {
+            let pass = self.passes.entry(keyspace_id).or_default();
+            *pass = (*pass).max(self.virtual_time);
+        }
+        self.queues.entry(keyspace_id).or_default().push_back(waiter);
+    }
+
+    /// Dequeues the next waiter, from the waiting keyspace with the lowest
+    /// pass.
+    fn pop(&mut self, weights: &HashMap<u32, u32>) -> Option<oneshot::Sender<()>> {
+        let keyspace_id =
+            *self.queues.keys().min_by_key(|id| (self.passes.get(id).copied().unwrap_or(0), **id))?;
+        let queue = self.queues.get_mut(&keyspace_id)?;
+        let waiter = queue.pop_front();
+        if queue.is_empty() {
+            self.queues.remove(&keyspace_id);
+        }
+        let weight = weights.get(&keyspace_id).copied().unwrap_or(1).max(1) as u64;
+        let pass = self.passes.entry(keyspace_id).or_default();
+        self.virtual_time = *pass;
+        *pass += STRIDE / weight;
+        waiter
+    }
+}
+
+impl AdmissionQueue {
+    /// Creates an admission queue allowing up to `capacity` requests in
+    /// flight.
+    pub fn new(capacity: usize) -> Self {
+        let state = QueueState {
+            available: capacity,
+            weights: HashMap::new(),
+            high: FairQueue::default(),
+            normal: FairQueue::default(),
+        };
+        Self { state: Arc::new(Mutex::new(state)) }
+    }
+
+    /// Sets the keyspace weights, by keyspace id. Keyspaces not listed, or
+    /// listed with weight 0, have weight 1.
+    pub fn with_weights(self, weights: HashMap<u32, u32>) -> Self {
+        if let Ok(mut state) = self.state.lock() {
+            state.weights = weights;
+        }
+        self
+    }
+
+    /// Waits until the request of the given keyspace is admitted. The request
+    /// stays in flight until the returned permit is dropped.
+    pub async fn admit(&self, priority: Priority, keyspace_id: u32) -> Result<Permit> {
+        let mut waiter = {
+            let mut state = self.state.lock()?;
+            if state.available > 0 {
+                state.available -= 1;
+                return Ok(Permit { state: self.state.clone() });
+            }
+            let (tx, rx) = oneshot::channel();
+            match priority {
+                Priority::High => state.high.push(keyspace_id, tx),
+                Priority::Normal => state.normal.push(keyspace_id, tx),
+            }
+            Waiter { rx, state: self.state.clone() }
+        };
+        (&mut waiter.rx).await?;
+        Ok(Permit { state: self.state.clone() })
+    }
+}
+
+/// A queued request. If the request is cancelled after it was handed a slot
+/// but before it noticed, the slot is released on drop.
+struct Waiter {
+    rx: oneshot::Receiver<()>,
+    state: Arc<Mutex<QueueState>>,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        self.rx.close();
+        if self.rx.try_recv().is_ok() {
+            if let Ok(mut state) = self.state.lock() {
+                state.release();
+            }
+        }
+    }
+}
+
+/// An admitted request's slot, released when dropped.
+pub struct Permit {
+    state: Arc<Mutex<QueueState>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Queues the given requests, in order, behind a request holding the
+    /// queue's only slot, then releases it. Returns the indexes of the
+    /// requests in the order they were admitted.
+    async fn admission_order(queue: AdmissionQueue, requests: &[(Priority, u32)]) -> Vec<usize> {
+        let queue = Arc::new(queue);
+        let holder = queue.admit(Priority::Normal, 0).await.unwrap();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut tasks = Vec::new();
+        for (i, &(priority, keyspace_id)) in requests.iter().enumerate() {
+            let (queue, order) = (queue.clone(), order.clone());
+            tasks.push(tokio::spawn(async move {
+                let _permit = queue.admit(priority, keyspace_id).await.unwrap();
+                order.lock().unwrap().push(i);
+            }));
+            // Let the request queue up before the next one.
+            tokio::task::yield_now().await;
+        }
+        drop(holder);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let order = order.lock().unwrap().clone();
+        order
+    }
+
+    #[tokio::test]
+    async fn requests_are_admitted_right_away_while_there_is_capacity() {
+        let queue = AdmissionQueue::new(2);
+        let _first = queue.admit(Priority::Normal, 0).await.unwrap();
+        let second = tokio::time::timeout(Duration::from_secs(1), queue.admit(Priority::Normal, 0)).await;
+        assert!(second.is_ok());
+    }
+
+    #[tokio::test]
+    async fn high_priority_requests_go_first_under_contention() {
+        use Priority::*;
+        let requests = [(Normal, 0), (Normal, 0), (High, 0), (Normal, 0), (High, 0)];
+        assert_eq!(admission_order(AdmissionQueue::new(1), &requests).await, vec![2, 4, 0, 1, 3]);
+    }
+
+    #[tokio::test]
+    async fn a_heavier_keyspace_makes_progress_behind_a_flood() {
+        use Priority::*;
+        // Keyspace 1 floods the queue before keyspace 2, which has 3 times its
+        // weight, queues anything.
+        let mut requests = vec![(Normal, 1); 30];
+        requests.extend([(Normal, 2); 10]);
+        let queue = AdmissionQueue::new(1).with_weights(HashMap::from([(1, 1), (2, 3)]));
+        let order = admission_order(queue, &requests).await;
+
+        let heavy: Vec<usize> = order.iter().enumerate().filter(|(_, &i)| i >= 30).map(|(n, _)| n).collect();
+        assert_eq!(heavy.len(), 10);
+        assert!(*heavy.last().unwrap() < 15, "{:?}", order);
+        // Within a keyspace, requests are admitted in FIFO order.
+        assert!(order.iter().filter(|&&i| i < 30).collect::<Vec<_>>().windows(2).all(|w| w[0] < w[1]));
+        assert!(order.iter().filter(|&&i| i >= 30).collect::<Vec<_>>().windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[tokio::test]
+    async fn equal_weights_take_turns() {
+        use Priority::*;
+        let mut requests = vec![(Normal, 1); 4];
+        requests.extend([(Normal, 2); 4]);
+        let order = admission_order(AdmissionQueue::new(1), &requests).await;
+        assert_eq!(order, vec![0, 4, 1, 5, 2, 6, 3, 7]);
+    }
+}
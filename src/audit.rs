@@ -0,0 +1,133 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::checked::add_or_err;
+use crate::clock::{Clock, SystemClock};
+use crate::error::{Error, Result};
+
+/// The default size in bytes at which the audit log is rotated.
+pub const DEFAULT_AUDIT_LOG_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+/// The number of rotated audit log files kept, as `<path>.1` (the newest)
+/// through `<path>.5`.
+const ROTATED_FILES: u32 = 5;
+
+/// A record of a timestamp window issued by the TSO.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AuditRecord {
+    /// The record sequence number, monotonic across restarts and rotations.
+    pub seq: u64,
+    /// The wall-clock time the window was issued, in milliseconds.
+    pub time_ms: u64,
+    /// The first timestamp of the window.
+    pub window_start: u64,
+    /// The end of the window (exclusive), i.e. the persisted watermark.
+    pub window_end: u64,
+}
+
+impl AuditRecord {
+    /// Formats the record as a log line, without the trailing newline.
+    fn format(&self) -> String {
+        format!(
+            "seq={} time_ms={} start={} end={}",
+            self.seq, self.time_ms, self.window_start, self.window_end
+        )
+    }
+
+    /// Parses a log line.
+    fn parse(line: &str) -> Result<Self> {
+        let mut fields = line.split(' ').map(|field| field.split_once('='));
+        let mut next = |name: &str| -> Result<u64> {
+            match fields.next().flatten() {
+                Some((key, value)) if key == name => Ok(value.parse()?),
+                _ => Err(Error::Parse(format!("Invalid audit record {:?}", line))),
+            }
+        };
+        Ok(Self {
+            seq: next("seq")?,
+            time_ms: next("time_ms")?,
+            window_start: next("start")?,
+            window_end: next("end")?,
+        })
+    }
+}
+
+/// An append-only audit log of the timestamp windows issued by the TSO, one
+/// line per window rather than per timestamp to keep the overhead minimal.
+/// The log is rotated once it exceeds a maximum size.
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    state: Mutex<AuditState>,
+}
+
+struct AuditState {
+    file: File,
+    size: u64,
+    last: Option<AuditRecord>,
+}
+
+impl AuditLog {
+    /// Opens the audit log at the given path, creating it if needed and
+    /// resuming the sequence from the last record, if any.
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64) -> Result<Self> {
+        let path = path.into();
+        let last = match Self::read_last(&path)? {
+            Some(record) => Some(record),
+            None => Self::read_last(&rotated_path(&path, 1))?,
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, max_bytes, state: Mutex::new(AuditState { file, size, last }) })
+    }
+
+    /// Reads the last record of a log file, if it exists and isn't empty.
+    fn read_last(path: &Path) -> Result<Option<AuditRecord>> {
+        match fs::read_to_string(path) {
+            Ok(content) => content.lines().last().map(AuditRecord::parse).transpose(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns the last record written, if any.
+    pub fn last(&self) -> Result<Option<AuditRecord>> {
+        Ok(self.state.lock()?.last)
+    }
+
+    /// Records an issued window, rotating the log first if it is full.
+    pub fn record(&self, window_start: u64, window_end: u64) -> Result<()> {
+        let mut state = self.state.lock()?;
+        if state.size >= self.max_bytes {
+            for n in (1..ROTATED_FILES).rev() {
+                match fs::rename(rotated_path(&self.path, n), rotated_path(&self.path, n + 1)) {
+                    Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+            state.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            state.size = 0;
+        }
+        let record = AuditRecord {
+            seq: state.last.map_or(Ok(1), |last| add_or_err(last.seq, 1))?,
+            time_ms: SystemClock.now_ms(),
+            window_start,
+            window_end,
+        };
+        let line = format!("{}\n", record.format());
+        state.file.write_all(line.as_bytes())?;
+        state.size += line.len() as u64;
+        state.last = Some(record);
+        Ok(())
+    }
+}
+
+/// Returns the path of the nth rotated log file.
+fn rotated_path(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    name.into()
+}
@@ -0,0 +1,142 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::Duration;
+
+/// An exponential backoff with jitter, yielding successive sleep durations
+/// between retries. Each delay is the current base delay reduced by a random
+/// fraction of up to `jitter`, and the base delay grows by `multiplier` up to
+/// `max` after every retry. With a retry budget, it stops yielding delays
+/// once the budget is exhausted, so callers fail fast instead.
+pub struct Backoff {
+    /// The base delay of the first retry.
+    initial: Duration,
+    /// The maximum base delay.
+    max: Duration,
+    /// The factor the base delay grows by per retry.
+    multiplier: f64,
+    /// The maximum fraction of the base delay removed as jitter, in [0, 1].
+    jitter: f64,
+    /// The base delay of the next retry.
+    current: Duration,
+    /// The jitter source.
+    rng: StdRng,
+    /// The retry budget, if any.
+    budget: Option<RetryBudget>,
+}
+
+impl Backoff {
+    /// Creates a new backoff with an entropy-seeded jitter source.
+    pub fn new(initial: Duration, max: Duration, multiplier: f64, jitter: f64) -> Self {
+        Self {
+            initial,
+            max,
+            multiplier: multiplier.max(1.0),
+            jitter: jitter.clamp(0.0, 1.0),
+            current: initial,
+            rng: StdRng::from_entropy(),
+            budget: None,
+        }
+    }
+
+    /// Limits retries with a retry budget, see `RetryBudget::new()`.
+    pub fn with_retry_budget(mut self, ratio: f64, max_retries: u32) -> Self {
+        self.budget = Some(RetryBudget::new(ratio, max_retries));
+        self
+    }
+
+    /// Seeds the jitter source, making the delays deterministic.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// Resets the backoff after a success, so the next retry starts over,
+    /// and credits the retry budget, if any.
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+        if let Some(budget) = &mut self.budget {
+            budget.record_success();
+        }
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        if let Some(budget) = &mut self.budget {
+            if !budget.try_retry() {
+                return None;
+            }
+        }
+        let delay = self.current.mul_f64(1.0 - self.jitter * self.rng.gen::<f64>());
+        self.current = self.current.mul_f64(self.multiplier).min(self.max);
+        Some(delay)
+    }
+}
+
+/// A retry budget, for client-side adaptive throttling: retries are only
+/// allowed as a fraction of recent successful requests, so that clients
+/// retrying on errors like `NotLeader` or `Unavailable` don't amplify the
+/// load on a struggling PD. Each success credits `ratio` retries, up to
+/// `max_retries`, and each retry spends one.
+#[derive(Clone, Debug)]
+pub struct RetryBudget {
+    /// The retries credited per success.
+    ratio: f64,
+    /// The maximum retries that can be saved up.
+    max_retries: f64,
+    /// The retries currently available.
+    balance: f64,
+}
+
+impl RetryBudget {
+    /// Creates a retry budget crediting `ratio` retries per success, e.g. 0.1
+    /// to allow one retry per ten successes, and saving up at most
+    /// `max_retries`. It starts full, so a new client may retry right away.
+    pub fn new(ratio: f64, max_retries: u32) -> Self {
+        let max_retries = max_retries as f64;
+        Self { ratio: ratio.max(0.0), max_retries, balance: max_retries }
+    }
+
+    /// Credits the budget for a successful request.
+    pub fn record_success(&mut self) {
+        self.balance = (self.balance + self.ratio).min(self.max_retries);
+    }
+
+    /// Spends a retry, returning false if the budget is exhausted, in which
+    /// case the caller should fail fast.
+    pub fn try_retry(&mut self) -> bool {
+        if self.balance < 1.0 {
+            return false;
+        }
+        self.balance -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_grow_up_to_the_max_and_restart_on_reset() {
+        let ms = Duration::from_millis;
+        let mut backoff = Backoff::new(ms(10), ms(50), 2.0, 0.0);
+        let delays: Vec<Duration> = backoff.by_ref().take(5).collect();
+        assert_eq!(delays, vec![ms(10), ms(20), ms(40), ms(50), ms(50)]);
+        backoff.reset();
+        assert_eq!(backoff.next(), Some(ms(10)));
+    }
+
+    #[test]
+    fn seeded_jitter_is_deterministic_and_bounded() {
+        let ms = Duration::from_millis;
+        let delays = |seed| -> Vec<Duration> {
+            Backoff::new(ms(100), ms(100), 2.0, 0.5).with_seed(seed).take(20).collect()
+        };
+        assert_eq!(delays(7), delays(7));
+        assert_ne!(delays(7), delays(8));
+        assert!(delays(7).iter().all(|delay| *delay > ms(50) && *delay <= ms(100)));
+    }
+}
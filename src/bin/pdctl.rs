@@ -0,0 +1,133 @@
+//! A command-line tool for poking a running PD, e.g.:
+//!
+//!     pdctl --endpoint 127.0.0.1:2379 get-ts
+//!     FEATHERPD_ENDPOINT=unix:/tmp/pd.sock pdctl status
+
+use std::process::ExitCode;
+
+use featherpd::admin::ADMIN_TOKEN_KEY;
+use featherpd::client::BlockingPdClient;
+use featherpd::config::ClientConfig;
+use featherpd::error::{Error, Result};
+use featherpd::metrics::MetricsSnapshot;
+use featherpd::proto::placement_driver::{ClusterStatusRequest, DumpTopologyRequest, QueryStoresRequest};
+use featherpd::status::ClusterStatus;
+use featherpd::store::StoreInfo;
+use featherpd::transport::Address;
+use tonic::metadata::{Ascii, MetadataValue};
+
+/// The environment variable the endpoint is read from if not given with
+/// `--endpoint`.
+const ENDPOINT_VAR: &str = "FEATHERPD_ENDPOINT";
+
+/// The environment variable the admin token for admin commands is read
+/// from, if any.
+const ADMIN_TOKEN_VAR: &str = "FEATHERPD_ADMIN_TOKEN";
+
+const USAGE: &str = "usage: pdctl [--endpoint ADDR] <command>
+
+commands:
+    get-ts         allocate and print one timestamp
+    peek-ts        print the last allocated timestamp, without allocating
+    status         print the cluster status
+    list-stores    print the live stores, one per line
+    metrics        print the metrics in the Prometheus text format
+    topology       print the cluster topology as JSON (admin)
+    topology-dot   print the cluster topology as a GraphViz DOT graph (admin)
+
+The endpoint is a TCP address such as 127.0.0.1:2379 or a UNIX domain socket
+such as unix:/tmp/pd.sock, and defaults to $FEATHERPD_ENDPOINT. Admin commands
+send the admin token in $FEATHERPD_ADMIN_TOKEN, if set.";
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let (mut endpoint, mut command) = (std::env::var(ENDPOINT_VAR).ok(), None);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--endpoint" => endpoint = args.next(),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            }
+            _ if command.is_none() => command = Some(arg),
+            _ => return usage_error(&format!("unexpected argument {}", arg)),
+        }
+    }
+    let (endpoint, command) = match (endpoint, command) {
+        (Some(endpoint), Some(command)) => (endpoint, command),
+        (None, _) => return usage_error(&format!("no endpoint given with --endpoint or ${}", ENDPOINT_VAR)),
+        (_, None) => return usage_error("no command given"),
+    };
+    match run(&endpoint, &command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => failure(&err),
+    }
+}
+
+/// Runs a command against the PD at the given endpoint.
+fn run(endpoint: &str, command: &str) -> Result<()> {
+    let addr: Address = endpoint.parse()?;
+    let mut client = BlockingPdClient::connect(&addr, &ClientConfig::default())?;
+    match command {
+        "get-ts" => println!("{}", client.get_timestamp()?),
+        "peek-ts" => {
+            let status = cluster_status(&mut client)?;
+            match status.tso_last_allocated {
+                Some(ts) => println!("{}", ts),
+                None => println!("none"),
+            }
+        }
+        "status" => println!("{:#?}", cluster_status(&mut client)?),
+        "metrics" => print!("{}", MetricsSnapshot::from(&cluster_status(&mut client)?).to_prometheus()),
+        "list-stores" => {
+            let reply = client.call(|mut client| async move {
+                client.query_stores(QueryStoresRequest { selector: String::new() }).await
+            })?;
+            let stores: Vec<StoreInfo> = bincode::deserialize(&reply.stores)?;
+            for store in stores {
+                println!(
+                    "{}\t{}\t{:?}\t{}/{} bytes used",
+                    store.id, store.address, store.state, store.used, store.capacity
+                );
+            }
+        }
+        "topology" | "topology-dot" => {
+            let dot = command == "topology-dot";
+            let token: Option<MetadataValue<Ascii>> = match std::env::var(ADMIN_TOKEN_VAR) {
+                Ok(token) => {
+                    Some(token.parse().map_err(|_| Error::Value(format!("Invalid ${}", ADMIN_TOKEN_VAR)))?)
+                }
+                Err(_) => None,
+            };
+            let reply = client.call(|mut client| {
+                let mut request = tonic::Request::new(DumpTopologyRequest { dot });
+                if let Some(token) = token.clone() {
+                    request.metadata_mut().insert(ADMIN_TOKEN_KEY, token);
+                }
+                async move { client.dump_topology(request).await }
+            })?;
+            print!("{}", if dot { reply.dot } else { reply.json + "\n" });
+        }
+        command => return Err(Error::Value(format!("Unknown command {}", command))),
+    }
+    Ok(())
+}
+
+/// Fetches the cluster status.
+fn cluster_status(client: &mut BlockingPdClient) -> Result<ClusterStatus> {
+    let reply =
+        client.call(|mut client| async move { client.get_cluster_status(ClusterStatusRequest {}).await })?;
+    Ok(bincode::deserialize(&reply.status)?)
+}
+
+/// Prints a usage error, returning the exit code for it.
+fn usage_error(msg: &str) -> ExitCode {
+    eprintln!("pdctl: {}\n\n{}", msg, USAGE);
+    ExitCode::from(2)
+}
+
+/// Prints an error, returning the exit code for it.
+fn failure(err: &Error) -> ExitCode {
+    eprintln!("pdctl: {}", err);
+    ExitCode::FAILURE
+}
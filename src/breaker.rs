@@ -0,0 +1,260 @@
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::checkpoint::{Checkpoint, CheckpointStore};
+use crate::clock::{Clock, SystemClock};
+use crate::error::{Error, Result};
+
+/// The default number of consecutive checkpoint write failures that open the
+/// circuit.
+pub const DEFAULT_BREAKER_FAILURE_THRESHOLD: u32 = 5;
+
+/// The default time between probe writes while the circuit is open, in
+/// milliseconds.
+pub const DEFAULT_BREAKER_PROBE_INTERVAL_MS: u64 = 1_000;
+
+/// The state of a circuit breaker.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BreakerState {
+    /// Calls go through.
+    Closed,
+    /// Calls fail fast, until the next probe.
+    Open,
+    /// A single probe call is going through, which closes the circuit if it
+    /// succeeds and reopens it otherwise.
+    HalfOpen,
+}
+
+/// A circuit breaker. After `threshold` consecutive failures it opens and
+/// fails calls fast, letting a single probe call through every
+/// `probe_interval_ms` to find out whether to close again.
+pub struct CircuitBreaker {
+    threshold: u32,
+    probe_interval_ms: u64,
+    state: BreakerState,
+    consecutive_failures: u32,
+    /// The time the circuit last opened, in milliseconds.
+    opened_at_ms: u64,
+}
+
+impl CircuitBreaker {
+    /// Creates a closed circuit breaker.
+    pub fn new(threshold: u32, probe_interval_ms: u64) -> Self {
+        Self {
+            threshold,
+            probe_interval_ms,
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            opened_at_ms: 0,
+        }
+    }
+
+    /// Returns the breaker state.
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    /// Returns true if a call may go through now. Once the probe interval has
+    /// passed, an open circuit goes half-open and lets one probe call through.
+    pub fn allow(&mut self, now_ms: u64) -> bool {
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::HalfOpen => false,
+            BreakerState::Open if now_ms >= self.opened_at_ms.saturating_add(self.probe_interval_ms) => {
+                self.state = BreakerState::HalfOpen;
+                true
+            }
+            BreakerState::Open => false,
+        }
+    }
+
+    /// Records the outcome of a call that was let through.
+    pub fn record(&mut self, success: bool, now_ms: u64) {
+        if success {
+            self.state = BreakerState::Closed;
+            self.consecutive_failures = 0;
+            return;
+        }
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.state == BreakerState::HalfOpen || self.consecutive_failures >= self.threshold {
+            self.state = BreakerState::Open;
+            self.opened_at_ms = now_ms;
+        }
+    }
+}
+
+/// A checkpoint store guarded by a circuit breaker, so that a failing store
+/// makes timestamp allocation fail fast with "persistence unavailable"
+/// instead of stalling on every write. Loads are not guarded, since they
+/// only happen during recovery.
+pub struct BreakerCheckpoint {
+    inner: Arc<dyn CheckpointStore>,
+    breaker: Mutex<CircuitBreaker>,
+    /// The clock timing the probes. Settable after construction, since the
+    /// breaker is shared with the TSO before the server's clock is injected.
+    clock: RwLock<Arc<dyn Clock>>,
+}
+
+impl BreakerCheckpoint {
+    /// Guards a checkpoint store with a circuit breaker, timed by the system
+    /// clock.
+    pub fn new(inner: Arc<dyn CheckpointStore>, threshold: u32, probe_interval_ms: u64) -> Self {
+        Self {
+            inner,
+            breaker: Mutex::new(CircuitBreaker::new(threshold, probe_interval_ms)),
+            clock: RwLock::new(Arc::new(SystemClock)),
+        }
+    }
+
+    /// Uses the given clock instead of the system clock.
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        *self.clock.write().unwrap_or_else(|err| err.into_inner()) = clock;
+    }
+
+    /// Returns the breaker state.
+    pub fn state(&self) -> Result<BreakerState> {
+        Ok(self.breaker.lock()?.state())
+    }
+}
+
+impl CheckpointStore for BreakerCheckpoint {
+    fn load(&self) -> Result<Option<Checkpoint>> {
+        self.inner.load()
+    }
+
+    fn save(&self, checkpoint: &Checkpoint) -> Result<u64> {
+        let clock = self.clock.read()?.clone();
+        if !self.breaker.lock()?.allow(clock.now_ms()) {
+            return Err(Error::Internal("persistence unavailable".into()));
+        }
+        let result = self.inner.save(checkpoint);
+        let mut breaker = self.breaker.lock()?;
+        let before = breaker.state();
+        // A read-only store isn't going to recover by itself, so it's left to
+        // fail on its own rather than opening the circuit. A failed probe
+        // still reopens it though, or it would stay half-open for good.
+        if let Err(Error::PersistenceReadOnly(_)) = &result {
+            if before == BreakerState::HalfOpen {
+                breaker.record(false, clock.now_ms());
+            }
+            return result;
+        }
+        breaker.record(result.is_ok(), clock.now_ms());
+        match (before, breaker.state(), &result) {
+            (BreakerState::Closed, BreakerState::Open, Err(err)) => warn!(
+                "Checkpoint writes keep failing ({}), failing allocations fast until the store recovers",
+                err
+            ),
+            (BreakerState::HalfOpen, BreakerState::Closed, _) => info!("Checkpoint writes recovered"),
+            _ => {}
+        }
+        result
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    /// A checkpoint store failing writes with a settable error.
+    #[derive(Default)]
+    struct FlakyCheckpoint {
+        error: Mutex<Option<Error>>,
+    }
+
+    impl FlakyCheckpoint {
+        fn fail_with(&self, error: Option<Error>) {
+            *self.error.lock().unwrap() = error;
+        }
+    }
+
+    impl CheckpointStore for FlakyCheckpoint {
+        fn load(&self) -> Result<Option<Checkpoint>> {
+            Ok(None)
+        }
+
+        fn save(&self, _: &Checkpoint) -> Result<u64> {
+            match self.error.lock().unwrap().clone() {
+                Some(error) => Err(error),
+                None => Ok(0),
+            }
+        }
+    }
+
+    /// Returns a breaker opening after two failures and probing every 100ms,
+    /// with its store and clock.
+    fn breaker() -> (BreakerCheckpoint, Arc<FlakyCheckpoint>, Arc<ManualClock>) {
+        let store = Arc::new(FlakyCheckpoint::default());
+        let clock = Arc::new(ManualClock::new(1_000));
+        let breaker = BreakerCheckpoint::new(store.clone(), 2, 100);
+        breaker.set_clock(clock.clone());
+        (breaker, store, clock)
+    }
+
+    fn save(breaker: &BreakerCheckpoint) -> Result<u64> {
+        breaker.save(&Checkpoint { window_end: 1, last_allocated: None })
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures_and_closes_on_a_successful_probe() {
+        let (breaker, store, clock) = breaker();
+        store.fail_with(Some(Error::Internal("disk".into())));
+        assert!(save(&breaker).is_err());
+        assert_eq!(breaker.state().unwrap(), BreakerState::Closed);
+        assert!(save(&breaker).is_err());
+        assert_eq!(breaker.state().unwrap(), BreakerState::Open);
+
+        // Fails fast until the probe interval passes, on the injected clock.
+        store.fail_with(None);
+        assert_eq!(save(&breaker), Err(Error::Internal("persistence unavailable".into())));
+        clock.advance(100);
+        assert_eq!(save(&breaker), Ok(0));
+        assert_eq!(breaker.state().unwrap(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn a_failed_probe_reopens_the_circuit() {
+        let (breaker, store, clock) = breaker();
+        store.fail_with(Some(Error::Internal("disk".into())));
+        assert!(save(&breaker).is_err());
+        assert!(save(&breaker).is_err());
+        clock.advance(100);
+        assert_eq!(save(&breaker), Err(Error::Internal("disk".into())));
+        assert_eq!(breaker.state().unwrap(), BreakerState::Open);
+    }
+
+    #[test]
+    fn a_read_only_probe_reopens_the_circuit() {
+        let (breaker, store, clock) = breaker();
+        store.fail_with(Some(Error::Internal("disk".into())));
+        assert!(save(&breaker).is_err());
+        assert!(save(&breaker).is_err());
+
+        clock.advance(100);
+        store.fail_with(Some(Error::PersistenceReadOnly("read-only".into())));
+        assert!(matches!(save(&breaker), Err(Error::PersistenceReadOnly(_))));
+        assert_eq!(breaker.state().unwrap(), BreakerState::Open);
+
+        // The next probe goes through once the store recovers.
+        clock.advance(100);
+        store.fail_with(None);
+        assert_eq!(save(&breaker), Ok(0));
+        assert_eq!(breaker.state().unwrap(), BreakerState::Closed);
+    }
+
+    #[test]
+    fn read_only_failures_dont_open_a_closed_circuit() {
+        let (breaker, store, _) = breaker();
+        store.fail_with(Some(Error::PersistenceReadOnly("read-only".into())));
+        for _ in 0..5 {
+            assert!(matches!(save(&breaker), Err(Error::PersistenceReadOnly(_))));
+        }
+        assert_eq!(breaker.state().unwrap(), BreakerState::Closed);
+    }
+}
@@ -0,0 +1,29 @@
+use crate::error::{Error, Result};
+
+/// Adds two integers, returning `Error::Internal` on overflow instead of
+/// panicking in debug builds or wrapping in release builds. For timestamp,
+/// id and epoch arithmetic, where a wrapped value would silently break
+/// monotonicity.
+pub fn add_or_err(a: u64, b: u64) -> Result<u64> {
+    a.checked_add(b).ok_or_else(|| Error::Internal(format!("Integer overflow adding {} to {}", b, a)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adds_up_to_u64_max() {
+        assert_eq!(add_or_err(1, 2), Ok(3));
+        assert_eq!(add_or_err(u64::MAX - 1, 1), Ok(u64::MAX));
+        assert_eq!(add_or_err(u64::MAX, 0), Ok(u64::MAX));
+        assert_eq!(add_or_err(0, u64::MAX), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn overflow_past_u64_max_is_an_error() {
+        assert!(matches!(add_or_err(u64::MAX, 1), Err(Error::Internal(_))));
+        assert!(matches!(add_or_err(1, u64::MAX), Err(Error::Internal(_))));
+        assert!(matches!(add_or_err(u64::MAX, u64::MAX), Err(Error::Internal(_))));
+    }
+}
@@ -0,0 +1,289 @@
+use log::error;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::snapshot::PersistenceFormat;
+
+/// A persisted TSO checkpoint.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The watermark, i.e. the end of the last reserved timestamp window. No
+    /// timestamp at or above it has ever been handed out, so a restarted
+    /// server can safely resume from it.
+    pub window_end: u64,
+    /// The last timestamp actually handed out as of the checkpoint, if any.
+    /// This is for auditing only: it is accurate as of a clean shutdown, but
+    /// lags behind during operation, so recovery must never resume from it.
+    pub last_allocated: Option<u64>,
+}
+
+/// A durable store for the TSO checkpoint.
+pub trait CheckpointStore: Send + Sync {
+    /// Loads the persisted checkpoint, or None if none was ever persisted.
+    fn load(&self) -> Result<Option<Checkpoint>>;
+
+    /// Durably persists the checkpoint, returning the number of bytes written
+    /// to storage. Must not return before it is synced.
+    fn save(&self, checkpoint: &Checkpoint) -> Result<u64>;
+
+    /// Returns true if the store has turned out to be read-only, and fails
+    /// all writes with `Error::PersistenceReadOnly`.
+    fn read_only(&self) -> bool {
+        false
+    }
+}
+
+/// An in-memory checkpoint store, for ephemeral servers and tests.
+#[derive(Default)]
+pub struct MemoryCheckpoint {
+    checkpoint: Mutex<Option<Checkpoint>>,
+}
+
+impl MemoryCheckpoint {
+    /// Creates a new, empty in-memory checkpoint store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl CheckpointStore for MemoryCheckpoint {
+    fn load(&self) -> Result<Option<Checkpoint>> {
+        Ok(*self.checkpoint.lock()?)
+    }
+
+    fn save(&self, checkpoint: &Checkpoint) -> Result<u64> {
+        *self.checkpoint.lock()? = Some(*checkpoint);
+        Ok(0)
+    }
+}
+
+/// How checkpoint writes are synced to disk.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+pub enum SyncPolicy {
+    /// fsync the file and its directory. The safe default.
+    Full,
+    /// fdatasync the file, skipping metadata that isn't needed to read it
+    /// back. Slightly cheaper, and still safe on most filesystems.
+    Data,
+    /// Don't sync at all, relying on the OS to write back eventually. Only
+    /// for tests: on power loss, the persisted watermark may be lost or stale,
+    /// and the TSO may hand out timestamps it has handed out before.
+    None,
+}
+
+/// A checkpoint store keeping the checkpoint in a file. Writes go to a
+/// temporary file which is synced and then atomically renamed into place.
+/// The checkpoint is written in the configured format, and read back in
+/// either, so the format can be changed across restarts.
+///
+/// A write failing because the file system is read-only, full, or denies
+/// access makes the store read-only until restarted: all further writes fail
+/// right away with `Error::PersistenceReadOnly`, so the TSO stops serving
+/// rather than handing out timestamps it can't persist.
+pub struct FileCheckpoint {
+    path: PathBuf,
+    sync_policy: SyncPolicy,
+    format: PersistenceFormat,
+    /// The write error that made the store read-only, if any.
+    read_only: Mutex<Option<Error>>,
+}
+
+impl FileCheckpoint {
+    /// Creates a checkpoint store backed by the given file path, syncing
+    /// writes with `SyncPolicy::Full` and writing them with bincode.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            sync_policy: SyncPolicy::Full,
+            format: PersistenceFormat::Bincode,
+            read_only: Mutex::new(None),
+        }
+    }
+
+    /// Sets the sync policy for checkpoint writes.
+    pub fn with_sync_policy(mut self, sync_policy: SyncPolicy) -> Self {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets the serialization format for checkpoint writes.
+    pub fn with_format(mut self, format: PersistenceFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Writes the checkpoint to a temporary file, and renames it into place.
+    fn write(&self, checkpoint: &Checkpoint) -> std::io::Result<u64> {
+        // Append to the full file name rather than replacing the extension, so
+        // checkpoints that only differ in their extension, e.g. those of
+        // keyspace TSOs, don't share a temporary file.
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        let bytes = match self.format {
+            PersistenceFormat::Bincode => bincode::serialize(checkpoint).map_err(std::io::Error::other)?,
+            PersistenceFormat::Json => serde_json::to_vec(checkpoint)?,
+        };
+        file.write_all(&bytes)?;
+        match self.sync_policy {
+            SyncPolicy::Full => file.sync_all()?,
+            SyncPolicy::Data => file.sync_data()?,
+            SyncPolicy::None => {}
+        }
+        fs::rename(&tmp, &self.path)?;
+        if self.sync_policy == SyncPolicy::Full {
+            // Sync the directory too, so the rename itself is durable.
+            let dir = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(bytes.len() as u64)
+    }
+}
+
+impl CheckpointStore for FileCheckpoint {
+    fn load(&self) -> Result<Option<Checkpoint>> {
+        match fs::read(&self.path) {
+            // A bincode checkpoint is never a valid JSON object, so trying JSON
+            // first can't misparse one.
+            Ok(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(checkpoint) => Ok(Some(checkpoint)),
+                Err(_) => Ok(Some(bincode::deserialize(&bytes)?)),
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save(&self, checkpoint: &Checkpoint) -> Result<u64> {
+        let mut read_only = self.read_only.lock()?;
+        if let Some(err) = &*read_only {
+            return Err(err.clone());
+        }
+        match self.write(checkpoint) {
+            Err(err) if is_read_only(&err) => {
+                error!(
+                    "Checkpoint {} can't be written ({}), refusing to serve timestamps",
+                    self.path.display(),
+                    err
+                );
+                let err = Error::PersistenceReadOnly(format!(
+                    "persistence read-only: checkpoint {} can't be written: {}",
+                    self.path.display(),
+                    err
+                ));
+                *read_only = Some(err.clone());
+                Err(err)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only.lock().map_or(true, |read_only| read_only.is_some())
+    }
+}
+
+/// Returns true if a write error means the storage can't be written at all,
+/// as opposed to a transient failure.
+fn is_read_only(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::PermissionDenied
+            | ErrorKind::ReadOnlyFilesystem
+            | ErrorKind::StorageFull
+            | ErrorKind::QuotaExceeded
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a fresh, empty scratch directory for a test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("featherpd-checkpoint-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn checkpoints_round_trip_in_either_format() {
+        let dir = scratch_dir("formats");
+        let checkpoint = Checkpoint { window_end: 2_000, last_allocated: Some(1_234) };
+        for format in [PersistenceFormat::Bincode, PersistenceFormat::Json] {
+            let store = FileCheckpoint::new(dir.join(format!("{:?}", format))).with_format(format);
+            assert_eq!(store.load().unwrap(), None);
+            store.save(&checkpoint).unwrap();
+            assert_eq!(store.load().unwrap(), Some(checkpoint));
+        }
+        let json = fs::read_to_string(dir.join("Json")).unwrap();
+        assert!(json.contains("\"window_end\":2000"), "{}", json);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn checkpoints_round_trip_with_each_sync_policy() {
+        let dir = scratch_dir("sync");
+        for (i, sync_policy) in [SyncPolicy::Full, SyncPolicy::Data, SyncPolicy::None].into_iter().enumerate()
+        {
+            let path = dir.join(format!("{:?}", sync_policy));
+            let store = FileCheckpoint::new(&path).with_sync_policy(sync_policy);
+            assert_eq!(store.load().unwrap(), None);
+            for window_end in [1_000, 2_000] {
+                let checkpoint = Checkpoint { window_end: window_end + i as u64, last_allocated: Some(999) };
+                store.save(&checkpoint).unwrap();
+                assert_eq!(FileCheckpoint::new(&path).load().unwrap(), Some(checkpoint));
+            }
+        }
+        // Only the checkpoints are left, without their temporary files.
+        let mut names: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, ["Data", "Full", "None"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn checkpoints_load_after_switching_formats() {
+        let dir = scratch_dir("switch");
+        let path = dir.join("tso");
+        let first = Checkpoint { window_end: 1_000, last_allocated: None };
+        FileCheckpoint::new(&path).with_format(PersistenceFormat::Bincode).save(&first).unwrap();
+        let store = FileCheckpoint::new(&path).with_format(PersistenceFormat::Json);
+        assert_eq!(store.load().unwrap(), Some(first));
+
+        let second = Checkpoint { window_end: 2_000, last_allocated: Some(1_500) };
+        store.save(&second).unwrap();
+        assert_eq!(FileCheckpoint::new(&path).load().unwrap(), Some(second));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn write_errors_are_told_apart() {
+        for kind in [ErrorKind::PermissionDenied, ErrorKind::ReadOnlyFilesystem, ErrorKind::StorageFull] {
+            assert!(is_read_only(&kind.into()), "{:?}", kind);
+        }
+        for kind in [ErrorKind::Interrupted, ErrorKind::TimedOut, ErrorKind::Other] {
+            assert!(!is_read_only(&kind.into()), "{:?}", kind);
+        }
+    }
+
+    // sysfs refuses to create files, even for root.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn an_unwritable_checkpoint_latches_read_only() {
+        let store = FileCheckpoint::new("/sys/featherpd-checkpoint-test");
+        assert!(!store.read_only());
+        assert_eq!(store.load().unwrap(), None);
+
+        let checkpoint = Checkpoint { window_end: 1_000, last_allocated: None };
+        let err = store.save(&checkpoint).unwrap_err();
+        assert!(matches!(&err, Error::PersistenceReadOnly(_)), "{:?}", err);
+        assert!(store.read_only());
+        assert_eq!(store.save(&checkpoint), Err(err));
+    }
+}
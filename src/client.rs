@@ -0,0 +1,295 @@
+use log::warn;
+use std::future::Future;
+use std::time::Duration;
+use tonic::transport::Channel;
+
+use crate::backoff::Backoff;
+use crate::config::ClientConfig;
+use crate::error::{Error, Result};
+use crate::proto::placement_driver::{PlacementDriverClient, TsoRequest};
+use crate::transport::{connect, Address};
+
+/// The default delay before the first retry of a request, in milliseconds.
+pub const DEFAULT_RETRY_INITIAL_MS: u64 = 50;
+
+/// The default maximum delay between retries of a request, in milliseconds.
+pub const DEFAULT_RETRY_MAX_MS: u64 = 2_000;
+
+/// The default maximum number of retries of a single request.
+pub const DEFAULT_MAX_RETRIES: u32 = 10;
+
+/// The default retries credited to the retry budget per successful request.
+pub const DEFAULT_RETRY_BUDGET_RATIO: f64 = 0.1;
+
+/// The default maximum retries the retry budget saves up.
+pub const DEFAULT_RETRY_BUDGET_MAX: u32 = 10;
+
+/// A PD client retrying requests that failed with `Error::NotLeader` or
+/// `Error::Unavailable`, e.g. during a leader change or while the PD is
+/// bootstrapping, after a jittered exponential backoff. Other errors are
+/// returned right away. Retries are limited by a retry budget shared by all
+/// requests, see `RetryBudget`, so that during an outage the client fails
+/// fast rather than amplifying the load on the PD.
+pub struct PdClient {
+    /// The underlying gRPC client.
+    client: PlacementDriverClient<Channel>,
+    /// The backoff between retries.
+    backoff: Backoff,
+    /// The maximum number of retries of a single request.
+    max_retries: u32,
+}
+
+impl PdClient {
+    /// Connects to the PD at the given address, see `transport::connect()`.
+    pub async fn connect(addr: &Address, config: &ClientConfig) -> Result<Self> {
+        Ok(Self::new(connect(addr, config).await?))
+    }
+
+    /// Wraps a connected gRPC client.
+    pub fn new(client: PlacementDriverClient<Channel>) -> Self {
+        Self {
+            client,
+            backoff: Backoff::new(
+                Duration::from_millis(DEFAULT_RETRY_INITIAL_MS),
+                Duration::from_millis(DEFAULT_RETRY_MAX_MS),
+                2.0,
+                0.2,
+            )
+            .with_retry_budget(DEFAULT_RETRY_BUDGET_RATIO, DEFAULT_RETRY_BUDGET_MAX),
+            max_retries: DEFAULT_MAX_RETRIES,
+        }
+    }
+
+    /// Sets the backoff between retries, along with its retry budget, if
+    /// any. Without one, retries are only limited per request.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets the retry budget, crediting `ratio` retries per successful
+    /// request and saving up at most `max_retries`, see `RetryBudget::new()`.
+    pub fn with_retry_budget(mut self, ratio: f64, max_retries: u32) -> Self {
+        self.backoff = self.backoff.with_retry_budget(ratio, max_retries);
+        self
+    }
+
+    /// Sets the maximum number of retries of a single request.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Sends a request, retrying it as long as it fails with a retryable
+    /// error and retries are left, both for the request and in the budget. The request is built per attempt, from a
+    /// clone of the underlying gRPC client.
+    pub async fn call<T, F, Fut>(&mut self, mut request: F) -> Result<T>
+    where
+        F: FnMut(PlacementDriverClient<Channel>) -> Fut,
+        Fut: Future<Output = std::result::Result<tonic::Response<T>, tonic::Status>>,
+    {
+        let mut retries = 0;
+        loop {
+            let status = match request(self.client.clone()).await {
+                Ok(reply) => {
+                    self.backoff.reset();
+                    return Ok(reply.into_inner());
+                }
+                Err(status) => status,
+            };
+            let retryable = status.code() == tonic::Code::Unavailable;
+            let err = Error::from(status);
+            if !(retryable || matches!(err, Error::NotLeader | Error::Unavailable(_))) {
+                return Err(err);
+            }
+            let delay = match self.backoff.next() {
+                Some(delay) if retries < self.max_retries => delay,
+                _ => return Err(err),
+            };
+            retries += 1;
+            warn!("PD request failed, retrying in {:?}: {}", delay, err);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Allocates a timestamp.
+    pub async fn get_timestamp(&mut self) -> Result<u64> {
+        let reply = self
+            .call(|mut client| async move {
+                client.get_timestamp(TsoRequest { count: 1, ..Default::default() }).await
+            })
+            .await?;
+        Ok(reply.timestamp)
+    }
+}
+
+/// A `PdClient` for synchronous code, e.g. command-line tools, owning the
+/// runtime its requests run on. It must not be used from within another
+/// runtime, where blocking on requests panics.
+pub struct BlockingPdClient {
+    /// The runtime requests run on.
+    runtime: tokio::runtime::Runtime,
+    /// The underlying asynchronous client.
+    client: PdClient,
+}
+
+impl BlockingPdClient {
+    /// Connects to the PD at the given address, see `PdClient::connect()`.
+    pub fn connect(addr: &Address, config: &ClientConfig) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let client = runtime.block_on(PdClient::connect(addr, config))?;
+        Ok(Self { runtime, client })
+    }
+
+    /// Sends a request, see `PdClient::call()`.
+    pub fn call<T, F, Fut>(&mut self, request: F) -> Result<T>
+    where
+        F: FnMut(PlacementDriverClient<Channel>) -> Fut,
+        Fut: Future<Output = std::result::Result<tonic::Response<T>, tonic::Status>>,
+    {
+        self.runtime.block_on(self.client.call(request))
+    }
+
+    /// Allocates a timestamp.
+    pub fn get_timestamp(&mut self) -> Result<u64> {
+        self.runtime.block_on(self.client.get_timestamp())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::server::FeatherPD;
+    use crate::transport::spawn_serve;
+
+    /// Returns a fresh UNIX domain socket address for a test.
+    fn socket(name: &str) -> Address {
+        let path =
+            std::env::temp_dir().join(format!("featherpd-client-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Address::Unix(path)
+    }
+
+    /// Returns a backoff short enough for tests.
+    fn fast_backoff() -> Backoff {
+        Backoff::new(Duration::from_millis(1), Duration::from_millis(5), 2.0, 0.0)
+    }
+
+    #[tokio::test]
+    async fn retries_until_the_pd_serves() {
+        let addr = socket("retries");
+        let pd = FeatherPD::from_config(&Config::default()).unwrap();
+        let server = spawn_serve(pd.clone(), &addr, std::future::pending()).unwrap();
+        let mut client = PdClient::connect(&addr, &ClientConfig::default())
+            .await
+            .unwrap()
+            .with_backoff(fast_backoff())
+            .with_max_retries(1_000);
+
+        let recovery = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            pd.recover().await
+        });
+
+        let mut attempts = 0;
+        let reply = client
+            .call(|mut client| {
+                attempts += 1;
+                async move { client.get_timestamp(TsoRequest { count: 1, ..Default::default() }).await }
+            })
+            .await
+            .unwrap();
+        assert!(reply.timestamp > 0);
+        assert!(attempts > 1);
+        recovery.await.unwrap().unwrap();
+        server.abort();
+    }
+
+    #[test]
+    fn the_blocking_client_serves_requests_on_its_own_runtime() {
+        let addr = socket("blocking");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let pd = FeatherPD::from_config(&Config::default()).unwrap();
+        runtime.block_on(pd.recover()).unwrap();
+        let server = {
+            let _guard = runtime.enter();
+            spawn_serve(pd, &addr, std::future::pending()).unwrap()
+        };
+        let mut client = BlockingPdClient::connect(&addr, &ClientConfig::default()).unwrap();
+
+        let first = client.get_timestamp().unwrap();
+        let reply = client
+            .call(|mut client| async move {
+                client.get_timestamp(TsoRequest { count: 1, ..Default::default() }).await
+            })
+            .unwrap();
+        assert!(reply.timestamp > first);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_retries() {
+        let addr = socket("gives-up");
+        let pd = FeatherPD::from_config(&Config::default()).unwrap();
+        let server = spawn_serve(pd, &addr, std::future::pending()).unwrap();
+        let mut client = PdClient::connect(&addr, &ClientConfig::default())
+            .await
+            .unwrap()
+            .with_backoff(fast_backoff())
+            .with_max_retries(2);
+
+        let mut attempts = 0;
+        let result = client
+            .call(|mut client| {
+                attempts += 1;
+                async move { client.get_timestamp(TsoRequest { count: 1, ..Default::default() }).await }
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Unavailable(_))));
+        assert_eq!(attempts, 3);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn a_storm_of_failures_exhausts_the_retry_budget() {
+        let addr = socket("budget");
+        let pd = FeatherPD::from_config(&Config::default()).unwrap();
+        let server = spawn_serve(pd.clone(), &addr, std::future::pending()).unwrap();
+        let mut client = PdClient::connect(&addr, &ClientConfig::default())
+            .await
+            .unwrap()
+            .with_backoff(fast_backoff())
+            .with_max_retries(1_000)
+            .with_retry_budget(0.5, 3);
+
+        // The first request spends the whole budget, the rest fail fast.
+        let mut attempts = 0;
+        for _ in 0..100 {
+            let result = client
+                .call(|mut client| {
+                    attempts += 1;
+                    async move { client.get_timestamp(TsoRequest { count: 1, ..Default::default() }).await }
+                })
+                .await;
+            assert!(matches!(result, Err(Error::Unavailable(_))));
+        }
+        assert_eq!(attempts, 100 + 3);
+
+        // Successes credit the budget again.
+        pd.recover().await.unwrap();
+        client.get_timestamp().await.unwrap();
+        client.get_timestamp().await.unwrap();
+        pd.begin_shutdown().unwrap();
+        let mut attempts = 0;
+        let result = client
+            .call(|mut client| {
+                attempts += 1;
+                async move { client.get_timestamp(TsoRequest { count: 1, ..Default::default() }).await }
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Unavailable(_))));
+        assert_eq!(attempts, 2);
+        server.abort();
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+use crate::error::Result;
+
+/// The waiters of each running computation, by key.
+type Waiters<K, V> = Arc<Mutex<HashMap<K, Vec<oneshot::Sender<Result<V>>>>>>;
+
+/// Coalesces concurrent identical computations: while one is running for a
+/// key, callers with the same key wait for its result instead of repeating
+/// it, e.g. for lookups of a hot key. Results aren't cached: a call starting
+/// after the computation finished runs it again.
+pub struct SingleFlight<K, V> {
+    /// The running computations.
+    in_flight: Waiters<K, V>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> SingleFlight<K, V> {
+    /// Creates a coalescing layer with nothing in flight.
+    pub fn new() -> Self {
+        Self { in_flight: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Runs the computation for the key, or waits for the one already
+    /// running and returns its result, error included. If the running one is
+    /// cancelled, a waiter runs it instead.
+    pub async fn run<F, Fut>(&self, key: K, compute: F) -> Result<V>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<V>>,
+    {
+        loop {
+            let rx = {
+                let mut in_flight = self.in_flight.lock()?;
+                match in_flight.get_mut(&key) {
+                    Some(waiters) => {
+                        let (tx, rx) = oneshot::channel();
+                        waiters.push(tx);
+                        rx
+                    }
+                    None => {
+                        in_flight.insert(key.clone(), Vec::new());
+                        break;
+                    }
+                }
+            };
+            // The sender is only dropped without a result if the running
+            // computation was cancelled, in which case take over.
+            if let Ok(result) = rx.await {
+                return result;
+            }
+        }
+        let mut guard = InFlight { in_flight: self.in_flight.clone(), key, finished: false };
+        let result = compute().await;
+        for waiter in guard.finish()? {
+            let _ = waiter.send(result.clone());
+        }
+        result
+    }
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A running computation's entry in the in-flight map, removed when
+/// dropped, so that a cancelled computation doesn't leave its waiters
+/// hanging.
+struct InFlight<K: Eq + Hash, V> {
+    in_flight: Waiters<K, V>,
+    key: K,
+    /// Whether the entry was already removed by `finish()`. A later entry
+    /// for the same key belongs to another computation.
+    finished: bool,
+}
+
+impl<K: Eq + Hash, V> InFlight<K, V> {
+    /// Removes the entry, returning its waiters.
+    fn finish(&mut self) -> Result<Vec<oneshot::Sender<Result<V>>>> {
+        self.finished = true;
+        Ok(self.in_flight.lock()?.remove(&self.key).unwrap_or_default())
+    }
+}
+
+impl<K: Eq + Hash, V> Drop for InFlight<K, V> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        if let Ok(mut in_flight) = self.in_flight.lock() {
+            in_flight.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::Semaphore;
+
+    /// Runs `count` concurrent calls for the same key, whose computations
+    /// return `result` once let through a gate, and returns the calls'
+    /// results with the number of computations run.
+    async fn run_concurrently(count: usize, result: Result<u64>) -> (Vec<Result<u64>>, usize) {
+        let flight = Arc::new(SingleFlight::new());
+        let gate = Arc::new(Semaphore::new(0));
+        let runs = Arc::new(AtomicUsize::new(0));
+        let mut tasks = Vec::new();
+        for _ in 0..count {
+            let (flight, gate, runs, result) = (flight.clone(), gate.clone(), runs.clone(), result.clone());
+            tasks.push(tokio::spawn(async move {
+                let compute = || async {
+                    runs.fetch_add(1, Ordering::SeqCst);
+                    let _permit = gate.acquire().await.unwrap();
+                    result.clone()
+                };
+                flight.run("key", compute).await
+            }));
+            // Let the call start before the next one.
+            tokio::task::yield_now().await;
+        }
+        gate.add_permits(count);
+        let mut results = Vec::new();
+        for task in tasks {
+            results.push(task.await.unwrap());
+        }
+        (results, runs.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_share_one_computation() {
+        let (results, runs) = run_concurrently(50, Ok(7)).await;
+        assert_eq!(runs, 1);
+        assert!(results.iter().all(|result| *result == Ok(7)));
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_share_an_error() {
+        let (results, runs) = run_concurrently(10, Err(Error::Internal("boom".into()))).await;
+        assert_eq!(runs, 1);
+        assert!(results.iter().all(|result| *result == Err(Error::Internal("boom".into()))));
+    }
+
+    #[tokio::test]
+    async fn results_are_not_cached() {
+        let flight = SingleFlight::new();
+        let runs = AtomicUsize::new(0);
+        for _ in 0..3 {
+            let compute = || async { Ok(runs.fetch_add(1, Ordering::SeqCst)) };
+            flight.run(1, compute).await.unwrap();
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn a_waiter_takes_over_a_cancelled_computation() {
+        let flight = Arc::new(SingleFlight::new());
+        let gate = Arc::new(Semaphore::new(0));
+        let first = tokio::spawn({
+            let (flight, gate) = (flight.clone(), gate.clone());
+            async move {
+                let compute = || async {
+                    let _permit = gate.acquire().await.unwrap();
+                    Ok(1)
+                };
+                flight.run("key", compute).await
+            }
+        });
+        tokio::task::yield_now().await;
+        let second = tokio::spawn({
+            let flight = flight.clone();
+            async move { flight.run("key", || async { Ok(2) }).await }
+        });
+        tokio::task::yield_now().await;
+
+        first.abort();
+        assert!(first.await.unwrap_err().is_cancelled());
+        assert_eq!(second.await.unwrap(), Ok(2));
+        assert!(flight.in_flight.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,69 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The default number of stale versions above which a region should be
+/// compacted.
+pub const DEFAULT_COMPACTION_STALE_VERSIONS: u64 = 100_000;
+
+/// The default number of regions recommended for compaction at once.
+pub const DEFAULT_MAX_CONCURRENT_COMPACTIONS: usize = 4;
+
+/// A region recommended for compaction.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CompactionCandidate {
+    /// The region id.
+    pub region_id: u64,
+    /// The number of stale versions in the region, as last reported.
+    pub stale_versions: u64,
+}
+
+/// Recommends regions for compaction based on the stale versions reported
+/// in region heartbeats. The GC safe point only tells stores what they may
+/// collect; this coordinates when they do so, recommending at most a fixed
+/// number of regions at once so the whole cluster doesn't compact at the
+/// same time.
+pub struct CompactionHints {
+    /// The number of stale versions above which a region should be compacted.
+    threshold: u64,
+    /// The maximum number of regions recommended for compaction at once.
+    max_concurrent: usize,
+    /// The regions above the threshold, by region id.
+    candidates: HashMap<u64, u64>,
+}
+
+impl CompactionHints {
+    /// Creates a new compaction hint publisher.
+    pub fn new(threshold: u64, max_concurrent: usize) -> Self {
+        Self { threshold, max_concurrent, candidates: HashMap::new() }
+    }
+
+    /// Records the stale versions of a region, returning true if the region
+    /// should compact now, i.e. if it is among the candidates with the most
+    /// stale versions.
+    pub fn observe(&mut self, region_id: u64, stale_versions: u64) -> bool {
+        if stale_versions <= self.threshold {
+            self.candidates.remove(&region_id);
+            return false;
+        }
+        self.candidates.insert(region_id, stale_versions);
+        self.candidates().iter().any(|c| c.region_id == region_id)
+    }
+
+    /// Forgets a region, e.g. after it was removed from the routing table.
+    pub fn remove(&mut self, region_id: u64) {
+        self.candidates.remove(&region_id);
+    }
+
+    /// Returns the regions currently recommended for compaction, most stale
+    /// versions first.
+    pub fn candidates(&self) -> Vec<CompactionCandidate> {
+        let mut candidates: Vec<CompactionCandidate> = self
+            .candidates
+            .iter()
+            .map(|(&region_id, &stale_versions)| CompactionCandidate { region_id, stale_versions })
+            .collect();
+        candidates.sort_by_key(|c| (std::cmp::Reverse(c.stale_versions), c.region_id));
+        candidates.truncate(self.max_concurrent);
+        candidates
+    }
+}
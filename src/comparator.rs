@@ -0,0 +1,84 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::Arc;
+
+/// An ordering of keys, for storage engines that don't order keys by raw
+/// bytes, e.g. because of a custom collation. Routing is only correct if it
+/// matches the engine's ordering.
+pub trait KeyComparator: Send + Sync {
+    /// Compares two non-empty keys.
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// The default, lexicographic byte ordering.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lexicographic;
+
+impl KeyComparator for Lexicographic {
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Compares two keys or start keys. The empty key is the left sentinel, and
+/// sorts before every other key regardless of the comparator.
+pub fn compare_keys(comparator: &dyn KeyComparator, a: &[u8], b: &[u8]) -> Ordering {
+    match (a.is_empty(), b.is_empty()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => comparator.compare(a, b),
+    }
+}
+
+/// Returns true if a key or start key is below an end key, where an empty
+/// end key is the right sentinel, i.e. positive infinity.
+pub fn key_before(comparator: &dyn KeyComparator, key: &[u8], end_key: &[u8]) -> bool {
+    end_key.is_empty() || compare_keys(comparator, key, end_key) == Ordering::Less
+}
+
+/// A key ordered by a comparator, as used to key the routing table's region
+/// maps.
+#[derive(Clone)]
+pub struct OrderedKey {
+    key: Vec<u8>,
+    comparator: Arc<dyn KeyComparator>,
+}
+
+impl OrderedKey {
+    /// Wraps a key, ordering it by the given comparator.
+    pub fn new(key: Vec<u8>, comparator: Arc<dyn KeyComparator>) -> Self {
+        Self { key, comparator }
+    }
+
+    /// Returns the raw key.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl fmt::Debug for OrderedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.key.fmt(f)
+    }
+}
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedKey {}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_keys(self.comparator.as_ref(), &self.key, &other.key)
+    }
+}
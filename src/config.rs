@@ -0,0 +1,534 @@
+use serde_derive::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::audit::DEFAULT_AUDIT_LOG_MAX_BYTES;
+use crate::breaker::{DEFAULT_BREAKER_FAILURE_THRESHOLD, DEFAULT_BREAKER_PROBE_INTERVAL_MS};
+use crate::checkpoint::SyncPolicy;
+use crate::error::Result;
+use crate::gossip::DEFAULT_GOSSIP_MAX_STEP;
+use crate::lease::DEFAULT_LEASE_RENEW_MARGIN_MS;
+use crate::resolver::{DEFAULT_STALE_CACHE_CAPACITY, DEFAULT_VIRTUAL_NODES};
+use crate::snapshot::{PersistenceFormat, DEFAULT_CHANGE_LOG_CAPACITY, DEFAULT_CHANGE_LOG_MAX_AGE_MS};
+use crate::store::{DEFAULT_DRAIN_TIMEOUT_MS, DEFAULT_TOMBSTONE_RETENTION_MS};
+use crate::transfer::DEFAULT_TRANSFER_TIMEOUT_MS;
+use crate::tso::{DEFAULT_FIRST_TS, DEFAULT_WINDOW_SIZE};
+
+/// The FeatherPD server configuration. All keys are optional and fall back to
+/// their defaults.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The timestamp oracle configuration.
+    pub tso: TsoConfig,
+    /// The region configuration.
+    pub region: RegionConfig,
+    /// The store configuration.
+    pub store: StoreConfig,
+    /// The replica placement configuration.
+    pub placement: PlacementConfig,
+    /// The gRPC server configuration.
+    pub server: ServerConfig,
+    /// The gRPC client configuration.
+    pub client: ClientConfig,
+    /// The watermark gossip configuration.
+    pub gossip: GossipConfig,
+    /// The persistence configuration.
+    pub persistence: PersistenceConfig,
+    /// The change log configuration.
+    pub changelog: ChangeLogConfig,
+    /// The data-location resolver configuration.
+    pub resolver: ResolverConfig,
+    /// The data-location lookup configuration.
+    pub dataloc: DataLocConfig,
+    /// The cluster bootstrap configuration.
+    pub cluster: ClusterConfig,
+    /// The region and store id allocation configuration.
+    pub id: IdConfig,
+    /// Per-keyspace configuration, for keyspaces that need any.
+    pub keyspaces: Vec<KeyspaceConfig>,
+}
+
+impl Config {
+    /// Loads the configuration from a file, in any format supported by the
+    /// config crate (e.g. TOML or YAML).
+    pub fn from_file(path: &str) -> Result<Self> {
+        Ok(config::Config::builder().add_source(config::File::with_name(path)).build()?.try_deserialize()?)
+    }
+
+    /// Loads the configuration from environment variables with the given
+    /// prefix, using `__` as the separator, e.g. `FEATHERPD__TSO__WINDOW_SIZE`
+    /// for `tso.window_size` with the prefix `FEATHERPD`.
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        Ok(config::Config::builder().add_source(Self::env_source(prefix)).build()?.try_deserialize()?)
+    }
+
+    /// Loads the configuration from a file, overridden by environment
+    /// variables with the given prefix as for `from_env()`.
+    pub fn from_file_and_env(path: &str, prefix: &str) -> Result<Self> {
+        Ok(config::Config::builder()
+            .add_source(config::File::with_name(path))
+            .add_source(Self::env_source(prefix))
+            .build()?
+            .try_deserialize()?)
+    }
+
+    /// Returns the environment variable source for the given prefix.
+    fn env_source(prefix: &str) -> config::Environment {
+        config::Environment::with_prefix(prefix).prefix_separator("__").separator("__").try_parsing(true)
+    }
+}
+
+/// The `tso` section of the configuration.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct TsoConfig {
+    /// The number of timestamps reserved by each persisted window.
+    pub window_size: u64,
+    /// The checkpoint file path. If unset, the watermark is kept in memory
+    /// only, and timestamps may regress across restarts.
+    pub checkpoint_path: Option<PathBuf>,
+    /// How checkpoint writes are synced to disk.
+    pub sync_policy: SyncPolicy,
+    /// How long to wait after taking over before serving timestamps, in
+    /// milliseconds. Must be at least as long as the maximum lease of a
+    /// previous leader, so that it can no longer be serving timestamps.
+    pub leader_grace_ms: u64,
+    /// The first timestamp handed out by a fresh cluster, either 0 or 1.
+    /// Has no effect once a checkpoint has been persisted.
+    pub first_ts: u64,
+    /// The maximum number of timestamp requests served at once, or 0 for no
+    /// limit. Excess requests queue up, high-priority ones first, and are
+    /// shared between keyspaces by their `tso_weight`.
+    pub max_inflight: usize,
+    /// The path of the audit log of issued timestamp windows. If unset, no
+    /// audit log is kept.
+    pub audit_log_path: Option<PathBuf>,
+    /// The size in bytes at which the audit log is rotated.
+    pub audit_log_max_bytes: u64,
+    /// The number of consecutive checkpoint write failures after which
+    /// allocations fail fast, or 0 to never fail fast.
+    pub breaker_failure_threshold: u32,
+    /// How often to probe a failing checkpoint store for recovery while
+    /// allocations fail fast, in milliseconds.
+    pub breaker_probe_interval_ms: u64,
+    /// The leader lease duration, in milliseconds, or 0 for no lease. Once
+    /// the lease expires without being renewed, timestamps are refused with
+    /// `Error::NotLeader`. Peers' `leader_grace_ms` must be at least this
+    /// long.
+    pub lease_ms: u64,
+    /// How long before the lease expires it is renewed, in milliseconds, so
+    /// that a briefly stalled renewal doesn't make the leader step down.
+    /// Must be below `lease_ms`.
+    pub lease_renew_margin_ms: u64,
+}
+
+impl Default for TsoConfig {
+    fn default() -> Self {
+        Self {
+            window_size: DEFAULT_WINDOW_SIZE,
+            checkpoint_path: None,
+            sync_policy: SyncPolicy::Full,
+            leader_grace_ms: 0,
+            first_ts: DEFAULT_FIRST_TS,
+            max_inflight: 0,
+            audit_log_path: None,
+            audit_log_max_bytes: DEFAULT_AUDIT_LOG_MAX_BYTES,
+            breaker_failure_threshold: DEFAULT_BREAKER_FAILURE_THRESHOLD,
+            breaker_probe_interval_ms: DEFAULT_BREAKER_PROBE_INTERVAL_MS,
+            lease_ms: 0,
+            lease_renew_margin_ms: DEFAULT_LEASE_RENEW_MARGIN_MS,
+        }
+    }
+}
+
+/// The `region` section of the configuration.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RegionConfig {
+    /// The size in bytes above which a region should be split.
+    pub max_size: u64,
+    /// The number of replicas to place for each region.
+    pub replicas: u32,
+    /// The maximum number of regions across all keyspaces, or 0 for no
+    /// limit. Splits beyond it are declined.
+    pub max_count: usize,
+    /// The time a leader transfer waits for the new leader to confirm before
+    /// it is aborted, in milliseconds.
+    pub transfer_timeout_ms: u64,
+    /// What to do when a region is created or split while fewer stores are
+    /// live than `replicas`.
+    pub undersized_policy: UndersizedPolicy,
+    /// Whether a region heartbeat identical to the region's previous one
+    /// skips reconciling it with the routing table, only recording its load.
+    /// Cuts PD CPU in large, stable clusters, where most heartbeats carry no
+    /// changes.
+    pub skip_unchanged_heartbeats: bool,
+}
+
+impl Default for RegionConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 96 * 1024 * 1024,
+            replicas: 3,
+            max_count: 0,
+            transfer_timeout_ms: DEFAULT_TRANSFER_TIMEOUT_MS,
+            undersized_policy: UndersizedPolicy::Reject,
+            skip_unchanged_heartbeats: true,
+        }
+    }
+}
+
+/// How to handle region creation while too few stores are live to hold
+/// every replica.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub enum UndersizedPolicy {
+    /// Reject the creation with `Error::NoAvailableStores`, so the problem
+    /// surfaces right away rather than as an under-replicated region later.
+    #[default]
+    Reject,
+    /// Create the region undersized, and schedule adding replicas on the
+    /// live stores that don't hold one yet. Replicas beyond the live stores
+    /// must be added once more stores are up.
+    Replicate,
+}
+
+/// The `store` section of the configuration.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct StoreConfig {
+    /// The unit stores report region sizes in.
+    pub size_unit: SizeUnit,
+    /// The used space ratio at or above which no new regions are placed on
+    /// a store.
+    pub space_high_ratio: f64,
+    /// The used space ratio at or above which regions should be evacuated
+    /// from a store.
+    pub space_critical_ratio: f64,
+    /// How long the id of a removed store stays reserved, in milliseconds.
+    /// Re-registering it is rejected until then.
+    pub tombstone_retention_ms: u64,
+    /// How long a store may take to drain, in milliseconds, or 0 for no
+    /// limit. Past it, a store still holding replicas is reported as a
+    /// stalled drain for an operator to resolve, but keeps draining: its
+    /// replicas are never dropped, which could lose data.
+    pub drain_timeout_ms: u64,
+    /// The maximum number of regions a store may hold a replica of, or 0 for
+    /// no limit. New regions aren't placed on stores at the cap even if they
+    /// have space left, since every region has a fixed overhead, e.g. its
+    /// raft group.
+    pub max_regions: usize,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            size_unit: SizeUnit::B,
+            space_high_ratio: 0.8,
+            space_critical_ratio: 0.95,
+            tombstone_retention_ms: DEFAULT_TOMBSTONE_RETENTION_MS,
+            drain_timeout_ms: DEFAULT_DRAIN_TIMEOUT_MS,
+            max_regions: 0,
+        }
+    }
+}
+
+/// A unit of region size, as reported by stores in heartbeats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub enum SizeUnit {
+    /// Bytes.
+    #[default]
+    B,
+    /// Kibibytes.
+    KB,
+    /// Mebibytes.
+    MB,
+}
+
+impl SizeUnit {
+    /// Converts a size in this unit to bytes, saturating on overflow.
+    pub fn to_bytes(self, size: u64) -> u64 {
+        match self {
+            SizeUnit::B => size,
+            SizeUnit::KB => size.saturating_mul(1024),
+            SizeUnit::MB => size.saturating_mul(1024 * 1024),
+        }
+    }
+}
+
+/// The default maximum size of a gRPC message, in bytes. Well above tonic's
+/// default of 4 MiB, which large range replies and snapshots can exceed.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// The default time after which a request fails with a timeout, in
+/// milliseconds.
+pub const DEFAULT_RPC_TIMEOUT_MS: u64 = 30_000;
+
+/// The `placement` section of the configuration.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PlacementConfig {
+    /// Seeds the random choice between equally good stores when placing
+    /// replicas and picking leaders, so the same topology and requests yield
+    /// the same placement, e.g. to reproduce a placement bug report. Meant
+    /// for tests and reproductions, not production. If unset, the choice is
+    /// seeded from entropy.
+    pub seed: Option<u64>,
+}
+
+/// The `server` section of the configuration.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// The maximum size of a gRPC message sent or received, in bytes.
+    pub max_message_size: usize,
+    /// The maximum number of concurrent HTTP/2 streams per connection.
+    pub max_concurrent_streams: u32,
+    /// The maximum number of requests in flight across all connections.
+    /// Excess requests are rejected with RESOURCE_EXHAUSTED. Raise it if
+    /// clients see such rejections while the PD still has spare CPU and
+    /// memory, and lower it if the PD runs out of memory under load.
+    pub max_in_flight: usize,
+    /// Whether to benchmark timestamp allocation and checkpoint writes on
+    /// recovery, warning if the disk is too slow for the window size.
+    pub startup_selfcheck: bool,
+    /// The time after which a request fails with `Error::Timeout`, in
+    /// milliseconds, or 0 for no limit. Applies even to clients that set no
+    /// deadline of their own.
+    pub default_rpc_timeout_ms: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_concurrent_streams: 1024,
+            max_in_flight: 10_000,
+            startup_selfcheck: false,
+            default_rpc_timeout_ms: DEFAULT_RPC_TIMEOUT_MS,
+        }
+    }
+}
+
+/// The `client` section of the configuration.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    /// The maximum size of a gRPC message sent or received, in bytes. Should
+    /// match the server's.
+    pub max_message_size: usize,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self { max_message_size: DEFAULT_MAX_MESSAGE_SIZE }
+    }
+}
+
+/// An entry of the `keyspaces` list of the configuration.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct KeyspaceConfig {
+    /// The keyspace id.
+    pub id: u32,
+    /// A prefix, e.g. a tenant or table prefix, that clients wrap all keys of
+    /// the keyspace with. Data-location lookups strip it from incoming keys
+    /// and reject keys without it, and add it back to the returned ranges, so
+    /// the routing table only holds the unprefixed keys. Empty for none.
+    pub strip_prefix: String,
+    /// The keyspace's weight when timestamp requests queue up because
+    /// `tso.max_inflight` is reached, or 0 for the default weight of 1. It
+    /// guarantees a minimum share of the admitted requests relative to other
+    /// waiting keyspaces, not a cap: without contention, any keyspace may use
+    /// the full capacity.
+    pub tso_weight: u32,
+}
+
+/// The `persistence` section of the configuration.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct PersistenceConfig {
+    /// The serialization format of snapshots and TSO checkpoints. `Json` is
+    /// larger than the default `Bincode`, but human-readable for debugging.
+    /// Either format is read back regardless of this setting.
+    pub format: PersistenceFormat,
+}
+
+/// The `changelog` section of the configuration.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ChangeLogConfig {
+    /// The maximum number of routing and store changes retained for
+    /// incremental snapshots. Replicas further behind need a full snapshot.
+    pub max_entries: usize,
+    /// The maximum age of retained changes in milliseconds, or 0 for no
+    /// limit. Replicas further behind need a full snapshot.
+    pub max_age_ms: u64,
+}
+
+impl Default for ChangeLogConfig {
+    fn default() -> Self {
+        Self { max_entries: DEFAULT_CHANGE_LOG_CAPACITY, max_age_ms: DEFAULT_CHANGE_LOG_MAX_AGE_MS }
+    }
+}
+
+/// How data-location lookups are resolved.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub enum ResolverKind {
+    /// From the routing tables of the reported regions.
+    #[default]
+    RoutingTable,
+    /// By consistent hashing of keys over the live stores, for schemas
+    /// without range scans. See `ConsistentHashResolver`.
+    ConsistentHash,
+}
+
+/// The `resolver` section of the configuration.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ResolverConfig {
+    /// How data-location lookups are resolved.
+    pub kind: ResolverKind,
+    /// The number of points each store owns on the consistent hash ring.
+    /// More points spread keys more evenly, at the cost of memory.
+    pub virtual_nodes: usize,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self { kind: ResolverKind::RoutingTable, virtual_nodes: DEFAULT_VIRTUAL_NODES }
+    }
+}
+
+/// The `dataloc` section of the configuration.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct DataLocConfig {
+    /// Whether a data-location lookup failing transiently, e.g. because the
+    /// location resolver's backend is briefly unavailable, is served the key's
+    /// last successful lookup instead, flagged as stale. Off by default, for
+    /// deployments that can't act on stale routing.
+    pub serve_stale_on_error: bool,
+    /// The number of keys whose last successful lookup is kept to fall back
+    /// on, with `serve_stale_on_error`.
+    pub stale_cache_capacity: usize,
+}
+
+impl Default for DataLocConfig {
+    fn default() -> Self {
+        Self { serve_stale_on_error: false, stale_cache_capacity: DEFAULT_STALE_CACHE_CAPACITY }
+    }
+}
+
+/// The `cluster` section of the configuration.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// Whether a cluster without regions creates a single region spanning
+    /// the whole default keyspace, as soon as enough stores have registered
+    /// to hold `region.replicas` replicas of it. Lookups then succeed from
+    /// the start, without an operator creating the first region.
+    pub create_initial_region: bool,
+    /// The file marking the cluster as bootstrapped once it has had regions,
+    /// so a restarted leader doesn't create the initial region again before
+    /// the stores have reported theirs. Defaults to `tso.checkpoint_path`
+    /// with `.bootstrapped` appended, if set, and is kept in memory only
+    /// otherwise.
+    pub bootstrap_marker_path: Option<PathBuf>,
+}
+
+/// The `id` section of the configuration.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct IdConfig {
+    /// The checkpoint file path of the id allocator, which persists windows
+    /// of ids like the TSO. Defaults to `tso.checkpoint_path` with `.ids`
+    /// appended. If neither is set, ids are kept in memory only, and are
+    /// reused across restarts.
+    pub checkpoint_path: Option<PathBuf>,
+}
+
+/// The `gossip` section of the configuration.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct GossipConfig {
+    /// The UDP address to gossip watermarks on. If unset, gossip is off.
+    pub listen_addr: Option<SocketAddr>,
+    /// The gossip addresses of the peer PDs.
+    pub peers: Vec<SocketAddr>,
+    /// How often to send our watermark to the peers, in milliseconds.
+    pub interval_ms: u64,
+    /// How far one gossiped watermark may move our TSO forward. A higher one
+    /// only moves it this far, and later rounds move it the rest of the way,
+    /// so a bogus datagram can't exhaust the timestamp space.
+    pub max_step: u64,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self { listen_addr: None, peers: Vec::new(), interval_ms: 1000, max_step: DEFAULT_GOSSIP_MAX_STEP }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn environment_variables_override_the_file() {
+        let dir = std::env::temp_dir().join(format!("featherpd-config-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("featherpd.toml");
+        std::fs::write(&path, "[tso]\nwindow_size = 10\nfirst_ts = 0\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        // A prefix of its own, so no other test sees these variables.
+        let prefix = "FEATHERPD_CONFIG_TEST";
+        std::env::set_var("FEATHERPD_CONFIG_TEST__TSO__WINDOW_SIZE", "500");
+        std::env::set_var("FEATHERPD_CONFIG_TEST__REGION__REPLICAS", "5");
+
+        let config = Config::from_env(prefix).unwrap();
+        assert_eq!(config.tso.window_size, 500);
+        assert_eq!(config.region.replicas, 5);
+        assert_eq!(config.tso.first_ts, Config::default().tso.first_ts);
+
+        let config = Config::from_file_and_env(path, prefix).unwrap();
+        assert_eq!(config.tso.window_size, 500);
+        assert_eq!(config.region.replicas, 5);
+        assert_eq!(config.tso.first_ts, 0);
+
+        let config = Config::from_file(path).unwrap();
+        assert_eq!(config.tso.window_size, 10);
+        assert_eq!(config.region.replicas, Config::default().region.replicas);
+
+        std::env::set_var("FEATHERPD_CONFIG_TEST__TSO__WINDOW_SIZE", "many");
+        assert!(matches!(Config::from_env(prefix), Err(Error::Config(_))));
+
+        std::env::remove_var("FEATHERPD_CONFIG_TEST__TSO__WINDOW_SIZE");
+        std::env::remove_var("FEATHERPD_CONFIG_TEST__REGION__REPLICAS");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sync_policies_are_parsed_by_name() {
+        let dir = std::env::temp_dir().join(format!("featherpd-config-sync-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        assert_eq!(Config::default().tso.sync_policy, SyncPolicy::Full);
+        for (name, sync_policy) in
+            [("Full", SyncPolicy::Full), ("Data", SyncPolicy::Data), ("None", SyncPolicy::None)]
+        {
+            let path = dir.join(format!("{}.toml", name));
+            std::fs::write(&path, format!("[tso]\nsync_policy = \"{}\"\n", name)).unwrap();
+            let config = Config::from_file(path.to_str().unwrap()).unwrap();
+            assert_eq!(config.tso.sync_policy, sync_policy);
+        }
+
+        let path = dir.join("unknown.toml");
+        std::fs::write(&path, "[tso]\nsync_policy = \"Sometimes\"\n").unwrap();
+        assert!(matches!(Config::from_file(path.to_str().unwrap()), Err(Error::Config(_))));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
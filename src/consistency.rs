@@ -0,0 +1,33 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// A violated internal invariant, found by a consistency check.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Violation {
+    /// The persisted watermark is not above the last allocated timestamp, so
+    /// a restart could hand out timestamps again.
+    WatermarkBehind { watermark: Option<u64>, last_allocated: u64 },
+    /// Two regions in a keyspace overlap.
+    RegionOverlap { keyspace_id: u32, region_id: u64, other_region_id: u64 },
+    /// The region id index and the region maps disagree about a region.
+    RegionIndexMismatch { region_id: u64 },
+    /// A region has a replica on a store missing from the store registry.
+    UnknownStore { region_id: u64, store_id: u64 },
+    /// A region has a replica on a store that was removed, and is only kept
+    /// around as a tombstone.
+    RemovedStore { region_id: u64, store_id: u64 },
+}
+
+/// The result of a consistency check. The PD is self-consistent if there are
+/// no violations.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConsistencyReport {
+    /// The violated invariants.
+    pub violations: Vec<Violation>,
+}
+
+impl ConsistencyReport {
+    /// Returns true if no invariant is violated.
+    pub fn is_consistent(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
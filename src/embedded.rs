@@ -0,0 +1,40 @@
+use std::ops::Deref;
+use std::sync::Arc;
+
+use crate::server::FeatherPD;
+
+/// A cloneable handle to an in-process FeatherPD, for embedding the PD in the
+/// same process as a storage engine without running a gRPC server. It derefs
+/// to `FeatherPD`, whose public methods (e.g. `get_next_ts()`, `lookup()` and
+/// `register_store()`) make up the embedded API, and can be cloned freely
+/// across tasks.
+///
+/// Embedded mode runs a single PD instance, so there is no leader leasing:
+/// leave `tso.leader_grace_ms` at its default of 0. Admin checks such as the
+/// admin token only apply to gRPC requests, and are skipped by direct calls.
+#[derive(Clone)]
+pub struct FeatherPdHandle {
+    pd: Arc<FeatherPD>,
+}
+
+impl FeatherPdHandle {
+    /// Creates a handle to the given server, which must have been recovered
+    /// before it serves timestamps.
+    pub fn new(pd: FeatherPD) -> Self {
+        Self { pd: Arc::new(pd) }
+    }
+
+    /// Creates a handle to a server with purely in-memory state, ready to
+    /// serve right away.
+    pub fn in_memory() -> Self {
+        Self::new(FeatherPD::default())
+    }
+}
+
+impl Deref for FeatherPdHandle {
+    type Target = FeatherPD;
+
+    fn deref(&self) -> &FeatherPD {
+        &self.pd
+    }
+}
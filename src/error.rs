@@ -1,5 +1,6 @@
 use serde_derive::{Deserialize, Serialize};
 use std::fmt::{self, Display};
+use std::sync::RwLock;
 
 /// Result returning Error
 pub type Result<T> = std::result::Result<T, Error>;
@@ -7,6 +8,36 @@ pub type Result<T> = std::result::Result<T, Error>;
 /// RPC-Result returning Error
 pub type RpcResult<T> = std::result::Result<tonic::Response<T>, tonic::Status>;
 
+/// A hook choosing the gRPC status code an error is returned with, e.g. to
+/// return `Serialization` as ABORTED for a particular client stack. It can
+/// fall back to `default_status_code()` for errors it doesn't care about.
+pub type StatusCodeHook = fn(&Error) -> tonic::Code;
+
+/// The installed status code hook, if any.
+static STATUS_CODE_HOOK: RwLock<Option<StatusCodeHook>> = RwLock::new(None);
+
+/// Installs a hook overriding the status code of errors returned over gRPC,
+/// or removes it with None. The hook is process-wide. Only the code is
+/// affected: the message keeps its `[Tag]` prefix, so clients still parse
+/// the error back into the right variant.
+pub fn set_status_code_hook(hook: Option<StatusCodeHook>) {
+    *STATUS_CODE_HOOK.write().unwrap_or_else(|err| err.into_inner()) = hook;
+}
+
+/// Returns the status code an error is returned with by default.
+pub fn default_status_code(err: &Error) -> tonic::Code {
+    match err {
+        Error::Unavailable(_) | Error::NoAvailableStores(_) | Error::PersistenceReadOnly(_) => {
+            tonic::Code::Unavailable
+        }
+        Error::NotFound(_) => tonic::Code::NotFound,
+        Error::PermissionDenied(_) => tonic::Code::PermissionDenied,
+        Error::SnapshotRequired => tonic::Code::FailedPrecondition,
+        Error::Timeout => tonic::Code::DeadlineExceeded,
+        _ => tonic::Code::Internal,
+    }
+}
+
 /// toyDB errors. All except Internal are considered user-facing.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Error {
@@ -18,6 +49,20 @@ pub enum Error {
     Serialization,
     Value(String),
     NotLeader,
+    Unavailable(String),
+    NotFound(String),
+    PermissionDenied(String),
+    /// Not enough live stores to place the given number of replicas.
+    NoAvailableStores(u32),
+    /// The requested changes are no longer retained, and a full snapshot is
+    /// required instead.
+    SnapshotRequired,
+    /// The operation didn't complete within its deadline.
+    Timeout,
+    /// Persisted state can't be written, e.g. because the file system is
+    /// read-only or full. Unlike transient write failures, this won't go away
+    /// without operator intervention.
+    PersistenceReadOnly(String),
 }
 
 impl std::error::Error for Error {}
@@ -25,13 +70,23 @@ impl std::error::Error for Error {}
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> fmt::Result {
         match self {
-            Error::Config(s) | Error::Internal(s) | Error::Parse(s) | Error::Value(s) => {
+            Error::Config(s)
+            | Error::Internal(s)
+            | Error::Parse(s)
+            | Error::Value(s)
+            | Error::Unavailable(s)
+            | Error::NotFound(s)
+            | Error::PermissionDenied(s)
+            | Error::PersistenceReadOnly(s) => {
                 write!(f, "{}", s)
             }
             Error::Abort => write!(f, "Operation aborted"),
             Error::Serialization => write!(f, "Serialization failure, retry transaction"),
             Error::ReadOnly => write!(f, "Read-only transaction"),
             Error::NotLeader => write!(f, "Not leader"),
+            Error::NoAvailableStores(n) => write!(f, "Not enough available stores, need {}", n),
+            Error::SnapshotRequired => write!(f, "Changes no longer retained, full snapshot required"),
+            Error::Timeout => write!(f, "Operation timed out"),
         }
     }
 }
@@ -60,6 +115,18 @@ impl From<log::SetLoggerError> for Error {
     }
 }
 
+impl From<prost::DecodeError> for Error {
+    fn from(err: prost::DecodeError) -> Self {
+        Error::Parse(err.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Self {
+        Error::Internal(err.to_string())
+    }
+}
+
 impl From<std::array::TryFromSliceError> for Error {
     fn from(err: std::array::TryFromSliceError) -> Self {
         Error::Internal(err.to_string())
@@ -108,6 +175,12 @@ impl From<tokio::task::JoinError> for Error {
     }
 }
 
+impl From<tonic::transport::Error> for Error {
+    fn from(err: tonic::transport::Error) -> Self {
+        Error::Internal(err.to_string())
+    }
+}
+
 // see https://github.com/tokio-rs/tokio/pull/3263: remove try_recv() from mpsc types
 //
 // impl From<tokio::sync::mpsc::error::TryRecvError> for Error {
@@ -136,16 +209,29 @@ impl From<tokio::sync::oneshot::error::RecvError> for Error {
 
 impl From<tonic::Status> for Error {
     fn from(err: tonic::Status) -> Self {
-        let chunks = err.message().split(" ").collect::<Vec<_>>();
-        match chunks[0] {
-            "[Config]" => Error::Config(chunks[1..].join(" ")),
-            "[Internal]" => Error::Internal(chunks[1..].join(" ")),
-            "[Parse]" => Error::Parse(chunks[1..].join(" ")),
-            "[Value]" => Error::Value(chunks[1..].join(" ")),
+        // Statuses may come from any peer, so the message is untrusted: it may
+        // be empty, lack a type tag, or lack a message after the tag.
+        let (kind, msg) = err.message().split_once(' ').unwrap_or((err.message(), ""));
+        let msg = msg.to_string();
+        match kind {
+            "[Config]" => Error::Config(msg),
+            "[Internal]" => Error::Internal(msg),
+            "[Parse]" => Error::Parse(msg),
+            "[Value]" => Error::Value(msg),
             "[Abort]" => Error::Abort,
             "[ReadOnly]" => Error::ReadOnly,
             "[Serialization]" => Error::Serialization,
             "[NotLeader]" => Error::NotLeader,
+            "[Unavailable]" => Error::Unavailable(msg),
+            "[NotFound]" => Error::NotFound(msg),
+            "[PermissionDenied]" => Error::PermissionDenied(msg),
+            "[SnapshotRequired]" => Error::SnapshotRequired,
+            "[Timeout]" => Error::Timeout,
+            "[PersistenceReadOnly]" => Error::PersistenceReadOnly(msg),
+            "[NoStores]" => match msg.rsplit(' ').next().and_then(|n| n.parse().ok()) {
+                Some(n) => Error::NoAvailableStores(n),
+                None => Error::Internal(format!("Invalid error: {:?}", err.message())),
+            },
             _ => Error::Internal(format!("Unknown error type: {:?}", err.message())),
         }
     }
@@ -153,16 +239,79 @@ impl From<tonic::Status> for Error {
 
 impl From<Error> for tonic::Status {
     fn from(err: Error) -> Self {
+        let hook = *STATUS_CODE_HOOK.read().unwrap_or_else(|err| err.into_inner());
+        let code = hook.map_or_else(|| default_status_code(&err), |hook| hook(&err));
         let msg = match err {
             Error::Config(s) => format!("[Config] {}", s),
             Error::Internal(s) => format!("[Internal] {}", s),
             Error::Parse(s) => format!("[Parse] {}", s),
             Error::Value(s) => format!("[Value] {}", s),
-            Error::Abort => format!("[Abort] Operation aborted"),
-            Error::ReadOnly => format!("[ReadOnly] Read-only transaction"),
-            Error::Serialization => format!("[Serialization] Serialization failure, retry transaction"),
-            Error::NotLeader => format!("[NotLeader] Not leader"),
+            Error::Abort => "[Abort] Operation aborted".to_string(),
+            Error::ReadOnly => "[ReadOnly] Read-only transaction".to_string(),
+            Error::Serialization => "[Serialization] Serialization failure, retry transaction".to_string(),
+            Error::NotLeader => "[NotLeader] Not leader".to_string(),
+            Error::Unavailable(s) => format!("[Unavailable] {}", s),
+            Error::NotFound(s) => format!("[NotFound] {}", s),
+            Error::PermissionDenied(s) => format!("[PermissionDenied] {}", s),
+            Error::NoAvailableStores(n) => format!("[NoStores] Not enough available stores, need {}", n),
+            Error::SnapshotRequired => {
+                "[SnapshotRequired] Changes no longer retained, full snapshot required".to_string()
+            }
+            Error::Timeout => "[Timeout] Operation timed out".to_string(),
+            Error::PersistenceReadOnly(s) => format!("[PersistenceReadOnly] {}", s),
         };
-        tonic::Status::internal(msg)
+        tonic::Status::new(code, msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_available_stores_round_trips_through_a_status() {
+        let status = tonic::Status::from(Error::NoAvailableStores(3));
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+        assert_eq!(status.message(), "[NoStores] Not enough available stores, need 3");
+        assert_eq!(Error::from(status), Error::NoAvailableStores(3));
+    }
+
+    #[test]
+    fn a_no_stores_status_without_a_count_is_invalid() {
+        let status = tonic::Status::unavailable("[NoStores] Not enough available stores");
+        assert!(matches!(Error::from(status), Error::Internal(_)));
+    }
+
+    #[test]
+    fn the_status_code_hook_only_changes_the_code() {
+        fn aborted_serialization(err: &Error) -> tonic::Code {
+            match err {
+                Error::Serialization => tonic::Code::Aborted,
+                err => default_status_code(err),
+            }
+        }
+        set_status_code_hook(Some(aborted_serialization));
+        let status = tonic::Status::from(Error::Serialization);
+        let unavailable = tonic::Status::from(Error::NoAvailableStores(3));
+        set_status_code_hook(None);
+
+        assert_eq!(status.code(), tonic::Code::Aborted);
+        assert_eq!(Error::from(status), Error::Serialization);
+        assert_eq!(unavailable.code(), tonic::Code::Unavailable);
+        assert_eq!(tonic::Status::from(Error::Serialization).code(), tonic::Code::Internal);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn undecodable_protobuf_messages_are_parse_errors() {
+        use crate::proto::placement_driver::TsoRequest;
+        use prost::Message;
+
+        fn decode(bytes: &[u8]) -> Result<TsoRequest> {
+            Ok(TsoRequest::decode(bytes)?)
+        }
+        // A truncated varint, and a field with the reserved wire type 7.
+        assert!(matches!(decode(&[0x08, 0xff]), Err(Error::Parse(_))));
+        assert!(matches!(decode(&[0x0f, 0x00]), Err(Error::Parse(_))));
+        assert_eq!(decode(&[0x10, 0x03]).unwrap().count, 3);
+    }
+}
@@ -0,0 +1,213 @@
+use log::warn;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::GossipConfig;
+use crate::error::{Error, Result};
+use crate::server::FeatherPD;
+use crate::task;
+
+/// The default for how far one gossiped watermark may move the TSO forward.
+pub const DEFAULT_GOSSIP_MAX_STEP: u64 = 1 << 32;
+
+/// Starts gossiping watermarks as configured, if `gossip.listen_addr` is set.
+/// See `run()`.
+pub async fn start(
+    pd: Arc<FeatherPD>,
+    config: &GossipConfig,
+    shutdown: CancellationToken,
+) -> Result<Option<JoinHandle<Result<()>>>> {
+    let Some(listen_addr) = config.listen_addr else {
+        return Ok(None);
+    };
+    let socket = UdpSocket::bind(listen_addr).await?;
+    let (peers, interval) = (config.peers.clone(), Duration::from_millis(config.interval_ms));
+    let gossiping = run(pd, socket, peers, interval, config.max_step, shutdown);
+    Ok(Some(task::spawn("featherpd-gossip", gossiping)))
+}
+
+/// Gossips the TSO's next timestamp watermark with peer PDs over UDP: every
+/// interval, our next timestamp is sent to each peer, and a higher one
+/// received from a peer, e.g. after a partition heals, moves our TSO forward
+/// to it. The persisted window end isn't gossiped, since every window renewal
+/// would then push the peers a whole window ahead.
+///
+/// This is a best-effort safety net against timestamp regressions between
+/// loosely coupled PDs, not a consensus mechanism: datagrams may be lost or
+/// delayed, so two PDs may still hand out overlapping timestamps between
+/// rounds. Returns once `shutdown` is cancelled.
+///
+/// Datagrams from anyone but the peers are dropped, and one watermark moves
+/// our TSO at most `max_step` forward, so that a stray or forged datagram
+/// can't push it to the end of the timestamp space. Errors, e.g. failing to
+/// persist the moved TSO, are logged and gossip carries on.
+pub async fn run(
+    pd: Arc<FeatherPD>,
+    socket: UdpSocket,
+    peers: Vec<SocketAddr>,
+    interval: Duration,
+    max_step: u64,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let mut ticker = tokio::time::interval(interval);
+    let mut buf = [0; 8];
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let watermark = match pd.next_ts() {
+                    Ok(next_ts) => next_ts.to_be_bytes(),
+                    Err(err) => {
+                        warn!("Failed to read the watermark to gossip: {}", err);
+                        continue;
+                    }
+                };
+                for peer in &peers {
+                    if let Err(err) = socket.send_to(&watermark, peer).await {
+                        warn!("Failed to gossip watermark to {}: {}", peer, err);
+                    }
+                }
+            }
+            received = socket.recv_from(&mut buf) => {
+                let (len, from) = match received {
+                    Ok(received) => received,
+                    Err(err) => {
+                        warn!("Failed to receive gossip: {}", err);
+                        continue;
+                    }
+                };
+                if !peers.contains(&from) {
+                    warn!("Ignoring gossip from {}, which isn't a peer", from);
+                    continue;
+                }
+                match decode(&buf[..len]) {
+                    Ok(watermark) => {
+                        if let Err(err) = observe(&pd, watermark, max_step) {
+                            warn!("Failed to observe watermark {} gossiped by {}: {}", watermark, from, err);
+                        }
+                    }
+                    Err(err) => warn!("Ignoring gossip from {}: {}", from, err),
+                }
+            }
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+    }
+}
+
+/// Moves the TSO forward to a peer's watermark, but at most `max_step`.
+fn observe(pd: &FeatherPD, watermark: u64, max_step: u64) -> Result<bool> {
+    let limit = pd.next_ts()?.saturating_add(max_step);
+    if watermark > limit {
+        warn!("Gossiped watermark {} is more than {} ahead, moving only to {}", watermark, max_step, limit);
+    }
+    pd.observe_peer_watermark(watermark.min(limit))
+}
+
+/// Decodes a gossiped watermark, a big-endian u64.
+fn decode(bytes: &[u8]) -> Result<u64> {
+    let bytes: [u8; 8] =
+        bytes.try_into().map_err(|_| Error::Parse(format!("Invalid watermark of {} bytes", bytes.len())))?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Binds a gossip socket on a free local port.
+    async fn bind() -> (UdpSocket, SocketAddr) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        (socket, addr)
+    }
+
+    /// Waits for the PD's next timestamp to reach the target.
+    async fn wait_for_next_ts(pd: &FeatherPD, target: u64) {
+        for _ in 0..500 {
+            if pd.next_ts().unwrap() >= target {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("next timestamp {} never reached {}", pd.next_ts().unwrap(), target);
+    }
+
+    #[tokio::test]
+    async fn two_pds_exchange_watermarks() {
+        let (a, b) = (Arc::new(FeatherPD::default()), Arc::new(FeatherPD::default()));
+        let ((socket_a, addr_a), (socket_b, addr_b)) = (bind().await, bind().await);
+        let (interval, shutdown) = (Duration::from_millis(10), CancellationToken::new());
+        let task_a =
+            tokio::spawn(run(a.clone(), socket_a, vec![addr_b], interval, 1 << 32, shutdown.clone()));
+        let task_b =
+            tokio::spawn(run(b.clone(), socket_b, vec![addr_a], interval, 1 << 32, shutdown.clone()));
+
+        // A is ahead, so B catches up with it.
+        for _ in 0..100 {
+            a.get_next_ts().unwrap();
+        }
+        let ahead = a.next_ts().unwrap();
+        wait_for_next_ts(&b, ahead).await;
+        assert_eq!(b.next_ts().unwrap(), ahead);
+        assert!(b.get_next_ts().unwrap() >= ahead);
+
+        // Now B is ahead, and A catches up, never moving backward.
+        for _ in 0..50 {
+            b.get_next_ts().unwrap();
+        }
+        let ahead = b.next_ts().unwrap();
+        wait_for_next_ts(&a, ahead).await;
+        assert_eq!(a.next_ts().unwrap(), ahead);
+
+        shutdown.cancel();
+        task_a.await.unwrap().unwrap();
+        task_b.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn ignores_gossip_from_non_peers() {
+        let pd = Arc::new(FeatherPD::default());
+        let ((socket, addr), (_peer, peer_addr), (stranger, _)) = (bind().await, bind().await, bind().await);
+        let shutdown = CancellationToken::new();
+        let interval = Duration::from_secs(60);
+        let task =
+            tokio::spawn(run(pd.clone(), socket, vec![peer_addr], interval, 1 << 32, shutdown.clone()));
+
+        let before = pd.next_ts().unwrap();
+        stranger.send_to(&u64::MAX.to_be_bytes(), addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(pd.next_ts().unwrap(), before);
+
+        shutdown.cancel();
+        task.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn caps_how_far_one_datagram_moves_the_tso() {
+        let pd = Arc::new(FeatherPD::default());
+        let ((socket, addr), (peer, peer_addr)) = (bind().await, bind().await);
+        let shutdown = CancellationToken::new();
+        let interval = Duration::from_secs(60);
+        let task = tokio::spawn(run(pd.clone(), socket, vec![peer_addr], interval, 1000, shutdown.clone()));
+
+        let before = pd.next_ts().unwrap();
+        peer.send_to(&u64::MAX.to_be_bytes(), addr).await.unwrap();
+        wait_for_next_ts(&pd, before + 1000).await;
+        peer.send_to(&u64::MAX.to_be_bytes(), addr).await.unwrap();
+        wait_for_next_ts(&pd, before + 2000).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pd.next_ts().unwrap(), before + 2000);
+
+        shutdown.cancel();
+        task.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn decode_rejects_wrong_lengths() {
+        assert_eq!(decode(&42u64.to_be_bytes()).unwrap(), 42);
+        assert!(matches!(decode(&[1, 2, 3]), Err(Error::Parse(_))));
+    }
+}
@@ -0,0 +1,48 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The number of low bits of an HLC timestamp holding its logical counter.
+/// The bits above hold the physical part, in milliseconds since the UNIX
+/// epoch.
+pub const HLC_LOGICAL_BITS: u32 = 18;
+
+/// The earliest physical time an HLC timestamp may carry, in milliseconds
+/// since the UNIX epoch (2020-01-01T00:00:00Z). Plain counter timestamps, as
+/// the TSO hands out, stay far below the first HLC timestamp at this time,
+/// about 4 * 10^17, so they are told apart by it.
+pub const HLC_MIN_PHYSICAL_MS: u64 = 1_577_836_800_000;
+
+/// Converts an HLC timestamp to the wall-clock time of its physical part,
+/// e.g. to read raw timestamps printed in logs. Returns None if the
+/// timestamp isn't an HLC one, i.e. its physical part is before
+/// `HLC_MIN_PHYSICAL_MS`, which holds for all timestamps of the counter TSO.
+pub fn hlc_to_datetime(ts: u64) -> Option<SystemTime> {
+    let physical_ms = ts >> HLC_LOGICAL_BITS;
+    if physical_ms < HLC_MIN_PHYSICAL_MS {
+        return None;
+    }
+    UNIX_EPOCH.checked_add(Duration::from_millis(physical_ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hlc_timestamps_convert_to_their_physical_time() {
+        let physical_ms = 1_700_000_000_123;
+        for logical in [0, 1, (1 << HLC_LOGICAL_BITS) - 1] {
+            let ts = physical_ms << HLC_LOGICAL_BITS | logical;
+            assert_eq!(hlc_to_datetime(ts), Some(UNIX_EPOCH + Duration::from_millis(physical_ms)));
+        }
+        let earliest = HLC_MIN_PHYSICAL_MS << HLC_LOGICAL_BITS;
+        assert_eq!(hlc_to_datetime(earliest), Some(UNIX_EPOCH + Duration::from_millis(HLC_MIN_PHYSICAL_MS)));
+        assert!(hlc_to_datetime(u64::MAX).is_some());
+    }
+
+    #[test]
+    fn counter_timestamps_are_not_hlc() {
+        for ts in [0, 1, 1_000_000, 1 << 40, (HLC_MIN_PHYSICAL_MS << HLC_LOGICAL_BITS) - 1] {
+            assert_eq!(hlc_to_datetime(ts), None, "{}", ts);
+        }
+    }
+}
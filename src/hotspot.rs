@@ -0,0 +1,73 @@
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::comparator::{compare_keys, KeyComparator};
+use crate::routing::RegionInfo;
+
+/// The default combined read and write QPS above which a region is hot.
+pub const DEFAULT_HOT_REGION_QPS: u64 = 5000;
+
+/// A region whose load exceeds the hotspot threshold.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hotspot {
+    /// The hot region's id.
+    pub region_id: u64,
+    /// The approximate read QPS, as last reported.
+    pub read_qps: u64,
+    /// The approximate write QPS, as last reported.
+    pub write_qps: u64,
+    /// The recommended split key, i.e. the region's median key, if the
+    /// region reported one that lies strictly inside it.
+    pub split_key: Option<Vec<u8>>,
+}
+
+/// Detects hot regions from the load reported in region heartbeats. Unlike
+/// size-based splitting, this catches small regions that take a lot of load.
+pub struct HotspotDetector {
+    /// The combined read and write QPS above which a region is hot.
+    threshold: u64,
+    /// The currently hot regions, by region id.
+    hotspots: HashMap<u64, Hotspot>,
+}
+
+impl HotspotDetector {
+    /// Creates a new hotspot detector with the given QPS threshold.
+    pub fn new(threshold: u64) -> Self {
+        Self { threshold, hotspots: HashMap::new() }
+    }
+
+    /// Records the load of a region, flagging or clearing it as a hotspot.
+    /// The median key is only recommended for splitting if it lies strictly
+    /// inside the region, with keys ordered by the given comparator.
+    pub fn observe(
+        &mut self,
+        region: &RegionInfo,
+        read_qps: u64,
+        write_qps: u64,
+        median_key: Vec<u8>,
+        comparator: &dyn KeyComparator,
+    ) {
+        if read_qps.saturating_add(write_qps) <= self.threshold {
+            self.hotspots.remove(&region.id);
+            return;
+        }
+        let split_key = Some(median_key).filter(|key| {
+            compare_keys(comparator, key, &region.start_key) == Ordering::Greater
+                && region.contains_by(key, comparator)
+        });
+        self.hotspots.insert(region.id, Hotspot { region_id: region.id, read_qps, write_qps, split_key });
+    }
+
+    /// Forgets a region, e.g. after it was removed from the routing table.
+    pub fn remove(&mut self, region_id: u64) {
+        self.hotspots.remove(&region_id);
+    }
+
+    /// Returns the current hotspots, hottest first.
+    pub fn hotspots(&self) -> Vec<Hotspot> {
+        let mut hotspots: Vec<Hotspot> = self.hotspots.values().cloned().collect();
+        hotspots.sort_by_key(|h| std::cmp::Reverse(h.read_qps.saturating_add(h.write_qps)));
+        hotspots
+    }
+}
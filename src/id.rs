@@ -0,0 +1,71 @@
+use std::sync::{Arc, Mutex};
+
+use crate::checkpoint::{CheckpointStore, MemoryCheckpoint};
+use crate::error::Result;
+use crate::tso::{TimestampOracle, DEFAULT_WINDOW_SIZE};
+
+/// Allocates region and store ids.
+///
+/// Implementations are solely responsible for uniqueness: every id returned
+/// must be distinct from every id returned before, including by previous runs
+/// of the PD, and must not be 0, which means "unset" throughout. Ids need not
+/// be dense or ordered, so they may encode information such as the keyspace
+/// in their high bits.
+pub trait IdAllocator: Send + Sync {
+    /// Allocates a new, unique id.
+    fn next(&self) -> Result<u64>;
+}
+
+/// The default id allocator, handing out a monotonic sequence starting at 1.
+/// Like the TSO, it persists windows of ids ahead of time, so ids are never
+/// reused across restarts as long as the checkpoint store is durable.
+pub struct MonotonicIdAllocator {
+    ids: Mutex<TimestampOracle>,
+}
+
+impl MonotonicIdAllocator {
+    /// Creates an id allocator persisting to the given checkpoint store,
+    /// which must not be shared with the TSO, resuming from any previously
+    /// persisted window.
+    pub fn new(checkpoint: Arc<dyn CheckpointStore>) -> Result<Self> {
+        let mut ids = TimestampOracle::new(checkpoint.clone(), DEFAULT_WINDOW_SIZE);
+        ids.recover(checkpoint.load()?);
+        Ok(Self { ids: Mutex::new(ids) })
+    }
+
+    /// Creates an id allocator that only keeps its state in memory. Ids may
+    /// be reused across restarts.
+    pub fn in_memory() -> Self {
+        let mut ids = TimestampOracle::new(Arc::new(MemoryCheckpoint::new()), DEFAULT_WINDOW_SIZE);
+        ids.recover(None);
+        Self { ids: Mutex::new(ids) }
+    }
+}
+
+impl IdAllocator for MonotonicIdAllocator {
+    fn next(&self) -> Result<u64> {
+        self.ids.lock()?.get_next_ts()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resumes_above_the_ids_handed_out_before_a_restart() {
+        let checkpoint: Arc<dyn CheckpointStore> = Arc::new(MemoryCheckpoint::new());
+        let ids = MonotonicIdAllocator::new(checkpoint.clone()).unwrap();
+        let before: Vec<u64> = (0..10).map(|_| ids.next().unwrap()).collect();
+        assert_eq!(before, (1..=10).collect::<Vec<_>>());
+
+        let restarted = MonotonicIdAllocator::new(checkpoint).unwrap();
+        assert!(restarted.next().unwrap() > 10);
+    }
+
+    #[test]
+    fn in_memory_ids_restart_at_one() {
+        assert_eq!(MonotonicIdAllocator::in_memory().next().unwrap(), 1);
+        assert_eq!(MonotonicIdAllocator::in_memory().next().unwrap(), 1);
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+
+use crate::error::{Error, Result};
+
+/// Store labels, e.g. `zone=us-east` or `disk=ssd`, by label key.
+pub type Labels = BTreeMap<String, String>;
+
+/// A single label requirement.
+#[derive(Clone, Debug, PartialEq)]
+enum Requirement {
+    /// `key=value`: the label must have the given value.
+    Equals(String, String),
+    /// `key in (a,b)`: the label must have one of the given values.
+    In(String, Vec<String>),
+}
+
+impl Requirement {
+    /// Returns true if the labels satisfy the requirement. A missing label
+    /// never does.
+    fn matches(&self, labels: &Labels) -> bool {
+        match self {
+            Requirement::Equals(key, value) => labels.get(key) == Some(value),
+            Requirement::In(key, values) => labels.get(key).is_some_and(|v| values.contains(v)),
+        }
+    }
+}
+
+/// A label selector, matching labels that satisfy all of its comma-separated
+/// requirements. A requirement is either an equality, e.g. `zone=us-east`, or
+/// a set membership, e.g. `zone in (us-east,us-west)`. The empty selector
+/// matches everything.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LabelSelector {
+    requirements: Vec<Requirement>,
+}
+
+impl LabelSelector {
+    /// Parses a label selector, returning `Error::Parse` if it is malformed.
+    pub fn parse(selector: &str) -> Result<Self> {
+        let mut requirements = Vec::new();
+        let mut rest = selector.trim();
+        while !rest.is_empty() {
+            let (requirement, tail) = Self::parse_requirement(rest)?;
+            requirements.push(requirement);
+            rest = tail.trim_start();
+            if let Some(tail) = rest.strip_prefix(',') {
+                rest = tail.trim_start();
+                if rest.is_empty() {
+                    return Err(Error::Parse(format!("Trailing comma in label selector {:?}", selector)));
+                }
+            } else if !rest.is_empty() {
+                return Err(Error::Parse(format!("Unexpected {:?} in label selector {:?}", rest, selector)));
+            }
+        }
+        Ok(Self { requirements })
+    }
+
+    /// Parses a single requirement, returning it and the unparsed tail.
+    fn parse_requirement(input: &str) -> Result<(Requirement, &str)> {
+        let end = input.find([',', '=', ' ', '(', ')']).unwrap_or(input.len());
+        let key = parse_label(&input[..end])?;
+        let rest = input[end..].trim_start();
+        if let Some(rest) = rest.strip_prefix('=') {
+            let rest = rest.trim_start();
+            let end = rest.find([',', ' ']).unwrap_or(rest.len());
+            let value = parse_label(&rest[..end])?;
+            return Ok((Requirement::Equals(key, value), &rest[end..]));
+        }
+        if let Some(rest) = rest.strip_prefix("in").map(str::trim_start).and_then(|r| r.strip_prefix('(')) {
+            let end = rest.find(')').ok_or_else(|| Error::Parse(format!("Unclosed set in {:?}", input)))?;
+            let values = rest[..end].split(',').map(|v| parse_label(v.trim())).collect::<Result<Vec<_>>>()?;
+            return Ok((Requirement::In(key, values), &rest[end + 1..]));
+        }
+        Err(Error::Parse(format!("Expected = or in after label key {:?}", key)))
+    }
+
+    /// Returns true if the labels satisfy every requirement.
+    pub fn matches(&self, labels: &Labels) -> bool {
+        self.requirements.iter().all(|requirement| requirement.matches(labels))
+    }
+}
+
+/// Parses a label key or value, which must be non-empty and must not contain
+/// whitespace or any of the selector syntax characters `=,()`.
+pub fn parse_label(s: &str) -> Result<String> {
+    if s.is_empty() {
+        return Err(Error::Parse("Empty label key or value".into()));
+    }
+    if s.contains(|c: char| c.is_whitespace() || "=,()".contains(c)) {
+        return Err(Error::Parse(format!("Invalid label key or value {:?}", s)));
+    }
+    Ok(s.to_string())
+}
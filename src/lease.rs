@@ -0,0 +1,96 @@
+use log::warn;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::backoff::Backoff;
+use crate::config::TsoConfig;
+use crate::error::Result;
+use crate::server::FeatherPD;
+use crate::task;
+
+/// The default time left on the lease at which it is renewed, in
+/// milliseconds. A renewal stalled for less than this doesn't let the lease
+/// expire.
+pub const DEFAULT_LEASE_RENEW_MARGIN_MS: u64 = 1_000;
+
+/// Starts renewing the leader lease as configured, if `tso.lease_ms` is set.
+/// See `run()`.
+pub fn start(
+    pd: Arc<FeatherPD>,
+    config: &TsoConfig,
+    shutdown: CancellationToken,
+) -> Option<JoinHandle<Result<()>>> {
+    if config.lease_ms == 0 {
+        return None;
+    }
+    let interval = Duration::from_millis(config.lease_ms.saturating_sub(config.lease_renew_margin_ms).max(1));
+    Some(task::spawn("featherpd-lease", run(pd, interval, shutdown)))
+}
+
+/// Renews the leader lease every interval, i.e. `lease_renew_margin_ms`
+/// before it expires, so that brief stalls of the renewal don't make the
+/// leader step down. If renewals stall for longer, e.g. because the process
+/// was paused, the lease expires and timestamps are refused with
+/// `Error::NotLeader` until the next renewal, by which time a new leader may
+/// have taken over after its `tso.leader_grace_ms`. A failed renewal is
+/// retried after a jittered backoff, up to the interval, rather than a whole
+/// interval later. Returns once `shutdown` is cancelled.
+pub async fn run(pd: Arc<FeatherPD>, interval: Duration, shutdown: CancellationToken) -> Result<()> {
+    let mut backoff = Backoff::new(interval / 10, interval, 2.0, 0.2);
+    let mut delay = Duration::ZERO;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+        delay = match pd.renew_lease() {
+            Ok(_) => {
+                backoff.reset();
+                interval
+            }
+            Err(err) => {
+                let delay = backoff.next().unwrap_or(interval);
+                warn!("Failed to renew the leader lease, retrying in {:?}: {}", delay, err);
+                delay
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::config::Config;
+    use crate::error::Error;
+
+    #[tokio::test(start_paused = true)]
+    async fn the_lease_is_renewed_before_it_expires() {
+        let clock = Arc::new(ManualClock::new(4_000_000_000_000));
+        let mut config = Config::default();
+        config.tso.lease_ms = 3_000;
+        config.tso.lease_renew_margin_ms = 1_000;
+        let pd = Arc::new(FeatherPD::from_config(&config).unwrap().with_clock(clock.clone()));
+        pd.recover().await.unwrap();
+        assert!(start(pd.clone(), &TsoConfig::default(), CancellationToken::new()).is_none());
+
+        // Timestamps keep being served for several leases, as each renewal
+        // comes lease_renew_margin_ms before the lease would expire.
+        let shutdown = CancellationToken::new();
+        let handle = start(pd.clone(), &config.tso, shutdown.clone()).unwrap();
+        for _ in 0..100 {
+            clock.advance(100);
+            tokio::time::advance(Duration::from_millis(100)).await;
+            tokio::task::yield_now().await;
+            pd.get_next_ts().unwrap();
+        }
+
+        // Once renewals stop, the lease runs out.
+        shutdown.cancel();
+        handle.await.unwrap().unwrap();
+        clock.advance(config.tso.lease_ms);
+        assert_eq!(pd.get_next_ts(), Err(Error::NotLeader));
+    }
+}
@@ -1,3 +1,50 @@
+pub mod admin;
+pub mod admission;
+pub mod audit;
+pub mod backoff;
+pub mod breaker;
+pub mod checked;
+pub mod checkpoint;
+pub mod client;
+pub mod clock;
+pub mod coalesce;
+pub mod compaction;
+pub mod comparator;
+pub mod config;
+pub mod consistency;
+pub mod embedded;
 pub mod error;
+pub mod gossip;
+#[cfg(feature = "hlc")]
+pub mod hlc;
+pub mod hotspot;
+pub mod id;
+pub mod labels;
+pub mod lease;
+pub mod limit;
+pub mod metrics;
+pub mod oplog;
+pub mod peer;
 pub mod proto;
-pub mod server;
\ No newline at end of file
+pub mod resolver;
+pub mod routing;
+pub mod scheduler;
+pub mod selfcheck;
+pub mod server;
+pub mod shutdown;
+pub mod snapshot;
+pub mod split;
+pub mod stability;
+pub mod status;
+pub mod store;
+pub mod task;
+#[cfg(feature = "testutil")]
+pub mod testutil;
+pub mod topology;
+#[cfg(feature = "toydb-compat")]
+pub mod toydb;
+pub mod transfer;
+pub mod transport;
+pub mod tso;
+pub mod validate;
+pub mod watch;
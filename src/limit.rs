@@ -0,0 +1,205 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tonic::body::BoxBody;
+use tonic::codegen::http::{Request, Response};
+use tonic::codegen::BoxFuture;
+use tower::{Layer, Service};
+
+use crate::error::Error;
+
+/// A tower layer limiting the number of requests in flight across all
+/// connections. Requests beyond the limit are rejected right away with
+/// `RESOURCE_EXHAUSTED`, rather than piling up until the process runs out of
+/// memory, so clients can back off and retry.
+#[derive(Clone)]
+pub struct InFlightLimitLayer {
+    limit: usize,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl InFlightLimitLayer {
+    /// Creates a layer allowing up to `limit` requests in flight.
+    pub fn new(limit: usize) -> Self {
+        Self { limit, in_flight: Arc::new(AtomicUsize::new(0)) }
+    }
+}
+
+impl<S> Layer<S> for InFlightLimitLayer {
+    type Service = InFlightLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        InFlightLimit { inner, limit: self.limit, in_flight: self.in_flight.clone() }
+    }
+}
+
+/// A service limiting the number of requests in flight. See
+/// `InFlightLimitLayer`.
+#[derive(Clone)]
+pub struct InFlightLimit<S> {
+    inner: S,
+    limit: usize,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl<S, B> Service<Request<B>> for InFlightLimit<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        if self.in_flight.fetch_add(1, Ordering::SeqCst) >= self.limit {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            let status = tonic::Status::resource_exhausted("Too many requests in flight, retry later");
+            return Box::pin(async move { Ok(status.to_http()) });
+        }
+        let guard = InFlightGuard(self.in_flight.clone());
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let _guard = guard;
+            response.await
+        })
+    }
+}
+
+/// Releases an in-flight slot when dropped, even if the request is
+/// cancelled.
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A tower layer bounding how long a request may take to be handled, so a
+/// stuck operation, e.g. a hung checkpoint write, can't hang a client that
+/// set no deadline of its own. Requests exceeding it fail with
+/// `Error::Timeout`. Streaming responses are only bounded until the stream
+/// starts.
+#[derive(Clone)]
+pub struct TimeoutLayer {
+    timeout: Duration,
+}
+
+impl TimeoutLayer {
+    /// Creates a layer failing requests that take longer than `timeout`.
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl<S> Layer<S> for TimeoutLayer {
+    type Service = Timeout<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Timeout { inner, timeout: self.timeout }
+    }
+}
+
+/// A service bounding how long requests take. See `TimeoutLayer`.
+#[derive(Clone)]
+pub struct Timeout<S> {
+    inner: S,
+    timeout: Duration,
+}
+
+impl<S, B> Service<Request<B>> for Timeout<S>
+where
+    S: Service<Request<B>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<B>) -> Self::Future {
+        let response = tokio::time::timeout(self.timeout, self.inner.call(request));
+        Box::pin(async move {
+            match response.await {
+                Ok(response) => response,
+                Err(_) => Ok(tonic::Status::from(Error::Timeout).to_http()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::Infallible;
+    use tokio::sync::Semaphore;
+
+    /// A service whose requests wait for a permit of the semaphore.
+    #[derive(Clone)]
+    struct Gated(Arc<Semaphore>);
+
+    impl Service<Request<()>> for Gated {
+        type Response = Response<BoxBody>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Self::Error>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<()>) -> Self::Future {
+            let gate = self.0.clone();
+            Box::pin(async move {
+                gate.acquire().await.unwrap().forget();
+                Ok(Response::new(tonic::body::empty_body()))
+            })
+        }
+    }
+
+    /// Returns the gRPC status code of a response, OK if it carries none.
+    fn code(response: &Response<BoxBody>) -> tonic::Code {
+        tonic::Status::from_header_map(response.headers()).map_or(tonic::Code::Ok, |status| status.code())
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_limit_are_rejected_right_away() {
+        let gate = Arc::new(Semaphore::new(0));
+        let mut service = InFlightLimitLayer::new(2).layer(Gated(gate.clone()));
+        let in_flight: Vec<_> = (0..2).map(|_| tokio::spawn(service.call(Request::new(())))).collect();
+
+        for _ in 0..10 {
+            let response = service.call(Request::new(())).await.unwrap();
+            assert_eq!(code(&response), tonic::Code::ResourceExhausted);
+        }
+
+        gate.add_permits(3);
+        for request in in_flight {
+            assert_eq!(code(&request.await.unwrap().unwrap()), tonic::Code::Ok);
+        }
+        let response = service.call(Request::new(())).await.unwrap();
+        assert_eq!(code(&response), tonic::Code::Ok);
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_release_their_slot() {
+        let gate = Arc::new(Semaphore::new(0));
+        let mut service = InFlightLimitLayer::new(1).layer(Gated(gate.clone()));
+        let cancelled = service.call(Request::new(()));
+        let response = service.call(Request::new(())).await.unwrap();
+        assert_eq!(code(&response), tonic::Code::ResourceExhausted);
+
+        drop(cancelled);
+        gate.add_permits(1);
+        let response = service.call(Request::new(())).await.unwrap();
+        assert_eq!(code(&response), tonic::Code::Ok);
+    }
+}
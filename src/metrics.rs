@@ -0,0 +1,76 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::Write;
+
+use crate::status::ClusterStatus;
+
+/// A point-in-time snapshot of the server's metrics, rendered for scraping
+/// with `to_prometheus()`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct MetricsSnapshot {
+    /// The number of TSO windows persisted since startup because allocation
+    /// exhausted the previous one, as `featherpd_tso_window_refills_total`.
+    /// Each refill costs a checkpoint write and its sync, and refills happen
+    /// at the allocation rate over `tso.window_size`: if their rate shows up
+    /// in allocation latency, raise the window size.
+    pub tso_window_refills_total: u64,
+    /// The number of timestamps left in the current TSO window, as
+    /// `featherpd_tso_window_remaining`. It is at most how many timestamps
+    /// a restart would skip, so a window size that is large next to the
+    /// allocation rate only wastes timestamp space.
+    pub tso_window_remaining: u64,
+}
+
+impl MetricsSnapshot {
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let metrics = [
+            (
+                "featherpd_tso_window_refills_total",
+                "counter",
+                "TSO windows persisted because the previous one was exhausted.",
+                self.tso_window_refills_total,
+            ),
+            (
+                "featherpd_tso_window_remaining",
+                "gauge",
+                "Timestamps left in the current TSO window.",
+                self.tso_window_remaining,
+            ),
+        ];
+        for (name, kind, help, value) in metrics {
+            // Writing to a String can't fail.
+            let _ = write!(text, "# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value);
+        }
+        text
+    }
+}
+
+impl From<&ClusterStatus> for MetricsSnapshot {
+    fn from(status: &ClusterStatus) -> Self {
+        Self {
+            tso_window_refills_total: status.tso_window_refills,
+            tso_window_remaining: status.tso_window_remaining,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_in_the_prometheus_text_format() {
+        let metrics = MetricsSnapshot { tso_window_refills_total: 3, tso_window_remaining: 42 };
+        assert_eq!(
+            metrics.to_prometheus(),
+            "# HELP featherpd_tso_window_refills_total TSO windows persisted because the previous one \
+             was exhausted.\n\
+             # TYPE featherpd_tso_window_refills_total counter\n\
+             featherpd_tso_window_refills_total 3\n\
+             # HELP featherpd_tso_window_remaining Timestamps left in the current TSO window.\n\
+             # TYPE featherpd_tso_window_remaining gauge\n\
+             featherpd_tso_window_remaining 42\n"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::comparator::KeyComparator;
+use crate::error::{Error, Result};
+use crate::routing::{RegionInfo, RoutingTable, DEFAULT_KEYSPACE};
+use crate::snapshot::{Change, Snapshot};
+use crate::store::{StoreInfo, StoreRegistry, StoreState};
+
+/// The default number of operations retained before the oldest ones are
+/// compacted into the base snapshot.
+pub const DEFAULT_OPERATION_LOG_CAPACITY: usize = 10_000;
+
+/// A mutating operation, as recorded in the operation log.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Operation {
+    /// A store was registered or re-registered.
+    RegisterStore { store_id: u64 },
+    /// A store was moved to a new state, e.g. drained or removed.
+    SetStoreState { store_id: u64, state: StoreState },
+    /// A region was split, giving the right half the new region id.
+    SplitRegion { region_id: u64, new_region_id: u64 },
+    /// A region was inserted or replaced.
+    PutRegion { region_id: u64 },
+    /// A region heartbeat changed the region.
+    RegionHeartbeat { region_id: u64 },
+    /// A leader transfer was confirmed by the new leader's store.
+    TransferLeader { region_id: u64, store_id: u64 },
+    /// A keyspace was created.
+    CreateKeyspace { keyspace_id: u32 },
+    /// A keyspace was deleted, along with all of its regions.
+    DeleteKeyspace { keyspace_id: u32 },
+}
+
+/// An entry of the operation log.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OperationRecord {
+    /// The topology version resulting from the operation.
+    pub version: u64,
+    /// The time the operation was applied, in milliseconds.
+    pub timestamp_ms: u64,
+    /// The operation.
+    pub operation: Operation,
+    /// The routing and store changes the operation made, in order.
+    pub changes: Vec<Change>,
+}
+
+/// An append-only log of every mutating operation, for auditing and for
+/// point-in-time recovery of the topology. Unlike the change log, which only
+/// serves replication, it can reconstruct the topology at any version since
+/// its base snapshot. It is bounded by compaction: once more than `capacity`
+/// operations are retained, the oldest half is folded into the base
+/// snapshot.
+pub struct OperationLog {
+    /// The key ordering, for replaying region changes.
+    comparator: Arc<dyn KeyComparator>,
+    /// The topology version of the base snapshot.
+    base_version: u64,
+    /// The topology at `base_version`.
+    base: Snapshot,
+    /// The operations after the base snapshot, oldest first.
+    records: VecDeque<OperationRecord>,
+    /// The maximum number of operations retained before compacting.
+    capacity: usize,
+}
+
+impl OperationLog {
+    /// Creates an empty operation log, on top of an empty topology with only
+    /// the default keyspace.
+    pub fn new(capacity: usize, comparator: Arc<dyn KeyComparator>) -> Self {
+        let base = Snapshot { keyspaces: vec![DEFAULT_KEYSPACE], ..Default::default() };
+        Self { comparator, base_version: 0, base, records: VecDeque::new(), capacity: capacity.max(1) }
+    }
+
+    /// Appends an operation, compacting the log if it is full.
+    pub fn record(&mut self, record: OperationRecord) -> Result<()> {
+        self.records.push_back(record);
+        if self.records.len() > self.capacity {
+            let count = self.records.len() - self.capacity / 2;
+            let compacted: Vec<OperationRecord> = self.records.drain(..count).collect();
+            self.base_version = compacted.last().map_or(self.base_version, |record| record.version);
+            self.base = replay(&self.base, compacted.iter(), self.comparator.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the version of the base snapshot, below which the topology
+    /// can no longer be reconstructed.
+    pub fn base_version(&self) -> u64 {
+        self.base_version
+    }
+
+    /// Returns the retained operations resulting in a version above the
+    /// given one, oldest first.
+    pub fn since(&self, version: u64) -> Vec<OperationRecord> {
+        self.records.iter().filter(|record| record.version > version).cloned().collect()
+    }
+
+    /// Reconstructs the topology as of the given version, by replaying the
+    /// log onto its base snapshot.
+    pub fn snapshot_at(&self, version: u64) -> Result<Snapshot> {
+        self.replay_from(&self.base, self.base_version, version)
+    }
+
+    /// Reconstructs the topology as of `version` by replaying the log onto a
+    /// base snapshot taken at `base_version`, e.g. a backup. Returns
+    /// `Error::NotFound` if the operations in between are no longer
+    /// retained.
+    pub fn replay_from(&self, base: &Snapshot, base_version: u64, version: u64) -> Result<Snapshot> {
+        if version < base_version {
+            return Err(Error::Value(format!(
+                "Version {} is below the base snapshot version {}",
+                version, base_version
+            )));
+        }
+        if base_version < self.base_version {
+            return Err(Error::NotFound(format!(
+                "Operations after version {} are no longer retained, the oldest retained base is {}",
+                base_version, self.base_version
+            )));
+        }
+        let latest = self.records.back().map_or(self.base_version, |record| record.version);
+        if version > latest {
+            return Err(Error::Value(format!("Version {} is ahead of current version {}", version, latest)));
+        }
+        let records =
+            self.records.iter().filter(|record| record.version > base_version && record.version <= version);
+        replay(base, records, self.comparator.clone())
+    }
+}
+
+/// Applies the changes of the given operations to a snapshot, with the same
+/// semantics as applying them to the live routing table and store registry.
+fn replay<'a>(
+    base: &Snapshot,
+    records: impl Iterator<Item = &'a OperationRecord>,
+    comparator: Arc<dyn KeyComparator>,
+) -> Result<Snapshot> {
+    let mut routing = RoutingTable::with_comparator(comparator);
+    for &keyspace_id in &base.keyspaces {
+        if keyspace_id != DEFAULT_KEYSPACE {
+            routing.create_keyspace(keyspace_id)?;
+        }
+    }
+    for region in &base.regions {
+        routing.put_region(region.clone())?;
+    }
+    let mut stores = StoreRegistry::new();
+    for store in &base.stores {
+        stores.put(store.clone());
+    }
+    for change in records.flat_map(|record| &record.changes) {
+        match change.clone() {
+            Change::CreateKeyspace(keyspace_id) => routing.create_keyspace(keyspace_id)?,
+            Change::DeleteKeyspace(keyspace_id) => routing.delete_keyspace(keyspace_id)?,
+            Change::PutRegion(region) => routing.put_region(region)?,
+            Change::PutStore(store) => stores.put(store),
+        }
+    }
+    let mut regions: Vec<RegionInfo> = routing.regions().cloned().collect();
+    regions.sort_by_key(|region| region.id);
+    let mut stores: Vec<StoreInfo> = stores.stores().cloned().collect();
+    stores.sort_by_key(|store| store.id);
+    Ok(Snapshot { keyspaces: routing.keyspace_ids(), regions, stores })
+}
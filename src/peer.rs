@@ -0,0 +1,38 @@
+use std::net::SocketAddr;
+use tonic::transport::server::TcpConnectInfo;
+use tonic::Request;
+
+/// Returns the address of the client that sent a request, for per-client
+/// logic such as rate limiting and logging. Returns None for clients without
+/// a socket address, i.e. those connected over a UNIX domain socket, and for
+/// requests that didn't arrive through a tonic server (e.g. embedded calls).
+pub fn client_addr<T>(request: &Request<T>) -> Option<SocketAddr> {
+    request.extensions().get::<TcpConnectInfo>().and_then(|info| info.remote_addr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream, UnixStream};
+    use tonic::transport::server::Connected;
+
+    #[tokio::test]
+    async fn tcp_requests_carry_the_client_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let mut request = Request::new(());
+        request.extensions_mut().insert(server.connect_info());
+        assert_eq!(client_addr(&request), Some(client.local_addr().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn other_requests_carry_no_client_address() {
+        assert_eq!(client_addr(&Request::new(())), None);
+
+        let (server, _client) = UnixStream::pair().unwrap();
+        let mut request = Request::new(());
+        request.extensions_mut().insert(server.connect_info());
+        assert_eq!(client_addr(&request), None);
+    }
+}
@@ -1,5 +1,10 @@
 pub mod placement_driver {
     tonic::include_proto!("placement_driver");
-    pub use placement_driver_server::{PlacementDriver, PlacementDriverServer};
     pub use placement_driver_client::PlacementDriverClient;
-}
\ No newline at end of file
+    pub use placement_driver_server::{PlacementDriver, PlacementDriverServer};
+
+    /// The encoded file descriptor set of the PD service, for gRPC server
+    /// reflection.
+    #[cfg(feature = "reflection")]
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("placement_driver_descriptor");
+}
@@ -0,0 +1,280 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::proto::placement_driver::DataLocReply;
+use crate::routing::RegionInfo;
+use crate::store::{StoreInfo, StoreState};
+
+/// The default number of points each store owns on a consistent hash ring.
+pub const DEFAULT_VIRTUAL_NODES: usize = 64;
+
+/// The default number of last-known-good lookups kept to fall back on.
+pub const DEFAULT_STALE_CACHE_CAPACITY: usize = 10_000;
+
+/// Resolves data locations in place of the routing table, for deployments
+/// that compute locations algorithmically rather than tracking regions, e.g.
+/// by consistent hashing. See `FeatherPD::with_location_resolver()`.
+#[tonic::async_trait]
+pub trait LocationResolver: Send + Sync {
+    /// Resolves the location of a key in a keyspace. The key is passed as
+    /// the client sent it, i.e. with any configured keyspace prefix.
+    async fn resolve(&self, keyspace_id: u32, key: &[u8]) -> Result<DataLocReply>;
+
+    /// Called with every store registered or updated, in order, e.g. to track
+    /// the live stores.
+    fn put_store(&self, _store: &StoreInfo) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A resolver placing keys on a consistent hash ring of the live stores,
+/// with no per-region state. Each store owns `virtual_nodes` points on the
+/// ring, and a key is served by the stores owning the first `replicas`
+/// distinct points at or after its hash, the first being the leader. Stores
+/// join the ring when they come up and leave it when they go down, drain, or
+/// are removed, and only the keys of their own arcs move: about 1/N of the
+/// key space for N stores.
+///
+/// Since there are no key ranges, the returned region covers the looked-up
+/// key only, and its id is the ring point the key hashed to.
+pub struct ConsistentHashResolver {
+    /// The ring.
+    ring: Mutex<Ring>,
+    /// The number of points per store.
+    virtual_nodes: usize,
+    /// The number of replicas per key.
+    replicas: usize,
+}
+
+/// A consistent hash ring.
+#[derive(Default)]
+struct Ring {
+    /// The store owning each point.
+    points: BTreeMap<u64, u64>,
+    /// The stores on the ring.
+    stores: HashSet<u64>,
+}
+
+impl ConsistentHashResolver {
+    /// Creates a resolver with an empty ring, with `virtual_nodes` points per
+    /// store and `replicas` replicas per key.
+    pub fn new(virtual_nodes: usize, replicas: usize) -> Self {
+        Self {
+            ring: Mutex::new(Ring::default()),
+            virtual_nodes: virtual_nodes.max(1),
+            replicas: replicas.max(1),
+        }
+    }
+
+    /// Adds a store to the ring, if not already on it.
+    pub fn add_store(&self, store_id: u64) -> Result<()> {
+        let mut ring = self.ring.lock()?;
+        if ring.stores.insert(store_id) {
+            for point in self.points(store_id) {
+                ring.points.insert(point, store_id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a store from the ring, if on it.
+    pub fn remove_store(&self, store_id: u64) -> Result<()> {
+        let mut ring = self.ring.lock()?;
+        if ring.stores.remove(&store_id) {
+            for point in self.points(store_id) {
+                if ring.points.get(&point) == Some(&store_id) {
+                    ring.points.remove(&point);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the ring points of a store.
+    fn points(&self, store_id: u64) -> impl Iterator<Item = u64> {
+        (0..self.virtual_nodes as u64)
+            .map(move |vnode| hash(&[store_id.to_be_bytes(), vnode.to_be_bytes()].concat()))
+    }
+
+    /// Returns the ring point a key falls on and its replica stores, leader
+    /// first. Returns `Error::NoAvailableStores` if fewer stores than
+    /// replicas are on the ring.
+    fn place(&self, keyspace_id: u32, key: &[u8]) -> Result<(u64, Vec<u64>)> {
+        let ring = self.ring.lock()?;
+        if ring.stores.len() < self.replicas {
+            return Err(Error::NoAvailableStores(self.replicas as u32));
+        }
+        let hash = hash(&[&keyspace_id.to_be_bytes(), key].concat());
+        let mut points = ring.points.range(hash..).chain(ring.points.range(..hash)).peekable();
+        let point = points.peek().map_or(0, |(&point, _)| point);
+        let mut stores = Vec::with_capacity(self.replicas);
+        for (_, &store_id) in points {
+            if !stores.contains(&store_id) {
+                stores.push(store_id);
+                if stores.len() == self.replicas {
+                    break;
+                }
+            }
+        }
+        Ok((point, stores))
+    }
+}
+
+#[tonic::async_trait]
+impl LocationResolver for ConsistentHashResolver {
+    async fn resolve(&self, keyspace_id: u32, key: &[u8]) -> Result<DataLocReply> {
+        let (point, stores) = self.place(keyspace_id, key)?;
+        let mut end_key = key.to_vec();
+        end_key.push(0);
+        let region =
+            RegionInfo::new(point, keyspace_id, key.to_vec(), end_key, 1, stores.clone(), stores[0])?;
+        Ok(DataLocReply { regions: bincode::serialize(&vec![region])?, ..Default::default() })
+    }
+
+    fn put_store(&self, store: &StoreInfo) -> Result<()> {
+        match store.state {
+            StoreState::Up => self.add_store(store.id),
+            _ => self.remove_store(store.id),
+        }
+    }
+}
+
+/// The last successful data-location lookup of recently looked-up keys, to
+/// fall back on when lookups fail transiently. Once full, the keys first
+/// cached are forgotten first.
+pub struct StaleLocationCache {
+    capacity: usize,
+    /// The last successful reply and the time it was served at, in
+    /// milliseconds, by keyspace and key.
+    replies: HashMap<(u32, Vec<u8>), (DataLocReply, u64)>,
+    order: VecDeque<(u32, Vec<u8>)>,
+}
+
+impl StaleLocationCache {
+    /// Creates a cache remembering up to `capacity` lookups.
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, replies: HashMap::new(), order: VecDeque::new() }
+    }
+
+    /// Records a successful lookup.
+    pub fn put(&mut self, keyspace_id: u32, key: &[u8], reply: DataLocReply, now_ms: u64) {
+        let entry = (keyspace_id, key.to_vec());
+        if self.replies.insert(entry.clone(), (reply, now_ms)).is_some() {
+            return;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+        self.order.push_back(entry);
+    }
+
+    /// Returns the last successful lookup of a key, flagged as stale, with
+    /// its staleness as of `now_ms`.
+    pub fn get(&self, keyspace_id: u32, key: &[u8], now_ms: u64) -> Option<DataLocReply> {
+        let (reply, served_ms) = self.replies.get(&(keyspace_id, key.to_vec()))?;
+        Some(DataLocReply { stale: true, staleness_ms: now_ms.saturating_sub(*served_ms), ..reply.clone() })
+    }
+}
+
+/// Hashes bytes with 64-bit FNV-1a, which unlike the standard library's
+/// hasher is stable across releases, so placements survive upgrades. FNV
+/// alone barely changes the high bits for keys differing in their last
+/// bytes, so it is followed by MurmurHash3's finalizer to spread them
+/// around the ring.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash =
+        bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the leader store of each of `count` keys.
+    fn leaders(resolver: &ConsistentHashResolver, count: u32) -> Vec<u64> {
+        (0..count).map(|i| resolver.place(0, format!("key{}", i).as_bytes()).unwrap().1[0]).collect()
+    }
+
+    #[test]
+    fn adding_a_store_moves_about_its_share_of_the_keys() {
+        let resolver = ConsistentHashResolver::new(DEFAULT_VIRTUAL_NODES, 1);
+        for id in 1..=4 {
+            resolver.add_store(id).unwrap();
+        }
+        let before = leaders(&resolver, 10_000);
+        resolver.add_store(5).unwrap();
+        let after = leaders(&resolver, 10_000);
+
+        // Only keys moving to the new store move, about 1/5 of them.
+        let moved: Vec<_> = before.iter().zip(&after).filter(|(before, after)| before != after).collect();
+        assert!(moved.iter().all(|&(_, &after)| after == 5));
+        assert!((1_000..3_000).contains(&moved.len()), "{} keys moved", moved.len());
+    }
+
+    #[test]
+    fn removing_a_store_only_moves_its_keys() {
+        let resolver = ConsistentHashResolver::new(DEFAULT_VIRTUAL_NODES, 1);
+        for id in 1..=5 {
+            resolver.add_store(id).unwrap();
+        }
+        let before = leaders(&resolver, 10_000);
+        resolver.remove_store(3).unwrap();
+        let after = leaders(&resolver, 10_000);
+        for (before, after) in before.iter().zip(&after) {
+            assert_eq!(before == after, *before != 3);
+        }
+    }
+
+    #[test]
+    fn keys_are_placed_on_distinct_live_stores() {
+        let resolver = ConsistentHashResolver::new(8, 3);
+        let store = |id, state| StoreInfo {
+            id,
+            address: format!("store-{}", id),
+            state,
+            labels: Default::default(),
+            capacity: 0,
+            used: 0,
+            last_heartbeat_ms: 0,
+            weight: None,
+        };
+        for id in 1..=3 {
+            resolver.put_store(&store(id, StoreState::Up)).unwrap();
+        }
+        let (_, stores) = resolver.place(0, b"key").unwrap();
+        assert_eq!(stores.iter().collect::<HashSet<_>>().len(), 3);
+
+        // Too few live stores to place all replicas.
+        resolver.put_store(&store(2, StoreState::Down)).unwrap();
+        assert_eq!(resolver.place(0, b"key"), Err(Error::NoAvailableStores(3)));
+        resolver.put_store(&store(4, StoreState::Up)).unwrap();
+        let (_, stores) = resolver.place(0, b"key").unwrap();
+        assert!(!stores.contains(&2) && stores.contains(&4), "{:?}", stores);
+    }
+
+    #[test]
+    fn stale_locations_are_flagged_and_evicted_oldest_first() {
+        let mut cache = StaleLocationCache::new(2);
+        let reply = |ttl| DataLocReply { cache_ttl_ms: ttl, ..Default::default() };
+        cache.put(0, b"a", reply(1), 100);
+        cache.put(0, b"b", reply(2), 200);
+        assert_eq!(cache.get(1, b"a", 300), None);
+        let stale = cache.get(0, b"a", 300).unwrap();
+        assert_eq!((stale.stale, stale.staleness_ms, stale.cache_ttl_ms), (true, 200, 1));
+
+        // Refreshing a key keeps its place in line.
+        cache.put(0, b"a", reply(3), 300);
+        assert_eq!(cache.get(0, b"a", 300).unwrap().staleness_ms, 0);
+        cache.put(0, b"c", reply(4), 400);
+        assert_eq!(cache.get(0, b"a", 400), None);
+        assert!(cache.get(0, b"b", 400).is_some() && cache.get(0, b"c", 400).is_some());
+    }
+}
@@ -0,0 +1,619 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::Arc;
+
+use crate::checked::add_or_err;
+use crate::comparator::{compare_keys, key_before, KeyComparator, Lexicographic, OrderedKey};
+use crate::consistency::Violation;
+use crate::error::{Error, Result};
+
+/// The keyspace used by clients that don't specify one.
+pub const DEFAULT_KEYSPACE: u32 = 0;
+
+/// Metadata of a region, a contiguous key range [start_key, end_key) within
+/// a keyspace. An empty start key is the left sentinel, i.e. negative
+/// infinity, and an empty end key is the right sentinel, i.e. positive
+/// infinity, so the first region of a keyspace starts at the empty key and
+/// the last one ends at it.
+#[derive(Clone, Debug, Hash, PartialEq, Serialize, Deserialize)]
+pub struct RegionInfo {
+    /// The region id, unique across all keyspaces.
+    pub id: u64,
+    /// The keyspace the region belongs to.
+    pub keyspace_id: u32,
+    /// The inclusive start key. Empty means unbounded on the left.
+    pub start_key: Vec<u8>,
+    /// The exclusive end key. Empty means unbounded on the right.
+    pub end_key: Vec<u8>,
+    /// The region epoch, bumped on every split or merge.
+    pub epoch: u64,
+    /// The ids of the stores holding a replica.
+    pub stores: Vec<u64>,
+    /// The id of the store holding the leader replica.
+    pub leader: u64,
+    /// The approximate size of the region in bytes, as last reported.
+    pub approximate_size: u64,
+    /// The approximate number of keys in the region, as last reported. Only
+    /// an estimate, e.g. for query planning, and stale between heartbeats.
+    pub approximate_keys: u64,
+}
+
+impl RegionInfo {
+    /// Creates a region, with no approximate size or key count reported yet. Returns
+    /// `Error::Value` unless the start key is below the end key, where an
+    /// empty end key is positive infinity. Keys are compared
+    /// lexicographically; see `validate_by()` for other orderings.
+    pub fn new(
+        id: u64,
+        keyspace_id: u32,
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+        epoch: u64,
+        stores: Vec<u64>,
+        leader: u64,
+    ) -> Result<Self> {
+        let region = Self {
+            id,
+            keyspace_id,
+            start_key,
+            end_key,
+            epoch,
+            stores,
+            leader,
+            approximate_size: 0,
+            approximate_keys: 0,
+        };
+        region.validate()?;
+        Ok(region)
+    }
+
+    /// Checks that the start key is below the end key. Regions that didn't
+    /// come from `new()`, e.g. those decoded from heartbeats, must be checked
+    /// before use.
+    pub fn validate(&self) -> Result<()> {
+        self.validate_by(&Lexicographic)
+    }
+
+    /// Like `validate()`, but with keys ordered by the given comparator.
+    pub fn validate_by(&self, comparator: &dyn KeyComparator) -> Result<()> {
+        if !key_before(comparator, &self.start_key, &self.end_key) {
+            return Err(Error::Value(format!(
+                "Region {} has an empty or inverted range [{:?}, {:?})",
+                self.id, self.start_key, self.end_key
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns true if the region contains the given key. Since the empty
+    /// start key sorts before every other key, only the end key needs special
+    /// handling for its sentinel.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.contains_by(key, &Lexicographic)
+    }
+
+    /// Like `contains()`, but with keys ordered by the given comparator.
+    pub fn contains_by(&self, key: &[u8], comparator: &dyn KeyComparator) -> bool {
+        compare_keys(comparator, key, &self.start_key) != Ordering::Less
+            && key_before(comparator, key, &self.end_key)
+    }
+}
+
+/// The key range of a region on a store, checked against its neighbors in
+/// the routing table. Used to debug routing table corruption.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RangeDescription {
+    /// The region id.
+    pub region_id: u64,
+    /// The keyspace of the region.
+    pub keyspace_id: u32,
+    /// The inclusive start key.
+    pub start_key: Vec<u8>,
+    /// The exclusive end key. Empty means unbounded.
+    pub end_key: Vec<u8>,
+    /// True if no region ends exactly where this one starts.
+    pub gap_before: bool,
+    /// True if no region starts exactly where this one ends.
+    pub gap_after: bool,
+    /// The ids of neighboring regions whose ranges overlap this one.
+    pub overlaps: Vec<u64>,
+}
+
+/// The routing table, mapping keys to regions. Every keyspace has its own
+/// isolated region map keyed by start key, so identical keys in different
+/// keyspaces never route to the same region. Keys are ordered by a
+/// `KeyComparator`, lexicographically by default.
+pub struct RoutingTable {
+    /// The key ordering.
+    comparator: Arc<dyn KeyComparator>,
+    /// The per-keyspace region maps, keyed by start key.
+    keyspaces: HashMap<u32, BTreeMap<OrderedKey, RegionInfo>>,
+    /// A secondary index from region id to the region's keyspace and start
+    /// key, kept in sync with the region maps.
+    by_id: HashMap<u64, (u32, Vec<u8>)>,
+}
+
+impl RoutingTable {
+    /// Creates a new routing table containing only the default keyspace.
+    pub fn new() -> Self {
+        Self::with_comparator(Arc::new(Lexicographic))
+    }
+
+    /// Creates a new routing table containing only the default keyspace,
+    /// with keys ordered by the given comparator.
+    pub fn with_comparator(comparator: Arc<dyn KeyComparator>) -> Self {
+        let mut keyspaces = HashMap::new();
+        keyspaces.insert(DEFAULT_KEYSPACE, BTreeMap::new());
+        Self { comparator, keyspaces, by_id: HashMap::new() }
+    }
+
+    /// Returns the key ordering.
+    pub fn comparator(&self) -> &dyn KeyComparator {
+        self.comparator.as_ref()
+    }
+
+    /// Returns a key ordered by the comparator, for looking up region maps.
+    fn ordered(&self, key: &[u8]) -> OrderedKey {
+        OrderedKey::new(key.to_vec(), self.comparator.clone())
+    }
+
+    /// Creates a new, empty keyspace.
+    pub fn create_keyspace(&mut self, keyspace_id: u32) -> Result<()> {
+        if self.keyspaces.contains_key(&keyspace_id) {
+            return Err(Error::Value(format!("Keyspace {} already exists", keyspace_id)));
+        }
+        self.keyspaces.insert(keyspace_id, BTreeMap::new());
+        Ok(())
+    }
+
+    /// Deletes a keyspace along with all of its regions.
+    pub fn delete_keyspace(&mut self, keyspace_id: u32) -> Result<()> {
+        if keyspace_id == DEFAULT_KEYSPACE {
+            return Err(Error::Value("Cannot delete the default keyspace".into()));
+        }
+        match self.keyspaces.remove(&keyspace_id) {
+            Some(regions) => {
+                for region in regions.values() {
+                    self.by_id.remove(&region.id);
+                }
+                Ok(())
+            }
+            None => Err(Error::Value(format!("Unknown keyspace {}", keyspace_id))),
+        }
+    }
+
+    /// Inserts a region into its keyspace, replacing any region with the
+    /// same start key as well as any previous version of the region itself.
+    pub fn put_region(&mut self, region: RegionInfo) -> Result<()> {
+        self.keyspace(region.keyspace_id)?;
+        region.validate_by(self.comparator())?;
+        if let Some((keyspace_id, start_key)) = self.by_id.remove(&region.id) {
+            let start_key = self.ordered(&start_key);
+            self.keyspace_mut(keyspace_id)?.remove(&start_key);
+        }
+        let (id, keyspace_id, start_key) = (region.id, region.keyspace_id, region.start_key.clone());
+        let key = self.ordered(&start_key);
+        if let Some(replaced) = self.keyspace_mut(keyspace_id)?.insert(key, region) {
+            self.by_id.remove(&replaced.id);
+        }
+        self.by_id.insert(id, (keyspace_id, start_key));
+        Ok(())
+    }
+
+    /// Splits a region at the given key, atomically replacing it with a left
+    /// half keeping its id and a right half with the new id, both at a bumped
+    /// epoch. Since both halves are swapped in at once, a concurrent lookup
+    /// sees either the old region or the two new ones, never a gap.
+    pub fn split_region(
+        &mut self,
+        id: u64,
+        split_key: &[u8],
+        new_id: u64,
+    ) -> Result<(RegionInfo, RegionInfo)> {
+        let region = self.get_region(id)?;
+        if compare_keys(self.comparator(), split_key, &region.start_key) != Ordering::Greater
+            || !region.contains_by(split_key, self.comparator())
+        {
+            return Err(Error::Value(format!("Split key {:?} is not inside region {}", split_key, id)));
+        }
+        if self.by_id.contains_key(&new_id) {
+            return Err(Error::Value(format!("Region {} already exists", new_id)));
+        }
+        let epoch = add_or_err(region.epoch, 1)?;
+        // Both halves are validated with the comparator by put_region().
+        let left = RegionInfo { end_key: split_key.to_vec(), epoch, ..region.clone() };
+        let right = RegionInfo { id: new_id, start_key: split_key.to_vec(), epoch, ..region };
+        self.put_region(left.clone())?;
+        self.put_region(right.clone())?;
+        Ok((left, right))
+    }
+
+    /// Fetches a region by id.
+    pub fn get_region(&self, id: u64) -> Result<RegionInfo> {
+        self.by_id
+            .get(&id)
+            .and_then(|(keyspace_id, start_key)| {
+                self.keyspaces.get(keyspace_id)?.get(&self.ordered(start_key))
+            })
+            .cloned()
+            .ok_or_else(|| Error::NotFound(format!("Region {} not found", id)))
+    }
+
+    /// Looks up the region containing a key in a keyspace.
+    pub fn lookup(&self, keyspace_id: u32, key: &[u8]) -> Result<RegionInfo> {
+        self.keyspace(keyspace_id)?
+            .range(..=self.ordered(key))
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| region.contains_by(key, self.comparator()))
+            .cloned()
+            .ok_or_else(|| Error::Value(format!("No region for key {:?} in keyspace {}", key, keyspace_id)))
+    }
+
+    /// Returns the ids of all keyspaces, in ascending order.
+    pub fn keyspace_ids(&self) -> Vec<u32> {
+        let mut keyspace_ids: Vec<u32> = self.keyspaces.keys().copied().collect();
+        keyspace_ids.sort_unstable();
+        keyspace_ids
+    }
+
+    /// Returns the number of regions, across keyspaces.
+    pub fn region_count(&self) -> usize {
+        self.by_id.len()
+    }
+
+    /// Returns the number of replicas each store holds, by store id. Stores
+    /// without replicas are left out.
+    pub fn store_region_counts(&self) -> HashMap<u64, usize> {
+        let mut counts = HashMap::new();
+        for store_id in self.regions().flat_map(|region| &region.stores) {
+            *counts.entry(*store_id).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    /// Iterates over all regions, across keyspaces.
+    pub fn regions(&self) -> impl Iterator<Item = &RegionInfo> {
+        self.keyspaces.values().flat_map(|regions| regions.values())
+    }
+
+    /// Picks the leader of a new region among the stores holding its
+    /// replicas, spreading leadership evenly from the start: each candidate
+    /// is weighted by the inverse of the number of regions it already leads,
+    /// and the heaviest one wins, ties going to a store drawn from the given
+    /// RNG, see `placement.seed`. Since every pick raises the winner's count,
+    /// successive picks among the same stores take turns, as in a
+    /// round-robin. Returns 0 without candidates.
+    pub fn pick_leader(&self, candidates: &[u64], rng: &mut impl Rng) -> u64 {
+        let mut leader_counts: HashMap<u64, usize> = candidates.iter().map(|&id| (id, 0)).collect();
+        for region in self.regions() {
+            if let Some(count) = leader_counts.get_mut(&region.leader) {
+                *count += 1;
+            }
+        }
+        // The first of several least loaded candidates wins, so shuffling
+        // them from a fixed order draws the tie-break.
+        let mut candidates = candidates.to_vec();
+        candidates.sort_unstable();
+        candidates.shuffle(rng);
+        candidates.into_iter().min_by_key(|id| leader_counts[id]).unwrap_or(0)
+    }
+
+    /// Scans up to `limit` regions overlapping [start_key, end_key) in a
+    /// keyspace, in key order. An empty end key means unbounded. Pages only
+    /// ever contain whole regions; along with them, this returns the start key
+    /// to resume the scan from, which is empty once the range is exhausted.
+    /// Returns `Error::Value` unless the start key is below the end key.
+    pub fn scan(
+        &self,
+        keyspace_id: u32,
+        start_key: &[u8],
+        end_key: &[u8],
+        limit: usize,
+    ) -> Result<(Vec<RegionInfo>, Vec<u8>)> {
+        let comparator = self.comparator();
+        if !key_before(comparator, start_key, end_key) {
+            return Err(Error::Value("Range start key must be below its end key".into()));
+        }
+        let regions = self.keyspace(keyspace_id)?;
+        let from = regions
+            .range(..=self.ordered(start_key))
+            .next_back()
+            .filter(|(_, region)| region.contains_by(start_key, comparator))
+            .map(|(key, _)| key.clone())
+            .unwrap_or_else(|| self.ordered(start_key));
+        let page: Vec<RegionInfo> = regions
+            .range(from..)
+            .map(|(_, region)| region)
+            .take_while(|region| key_before(comparator, &region.start_key, end_key))
+            .take(limit)
+            .cloned()
+            .collect();
+        let next_start_key = match page.last() {
+            Some(last)
+                if page.len() == limit
+                    && !last.end_key.is_empty()
+                    && key_before(comparator, &last.end_key, end_key) =>
+            {
+                last.end_key.clone()
+            }
+            _ => Vec::new(),
+        };
+        Ok((page, next_start_key))
+    }
+
+    /// Describes the key ranges of all regions with a replica on the given
+    /// store, sorted by keyspace and start key, flagging gaps and overlaps
+    /// relative to each region's neighbors.
+    pub fn describe_store(&self, store_id: u64) -> Vec<RangeDescription> {
+        let comparator = self.comparator();
+        let mut ranges = Vec::new();
+        for keyspace_id in self.keyspace_ids() {
+            let regions = &self.keyspaces[&keyspace_id];
+            for region in regions.values().filter(|region| region.stores.contains(&store_id)) {
+                let start_key = self.ordered(&region.start_key);
+                let prev = regions.range(..start_key.clone()).next_back().map(|(_, r)| r);
+                let next =
+                    regions.range((Bound::Excluded(start_key), Bound::Unbounded)).next().map(|(_, r)| r);
+                let mut overlaps = Vec::new();
+                if let Some(prev) = prev {
+                    if key_before(comparator, &region.start_key, &prev.end_key) {
+                        overlaps.push(prev.id);
+                    }
+                }
+                if let Some(next) = next {
+                    if key_before(comparator, &next.start_key, &region.end_key) {
+                        overlaps.push(next.id);
+                    }
+                }
+                let gap_before = !region.start_key.is_empty()
+                    && prev.is_none_or(|prev| {
+                        !prev.end_key.is_empty()
+                            && compare_keys(comparator, &prev.end_key, &region.start_key) == Ordering::Less
+                    });
+                let gap_after = !region.end_key.is_empty()
+                    && next.is_none_or(|next| {
+                        compare_keys(comparator, &next.start_key, &region.end_key) == Ordering::Greater
+                    });
+                ranges.push(RangeDescription {
+                    region_id: region.id,
+                    keyspace_id,
+                    start_key: region.start_key.clone(),
+                    end_key: region.end_key.clone(),
+                    gap_before,
+                    gap_after,
+                    overlaps,
+                });
+            }
+        }
+        ranges
+    }
+
+    /// Checks the routing table invariants: regions within a keyspace never
+    /// overlap, and the region id index matches the region maps.
+    pub fn validate(&self) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        for keyspace_id in self.keyspace_ids() {
+            let mut prev: Option<&RegionInfo> = None;
+            for (start_key, region) in &self.keyspaces[&keyspace_id] {
+                if let Some(prev) = prev {
+                    if key_before(self.comparator(), &region.start_key, &prev.end_key) {
+                        violations.push(Violation::RegionOverlap {
+                            keyspace_id,
+                            region_id: prev.id,
+                            other_region_id: region.id,
+                        });
+                    }
+                }
+                if region.keyspace_id != keyspace_id
+                    || start_key.as_slice() != region.start_key
+                    || self.by_id.get(&region.id) != Some(&(keyspace_id, region.start_key.clone()))
+                {
+                    violations.push(Violation::RegionIndexMismatch { region_id: region.id });
+                }
+                prev = Some(region);
+            }
+        }
+        let mut dangling: Vec<u64> = self
+            .by_id
+            .iter()
+            .filter(|(_, (keyspace_id, start_key))| {
+                self.keyspaces
+                    .get(keyspace_id)
+                    .is_none_or(|regions| !regions.contains_key(&self.ordered(start_key)))
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        dangling.sort_unstable();
+        violations.extend(dangling.into_iter().map(|region_id| Violation::RegionIndexMismatch { region_id }));
+        violations
+    }
+
+    /// Returns the region map of a keyspace.
+    fn keyspace(&self, keyspace_id: u32) -> Result<&BTreeMap<OrderedKey, RegionInfo>> {
+        self.keyspaces
+            .get(&keyspace_id)
+            .ok_or_else(|| Error::Value(format!("Unknown keyspace {}", keyspace_id)))
+    }
+
+    /// Returns the mutable region map of a keyspace.
+    fn keyspace_mut(&mut self, keyspace_id: u32) -> Result<&mut BTreeMap<OrderedKey, RegionInfo>> {
+        self.keyspaces
+            .get_mut(&keyspace_id)
+            .ok_or_else(|| Error::Value(format!("Unknown keyspace {}", keyspace_id)))
+    }
+}
+
+impl Default for RoutingTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validate::MAX_KEY_LEN;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashSet;
+
+    fn region(id: u64, start_key: &[u8], end_key: &[u8]) -> RegionInfo {
+        RegionInfo::new(id, DEFAULT_KEYSPACE, start_key.to_vec(), end_key.to_vec(), 1, vec![1], 1).unwrap()
+    }
+
+    #[test]
+    fn keys_at_the_sentinels_route_to_the_first_and_last_regions() {
+        let mut routing = RoutingTable::new();
+        routing.put_region(region(1, b"", b"m")).unwrap();
+        routing.put_region(region(2, b"m", b"")).unwrap();
+
+        let lookup = |key: &[u8]| routing.lookup(DEFAULT_KEYSPACE, key).unwrap().id;
+        assert_eq!(lookup(b""), 1);
+        assert_eq!(lookup(&[0x00]), 1);
+        assert_eq!(lookup(b"a"), 1);
+        assert_eq!(lookup(&[b'l', 0xff, 0xff]), 1);
+        assert_eq!(lookup(b"m"), 2);
+        assert_eq!(lookup(&[0xff]), 2);
+        assert_eq!(lookup(&vec![0xff; MAX_KEY_LEN]), 2);
+    }
+
+    #[test]
+    fn a_single_region_spans_the_whole_keyspace() {
+        let mut routing = RoutingTable::new();
+        routing.put_region(region(1, b"", b"")).unwrap();
+        for key in [&b""[..], &[0x00], b"m", &vec![0xff; MAX_KEY_LEN]] {
+            assert_eq!(routing.lookup(DEFAULT_KEYSPACE, key).unwrap().id, 1);
+        }
+    }
+
+    #[test]
+    fn keys_before_the_first_region_have_no_region() {
+        let mut routing = RoutingTable::new();
+        routing.put_region(region(1, b"b", b"")).unwrap();
+        assert!(matches!(routing.lookup(DEFAULT_KEYSPACE, b""), Err(Error::Value(_))));
+        assert!(matches!(routing.lookup(DEFAULT_KEYSPACE, b"a"), Err(Error::Value(_))));
+        assert_eq!(routing.lookup(DEFAULT_KEYSPACE, b"b").unwrap().id, 1);
+    }
+
+    #[test]
+    fn empty_or_inverted_ranges_are_rejected() {
+        let new = |start_key: &[u8], end_key: &[u8]| {
+            RegionInfo::new(1, DEFAULT_KEYSPACE, start_key.to_vec(), end_key.to_vec(), 1, vec![1], 1)
+        };
+        assert!(matches!(new(b"m", b"m"), Err(Error::Value(_))));
+        assert!(matches!(new(b"m", b"a"), Err(Error::Value(_))));
+        assert!(new(b"", b"").is_ok());
+        assert!(new(b"m", b"").is_ok());
+
+        let mut routing = RoutingTable::new();
+        let inverted = RegionInfo { end_key: b"a".to_vec(), ..region(1, b"m", b"") };
+        assert!(matches!(routing.put_region(inverted), Err(Error::Value(_))));
+        assert_eq!(routing.region_count(), 0);
+    }
+
+    #[test]
+    fn new_regions_need_a_start_key_below_a_bounded_end_key() {
+        let new = |start_key: &[u8], end_key: &[u8]| {
+            RegionInfo::new(1, DEFAULT_KEYSPACE, start_key.to_vec(), end_key.to_vec(), 1, vec![1], 1)
+        };
+        assert!(new(b"", b"a").is_ok());
+        assert!(new(b"a", b"b").is_ok());
+        assert!(new(b"a", b"a\x00").is_ok());
+        assert!(matches!(new(b"b", b"a"), Err(Error::Value(_))));
+        assert!(matches!(new(b"a\x00", b"a"), Err(Error::Value(_))));
+    }
+
+    #[test]
+    fn decoded_regions_are_validated() {
+        let corrupt = RegionInfo { start_key: b"b".to_vec(), end_key: b"a".to_vec(), ..region(1, b"", b"") };
+        let decoded: RegionInfo = bincode::deserialize(&bincode::serialize(&corrupt).unwrap()).unwrap();
+        assert!(matches!(decoded.validate(), Err(Error::Value(_))));
+        assert!(region(1, b"a", b"b").validate().is_ok());
+    }
+
+    #[test]
+    fn splits_only_produce_valid_halves() {
+        let mut routing = RoutingTable::new();
+        routing.put_region(region(1, b"b", b"d")).unwrap();
+        for split_key in [&b"b"[..], b"a", b"d", b"e"] {
+            assert!(matches!(routing.split_region(1, split_key, 2), Err(Error::Value(_))));
+        }
+        let (left, right) = routing.split_region(1, b"c", 2).unwrap();
+        assert_eq!((left.start_key.as_slice(), left.end_key.as_slice()), (&b"b"[..], &b"c"[..]));
+        assert_eq!((right.start_key.as_slice(), right.end_key.as_slice()), (&b"c"[..], &b"d"[..]));
+        assert!(left.validate().is_ok() && right.validate().is_ok());
+    }
+
+    /// Places `count` regions, each on the candidates returned for its index
+    /// and led by the picked leader, returning the number led per store.
+    fn place_regions(count: u64, candidates: impl Fn(u64) -> Vec<u64>) -> HashMap<u64, usize> {
+        let mut routing = RoutingTable::new();
+        let rng = &mut StdRng::seed_from_u64(0);
+        for i in 0..count {
+            let stores = candidates(i);
+            let leader = routing.pick_leader(&stores, rng);
+            let (start_key, end_key) = (i.to_be_bytes().to_vec(), (i + 1).to_be_bytes().to_vec());
+            let region = RegionInfo::new(i + 1, DEFAULT_KEYSPACE, start_key, end_key, 1, stores, leader);
+            routing.put_region(region.unwrap()).unwrap();
+        }
+        let mut leader_counts = HashMap::new();
+        for region in routing.regions() {
+            *leader_counts.entry(region.leader).or_default() += 1;
+        }
+        leader_counts
+    }
+
+    #[test]
+    fn leaders_of_new_regions_take_turns() {
+        let leader_counts = place_regions(300, |_| vec![3, 1, 2]);
+        assert_eq!(leader_counts, HashMap::from([(1, 100), (2, 100), (3, 100)]));
+    }
+
+    #[test]
+    fn leaders_are_balanced_across_overlapping_placements() {
+        // Each region is placed on 3 of 5 stores, in rotation.
+        let leader_counts = place_regions(1_000, |i| (0..3).map(|j| (i + j) % 5 + 1).collect());
+        assert_eq!(leader_counts.len(), 5);
+        assert!(leader_counts.values().all(|&count| (190..=210).contains(&count)), "{:?}", leader_counts);
+    }
+
+    #[test]
+    fn leader_ties_are_drawn_from_the_rng() {
+        let mut routing = RoutingTable::new();
+        let pick =
+            |routing: &RoutingTable, seed| routing.pick_leader(&[3, 2, 5], &mut StdRng::seed_from_u64(seed));
+        assert_eq!(pick(&routing, 7), pick(&routing, 7));
+        let picks: HashSet<u64> = (0..50).map(|seed| pick(&routing, seed)).collect();
+        assert_eq!(picks, HashSet::from([2, 3, 5]));
+        assert_eq!(routing.pick_leader(&[], &mut StdRng::seed_from_u64(0)), 0);
+
+        // Only ties are drawn: a store leading fewer regions always wins.
+        for (id, leader) in [(1, 2), (2, 3)] {
+            let region = RegionInfo::new(
+                id,
+                DEFAULT_KEYSPACE,
+                vec![id as u8],
+                vec![id as u8 + 1],
+                1,
+                vec![leader],
+                leader,
+            );
+            routing.put_region(region.unwrap()).unwrap();
+        }
+        assert!((0..50).all(|seed| pick(&routing, seed) == 5));
+    }
+
+    #[test]
+    fn splitting_a_region_at_the_maximum_epoch_fails_cleanly() {
+        let mut routing = RoutingTable::new();
+        routing.put_region(RegionInfo { epoch: u64::MAX, ..region(1, b"", b"") }).unwrap();
+        assert!(matches!(routing.split_region(1, b"m", 2), Err(Error::Internal(_))));
+        assert_eq!(routing.get_region(1).unwrap().epoch, u64::MAX);
+        assert_eq!(routing.region_count(), 1);
+    }
+}
@@ -0,0 +1,235 @@
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+
+use crate::comparator::compare_keys;
+use crate::error::{Error, Result};
+use crate::routing::{RegionInfo, RoutingTable};
+use crate::store::{StoreRegistry, StoreState};
+
+/// A scheduling operation on a region. Each operation carries the region
+/// epoch it was computed against, and is rejected if the region has changed
+/// since. Operations may be computed by an external scheduler, in which case
+/// they are passed in bincode-encoded.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ScheduleOp {
+    /// Adds a replica of the region on a store.
+    AddReplica { region_id: u64, epoch: u64, store_id: u64 },
+    /// Removes the replica of the region on a store, which must not be the
+    /// leader.
+    RemoveReplica { region_id: u64, epoch: u64, store_id: u64 },
+    /// Transfers the region leadership to the replica on a store.
+    TransferLeader { region_id: u64, epoch: u64, to_store_id: u64 },
+    /// Splits the region at a key, giving the right half the new region id.
+    Split { region_id: u64, epoch: u64, split_key: Vec<u8>, new_region_id: u64 },
+    /// Merges the source region into the adjacent target region.
+    Merge { source_region_id: u64, source_epoch: u64, target_region_id: u64, target_epoch: u64 },
+}
+
+impl ScheduleOp {
+    /// Validates the operation against the current topology.
+    pub fn validate(&self, routing: &RoutingTable, stores: &StoreRegistry) -> Result<()> {
+        match self {
+            ScheduleOp::AddReplica { region_id, epoch, store_id } => {
+                let region = Self::region(routing, *region_id, *epoch)?;
+                Self::live_store(stores, *store_id)?;
+                if region.stores.contains(store_id) {
+                    return Err(Error::Value(format!(
+                        "Region {} already has a replica on store {}",
+                        region_id, store_id
+                    )));
+                }
+            }
+            ScheduleOp::RemoveReplica { region_id, epoch, store_id } => {
+                let region = Self::region(routing, *region_id, *epoch)?;
+                if !region.stores.contains(store_id) {
+                    return Err(Error::Value(format!(
+                        "Region {} has no replica on store {}",
+                        region_id, store_id
+                    )));
+                }
+                if region.leader == *store_id {
+                    return Err(Error::Value(format!(
+                        "Cannot remove the leader replica of region {}",
+                        region_id
+                    )));
+                }
+                if region.stores.len() == 1 {
+                    return Err(Error::Value(format!(
+                        "Cannot remove the last replica of region {}",
+                        region_id
+                    )));
+                }
+            }
+            ScheduleOp::TransferLeader { region_id, epoch, to_store_id } => {
+                let region = Self::region(routing, *region_id, *epoch)?;
+                Self::live_store(stores, *to_store_id)?;
+                if !region.stores.contains(to_store_id) {
+                    return Err(Error::Value(format!(
+                        "Region {} has no replica on store {}",
+                        region_id, to_store_id
+                    )));
+                }
+                if region.leader == *to_store_id {
+                    return Err(Error::Value(format!(
+                        "Store {} already leads region {}",
+                        to_store_id, region_id
+                    )));
+                }
+            }
+            ScheduleOp::Split { region_id, epoch, split_key, new_region_id } => {
+                let region = Self::region(routing, *region_id, *epoch)?;
+                let comparator = routing.comparator();
+                if compare_keys(comparator, split_key, &region.start_key) != Ordering::Greater
+                    || !region.contains_by(split_key, comparator)
+                {
+                    return Err(Error::Value(format!(
+                        "Split key {:?} is not inside region {}",
+                        split_key, region_id
+                    )));
+                }
+                if routing.get_region(*new_region_id).is_ok() {
+                    return Err(Error::Value(format!("Region {} already exists", new_region_id)));
+                }
+            }
+            ScheduleOp::Merge { source_region_id, source_epoch, target_region_id, target_epoch } => {
+                let source = Self::region(routing, *source_region_id, *source_epoch)?;
+                let target = Self::region(routing, *target_region_id, *target_epoch)?;
+                let adjacent = (!source.end_key.is_empty() && source.end_key == target.start_key)
+                    || (!target.end_key.is_empty() && target.end_key == source.start_key);
+                if source.keyspace_id != target.keyspace_id || !adjacent {
+                    return Err(Error::Value(format!(
+                        "Regions {} and {} are not adjacent",
+                        source_region_id, target_region_id
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the id of the region the operation applies to, or of the
+    /// source region for a merge.
+    pub fn region_id(&self) -> u64 {
+        match self {
+            ScheduleOp::AddReplica { region_id, .. }
+            | ScheduleOp::RemoveReplica { region_id, .. }
+            | ScheduleOp::TransferLeader { region_id, .. }
+            | ScheduleOp::Split { region_id, .. } => *region_id,
+            ScheduleOp::Merge { source_region_id, .. } => *source_region_id,
+        }
+    }
+
+    /// Fetches a region, checking that it is still at the given epoch.
+    fn region(routing: &RoutingTable, region_id: u64, epoch: u64) -> Result<RegionInfo> {
+        let region = routing.get_region(region_id)?;
+        if region.epoch != epoch {
+            return Err(Error::Value(format!(
+                "Region {} is at epoch {}, not {}",
+                region_id, region.epoch, epoch
+            )));
+        }
+        Ok(region)
+    }
+
+    /// Checks that a store is registered and up.
+    fn live_store(stores: &StoreRegistry, store_id: u64) -> Result<()> {
+        if stores.get(store_id)?.state != StoreState::Up {
+            return Err(Error::Value(format!("Store {} is not up", store_id)));
+        }
+        Ok(())
+    }
+}
+
+/// The queue of validated operations waiting to be carried out, oldest first.
+/// Operations leave it once carried out or made stale by a change to their
+/// region, see `retain()` and `remove()`.
+#[derive(Default)]
+pub struct OperationQueue {
+    ops: VecDeque<ScheduleOp>,
+}
+
+impl OperationQueue {
+    /// Creates an empty operation queue.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enqueues a validated operation, unless the same one is already queued.
+    /// Returns true if it was enqueued.
+    pub fn push(&mut self, op: ScheduleOp) -> bool {
+        if self.ops.contains(&op) {
+            return false;
+        }
+        self.ops.push_back(op);
+        true
+    }
+
+    /// Removes a queued operation, e.g. once it is confirmed as carried out.
+    /// Returns true if it was queued.
+    pub fn remove(&mut self, op: &ScheduleOp) -> bool {
+        let len = self.ops.len();
+        self.ops.retain(|queued| queued != op);
+        self.ops.len() < len
+    }
+
+    /// Keeps only the operations the predicate holds for, e.g. those still
+    /// valid against the current topology. Returns the dropped ones.
+    pub fn retain(&mut self, mut keep: impl FnMut(&ScheduleOp) -> bool) -> Vec<ScheduleOp> {
+        let mut dropped = Vec::new();
+        self.ops.retain(|op| {
+            let kept = keep(op);
+            if !kept {
+                dropped.push(op.clone());
+            }
+            kept
+        });
+        dropped
+    }
+
+    /// Returns the pending operations a store must carry out, i.e. those on
+    /// regions it leads, oldest first.
+    pub fn for_store(&self, store_id: u64, routing: &RoutingTable) -> Vec<ScheduleOp> {
+        self.ops
+            .iter()
+            .filter(|op| routing.get_region(op.region_id()).is_ok_and(|region| region.leader == store_id))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the pending operations, oldest first.
+    pub fn pending(&self) -> Vec<ScheduleOp> {
+        self.ops.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_replica(region_id: u64, epoch: u64, store_id: u64) -> ScheduleOp {
+        ScheduleOp::AddReplica { region_id, epoch, store_id }
+    }
+
+    #[test]
+    fn push_skips_queued_operations() {
+        let mut queue = OperationQueue::new();
+        assert!(queue.push(add_replica(1, 1, 2)));
+        assert!(!queue.push(add_replica(1, 1, 2)));
+        assert!(queue.push(add_replica(1, 2, 2)));
+        assert_eq!(queue.pending(), vec![add_replica(1, 1, 2), add_replica(1, 2, 2)]);
+    }
+
+    #[test]
+    fn remove_and_retain_drop_operations() {
+        let mut queue = OperationQueue::new();
+        for store_id in 1..=4 {
+            queue.push(add_replica(1, 1, store_id));
+        }
+        assert!(queue.remove(&add_replica(1, 1, 1)));
+        assert!(!queue.remove(&add_replica(1, 1, 1)));
+        let dropped = queue.retain(|op| !matches!(op, ScheduleOp::AddReplica { store_id: 3, .. }));
+        assert_eq!(dropped, vec![add_replica(1, 1, 3)]);
+        assert_eq!(queue.pending(), vec![add_replica(1, 1, 2), add_replica(1, 1, 4)]);
+    }
+}
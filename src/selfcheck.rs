@@ -0,0 +1,72 @@
+use log::{info, warn};
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::checkpoint::{Checkpoint, CheckpointStore, FileCheckpoint, MemoryCheckpoint};
+use crate::config::TsoConfig;
+use crate::error::Result;
+use crate::tso::TimestampOracle;
+
+/// The number of timestamps allocated by the self-check.
+pub const SELFCHECK_ALLOCATIONS: u64 = 100_000;
+
+/// The number of checkpoint writes timed by the self-check.
+pub const SELFCHECK_CHECKPOINT_WRITES: u32 = 10;
+
+/// The mean checkpoint write latency above which the disk is considered too
+/// slow for frequent window renewals.
+pub const SLOW_CHECKPOINT_WRITE: Duration = Duration::from_millis(10);
+
+/// The results of a startup self-check.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfCheckReport {
+    /// The mean time to allocate a timestamp, excluding checkpoint writes.
+    pub allocation_latency: Duration,
+    /// The mean time to write a checkpoint with the configured sync policy,
+    /// or None if checkpoints are kept in memory.
+    pub checkpoint_write_latency: Option<Duration>,
+}
+
+/// Benchmarks the TSO allocation path and checkpoint writes, logging the
+/// results, and warning if the disk is too slow for the configured window
+/// size. Neither touches the server's own TSO: allocation runs against an
+/// in-memory oracle, and checkpoints are written to a scratch file next to
+/// the configured checkpoint, which is removed afterwards. Blocks while
+/// syncing to disk.
+pub fn run(config: &TsoConfig) -> Result<SelfCheckReport> {
+    let mut tso = TimestampOracle::new(Arc::new(MemoryCheckpoint::new()), config.window_size);
+    tso.recover(None);
+    let start = Instant::now();
+    for _ in 0..SELFCHECK_ALLOCATIONS {
+        tso.get_next_ts()?;
+    }
+    let allocation_latency = start.elapsed() / SELFCHECK_ALLOCATIONS as u32;
+
+    let checkpoint_write_latency = match &config.checkpoint_path {
+        Some(path) => {
+            let mut scratch = path.clone().into_os_string();
+            scratch.push(".selfcheck");
+            let store = FileCheckpoint::new(&scratch).with_sync_policy(config.sync_policy);
+            let start = Instant::now();
+            for i in 0..SELFCHECK_CHECKPOINT_WRITES {
+                store.save(&Checkpoint { window_end: i as u64, last_allocated: None })?;
+            }
+            let latency = start.elapsed() / SELFCHECK_CHECKPOINT_WRITES;
+            fs::remove_file(&scratch)?;
+            Some(latency)
+        }
+        None => None,
+    };
+
+    let report = SelfCheckReport { allocation_latency, checkpoint_write_latency };
+    info!("Startup self-check: {:?}", report);
+    if let Some(latency) = checkpoint_write_latency.filter(|latency| *latency > SLOW_CHECKPOINT_WRITE) {
+        warn!(
+            "Checkpoint writes take {:?} on average, above {:?}: timestamp allocation will stall on \
+             every window renewal. Consider a larger tso.window_size than {}, or faster storage",
+            latency, SLOW_CHECKPOINT_WRITE, config.window_size
+        );
+    }
+    Ok(report)
+}
@@ -1,32 +1,3698 @@
+use log::{error, info, warn};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use tonic::{Request, Response};
+use tokio::sync::{mpsc, watch};
+use tokio_stream::wrappers::{ReceiverStream, WatchStream};
+use tokio_stream::StreamExt;
+use tonic::codegen::BoxStream;
+use tonic::{Request, Response, Streaming};
 
-use crate::error::{Result, RpcResult};
-use crate::proto::placement_driver::{PlacementDriver, TsoRequest, TsoReply, DataLocRequest, DataLocReply};
+use crate::admin::{check_admin_token, NonceCache, DEFAULT_NONCE_CAPACITY};
+use crate::admission::AdmissionQueue;
+use crate::audit::AuditLog;
+use crate::breaker::BreakerCheckpoint;
+use crate::checked::add_or_err;
+use crate::checkpoint::{Checkpoint, CheckpointStore, FileCheckpoint, MemoryCheckpoint};
+use crate::clock::{Clock, SystemClock};
+use crate::coalesce::SingleFlight;
+use crate::compaction::{
+    CompactionHints, DEFAULT_COMPACTION_STALE_VERSIONS, DEFAULT_MAX_CONCURRENT_COMPACTIONS,
+};
+use crate::comparator::{KeyComparator, Lexicographic};
+use crate::config::{Config, ResolverKind, UndersizedPolicy};
+use crate::consistency::{ConsistencyReport, Violation};
+use crate::error::{Error, Result, RpcResult};
+use crate::hotspot::{HotspotDetector, DEFAULT_HOT_REGION_QPS};
+use crate::id::{IdAllocator, MonotonicIdAllocator};
+use crate::labels::{LabelSelector, Labels};
+use crate::metrics::MetricsSnapshot;
+use crate::oplog::{Operation, OperationLog, OperationRecord, DEFAULT_OPERATION_LOG_CAPACITY};
+use crate::proto::placement_driver::{
+    self, AdvanceTimestampReply, AdvanceTimestampRequest, AllocIdReply, AllocIdRequest, BatchHeartbeatReply,
+    BatchHeartbeatRequest, ClusterStatusReply, ClusterStatusRequest, ConfirmTransferReply,
+    ConfirmTransferRequest, CreateKeyspaceReply, CreateKeyspaceRequest, DataLocRangeReply,
+    DataLocRangeRequest, DataLocReply, DataLocRequest, DeleteKeyspaceReply, DeleteKeyspaceRequest,
+    DescribeStoreKeyspaceReply, DescribeStoreKeyspaceRequest, DumpTopologyReply, DumpTopologyRequest,
+    FlushReply, FlushRequest, GetGcSafePointReply, GetGcSafePointRequest, GetRegionByIdReply,
+    GetRegionByIdRequest, InjectOperationReply, InjectOperationRequest, PinLeaderReply, PinLeaderRequest,
+    PlacementDriver, QueryStoresReply, QueryStoresRequest, RegionHeartbeatReply, RegionHeartbeatRequest,
+    RegisterStoreReply, RegisterStoreRequest, ReplayTopologyReply, ReplayTopologyRequest,
+    SetSchedulingEnabledReply, SetSchedulingEnabledRequest, SetStoreStateReply, SetStoreStateRequest,
+    StoreHeartbeatReply, StoreHeartbeatRequest, StoreOperations, TsoReply, TsoRequest, UnpinLeaderReply,
+    UnpinLeaderRequest, UpdateGcSafePointReply, UpdateGcSafePointRequest, VerifyConsistencyReply,
+    VerifyConsistencyRequest, WatchGcSafePointReply, WatchGcSafePointRequest, WatchTopologyReply,
+    WatchTopologyRequest,
+};
+use crate::resolver::{ConsistentHashResolver, LocationResolver, StaleLocationCache};
+use crate::routing::{RangeDescription, RegionInfo, RoutingTable, DEFAULT_KEYSPACE};
+use crate::scheduler::{OperationQueue, ScheduleOp};
+use crate::selfcheck;
+use crate::snapshot::{self, Change, ChangeLog, Snapshot};
+use crate::split;
+use crate::stability::TopologyActivity;
+use crate::status::{ClusterStatus, PinnedLeader, PinnedStoreWeight, SpaceAlert, StoreRegionCount};
+use crate::store::{check_weight, SpaceLevel, StoreInfo, StoreRegistry, StoreState};
+use crate::task;
+use crate::topology::{TopologyDump, TopologyRegion, TopologyStore, TOPOLOGY_SCHEMA_VERSION};
+use crate::transfer::{PendingTransfer, TransferTracker};
+use crate::tso::{TimestampOracle, DEFAULT_WINDOW_SIZE};
+use crate::validate::{
+    ValidatedDataLocRangeRequest, ValidatedDataLocRequest, ValidatedRegisterStoreRequest, ValidatedTsoRequest,
+};
+use crate::watch::{TopologyEvent, TopologyWatchers};
 
-/// A featherPD server with a TSO.
+/// The serving state of a FeatherPD server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServingState {
+    /// The persisted watermark has not been recovered yet. No timestamps may
+    /// be handed out, since they could fall below the old high-water mark.
+    Bootstrapping,
+    /// The watermark has been recovered and timestamps are being served.
+    Serving,
+    /// The server is shutting down. No timestamps are handed out anymore, so
+    /// that the TSO state persisted on shutdown is final.
+    ShuttingDown,
+}
+
+/// The replication role of a FeatherPD server.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// The leader serves timestamps and all mutations.
+    Leader,
+    /// A read-only follower serves possibly stale data locations from its
+    /// replicated routing table, to offload the leader. It never serves
+    /// timestamps, and rejects all mutations with `Error::NotLeader`.
+    ReadOnlyFollower,
+}
+
+/// How fresh the routing a data location is served from must be.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConsistencyLevel {
+    /// Only the leader's routing, which is always the freshest.
+    Strong,
+    /// The leader's routing, or a follower's that last synced with the
+    /// leader at most this many milliseconds ago.
+    BoundedStaleness(u64),
+    /// Any server's routing, however stale.
+    Eventual,
+}
+
+/// What a region's leader should do, in reply to its heartbeat.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RegionAdvice {
+    /// Whether to compact the region now.
+    pub compact: bool,
+    /// The keys to split the region at, in order, if it is oversized. Empty
+    /// if it shouldn't be split, or if too few keys were sampled to pick them.
+    pub split_keys: Vec<Vec<u8>>,
+}
+
+/// The number of replies buffered per `AllocateStream` call before the
+/// server stops reading further requests from it.
+pub const ALLOCATE_STREAM_CAPACITY: usize = 64;
+
+/// A featherPD server with a TSO. Clones share all state, e.g. to serve a
+/// stream from a spawned task.
+#[derive(Clone)]
 pub struct FeatherPD {
-    /// The next timestamp to be assigned.
-    next_ts: Arc<Mutex<u64>>,
+    /// The timestamp oracle.
+    tso: Arc<Mutex<TimestampOracle>>,
+    /// The oracles of keyspaces created with an isolated TSO, by keyspace id.
+    keyspace_tsos: Arc<Mutex<HashMap<u32, Arc<Mutex<TimestampOracle>>>>>,
+    /// The serving state.
+    state: Arc<Mutex<ServingState>>,
+    /// The replication role.
+    role: Role,
+    /// The leader's change log version the replicated state is at, if a
+    /// follower.
+    replicated_version: Arc<Mutex<u64>>,
+    /// The time the replicated state was last synced with the leader, in
+    /// milliseconds, or None if never.
+    replicated_at_ms: Arc<Mutex<Option<u64>>>,
+    /// The time before which no timestamps are served after taking over, in
+    /// milliseconds, to let a previous leader's lease expire.
+    grace_until_ms: Arc<Mutex<u64>>,
+    /// The time the leader lease expires at, in milliseconds, if
+    /// `tso.lease_ms` is set.
+    lease_expires_ms: Arc<Mutex<u64>>,
+    /// The GC safe point: versions below it may be garbage collected by the
+    /// stores, so historical reads below it can return missing data. Its
+    /// receivers are the subscribers to its advances.
+    gc_safe_point: Arc<watch::Sender<u64>>,
+    /// Set once the watchers are closed on shutdown, ending the GC safe point
+    /// streams.
+    watchers_closed: Arc<watch::Sender<bool>>,
+    /// The clock.
+    clock: Arc<dyn Clock>,
+    /// The key ordering of the routing tables.
+    key_comparator: Arc<dyn KeyComparator>,
+    /// The per-keyspace routing tables.
+    routing: Arc<Mutex<RoutingTable>>,
+    /// The resolver serving data-location lookups instead of the routing
+    /// tables, if any.
+    location_resolver: Option<Arc<dyn LocationResolver>>,
+    /// The data-location lookups in flight, by keyspace and key, so that
+    /// concurrent lookups of the same key share one.
+    location_lookups: Arc<SingleFlight<(u32, Vec<u8>), DataLocReply>>,
+    /// The last successful data-location lookups, if `dataloc.serve_stale_on_error`
+    /// is set.
+    stale_locations: Option<Arc<Mutex<StaleLocationCache>>>,
+    /// The registered stores.
+    stores: Arc<Mutex<StoreRegistry>>,
+    /// Draws the tie-breaks between equally good stores when placing
+    /// replicas and picking leaders, see `placement.seed`.
+    placement_rng: Arc<Mutex<StdRng>>,
+    /// Recent routing and store changes, for incremental snapshots.
+    changes: Arc<Mutex<ChangeLog>>,
+    /// The log of mutating operations, for auditing and point-in-time
+    /// recovery of the topology.
+    oplog: Arc<Mutex<OperationLog>>,
+    /// Scheduling operations waiting to be carried out.
+    operations: Arc<Mutex<OperationQueue>>,
+    /// Pending two-phase leader transfers.
+    transfers: Arc<Mutex<TransferTracker>>,
+    /// The stores region leaders are pinned to, by region id.
+    pinned_leaders: Arc<Mutex<HashMap<u64, u64>>>,
+    /// Whether scheduling is paused, see `set_scheduling_enabled()`.
+    scheduling_paused: Arc<Mutex<bool>>,
+    /// Subscribers to topology changes.
+    watchers: Arc<Mutex<TopologyWatchers>>,
+    /// Recent topology changes, for suggesting routing cache TTLs.
+    activity: Arc<Mutex<TopologyActivity>>,
+    /// The hot region detector, fed by region heartbeats.
+    hotspots: Arc<Mutex<HotspotDetector>>,
+    /// The compaction hint publisher, fed by region heartbeats.
+    compactions: Arc<Mutex<CompactionHints>>,
+    /// The digest of each region's last applied heartbeat, by region id.
+    /// Dropped whenever the region changes, so a matching heartbeat means the
+    /// routing entry is still the reported one.
+    heartbeat_digests: Arc<Mutex<HashMap<u64, u64>>>,
+    /// The number of region heartbeats that skipped reconciliation because
+    /// they were unchanged.
+    skipped_reconciliations: Arc<Mutex<u64>>,
+    /// The circuit breaker guarding checkpoint writes, if enabled.
+    persistence_breaker: Option<Arc<BreakerCheckpoint>>,
+    /// The timestamp request admission queue, if `tso.max_inflight` is set.
+    tso_admission: Option<Arc<AdmissionQueue>>,
+    /// The region and store id allocator. Persisted as configured by the
+    /// `id` section, and in memory only for servers created without a
+    /// configuration, whose ids restart at 1.
+    ids: Arc<dyn IdAllocator>,
+    /// Whether the cluster was bootstrapped, i.e. has had regions, so the
+    /// initial region must not be created (again).
+    bootstrapped: Arc<Mutex<bool>>,
+    /// The file marking the cluster as bootstrapped across restarts, if any.
+    bootstrap_marker: Option<PathBuf>,
+    /// The token required by admin RPCs, if any.
+    admin_token: Option<String>,
+    /// Recently seen admin request nonces, for replay protection.
+    admin_nonces: Arc<Mutex<NonceCache>>,
+    /// The server configuration.
+    config: Config,
 }
 
 impl FeatherPD {
-    /// Creates a new FeatherPD server.
+    /// Creates a new FeatherPD server with an in-memory checkpoint store.
     pub fn new() -> Result<Self> {
-        Ok(Self { next_ts: Arc::new(Mutex::new(1)) })
+        let pd = Self::with_checkpoint(Arc::new(MemoryCheckpoint::new()), DEFAULT_WINDOW_SIZE);
+        let checkpoint = pd.tso.lock()?.checkpoint().load()?;
+        pd.finish_recovery(checkpoint)?;
+        Ok(pd)
+    }
+
+    /// Creates a new FeatherPD server from a configuration. Like
+    /// `with_checkpoint()`, the server must be recovered before serving.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        if config.tso.window_size == 0 {
+            return Err(Error::Config("tso.window_size must be positive".into()));
+        }
+        if config.tso.lease_ms > 0 && config.tso.lease_renew_margin_ms >= config.tso.lease_ms {
+            return Err(Error::Config(format!(
+                "tso.lease_renew_margin_ms ({}) must be below tso.lease_ms ({})",
+                config.tso.lease_renew_margin_ms, config.tso.lease_ms
+            )));
+        }
+        if config.tso.first_ts > 1 {
+            return Err(Error::Config(format!("tso.first_ts must be 0 or 1, got {}", config.tso.first_ts)));
+        }
+        if config.server.max_message_size == 0 {
+            return Err(Error::Config("server.max_message_size must be positive".into()));
+        }
+        if config.server.max_concurrent_streams == 0 || config.server.max_in_flight == 0 {
+            return Err(Error::Config(
+                "server.max_concurrent_streams and server.max_in_flight must be positive".into(),
+            ));
+        }
+        if config.gossip.interval_ms == 0 {
+            return Err(Error::Config("gossip.interval_ms must be positive".into()));
+        }
+        let mut keyspace_ids = HashSet::new();
+        if let Some(keyspace) = config.keyspaces.iter().find(|keyspace| !keyspace_ids.insert(keyspace.id)) {
+            return Err(Error::Config(format!("keyspace {} is configured more than once", keyspace.id)));
+        }
+        if config.changelog.max_entries == 0 {
+            return Err(Error::Config("changelog.max_entries must be positive".into()));
+        }
+        if config.region.replicas == 0 {
+            return Err(Error::Config("region.replicas must be positive".into()));
+        }
+        if config.resolver.virtual_nodes == 0 {
+            return Err(Error::Config("resolver.virtual_nodes must be positive".into()));
+        }
+        if config.dataloc.serve_stale_on_error && config.dataloc.stale_cache_capacity == 0 {
+            return Err(Error::Config("dataloc.stale_cache_capacity must be positive".into()));
+        }
+        let (high, critical) = (config.store.space_high_ratio, config.store.space_critical_ratio);
+        if !(0.0 < high && high <= critical && critical <= 1.0) {
+            return Err(Error::Config(format!(
+                "store.space_high_ratio ({}) and store.space_critical_ratio ({}) must satisfy 0 < high <= critical <= 1",
+                high, critical
+            )));
+        }
+        let mut checkpoint: Arc<dyn CheckpointStore> = match &config.tso.checkpoint_path {
+            Some(path) => Arc::new(
+                FileCheckpoint::new(path)
+                    .with_sync_policy(config.tso.sync_policy)
+                    .with_format(config.persistence.format),
+            ),
+            None => Arc::new(MemoryCheckpoint::new()),
+        };
+        let mut persistence_breaker = None;
+        if config.tso.breaker_failure_threshold > 0 {
+            let breaker = Arc::new(BreakerCheckpoint::new(
+                checkpoint,
+                config.tso.breaker_failure_threshold,
+                config.tso.breaker_probe_interval_ms,
+            ));
+            checkpoint = breaker.clone();
+            persistence_breaker = Some(breaker);
+        }
+        let mut tso =
+            TimestampOracle::new(checkpoint, config.tso.window_size).with_first_ts(config.tso.first_ts);
+        if let Some(path) = &config.tso.audit_log_path {
+            tso = tso.with_audit_log(Arc::new(AuditLog::open(path, config.tso.audit_log_max_bytes)?));
+        }
+        let mut pd = Self::build(tso, ServingState::Bootstrapping, config.clone());
+        pd.persistence_breaker = persistence_breaker;
+        let next_to_checkpoint = |suffix: &str| {
+            config.tso.checkpoint_path.as_ref().map(|path| {
+                let mut path = path.clone().into_os_string();
+                path.push(suffix);
+                PathBuf::from(path)
+            })
+        };
+        let ids_path = config.id.checkpoint_path.clone().or_else(|| next_to_checkpoint(".ids"));
+        if let Some(path) = ids_path {
+            let checkpoint = FileCheckpoint::new(path)
+                .with_sync_policy(config.tso.sync_policy)
+                .with_format(config.persistence.format);
+            pd.ids = Arc::new(MonotonicIdAllocator::new(Arc::new(checkpoint))?);
+        }
+        pd.bootstrap_marker =
+            config.cluster.bootstrap_marker_path.clone().or_else(|| next_to_checkpoint(".bootstrapped"));
+        if let Some(path) = &pd.bootstrap_marker {
+            pd.bootstrapped = Arc::new(Mutex::new(path.try_exists()?));
+        }
+        if config.resolver.kind == ResolverKind::ConsistentHash {
+            pd.location_resolver = Some(Arc::new(ConsistentHashResolver::new(
+                config.resolver.virtual_nodes,
+                config.region.replicas as usize,
+            )));
+        }
+        Ok(pd)
+    }
+
+    /// Creates a new FeatherPD server configured by environment variables with
+    /// the given prefix, e.g. `FEATHERPD__TSO__WINDOW_SIZE`. See
+    /// `Config::from_env()`.
+    pub fn from_env(prefix: &str) -> Result<Self> {
+        Self::from_config(&Config::from_env(prefix)?)
+    }
+
+    /// Creates a new FeatherPD server backed by the given checkpoint store.
+    /// The server starts out bootstrapping, and rejects timestamp requests
+    /// until `recover()` has loaded the persisted watermark.
+    pub fn with_checkpoint(checkpoint: Arc<dyn CheckpointStore>, window_size: u64) -> Self {
+        let tso = TimestampOracle::new(checkpoint, window_size);
+        Self::build(tso, ServingState::Bootstrapping, Config::default())
+    }
+
+    /// Assembles a server around a timestamp oracle in the given state.
+    fn build(tso: TimestampOracle, state: ServingState, config: Config) -> Self {
+        Self {
+            tso: Arc::new(Mutex::new(tso)),
+            keyspace_tsos: Arc::new(Mutex::new(HashMap::new())),
+            state: Arc::new(Mutex::new(state)),
+            role: Role::Leader,
+            replicated_version: Arc::new(Mutex::new(0)),
+            replicated_at_ms: Arc::new(Mutex::new(None)),
+            grace_until_ms: Arc::new(Mutex::new(0)),
+            lease_expires_ms: Arc::new(Mutex::new(0)),
+            gc_safe_point: Arc::new(watch::channel(0).0),
+            watchers_closed: Arc::new(watch::channel(false).0),
+            clock: Arc::new(SystemClock),
+            key_comparator: Arc::new(Lexicographic),
+            location_resolver: None,
+            location_lookups: Arc::new(SingleFlight::new()),
+            stale_locations: match config.dataloc.serve_stale_on_error {
+                true => {
+                    Some(Arc::new(Mutex::new(StaleLocationCache::new(config.dataloc.stale_cache_capacity))))
+                }
+                false => None,
+            },
+            routing: Arc::new(Mutex::new(RoutingTable::new())),
+            stores: Arc::new(Mutex::new(StoreRegistry::new())),
+            placement_rng: Arc::new(Mutex::new(match config.placement.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            })),
+            changes: Arc::new(Mutex::new(ChangeLog::new(
+                config.changelog.max_entries,
+                config.changelog.max_age_ms,
+            ))),
+            oplog: Arc::new(Mutex::new(OperationLog::new(
+                DEFAULT_OPERATION_LOG_CAPACITY,
+                Arc::new(Lexicographic),
+            ))),
+            operations: Arc::new(Mutex::new(OperationQueue::new())),
+            transfers: Arc::new(Mutex::new(TransferTracker::new())),
+            pinned_leaders: Arc::new(Mutex::new(HashMap::new())),
+            scheduling_paused: Arc::new(Mutex::new(false)),
+            watchers: Arc::new(Mutex::new(TopologyWatchers::new())),
+            activity: Arc::new(Mutex::new(TopologyActivity::new())),
+            hotspots: Arc::new(Mutex::new(HotspotDetector::new(DEFAULT_HOT_REGION_QPS))),
+            compactions: Arc::new(Mutex::new(CompactionHints::new(
+                DEFAULT_COMPACTION_STALE_VERSIONS,
+                DEFAULT_MAX_CONCURRENT_COMPACTIONS,
+            ))),
+            heartbeat_digests: Arc::new(Mutex::new(HashMap::new())),
+            skipped_reconciliations: Arc::new(Mutex::new(0)),
+            persistence_breaker: None,
+            tso_admission: match config.tso.max_inflight {
+                0 => None,
+                capacity => {
+                    let weights = config
+                        .keyspaces
+                        .iter()
+                        .filter(|keyspace| keyspace.tso_weight > 0)
+                        .map(|keyspace| (keyspace.id, keyspace.tso_weight))
+                        .collect();
+                    Some(Arc::new(AdmissionQueue::new(capacity).with_weights(weights)))
+                }
+            },
+            ids: Arc::new(MonotonicIdAllocator::in_memory()),
+            bootstrapped: Arc::new(Mutex::new(false)),
+            bootstrap_marker: None,
+            admin_token: None,
+            admin_nonces: Arc::new(Mutex::new(NonceCache::new(DEFAULT_NONCE_CAPACITY))),
+            config,
+        }
+    }
+
+    /// Returns the server configuration.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Uses the given clock instead of the system clock, also for the
+    /// persistence circuit breaker's probes.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        if let Some(breaker) = &self.persistence_breaker {
+            breaker.set_clock(clock.clone());
+        }
+        self.clock = clock;
+        self
+    }
+
+    /// Allocates region and store ids with the given allocator, instead of
+    /// the default in-memory monotonic allocator.
+    pub fn with_id_allocator(mut self, ids: Arc<dyn IdAllocator>) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    /// Runs the server in the given role. Followers must be kept up to date
+    /// via `apply_snapshot()` and `apply_changes()`.
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.role = role;
+        self
+    }
+
+    /// Returns the replication role.
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Rejects mutations unless this server is the leader.
+    fn check_leader(&self) -> Result<()> {
+        match self.role {
+            Role::Leader => Ok(()),
+            Role::ReadOnlyFollower => Err(Error::NotLeader),
+        }
+    }
+
+    /// Orders keys by the given comparator instead of lexicographically, for
+    /// storage engines with a custom key ordering. Resets the routing table,
+    /// so it must be set before any regions are added.
+    pub fn with_key_comparator(mut self, comparator: Arc<dyn KeyComparator>) -> Self {
+        self.routing = Arc::new(Mutex::new(RoutingTable::with_comparator(comparator.clone())));
+        self.oplog =
+            Arc::new(Mutex::new(OperationLog::new(DEFAULT_OPERATION_LOG_CAPACITY, comparator.clone())));
+        self.key_comparator = comparator;
+        self
+    }
+
+    /// Serves data-location lookups with the given resolver instead of the
+    /// routing tables, e.g. a `ConsistentHashResolver` for deployments that
+    /// don't track regions. Range lookups still use the routing tables. The
+    /// resolver is told of stores registered from then on, so it must be set
+    /// before any are. See also the `resolver` configuration section.
+    pub fn with_location_resolver(mut self, resolver: Arc<dyn LocationResolver>) -> Self {
+        self.location_resolver = Some(resolver);
+        self
+    }
+
+    /// Requires admin RPCs to carry the given token.
+    pub fn with_admin_token(mut self, token: impl Into<String>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Recovers the watermark from the checkpoint store, and starts serving.
+    /// With `server.startup_selfcheck`, runs the self-check first.
+    pub async fn recover(&self) -> Result<()> {
+        if self.config.server.startup_selfcheck {
+            let config = self.config.tso.clone();
+            task::spawn_blocking("featherpd-selfcheck", move || selfcheck::run(&config)).await??;
+        }
+        let store = self.tso.lock()?.checkpoint();
+        let checkpoint = task::spawn_blocking("featherpd-recover", move || store.load()).await??;
+        self.finish_recovery(checkpoint)
+    }
+
+    /// Applies a recovered checkpoint and transitions to serving. Timestamps
+    /// are only handed out once the leader grace period has passed.
+    fn finish_recovery(&self, checkpoint: Option<Checkpoint>) -> Result<()> {
+        let watermark = checkpoint.map(|checkpoint| checkpoint.window_end);
+        let mut tso = self.tso.lock()?;
+        tso.recover(checkpoint);
+        // Every audited window was persisted first, so the last one should
+        // end exactly at the recovered watermark.
+        if let Some(last) = tso.audit_log().map(|audit_log| audit_log.last()).transpose()?.flatten() {
+            if Some(last.window_end) != watermark {
+                warn!(
+                    "Last audited TSO window (seq {}) ends at {}, but the recovered watermark is {:?}",
+                    last.seq, last.window_end, watermark
+                );
+            }
+        }
+        drop(tso);
+        *self.grace_until_ms.lock()? = self.clock.now_ms().saturating_add(self.config.tso.leader_grace_ms);
+        if self.role == Role::Leader {
+            self.renew_lease()?;
+        }
+        *self.state.lock()? = ServingState::Serving;
+        self.create_initial_region()?;
+        Ok(())
+    }
+
+    /// Extends the leader lease to `tso.lease_ms` from now, returning its new
+    /// expiry in milliseconds. See `lease::run()`, which calls this
+    /// periodically.
+    pub fn renew_lease(&self) -> Result<u64> {
+        self.check_leader()?;
+        let expires_ms = self.clock.now_ms().saturating_add(self.config.tso.lease_ms);
+        *self.lease_expires_ms.lock()? = expires_ms;
+        Ok(expires_ms)
+    }
+
+    /// Returns the current serving state.
+    pub fn serving_state(&self) -> Result<ServingState> {
+        Ok(*self.state.lock()?)
+    }
+
+    /// Allocates the next timestamp.
+    pub fn get_next_ts(&self) -> Result<u64> {
+        self.get_next_ts_batch(1, false).map(|(ts, _)| ts)
+    }
+
+    /// Allocates a batch of consecutive timestamps, returning the first one
+    /// and the number served. See `TimestampOracle::get_next_ts_batch()` for
+    /// how batches near overflow are handled.
+    pub fn get_next_ts_batch(&self, count: u64, partial_ok: bool) -> Result<(u64, u64)> {
+        self.check_tso_serving()?;
+        self.tso.lock()?.get_next_ts_batch(count, partial_ok)
+    }
+
+    /// Like `get_next_ts_batch()`, but allocates from the keyspace's own TSO
+    /// if it was created with one, and from the global TSO otherwise.
+    pub fn get_keyspace_ts_batch(
+        &self,
+        keyspace_id: u32,
+        count: u64,
+        partial_ok: bool,
+    ) -> Result<(u64, u64)> {
+        self.check_tso_serving()?;
+        self.tso_for(keyspace_id)?.lock()?.get_next_ts_batch(count, partial_ok)
+    }
+
+    /// Returns the TSO of the keyspace if it was created with one, and the
+    /// global TSO otherwise.
+    fn tso_for(&self, keyspace_id: u32) -> Result<Arc<Mutex<TimestampOracle>>> {
+        Ok(self.keyspace_tsos.lock()?.get(&keyspace_id).cloned().unwrap_or_else(|| self.tso.clone()))
+    }
+
+    /// Serves a timestamp request, for both unary and streaming allocation.
+    async fn serve_tso_request(&self, request: TsoRequest) -> Result<TsoReply> {
+        let request = ValidatedTsoRequest::try_from(request)?;
+        let _permit = match &self.tso_admission {
+            Some(admission) => Some(admission.admit(request.priority, request.keyspace_id).await?),
+            None => None,
+        };
+        // Extending the window persists the checkpoint, which may block for
+        // long on a slow or hung disk, so it runs off the async workers, where
+        // it can't stall other requests nor the server's RPC timeout.
+        self.check_tso_serving()?;
+        let tso = self.tso_for(request.keyspace_id)?;
+        let (timestamp, count) = task::spawn_blocking("featherpd-tso", move || {
+            tso.lock()?.get_next_ts_batch(request.count, request.partial_ok)
+        })
+        .await??;
+        // The wall-clock time lets clients estimate the round-trip latency. It
+        // is unrelated to the logical timestamp.
+        let server_time_ms = self.clock.now_ms();
+        Ok(TsoReply { timestamp, server_time_ms, count: count as u32 })
+    }
+
+    /// Checks that timestamps may be served, i.e. that this is the leader,
+    /// it has finished bootstrapping, its grace period has passed, and its
+    /// lease, if any, hasn't expired.
+    fn check_tso_serving(&self) -> Result<()> {
+        self.check_leader()?;
+        match self.serving_state()? {
+            ServingState::Bootstrapping => {
+                return Err(Error::Unavailable("Server is bootstrapping, retry later".into()))
+            }
+            ServingState::ShuttingDown => return Err(Error::Unavailable("Server is shutting down".into())),
+            ServingState::Serving => {}
+        }
+        let now_ms = self.clock.now_ms();
+        if now_ms < *self.grace_until_ms.lock()? {
+            return Err(Error::NotLeader);
+        }
+        if self.config.tso.lease_ms > 0 && now_ms >= *self.lease_expires_ms.lock()? {
+            return Err(Error::NotLeader);
+        }
+        Ok(())
+    }
+
+    /// Forces the TSO forward so the next timestamp is at least `target`,
+    /// e.g. to recover from timestamps having gone backward. Refuses to move
+    /// the TSO backward.
+    pub fn advance_timestamp(&self, target: u64) -> Result<()> {
+        self.check_leader()?;
+        if self.serving_state()? == ServingState::Bootstrapping {
+            return Err(Error::Unavailable("Server is bootstrapping, retry later".into()));
+        }
+        self.tso.lock()?.advance_to(target)?;
+        warn!("TSO manually advanced to {}", target);
+        Ok(())
+    }
+
+    /// Returns the GC safe point, or 0 if none was set.
+    pub fn gc_safe_point(&self) -> Result<u64> {
+        Ok(*self.gc_safe_point.borrow())
+    }
+
+    /// Advances the GC safe point, returning the new one, and notifies the
+    /// subscribers. The safe point never moves backward, so a lower value is
+    /// ignored. Refuses a safe point
+    /// at or above the next timestamp, since reads at current timestamps
+    /// would then miss data.
+    pub fn update_gc_safe_point(&self, safe_point: u64) -> Result<u64> {
+        self.check_leader()?;
+        let next_ts = self.tso.lock()?.next_ts();
+        if safe_point >= next_ts {
+            return Err(Error::Value(format!(
+                "GC safe point {} must be below the next timestamp {}",
+                safe_point, next_ts
+            )));
+        }
+        let advanced = self.gc_safe_point.send_if_modified(|current| {
+            let advanced = safe_point > *current;
+            if advanced {
+                *current = safe_point;
+            }
+            advanced
+        });
+        if advanced {
+            info!("GC safe point advanced to {}", safe_point);
+        }
+        self.gc_safe_point()
+    }
+
+    /// Subscribes to GC safe point advances. The receiver always holds the
+    /// latest safe point, so a slow subscriber skips intermediate values
+    /// rather than building up a backlog.
+    pub fn watch_gc_safe_point(&self) -> watch::Receiver<u64> {
+        self.gc_safe_point.subscribe()
+    }
+
+    /// Checks that a historical read at the given timestamp is still possible,
+    /// i.e. that it isn't below the GC safe point, so the client fails clearly
+    /// instead of the stores returning missing data.
+    pub fn check_read_ts(&self, read_ts: u64) -> Result<()> {
+        if read_ts < self.gc_safe_point()? {
+            return Err(Error::Value("requested timestamp below GC safe point".into()));
+        }
+        Ok(())
+    }
+
+    /// Returns the next timestamp to be handed out.
+    pub fn next_ts(&self) -> Result<u64> {
+        Ok(self.tso.lock()?.next_ts())
+    }
+
+    /// Handles a next timestamp gossiped by a peer PD, moving the TSO forward
+    /// to it if it is higher than ours, so we never hand out timestamps the
+    /// peer has handed out. Ignored while bootstrapping, since recovery
+    /// resumes from our own watermark. Returns true if the TSO moved.
+    pub fn observe_peer_watermark(&self, watermark: u64) -> Result<bool> {
+        if self.serving_state()? == ServingState::Bootstrapping {
+            return Ok(false);
+        }
+        let moved = self.tso.lock()?.bump_to(watermark)?;
+        if moved {
+            warn!("TSO moved forward to peer next timestamp {}", watermark);
+        }
+        Ok(moved)
+    }
+
+    /// Authorizes an admin request: checks its token, then rejects it if its
+    /// nonce was seen before.
+    fn check_admin<T>(&self, request: &Request<T>) -> Result<()> {
+        check_admin_token(request, self.admin_token.as_deref())?;
+        self.admin_nonces.lock()?.check(request)
+    }
+
+    /// Immediately persists the TSO checkpoint, returning the number of bytes
+    /// written and the persisted watermark. Refused while bootstrapping, since
+    /// it would overwrite the persisted watermark before it was recovered.
+    pub fn flush(&self) -> Result<(u64, u64)> {
+        self.check_leader()?;
+        if self.serving_state()? == ServingState::Bootstrapping {
+            return Err(Error::Unavailable("Server is bootstrapping, retry later".into()));
+        }
+        let tso = self.tso.lock()?;
+        let bytes_written = tso.persist()?;
+        Ok((bytes_written, tso.window_end()))
+    }
+
+    /// Checks the PD's internal invariants: the persisted watermark is above
+    /// the last allocated timestamp, the routing table is valid, and every
+    /// region replica is on a registered store that wasn't removed. Refused while bootstrapping,
+    /// since the TSO state isn't recovered yet.
+    pub fn verify_consistency(&self) -> Result<ConsistencyReport> {
+        if self.serving_state()? == ServingState::Bootstrapping {
+            return Err(Error::Unavailable("Server is bootstrapping, retry later".into()));
+        }
+        let mut violations = Vec::new();
+        let tso = self.tso.lock()?;
+        if let Some(last_allocated) = tso.last_allocated() {
+            let watermark = tso.checkpoint().load()?.map(|checkpoint| checkpoint.window_end);
+            if watermark.is_none_or(|watermark| watermark <= last_allocated) {
+                violations.push(Violation::WatermarkBehind { watermark, last_allocated });
+            }
+        }
+        let routing = self.routing.lock()?;
+        violations.extend(routing.validate());
+        let stores = self.stores.lock()?;
+        let mut regions: Vec<&RegionInfo> = routing.regions().collect();
+        regions.sort_by_key(|region| region.id);
+        for region in regions {
+            for &store_id in &region.stores {
+                match stores.get(store_id) {
+                    Ok(store) if store.state == StoreState::Removed => {
+                        violations.push(Violation::RemovedStore { region_id: region.id, store_id })
+                    }
+                    Ok(_) => {}
+                    Err(_) => violations.push(Violation::UnknownStore { region_id: region.id, store_id }),
+                }
+            }
+        }
+        Ok(ConsistencyReport { violations })
+    }
+
+    /// Stops handing out timestamps, as the first step of a shutdown. A server
+    /// that never finished bootstrapping stays bootstrapping.
+    pub fn begin_shutdown(&self) -> Result<()> {
+        let mut state = self.state.lock()?;
+        if *state == ServingState::Serving {
+            *state = ServingState::ShuttingDown;
+        }
+        Ok(())
+    }
+
+    /// Ends all watch streams, as the last step of a shutdown: topology
+    /// subscribers get a final `TopologyEvent::ShuttingDown` event, and GC safe
+    /// point streams end.
+    pub fn close_watchers(&self) -> Result<()> {
+        let mut watchers = self.watchers.lock()?;
+        self.watchers_closed.send_replace(true);
+        watchers.close();
+        Ok(())
+    }
+
+    /// Persists the final state of the global and keyspace TSOs. Should be
+    /// called on clean shutdown, so the checkpoints record the true last
+    /// allocated timestamps. A server that never finished bootstrapping has
+    /// nothing to persist.
+    pub fn shutdown(&self) -> Result<()> {
+        if self.serving_state()? == ServingState::Bootstrapping || self.role == Role::ReadOnlyFollower {
+            return Ok(());
+        }
+        self.flush()?;
+        for tso in self.keyspace_tsos.lock()?.values() {
+            tso.lock()?.persist()?;
+        }
+        Ok(())
+    }
+
+    /// Looks up the region containing a key in a keyspace. The lookup and its
+    /// containment check run under the routing table lock, so a concurrent
+    /// split can never yield a region whose bounds exclude the key. If the
+    /// keyspace has a `strip_prefix`, the key must carry it.
+    pub fn lookup(&self, keyspace_id: u32, key: &[u8]) -> Result<RegionInfo> {
+        let key = self.strip_key(keyspace_id, key)?;
+        let region = self.routing.lock()?.lookup(keyspace_id, key)?;
+        Ok(self.prefix_region(region))
+    }
+
+    /// Serves a coalesced data-location lookup. If `dataloc.serve_stale_on_error`
+    /// is set, a lookup failing transiently is served the key's last successful
+    /// lookup instead, if any, flagged as stale and with its staleness.
+    async fn locate_or_stale(&self, keyspace_id: u32, key: &[u8]) -> Result<DataLocReply> {
+        let result =
+            self.location_lookups.run((keyspace_id, key.to_vec()), || self.locate(keyspace_id, key)).await;
+        let Some(stale_locations) = &self.stale_locations else {
+            return result;
+        };
+        let now_ms = self.clock.now_ms();
+        match result {
+            Ok(reply) => {
+                stale_locations.lock()?.put(keyspace_id, key, reply.clone(), now_ms);
+                Ok(reply)
+            }
+            // Only transient failures fall back: a key that can't be located
+            // at all mustn't be served where it used to be.
+            Err(err @ (Error::Unavailable(_) | Error::Timeout | Error::Internal(_))) => {
+                match stale_locations.lock()?.get(keyspace_id, key, now_ms) {
+                    Some(reply) => {
+                        warn!("Serving stale location of key {:?} in keyspace {}: {}", key, keyspace_id, err);
+                        Ok(reply)
+                    }
+                    None => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Serves a data-location lookup, from the location resolver if any and
+    /// the routing table otherwise. The staleness is left for the caller to
+    /// fill in, since coalesced lookups may differ in it.
+    async fn locate(&self, keyspace_id: u32, key: &[u8]) -> Result<DataLocReply> {
+        if let Some(resolver) = &self.location_resolver {
+            return resolver.resolve(keyspace_id, key).await;
+        }
+        let region = self.lookup(keyspace_id, key)?;
+        let transferring = self.is_transferring(region.id)?;
+        Ok(DataLocReply {
+            regions: bincode::serialize(&vec![region])?,
+            cache_ttl_ms: self.routing_cache_ttl_ms()?,
+            stale: self.role == Role::ReadOnlyFollower,
+            transferring,
+            staleness_ms: 0,
+        })
+    }
+
+    /// Returns the configured prefix stripped from the keys of a keyspace,
+    /// or an empty prefix if none.
+    fn strip_prefix(&self, keyspace_id: u32) -> &[u8] {
+        match self.config.keyspaces.iter().find(|keyspace| keyspace.id == keyspace_id) {
+            Some(keyspace) => keyspace.strip_prefix.as_bytes(),
+            None => &[],
+        }
+    }
+
+    /// Strips the keyspace's prefix from a client key, rejecting keys that
+    /// lack it. The empty key is a sentinel, and passes through as is.
+    fn strip_key<'a>(&self, keyspace_id: u32, key: &'a [u8]) -> Result<&'a [u8]> {
+        let prefix = self.strip_prefix(keyspace_id);
+        if key.is_empty() {
+            return Ok(key);
+        }
+        match key.strip_prefix(prefix) {
+            Some(stripped) => Ok(stripped),
+            None => Err(Error::Value(format!(
+                "Key {:?} of keyspace {} lacks the keyspace prefix {:?}",
+                String::from_utf8_lossy(key),
+                keyspace_id,
+                String::from_utf8_lossy(prefix)
+            ))),
+        }
+    }
+
+    /// Adds the keyspace's prefix back to a non-sentinel key.
+    fn prefix_key(&self, keyspace_id: u32, key: Vec<u8>) -> Vec<u8> {
+        let prefix = self.strip_prefix(keyspace_id);
+        if key.is_empty() || prefix.is_empty() {
+            return key;
+        }
+        [prefix, &key].concat()
+    }
+
+    /// Adds the keyspace's prefix back to a region's bounds, for returning
+    /// it to clients.
+    fn prefix_region(&self, region: RegionInfo) -> RegionInfo {
+        RegionInfo {
+            start_key: self.prefix_key(region.keyspace_id, region.start_key),
+            end_key: self.prefix_key(region.keyspace_id, region.end_key),
+            ..region
+        }
+    }
+
+    /// Returns how long clients should cache routing information, in
+    /// milliseconds: shorter while the topology is changing, e.g. due to
+    /// splits or leader transfers, and longer while it is quiescent.
+    pub fn routing_cache_ttl_ms(&self) -> Result<u64> {
+        Ok(self.activity.lock()?.cache_ttl_ms(self.clock.now_ms()))
+    }
+
+    /// Subscribes to topology changes, i.e. epoch and leader changes, of the
+    /// regions overlapping [start_key, end_key) in a keyspace. See
+    /// `TopologyWatchers` for how slow subscribers are handled. Refused once
+    /// the watchers are closed on shutdown.
+    pub fn watch_topology(
+        &self,
+        keyspace_id: u32,
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+    ) -> Result<mpsc::Receiver<TopologyEvent>> {
+        let mut watchers = self.watchers.lock()?;
+        if *self.watchers_closed.borrow() {
+            return Err(Error::Unavailable("Server is shutting down".into()));
+        }
+        Ok(watchers.subscribe(keyspace_id, start_key, end_key))
+    }
+
+    /// Records a topology change of the given regions, and notifies the
+    /// subscribers.
+    fn topology_changed(&self, regions: &[&RegionInfo]) -> Result<()> {
+        self.activity.lock()?.record(self.clock.now_ms());
+        let mut watchers = self.watchers.lock()?;
+        for region in regions {
+            watchers.notify(region, self.key_comparator.as_ref());
+        }
+        Ok(())
+    }
+
+    /// Scans a page of up to `limit` regions overlapping [start_key, end_key)
+    /// in a keyspace, returning them along with the start key of the next
+    /// page, or an empty key if this was the last page. If the keyspace has a
+    /// `strip_prefix`, non-empty bounds must carry it.
+    pub fn scan(
+        &self,
+        keyspace_id: u32,
+        start_key: &[u8],
+        end_key: &[u8],
+        limit: usize,
+    ) -> Result<(Vec<RegionInfo>, Vec<u8>)> {
+        let stripped_end_key = self.strip_key(keyspace_id, end_key)?;
+        if stripped_end_key.is_empty() && !end_key.is_empty() {
+            // The range ends at the bare prefix, before any of the keyspace's
+            // keys, rather than at the right sentinel.
+            return Ok((Vec::new(), Vec::new()));
+        }
+        let start_key = self.strip_key(keyspace_id, start_key)?;
+        let (regions, next_start_key) =
+            self.routing.lock()?.scan(keyspace_id, start_key, stripped_end_key, limit)?;
+        Ok((
+            regions.into_iter().map(|region| self.prefix_region(region)).collect(),
+            self.prefix_key(keyspace_id, next_start_key),
+        ))
+    }
+
+    /// Fetches a region by id.
+    pub fn get_region(&self, id: u64) -> Result<RegionInfo> {
+        self.routing.lock()?.get_region(id)
+    }
+
+    /// Fetches a region by id as of a past epoch, from the change log. Only
+    /// recent epochs are retained, and older ones return `Error::NotFound`.
+    pub fn get_region_at_epoch(&self, id: u64, epoch: u64) -> Result<RegionInfo> {
+        let routing = self.routing.lock()?;
+        match routing.get_region(id) {
+            Ok(region) if region.epoch == epoch => Ok(region),
+            _ => self.changes.lock()?.region_at_epoch(id, epoch),
+        }
+    }
+
+    /// Splits a region at the given key, giving the right half the new id.
+    /// Lookups never observe a partially applied split.
+    pub fn split_region(&self, id: u64, split_key: &[u8], new_id: u64) -> Result<(RegionInfo, RegionInfo)> {
+        self.check_leader()?;
+        let mut routing = self.routing.lock()?;
+        self.check_region_count(&routing, id)?;
+        let undersized = self.check_live_stores()?;
+        let (left, right) = routing.split_region(id, split_key, new_id)?;
+        if undersized {
+            self.schedule_replicas(&routing, &[&left, &right])?;
+        }
+        self.topology_changed(&[&left, &right])?;
+        self.log_changes(
+            Operation::SplitRegion { region_id: id, new_region_id: new_id },
+            vec![Change::PutRegion(left.clone()), Change::PutRegion(right.clone())],
+        )?;
+        Ok((left, right))
+    }
+
+    /// Declines splitting the given region if it would exceed the region
+    /// count cap.
+    fn check_region_count(&self, routing: &RoutingTable, region_id: u64) -> Result<()> {
+        let max_count = self.config.region.max_count;
+        if max_count > 0 && routing.region_count() >= max_count {
+            warn!(
+                "Declining to split region {}: the region count cap of {} is reached, consider merging \
+                 regions or raising region.max_size",
+                region_id, max_count
+            );
+            return Err(Error::Value(format!("Region count cap of {} reached", max_count)));
+        }
+        Ok(())
+    }
+
+    /// Checks that at least `region.replicas` stores are live before creating
+    /// regions. Returns `Error::NoAvailableStores` if not, unless the
+    /// undersized policy is to replicate, in which case it returns true to
+    /// have the caller schedule replicas for the new regions. Must be called
+    /// with the routing lock held.
+    fn check_live_stores(&self) -> Result<bool> {
+        let replicas = self.config.region.replicas;
+        let live = self.stores.lock()?.stores().filter(|store| store.state.is_live()).count();
+        if live >= replicas as usize {
+            return Ok(false);
+        }
+        match self.config.region.undersized_policy {
+            UndersizedPolicy::Reject => {
+                warn!("Declining to create a region: only {} of {} replica stores are live", live, replicas);
+                Err(Error::NoAvailableStores(replicas))
+            }
+            UndersizedPolicy::Replicate => Ok(true),
+        }
+    }
+
+    /// Schedules adding replicas of undersized regions, up to
+    /// `region.replicas`, on the stores a new region's replicas could be
+    /// placed on, heaviest first, see `StoreRegistry::placement_candidates()`.
+    /// Replicas already scheduled count toward both the target and the
+    /// stores' `store.max_regions` cap. Must be called with the routing lock
+    /// held.
+    fn schedule_replicas(&self, routing: &RoutingTable, regions: &[&RegionInfo]) -> Result<()> {
+        if self.scheduling_paused()? {
+            info!("Scheduling is paused, deferring replicas of undersized regions until it resumes");
+            return Ok(());
+        }
+        let replicas = self.config.region.replicas as usize;
+        let stores = self.stores.lock()?;
+        let mut operations = self.operations.lock()?;
+        self.prune_operations(routing, &stores, &mut operations)?;
+        let mut region_counts = routing.store_region_counts();
+        let mut scheduled: HashMap<(u64, u64), Vec<u64>> = HashMap::new();
+        for op in operations.pending() {
+            if let ScheduleOp::AddReplica { region_id, epoch, store_id } = op {
+                *region_counts.entry(store_id).or_insert(0) += 1;
+                scheduled.entry((region_id, epoch)).or_default().push(store_id);
+            }
+        }
+        for region in regions {
+            let scheduled = scheduled.remove(&(region.id, region.epoch)).unwrap_or_default();
+            let missing = replicas.saturating_sub(region.stores.len() + scheduled.len());
+            if missing == 0 {
+                continue;
+            }
+            let targets: Vec<u64> = stores
+                .placement_candidates(&self.config.store, &region_counts, &mut *self.placement_rng.lock()?)
+                .into_iter()
+                .filter(|id| !region.stores.contains(id) && !scheduled.contains(id))
+                .take(missing)
+                .collect();
+            warn!(
+                "Region {} is undersized with {} of {} replicas, scheduling replicas on stores {:?}",
+                region.id,
+                region.stores.len(),
+                replicas,
+                targets
+            );
+            if targets.len() < missing {
+                warn!(
+                    "Only {} of the {} missing replicas of region {} have a store to go to",
+                    targets.len(),
+                    missing,
+                    region.id
+                );
+            }
+            for store_id in targets {
+                let op = ScheduleOp::AddReplica { region_id: region.id, epoch: region.epoch, store_id };
+                if let Err(err) = op.validate(routing, &stores) {
+                    warn!("Not scheduling {:?}: {}", op, err);
+                    continue;
+                }
+                operations.push(op);
+                *region_counts.entry(store_id).or_insert(0) += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Inserts or replaces a region in the routing table, notifying the
+    /// topology subscribers. Creating a new region is subject to
+    /// `region.undersized_policy`.
+    pub fn put_region(&self, region: RegionInfo) -> Result<()> {
+        self.check_leader()?;
+        let mut routing = self.routing.lock()?;
+        let undersized = routing.get_region(region.id).is_err() && self.check_live_stores()?;
+        routing.put_region(region.clone())?;
+        if undersized {
+            self.schedule_replicas(&routing, &[&region])?;
+        }
+        self.topology_changed(&[&region])?;
+        self.log_changes(Operation::PutRegion { region_id: region.id }, vec![Change::PutRegion(region)])?;
+        Ok(())
+    }
+
+    /// Allocates a new region or store id.
+    pub fn alloc_id(&self) -> Result<u64> {
+        self.check_leader()?;
+        self.ids.next()
+    }
+
+    /// Registers a store, or re-registers it after a restart, pinning its
+    /// placement weight if given. A previously pinned weight is kept
+    /// otherwise.
+    pub fn register_store(
+        &self,
+        id: u64,
+        address: String,
+        labels: Labels,
+        weight: Option<u32>,
+    ) -> Result<StoreInfo> {
+        self.check_leader()?;
+        if let Some(weight) = weight {
+            check_weight(weight)?;
+        }
+        let mut stores = self.stores.lock()?;
+        let mut store = stores.register(id, address, labels, self.clock.now_ms())?;
+        if let Some(weight) = weight {
+            store = stores.set_weight(id, weight)?;
+        }
+        self.log_changes(Operation::RegisterStore { store_id: id }, vec![Change::PutStore(store.clone())])?;
+        drop(stores);
+        self.create_initial_region()?;
+        Ok(store)
+    }
+
+    /// Creates the initial region, spanning the whole default keyspace, if
+    /// `cluster.create_initial_region` is set and the cluster was never
+    /// bootstrapped, i.e. never had regions. Returns the region if created.
+    /// Does nothing while too few stores are up to place it, so it is retried
+    /// as stores register. The check and the creation happen under the
+    /// routing lock, and the cluster is marked bootstrapped, persistently if
+    /// configured, so it only ever happens once: not after the regions are
+    /// gone, nor after a restart with an empty routing table.
+    fn create_initial_region(&self) -> Result<Option<RegionInfo>> {
+        if !self.config.cluster.create_initial_region || self.role != Role::Leader {
+            return Ok(None);
+        }
+        let mut routing = self.routing.lock()?;
+        if *self.bootstrapped.lock()? {
+            return Ok(None);
+        }
+        if routing.region_count() > 0 {
+            self.mark_bootstrapped()?;
+            return Ok(None);
+        }
+        let region_counts = routing.store_region_counts();
+        let mut rng = self.placement_rng.lock()?;
+        let stores = match self.stores.lock()?.place(
+            self.config.region.replicas,
+            &self.config.store,
+            &region_counts,
+            &mut *rng,
+        ) {
+            Ok(stores) => stores,
+            Err(Error::NoAvailableStores(_)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let leader = routing.pick_leader(&stores, &mut *rng);
+        drop(rng);
+        let region =
+            RegionInfo::new(self.ids.next()?, DEFAULT_KEYSPACE, Vec::new(), Vec::new(), 1, stores, leader)?;
+        self.mark_bootstrapped()?;
+        routing.put_region(region.clone())?;
+        self.log_changes(
+            Operation::PutRegion { region_id: region.id },
+            vec![Change::PutRegion(region.clone())],
+        )?;
+        info!("Created initial region {} on stores {:?}", region.id, region.stores);
+        Ok(Some(region))
+    }
+
+    /// Marks the cluster as bootstrapped, creating the bootstrap marker file
+    /// if configured. Must be called with the routing lock held.
+    fn mark_bootstrapped(&self) -> Result<()> {
+        let mut bootstrapped = self.bootstrapped.lock()?;
+        if let Some(path) = &self.bootstrap_marker {
+            std::fs::File::create(path)?.sync_all()?;
+        }
+        *bootstrapped = true;
+        Ok(())
+    }
+
+    /// Moves a store to the given state, e.g. to drain or remove it, pinning
+    /// its placement weight if given. Returns `Error::Value` for an illegal
+    /// transition or weight, and `Error::Abort` if the topology version isn't
+    /// the expected one, see `topology_version()`.
+    pub fn set_store_state(
+        &self,
+        id: u64,
+        state: StoreState,
+        weight: Option<u32>,
+        expected_version: Option<u64>,
+    ) -> Result<StoreInfo> {
+        self.check_leader()?;
+        if let Some(weight) = weight {
+            check_weight(weight)?;
+        }
+        let _routing = self.routing.lock()?;
+        let mut stores = self.stores.lock()?;
+        self.check_topology_version(expected_version)?;
+        let mut store = stores.set_state(id, state, self.clock.now_ms(), &self.config.store)?;
+        if let Some(weight) = weight {
+            store = stores.set_weight(id, weight)?;
+        }
+        info!("Store {} is now {:?}", id, state);
+        self.log_changes(
+            Operation::SetStoreState { store_id: id, state },
+            vec![Change::PutStore(store.clone())],
+        )?;
+        Ok(store)
+    }
+
+    /// Records the changes made by a mutating operation in the change log
+    /// and the operation log. Must be called with the routing or store lock
+    /// held, like the changes themselves.
+    fn log_changes(&self, operation: Operation, changes: Vec<Change>) -> Result<()> {
+        let now_ms = self.clock.now_ms();
+        let mut change_log = self.changes.lock()?;
+        let mut digests = self.heartbeat_digests.lock()?;
+        for change in &changes {
+            match change {
+                Change::PutRegion(region) => _ = digests.remove(&region.id),
+                Change::DeleteKeyspace(_) => digests.clear(),
+                Change::PutStore(store) => self.resolver_put_store(store)?,
+                Change::CreateKeyspace(_) => {}
+            }
+            change_log.append(change.clone(), now_ms);
+        }
+        drop(digests);
+        let record =
+            OperationRecord { version: change_log.version(), timestamp_ms: now_ms, operation, changes };
+        self.oplog.lock()?.record(record)
+    }
+
+    /// Passes a registered or updated store to the location resolver, if any.
+    fn resolver_put_store(&self, store: &StoreInfo) -> Result<()> {
+        match &self.location_resolver {
+            Some(resolver) => resolver.put_store(store),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the retained mutating operations resulting in a topology
+    /// version above the given one, oldest first, for auditing.
+    pub fn operations_since(&self, version: u64) -> Result<Vec<OperationRecord>> {
+        Ok(self.oplog.lock()?.since(version))
+    }
+
+    /// Reconstructs the topology as of a past version from the operation
+    /// log, see `OperationLog::snapshot_at()`.
+    pub fn topology_at(&self, version: u64) -> Result<Snapshot> {
+        self.oplog.lock()?.snapshot_at(version)
+    }
+
+    /// Reconstructs the topology as of `version` by replaying the operation
+    /// log onto a snapshot taken at `base_version`, e.g. a backup from
+    /// `snapshot()`. See `OperationLog::replay_from()`.
+    pub fn replay_from(&self, base: &[u8], base_version: u64, version: u64) -> Result<Snapshot> {
+        let base: Snapshot = snapshot::decode(base)?;
+        self.oplog.lock()?.replay_from(&base, base_version, version)
+    }
+
+    /// Returns the topology version, which increases with every routing or
+    /// store change. Admin operations can pass the version they read to be
+    /// aborted if the topology changed since, for compare-and-swap semantics.
+    pub fn topology_version(&self) -> Result<u64> {
+        Ok(self.changes.lock()?.version())
+    }
+
+    /// Returns `Error::Abort` if the topology version differs from the
+    /// expected one, if any. Must be called with both the routing and store
+    /// locks held, since every topology change holds one of them, so that
+    /// the version can't change before the caller's own change.
+    fn check_topology_version(&self, expected_version: Option<u64>) -> Result<()> {
+        match expected_version {
+            Some(expected) if expected != self.topology_version()? => Err(Error::Abort),
+            _ => Ok(()),
+        }
+    }
+
+    /// Validates a scheduling operation against the current topology, e.g.
+    /// one computed by an external scheduler, and enqueues it. Like
+    /// `set_store_state()`, it is aborted if the topology version isn't the
+    /// expected one. Leader
+    /// transfers are also marked pending until the new leader's store
+    /// confirms them with `confirm_transfer()`.
+    pub fn inject_operation(&self, op: ScheduleOp, expected_version: Option<u64>) -> Result<()> {
+        self.check_leader()?;
+        let routing = self.routing.lock()?;
+        let stores = self.stores.lock()?;
+        self.check_topology_version(expected_version)?;
+        op.validate(&routing, &stores)?;
+        if let ScheduleOp::TransferLeader { region_id, to_store_id, .. } = &op {
+            match self.pinned_leaders.lock()?.get(region_id) {
+                Some(pinned) if pinned != to_store_id => {
+                    return Err(Error::Value(format!(
+                        "The leader of region {} is pinned to store {}",
+                        region_id, pinned
+                    )))
+                }
+                _ => {}
+            }
+        }
+        match &op {
+            ScheduleOp::Split { region_id, .. } => self.check_region_count(&routing, *region_id)?,
+            ScheduleOp::TransferLeader { region_id, epoch, to_store_id } => {
+                let now_ms = self.clock.now_ms();
+                let transfer = PendingTransfer {
+                    region_id: *region_id,
+                    epoch: *epoch,
+                    from_store_id: routing.get_region(*region_id)?.leader,
+                    to_store_id: *to_store_id,
+                    deadline_ms: now_ms.saturating_add(self.config.region.transfer_timeout_ms),
+                };
+                self.transfers.lock()?.begin(transfer, now_ms)?;
+            }
+            _ => {}
+        }
+        let mut operations = self.operations.lock()?;
+        self.prune_operations(&routing, &stores, &mut operations)?;
+        match operations.push(op.clone()) {
+            true => info!("Enqueued scheduling operation {:?}", op),
+            false => info!("Scheduling operation {:?} is already queued", op),
+        }
+        Ok(())
+    }
+
+    /// Completes a pending leader transfer once the new leader's store
+    /// confirms it is ready, flipping the leader and bumping the epoch, and
+    /// returns the updated region. Fails if the transfer timed out, or if the
+    /// region changed since it was started, in which case it is aborted.
+    pub fn confirm_transfer(&self, region_id: u64, store_id: u64) -> Result<RegionInfo> {
+        self.check_leader()?;
+        let mut routing = self.routing.lock()?;
+        let transfer = self.transfers.lock()?.confirm(region_id, store_id, self.clock.now_ms())?;
+        let mut region = routing.get_region(region_id)?;
+        if region.epoch != transfer.epoch || !region.stores.contains(&store_id) {
+            return Err(Error::Value(format!(
+                "Region {} changed during its leader transfer to store {}, aborted",
+                region_id, store_id
+            )));
+        }
+        self.operations.lock()?.remove(&ScheduleOp::TransferLeader {
+            region_id,
+            epoch: transfer.epoch,
+            to_store_id: store_id,
+        });
+        region.leader = store_id;
+        region.epoch = add_or_err(region.epoch, 1)?;
+        routing.put_region(region.clone())?;
+        self.topology_changed(&[&region])?;
+        self.log_changes(
+            Operation::TransferLeader { region_id, store_id },
+            vec![Change::PutRegion(region.clone())],
+        )?;
+        info!(
+            "Transferred leader of region {} from store {} to store {}",
+            region_id, transfer.from_store_id, store_id
+        );
+        Ok(region)
+    }
+
+    /// Pins a region's leader to a store holding one of its replicas, e.g.
+    /// for a latency-sensitive range. Transfers the leadership there if
+    /// needed, and rejects leader transfers to any other store until
+    /// unpinned.
+    pub fn pin_leader(&self, region_id: u64, store_id: u64) -> Result<()> {
+        self.check_leader()?;
+        let region = self.routing.lock()?.get_region(region_id)?;
+        if !region.stores.contains(&store_id) {
+            return Err(Error::Value(format!("Region {} has no replica on store {}", region_id, store_id)));
+        }
+        if region.leader != store_id && !self.is_transferring(region_id)? {
+            let op = ScheduleOp::TransferLeader { region_id, epoch: region.epoch, to_store_id: store_id };
+            self.inject_operation(op, None)?;
+        }
+        self.pinned_leaders.lock()?.insert(region_id, store_id);
+        info!("Pinned leader of region {} to store {}", region_id, store_id);
+        Ok(())
+    }
+
+    /// Unpins a region's leader, letting it be transferred again. Returns
+    /// `Error::NotFound` if it isn't pinned.
+    pub fn unpin_leader(&self, region_id: u64) -> Result<()> {
+        self.check_leader()?;
+        if self.pinned_leaders.lock()?.remove(&region_id).is_none() {
+            return Err(Error::NotFound(format!("The leader of region {} is not pinned", region_id)));
+        }
+        info!("Unpinned leader of region {}", region_id);
+        Ok(())
+    }
+
+    /// Pauses or resumes scheduling, e.g. to freeze the topology during a
+    /// bulk import. While paused, undersized regions get no replicas
+    /// scheduled, heartbeats advise no splits, and stores are handed no
+    /// operations, though heartbeats still update liveness and injected
+    /// operations are still queued. Resuming schedules replicas for the
+    /// regions left undersized in the meantime.
+    pub fn set_scheduling_enabled(&self, enabled: bool) -> Result<()> {
+        self.check_leader()?;
+        let routing = self.routing.lock()?;
+        let mut paused = self.scheduling_paused.lock()?;
+        if *paused != enabled {
+            return Ok(());
+        }
+        *paused = !enabled;
+        drop(paused);
+        if !enabled {
+            warn!("Scheduling paused");
+            return Ok(());
+        }
+        info!("Scheduling resumed");
+        let replicas = self.config.region.replicas as usize;
+        let undersized: Vec<&RegionInfo> =
+            routing.regions().filter(|region| region.stores.len() < replicas).collect();
+        if !undersized.is_empty() {
+            self.schedule_replicas(&routing, &undersized)?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if scheduling is paused, see `set_scheduling_enabled()`.
+    pub fn scheduling_paused(&self) -> Result<bool> {
+        Ok(*self.scheduling_paused.lock()?)
+    }
+
+    /// Returns true if a region's leader is being transferred.
+    pub fn is_transferring(&self, region_id: u64) -> Result<bool> {
+        Ok(self.transfers.lock()?.get(region_id, self.clock.now_ms()).is_some())
+    }
+
+    /// Returns the scheduling operations waiting to be carried out, oldest
+    /// first.
+    pub fn pending_operations(&self) -> Result<Vec<ScheduleOp>> {
+        let routing = self.routing.lock()?;
+        let stores = self.stores.lock()?;
+        let mut operations = self.operations.lock()?;
+        self.prune_operations(&routing, &stores, &mut operations)?;
+        Ok(operations.pending())
+    }
+
+    /// Drops the queued operations that no longer validate against the
+    /// topology, i.e. those carried out or made stale by a change to their
+    /// region, along with leader transfers no longer pending, e.g. aborted
+    /// at their deadline. Must be called with the routing and store locks
+    /// held.
+    fn prune_operations(
+        &self,
+        routing: &RoutingTable,
+        stores: &StoreRegistry,
+        operations: &mut OperationQueue,
+    ) -> Result<()> {
+        let now_ms = self.clock.now_ms();
+        let transfers = self.transfers.lock()?;
+        let dropped = operations.retain(|op| {
+            let pending = match op {
+                ScheduleOp::TransferLeader { region_id, to_store_id, .. } => transfers
+                    .get(*region_id, now_ms)
+                    .is_some_and(|transfer| transfer.to_store_id == *to_store_id),
+                _ => true,
+            };
+            pending && op.validate(routing, stores).is_ok()
+        });
+        for op in dropped {
+            info!("Dropped scheduling operation {:?}, carried out or no longer valid", op);
+        }
+        Ok(())
+    }
+
+    /// Returns the live stores matching a label selector such as
+    /// `zone=us-east,disk in (ssd,nvme)`. Returns `Error::Parse` for a
+    /// malformed selector, and an empty list if no store matches.
+    pub fn query_stores(&self, selector: &str) -> Result<Vec<StoreInfo>> {
+        let selector = LabelSelector::parse(selector)?;
+        Ok(self.stores.lock()?.query(&selector))
+    }
+
+    /// Picks the stores to place the replicas of a new region on, as many as
+    /// `region.replicas`.
+    pub fn place_replicas(&self) -> Result<Vec<u64>> {
+        let region_counts = self.routing.lock()?.store_region_counts();
+        let mut rng = self.placement_rng.lock()?;
+        self.stores.lock()?.place(self.config.region.replicas, &self.config.store, &region_counts, &mut *rng)
+    }
+
+    /// Picks the stores to place the replicas of a new region on, like
+    /// `place_replicas()`, along with the store to lead it, see
+    /// `RoutingTable::pick_leader()`.
+    pub fn place_region(&self) -> Result<(Vec<u64>, u64)> {
+        let routing = self.routing.lock()?;
+        let region_counts = routing.store_region_counts();
+        let mut rng = self.placement_rng.lock()?;
+        let stores = self.stores.lock()?.place(
+            self.config.region.replicas,
+            &self.config.store,
+            &region_counts,
+            &mut *rng,
+        )?;
+        let leader = routing.pick_leader(&stores, &mut *rng);
+        Ok((stores, leader))
+    }
+
+    /// Applies a store heartbeat, updating its space usage in bytes. Logs a
+    /// warning when the store crosses a space threshold, and an error when
+    /// its drain stalls.
+    pub fn store_heartbeat(&self, id: u64, capacity: u64, used: u64) -> Result<()> {
+        self.check_leader()?;
+        let routing = self.routing.lock()?;
+        let mut stores = self.stores.lock()?;
+        let (before, after) = stores.heartbeat(id, capacity, used, self.clock.now_ms())?;
+        self.log_space_level_change(&before, &after);
+        self.log_stalled_drain(&routing, &mut stores, id);
+        Ok(())
+    }
+
+    /// Applies a batch of store heartbeats, as (store id, capacity, used)
+    /// tuples, e.g. from a gateway aggregating many stores. The batch is
+    /// applied atomically: it is rejected as a whole if a store id is unknown
+    /// or appears twice. Returns the pending scheduling operations of each
+    /// store, in batch order.
+    pub fn batch_store_heartbeat(
+        &self,
+        heartbeats: &[(u64, u64, u64)],
+    ) -> Result<Vec<(u64, Vec<ScheduleOp>)>> {
+        self.check_leader()?;
+        let routing = self.routing.lock()?;
+        let mut stores = self.stores.lock()?;
+        let mut seen = HashSet::new();
+        for &(id, _, _) in heartbeats {
+            if !seen.insert(id) {
+                return Err(Error::Value(format!("Store {} appears twice in the heartbeat batch", id)));
+            }
+            stores.get(id)?;
+        }
+        let now_ms = self.clock.now_ms();
+        let paused = self.scheduling_paused()?;
+        let mut operations = self.operations.lock()?;
+        self.prune_operations(&routing, &stores, &mut operations)?;
+        let mut replies = Vec::with_capacity(heartbeats.len());
+        for &(id, capacity, used) in heartbeats {
+            let (before, after) = stores.heartbeat(id, capacity, used, now_ms)?;
+            self.log_space_level_change(&before, &after);
+            self.log_stalled_drain(&routing, &mut stores, id);
+            let ops = if paused { Vec::new() } else { operations.for_store(id, &routing) };
+            replies.push((id, ops));
+        }
+        Ok(replies)
+    }
+
+    /// Returns the draining stores past their drain timeout that still hold
+    /// replicas, by ascending id.
+    fn stalled_drains(&self, routing: &RoutingTable, stores: &StoreRegistry) -> Vec<u64> {
+        let mut overdue = stores.overdue_drains(self.clock.now_ms(), &self.config.store);
+        overdue.retain(|id| routing.regions().any(|region| region.stores.contains(id)));
+        overdue
+    }
+
+    /// Logs an error the first time a store's drain is found stalled.
+    fn log_stalled_drain(&self, routing: &RoutingTable, stores: &mut StoreRegistry, id: u64) {
+        if self.stalled_drains(routing, stores).contains(&id) && stores.report_drain_stall(id) {
+            error!(
+                "Store {} has been draining for over {}ms and still holds replicas, likely because \
+                 they have nowhere to go. It keeps draining, add capacity or move its regions manually",
+                id, self.config.store.drain_timeout_ms
+            );
+        }
+    }
+
+    /// Logs a store crossing a space threshold.
+    fn log_space_level_change(&self, before: &StoreInfo, after: &StoreInfo) {
+        let (id, config) = (after.id, &self.config.store);
+        let level = after.space_level(config);
+        if level != before.space_level(config) {
+            let percent = after.used_ratio() * 100.0;
+            match level {
+                SpaceLevel::Critical => warn!(
+                    "Store {} is critically full ({:.1}% used), its regions should be evacuated",
+                    id, percent
+                ),
+                SpaceLevel::High => {
+                    warn!("Store {} is nearly full ({:.1}% used), not placing new regions on it", id, percent)
+                }
+                SpaceLevel::Normal => info!("Store {} is no longer nearly full ({:.1}% used)", id, percent),
+            }
+        }
+    }
+
+    /// Applies a region heartbeat, updating the region's routing entry and
+    /// recording its load. The region's size must already be normalized to
+    /// bytes. Heartbeats from an older epoch are rejected. With
+    /// `region.skip_unchanged_heartbeats`, a heartbeat identical to the
+    /// region's previous one only records its load. Returns whether the
+    /// region should compact now, and where to split it if it is larger than
+    /// `region.max_size`, based on the keys sampled by the store.
+    pub fn region_heartbeat(
+        &self,
+        region: RegionInfo,
+        read_qps: u64,
+        write_qps: u64,
+        median_key: Vec<u8>,
+        stale_versions: u64,
+        sampled_keys: Vec<Vec<u8>>,
+    ) -> Result<RegionAdvice> {
+        self.check_leader()?;
+        let digest = if self.config.region.skip_unchanged_heartbeats {
+            let mut hasher = DefaultHasher::new();
+            region.hash(&mut hasher);
+            Some(hasher.finish())
+        } else {
+            None
+        };
+        if digest.is_some() && self.heartbeat_digests.lock()?.get(&region.id) == digest.as_ref() {
+            *self.skipped_reconciliations.lock()? += 1;
+            let compact =
+                self.observe_region_load(&region, read_qps, write_qps, median_key, stale_versions)?;
+            return Ok(RegionAdvice { compact, split_keys: self.advise_split(&region, sampled_keys)? });
+        }
+        let mut routing = self.routing.lock()?;
+        let current = routing.get_region(region.id).ok();
+        if let Some(current) = &current {
+            if current.epoch > region.epoch {
+                return Err(Error::Value(format!(
+                    "Stale heartbeat for region {} at epoch {}, current epoch is {}",
+                    region.id, region.epoch, current.epoch
+                )));
+            }
+        }
+        if current.as_ref().is_some_and(|c| c.epoch != region.epoch || c.leader != region.leader) {
+            self.topology_changed(&[&region])?;
+        }
+        if current.as_ref() != Some(&region) {
+            routing.put_region(region.clone())?;
+            self.log_changes(
+                Operation::RegionHeartbeat { region_id: region.id },
+                vec![Change::PutRegion(region.clone())],
+            )?;
+        }
+        if let Some(digest) = digest {
+            self.heartbeat_digests.lock()?.insert(region.id, digest);
+        }
+        drop(routing);
+        let compact = self.observe_region_load(&region, read_qps, write_qps, median_key, stale_versions)?;
+        Ok(RegionAdvice { compact, split_keys: self.advise_split(&region, sampled_keys)? })
+    }
+
+    /// Computes the keys to split a region larger than `region.max_size` at,
+    /// see `split::split_keys()`. Returns none while the region count cap is
+    /// reached, since the split would be declined.
+    fn advise_split(&self, region: &RegionInfo, sampled_keys: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let (max_size, max_count) = (self.config.region.max_size, self.config.region.max_count);
+        if self.scheduling_paused()? {
+            return Ok(Vec::new());
+        }
+        if region.approximate_size <= max_size
+            || (max_count > 0 && self.routing.lock()?.region_count() >= max_count)
+        {
+            return Ok(Vec::new());
+        }
+        let sample_count = sampled_keys.len();
+        match split::split_keys(region, sampled_keys, max_size, self.key_comparator.as_ref()) {
+            Some(split_keys) => Ok(split_keys),
+            None => {
+                info!(
+                    "Deferring the split of region {} ({} bytes): {} sampled keys are too few to split it evenly",
+                    region.id, region.approximate_size, sample_count
+                );
+                Ok(Vec::new())
+            }
+        }
+    }
+
+    /// Records the load reported by a region heartbeat, returning true if the
+    /// region should compact now.
+    fn observe_region_load(
+        &self,
+        region: &RegionInfo,
+        read_qps: u64,
+        write_qps: u64,
+        median_key: Vec<u8>,
+        stale_versions: u64,
+    ) -> Result<bool> {
+        self.hotspots.lock()?.observe(region, read_qps, write_qps, median_key, self.key_comparator.as_ref());
+        Ok(self.compactions.lock()?.observe(region.id, stale_versions))
+    }
+
+    /// Returns an overview of the cluster.
+    pub fn cluster_status(&self) -> Result<ClusterStatus> {
+        let mut persistence_read_only = false;
+        for keyspace_tso in self.keyspace_tsos.lock()?.values() {
+            persistence_read_only |= keyspace_tso.lock()?.checkpoint().read_only();
+        }
+        let tso = self.tso.lock()?;
+        persistence_read_only |= tso.checkpoint().read_only();
+        let (max_size, max_region_count) = (self.config.region.max_size, self.config.region.max_count);
+        let routing = self.routing.lock()?;
+        let region_count = routing.region_count();
+        // Splitting is declined at the region count cap, so don't recommend it.
+        let at_cap = max_region_count > 0 && region_count >= max_region_count;
+        let oversized_regions = if at_cap {
+            Vec::new()
+        } else {
+            routing
+                .regions()
+                .filter(|region| region.approximate_size > max_size)
+                .map(|region| region.id)
+                .collect()
+        };
+        let region_counts = routing.store_region_counts();
+        let stores = self.stores.lock()?;
+        let stalled_drains = self.stalled_drains(&routing, &stores);
+        drop(routing);
+        let mut store_regions: Vec<StoreRegionCount> = stores
+            .stores()
+            .map(|store| StoreRegionCount {
+                store_id: store.id,
+                region_count: region_counts.get(&store.id).copied().unwrap_or(0),
+            })
+            .collect();
+        store_regions.sort_by_key(|count| count.store_id);
+        let mut store_weights: Vec<PinnedStoreWeight> = stores
+            .stores()
+            .filter_map(|store| Some(PinnedStoreWeight { store_id: store.id, weight: store.weight? }))
+            .collect();
+        store_weights.sort_by_key(|weight| weight.store_id);
+        let store_tombstones = stores.tombstones(self.clock.now_ms());
+        let mut space_alerts: Vec<SpaceAlert> = stores
+            .stores()
+            .map(|store| SpaceAlert {
+                store_id: store.id,
+                used_ratio: store.used_ratio(),
+                level: store.space_level(&self.config.store),
+            })
+            .filter(|alert| alert.level != SpaceLevel::Normal)
+            .collect();
+        drop(stores);
+        space_alerts.sort_by_key(|alert| alert.store_id);
+        let mut pinned_leaders: Vec<PinnedLeader> = self
+            .pinned_leaders
+            .lock()?
+            .iter()
+            .map(|(&region_id, &store_id)| PinnedLeader { region_id, store_id })
+            .collect();
+        pinned_leaders.sort_by_key(|pin| pin.region_id);
+        Ok(ClusterStatus {
+            tso_window_end: tso.window_end(),
+            tso_last_allocated: tso.last_allocated(),
+            tso_window_refills: tso.window_refills(),
+            tso_window_remaining: tso.window_remaining(),
+            topology_version: self.topology_version()?,
+            change_log_entries: self.changes.lock()?.len(),
+            gc_safe_point: self.gc_safe_point()?,
+            persistence_breaker: self
+                .persistence_breaker
+                .as_ref()
+                .map(|breaker| breaker.state())
+                .transpose()?,
+            persistence_read_only,
+            hotspots: self.hotspots.lock()?.hotspots(),
+            oversized_regions,
+            region_count,
+            max_region_count,
+            store_regions,
+            store_weights,
+            max_regions_per_store: self.config.store.max_regions,
+            space_alerts,
+            store_tombstones,
+            stalled_drains,
+            compaction_candidates: self.compactions.lock()?.candidates(),
+            pinned_leaders,
+            scheduling_paused: self.scheduling_paused()?,
+            skipped_reconciliations: *self.skipped_reconciliations.lock()?,
+        })
+    }
+
+    /// Returns a snapshot of the server's metrics, see `MetricsSnapshot`.
+    pub fn metrics(&self) -> Result<MetricsSnapshot> {
+        let tso = self.tso.lock()?;
+        Ok(MetricsSnapshot {
+            tso_window_refills_total: tso.window_refills(),
+            tso_window_remaining: tso.window_remaining(),
+        })
+    }
+
+    /// Describes the key ranges of all regions on a store, flagging gaps and
+    /// overlaps with neighboring regions.
+    pub fn describe_store_keyspace(&self, store_id: u64) -> Result<Vec<RangeDescription>> {
+        Ok(self.routing.lock()?.describe_store(store_id))
+    }
+
+    /// Creates a new, empty keyspace. With `isolated_tso`, the keyspace gets
+    /// its own timestamp oracle, so tenants' timestamp spaces don't interfere,
+    /// at the cost of its timestamps not being ordered with respect to those
+    /// of other keyspaces. Its watermark is persisted next to the global one,
+    /// with the keyspace id as a suffix. Like `set_store_state()`, it is
+    /// aborted if the topology version isn't the expected one.
+    pub fn create_keyspace(
+        &self,
+        keyspace_id: u32,
+        isolated_tso: bool,
+        expected_version: Option<u64>,
+    ) -> Result<()> {
+        self.check_leader()?;
+        let mut routing = self.routing.lock()?;
+        let _stores = self.stores.lock()?;
+        self.check_topology_version(expected_version)?;
+        routing.create_keyspace(keyspace_id)?;
+        if isolated_tso {
+            let tso = match self.keyspace_tso(keyspace_id) {
+                Ok(tso) => tso,
+                Err(err) => {
+                    routing.delete_keyspace(keyspace_id)?;
+                    return Err(err);
+                }
+            };
+            self.keyspace_tsos.lock()?.insert(keyspace_id, Arc::new(Mutex::new(tso)));
+        }
+        self.log_changes(
+            Operation::CreateKeyspace { keyspace_id },
+            vec![Change::CreateKeyspace(keyspace_id)],
+        )?;
+        Ok(())
+    }
+
+    /// Creates and recovers the isolated timestamp oracle of a keyspace.
+    fn keyspace_tso(&self, keyspace_id: u32) -> Result<TimestampOracle> {
+        let config = &self.config.tso;
+        let checkpoint: Arc<dyn CheckpointStore> = match &config.checkpoint_path {
+            Some(path) => {
+                let mut path = path.clone().into_os_string();
+                path.push(format!(".keyspace-{}", keyspace_id));
+                Arc::new(
+                    FileCheckpoint::new(path)
+                        .with_sync_policy(config.sync_policy)
+                        .with_format(self.config.persistence.format),
+                )
+            }
+            None => Arc::new(MemoryCheckpoint::new()),
+        };
+        let mut tso =
+            TimestampOracle::new(checkpoint.clone(), config.window_size).with_first_ts(config.first_ts);
+        tso.recover(checkpoint.load()?);
+        Ok(tso)
+    }
+
+    /// Deletes a keyspace and all of its regions. Like `set_store_state()`,
+    /// it is aborted if the topology version isn't the expected one.
+    pub fn delete_keyspace(&self, keyspace_id: u32, expected_version: Option<u64>) -> Result<()> {
+        self.check_leader()?;
+        let mut routing = self.routing.lock()?;
+        let _stores = self.stores.lock()?;
+        self.check_topology_version(expected_version)?;
+        routing.delete_keyspace(keyspace_id)?;
+        // The oracle's checkpoint is kept, so a recreated keyspace resumes
+        // above its old timestamps.
+        self.keyspace_tsos.lock()?.remove(&keyspace_id);
+        self.log_changes(
+            Operation::DeleteKeyspace { keyspace_id },
+            vec![Change::DeleteKeyspace(keyspace_id)],
+        )?;
+        Ok(())
+    }
+
+    /// Returns a full snapshot of the routing and store state, encoded with
+    /// `snapshot::encode_as()` in the configured persistence format, along
+    /// with its version.
+    pub fn snapshot(&self) -> Result<(u64, Vec<u8>)> {
+        let routing = self.routing.lock()?;
+        let stores = self.stores.lock()?;
+        let version = self.changes.lock()?.version();
+        let mut regions: Vec<RegionInfo> = routing.regions().cloned().collect();
+        regions.sort_by_key(|region| region.id);
+        let mut stores: Vec<StoreInfo> = stores.stores().cloned().collect();
+        stores.sort_by_key(|store| store.id);
+        let snapshot = Snapshot { keyspaces: routing.keyspace_ids(), regions, stores };
+        Ok((version, snapshot::encode_as(self.config.persistence.format, &snapshot)?))
+    }
+
+    /// Dumps the cluster topology, for visualization and external tooling.
+    pub fn dump_topology(&self) -> Result<TopologyDump> {
+        let routing = self.routing.lock()?;
+        let stores = self.stores.lock()?;
+        let mut regions: Vec<TopologyRegion> = routing.regions().map(TopologyRegion::from).collect();
+        regions.sort_by_key(|region| region.id);
+        let mut stores: Vec<TopologyStore> = stores.stores().map(TopologyStore::from).collect();
+        stores.sort_by_key(|store| store.id);
+        Ok(TopologyDump {
+            schema_version: TOPOLOGY_SCHEMA_VERSION,
+            topology_version: self.topology_version()?,
+            keyspaces: routing.keyspace_ids(),
+            stores,
+            regions,
+        })
+    }
+
+    /// Returns the routing and store changes since the given version, encoded
+    /// with `snapshot::encode_as()` in the configured persistence format,
+    /// along with the new version, for incremental replication.
+    /// Returns `Error::SnapshotRequired` if the changes are no longer
+    /// retained, in which case the caller must fall back to `snapshot()`.
+    /// Store space usage is not tracked as a change, and is only up to date
+    /// in full snapshots.
+    pub fn snapshot_since(&self, version: u64) -> Result<(u64, Vec<u8>)> {
+        let changes = self.changes.lock()?;
+        Ok((
+            changes.version(),
+            snapshot::encode_as(self.config.persistence.format, &changes.since(version)?)?,
+        ))
+    }
+
+    /// Returns the leader's change log version the replicated state is at.
+    pub fn replicated_version(&self) -> Result<u64> {
+        Ok(*self.replicated_version.lock()?)
+    }
+
+    /// Returns how long ago the routing was last synced with the leader, in
+    /// milliseconds: 0 on the leader, and `u64::MAX` on a follower that never
+    /// synced.
+    pub fn routing_staleness_ms(&self) -> Result<u64> {
+        if self.role == Role::Leader {
+            return Ok(0);
+        }
+        Ok(match *self.replicated_at_ms.lock()? {
+            Some(synced_ms) => self.clock.now_ms().saturating_sub(synced_ms),
+            None => u64::MAX,
+        })
+    }
+
+    /// Checks that data locations may be served at the given consistency
+    /// level, returning the routing staleness. A follower refuses strong
+    /// reads with `Error::NotLeader`, and bounded-staleness reads with
+    /// `Error::Unavailable` if it hasn't synced within the bound.
+    pub fn check_consistency(&self, level: ConsistencyLevel) -> Result<u64> {
+        let staleness_ms = self.routing_staleness_ms()?;
+        match level {
+            ConsistencyLevel::Strong if self.role != Role::Leader => Err(Error::NotLeader),
+            ConsistencyLevel::BoundedStaleness(max_staleness_ms) if staleness_ms > max_staleness_ms => {
+                Err(Error::Unavailable(match staleness_ms {
+                    u64::MAX => "Routing was never synced with the leader".into(),
+                    _ => format!(
+                        "Routing was synced {}ms ago, above the {}ms bound",
+                        staleness_ms, max_staleness_ms
+                    ),
+                }))
+            }
+            _ => Ok(staleness_ms),
+        }
+    }
+
+    /// Replaces the routing and store state with a full snapshot from the
+    /// leader, as returned by its `snapshot()`. Only valid on followers. A
+    /// snapshot with duplicate region ids or overlapping regions, e.g. a
+    /// corrupted or badly merged one, is rejected as a whole with
+    /// `Error::Internal` listing the conflicts, leaving the state untouched.
+    pub fn apply_snapshot(&self, version: u64, snapshot: &[u8]) -> Result<()> {
+        if self.role != Role::ReadOnlyFollower {
+            return Err(Error::Value("Only followers apply replicated snapshots".into()));
+        }
+        let snapshot: Snapshot = snapshot::decode(snapshot)?;
+        let mut routing = RoutingTable::with_comparator(self.key_comparator.clone());
+        for keyspace_id in snapshot.keyspaces {
+            if keyspace_id != DEFAULT_KEYSPACE {
+                routing.create_keyspace(keyspace_id)?;
+            }
+        }
+        // put_region() would silently replace conflicting regions, so look
+        // for them first, and for partial overlaps once the table is built.
+        let mut conflicts = Vec::new();
+        for region in snapshot.regions {
+            if routing.get_region(region.id).is_ok() {
+                conflicts.push(format!("duplicate region id {}", region.id));
+            } else if let Ok(other) = routing.lookup(region.keyspace_id, &region.start_key) {
+                conflicts.push(format!(
+                    "regions {} and {} overlap in keyspace {}",
+                    other.id, region.id, region.keyspace_id
+                ));
+            } else {
+                routing.put_region(region)?;
+            }
+        }
+        for violation in routing.validate() {
+            conflicts.push(match violation {
+                Violation::RegionOverlap { keyspace_id, region_id, other_region_id } => format!(
+                    "regions {} and {} overlap in keyspace {}",
+                    region_id, other_region_id, keyspace_id
+                ),
+                violation => format!("{:?}", violation),
+            });
+        }
+        if !conflicts.is_empty() {
+            return Err(Error::Internal(format!(
+                "Snapshot has conflicting regions: {}",
+                conflicts.join(", ")
+            )));
+        }
+        let mut stores = StoreRegistry::new();
+        for store in snapshot.stores {
+            self.resolver_put_store(&store)?;
+            stores.put(store);
+        }
+        *self.routing.lock()? = routing;
+        *self.stores.lock()? = stores;
+        *self.replicated_version.lock()? = version;
+        *self.replicated_at_ms.lock()? = Some(self.clock.now_ms());
+        Ok(())
+    }
+
+    /// Replays the changes from the leader since `replicated_version()`, as
+    /// returned by its `snapshot_since()`. Only valid on followers.
+    pub fn apply_changes(&self, version: u64, changes: &[u8]) -> Result<()> {
+        if self.role != Role::ReadOnlyFollower {
+            return Err(Error::Value("Only followers apply replicated changes".into()));
+        }
+        let changes: Vec<Change> = snapshot::decode(changes)?;
+        let mut routing = self.routing.lock()?;
+        let mut stores = self.stores.lock()?;
+        for change in changes {
+            match change {
+                Change::CreateKeyspace(keyspace_id) => routing.create_keyspace(keyspace_id)?,
+                Change::DeleteKeyspace(keyspace_id) => routing.delete_keyspace(keyspace_id)?,
+                Change::PutRegion(region) => routing.put_region(region)?,
+                Change::PutStore(store) => {
+                    self.resolver_put_store(&store)?;
+                    stores.put(store)
+                }
+            }
+        }
+        *self.replicated_version.lock()? = version;
+        *self.replicated_at_ms.lock()? = Some(self.clock.now_ms());
+        Ok(())
+    }
+}
+
+impl Default for FeatherPD {
+    /// Creates a FeatherPD server with purely in-memory state. There is no
+    /// persisted watermark to recover, so it is serving right away.
+    fn default() -> Self {
+        let mut tso = TimestampOracle::new(Arc::new(MemoryCheckpoint::new()), DEFAULT_WINDOW_SIZE);
+        tso.recover(None);
+        Self::build(tso, ServingState::Serving, Config::default())
     }
 }
 
+/// Converts the expected topology version of an admin request, where 0
+/// means none.
+fn expected_version(version: u64) -> Option<u64> {
+    (version > 0).then_some(version)
+}
+
 #[tonic::async_trait]
 impl PlacementDriver for FeatherPD {
-    async fn get_timestamp(&self, _request: Request<TsoRequest>) -> RpcResult<TsoReply> {
-        let mut next_ts = self.next_ts.lock().unwrap();
-        let reply = TsoReply { timestamp: *next_ts };
-        *next_ts += 1;
+    async fn get_timestamp(&self, request: Request<TsoRequest>) -> RpcResult<TsoReply> {
+        Ok(Response::new(self.serve_tso_request(request.into_inner()).await?))
+    }
+
+    type AllocateStreamStream = BoxStream<TsoReply>;
+
+    async fn allocate_stream(
+        &self,
+        request: Request<Streaming<TsoRequest>>,
+    ) -> RpcResult<Self::AllocateStreamStream> {
+        let mut requests = request.into_inner();
+        let (tx, rx) = mpsc::channel(ALLOCATE_STREAM_CAPACITY);
+        let pd = self.clone();
+        // Requests are served in order, each with its own reply, so pipelined
+        // clients can match them up. The stream ends at the first error.
+        task::spawn("featherpd-allocate-stream", async move {
+            while let Some(request) = requests.next().await {
+                let reply = match request {
+                    Ok(request) => pd.serve_tso_request(request).await.map_err(tonic::Status::from),
+                    Err(status) => Err(status),
+                };
+                let failed = reply.is_err();
+                if tx.send(reply).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn advance_timestamp(
+        &self,
+        request: Request<AdvanceTimestampRequest>,
+    ) -> RpcResult<AdvanceTimestampReply> {
+        self.check_admin(&request)?;
+        self.advance_timestamp(request.into_inner().target)?;
+        Ok(Response::new(AdvanceTimestampReply {}))
+    }
+
+    async fn update_gc_safe_point(
+        &self,
+        request: Request<UpdateGcSafePointRequest>,
+    ) -> RpcResult<UpdateGcSafePointReply> {
+        self.check_admin(&request)?;
+        let safe_point = self.update_gc_safe_point(request.into_inner().safe_point)?;
+        Ok(Response::new(UpdateGcSafePointReply { safe_point }))
+    }
+
+    async fn get_gc_safe_point(
+        &self,
+        request: Request<GetGcSafePointRequest>,
+    ) -> RpcResult<GetGcSafePointReply> {
+        let read_ts = request.into_inner().read_ts;
+        if read_ts > 0 {
+            self.check_read_ts(read_ts)?;
+        }
+        Ok(Response::new(GetGcSafePointReply { safe_point: self.gc_safe_point()? }))
+    }
+
+    async fn flush(&self, request: Request<FlushRequest>) -> RpcResult<FlushReply> {
+        self.check_admin(&request)?;
+        let (bytes_written, watermark) = self.flush()?;
+        Ok(Response::new(FlushReply { bytes_written, watermark }))
+    }
+
+    async fn inject_operation(
+        &self,
+        request: Request<InjectOperationRequest>,
+    ) -> RpcResult<InjectOperationReply> {
+        self.check_admin(&request)?;
+        let request = request.into_inner();
+        let op: ScheduleOp = bincode::deserialize(&request.op).map_err(Error::from)?;
+        self.inject_operation(op, expected_version(request.expected_version))?;
+        Ok(Response::new(InjectOperationReply {}))
+    }
+
+    async fn pin_leader(&self, request: Request<PinLeaderRequest>) -> RpcResult<PinLeaderReply> {
+        self.check_admin(&request)?;
+        let request = request.into_inner();
+        self.pin_leader(request.region_id, request.store_id)?;
+        Ok(Response::new(PinLeaderReply {}))
+    }
+
+    async fn unpin_leader(&self, request: Request<UnpinLeaderRequest>) -> RpcResult<UnpinLeaderReply> {
+        self.check_admin(&request)?;
+        self.unpin_leader(request.into_inner().region_id)?;
+        Ok(Response::new(UnpinLeaderReply {}))
+    }
+
+    async fn set_scheduling_enabled(
+        &self,
+        request: Request<SetSchedulingEnabledRequest>,
+    ) -> RpcResult<SetSchedulingEnabledReply> {
+        self.check_admin(&request)?;
+        self.set_scheduling_enabled(request.into_inner().enabled)?;
+        Ok(Response::new(SetSchedulingEnabledReply {}))
+    }
+
+    async fn verify_consistency(
+        &self,
+        request: Request<VerifyConsistencyRequest>,
+    ) -> RpcResult<VerifyConsistencyReply> {
+        self.check_admin(&request)?;
+        let report = self.verify_consistency()?;
+        Ok(Response::new(VerifyConsistencyReply {
+            report: bincode::serialize(&report).map_err(Error::from)?,
+        }))
+    }
+
+    async fn get_data_location(&self, request: Request<DataLocRequest>) -> RpcResult<DataLocReply> {
+        let request = ValidatedDataLocRequest::try_from(request.into_inner())?;
+        let staleness_ms = self.check_consistency(request.consistency)?;
+        let (keyspace_id, key) = (request.keyspace_id, request.key);
+        let reply = self.locate_or_stale(keyspace_id, &key).await?;
+        Ok(Response::new(DataLocReply { staleness_ms: reply.staleness_ms.max(staleness_ms), ..reply }))
+    }
+
+    async fn get_data_location_range(
+        &self,
+        request: Request<DataLocRangeRequest>,
+    ) -> RpcResult<DataLocRangeReply> {
+        let request = ValidatedDataLocRangeRequest::try_from(request.into_inner())?;
+        let (regions, next_start_key) =
+            self.scan(request.keyspace_id, &request.start_key, &request.end_key, request.limit)?;
+        let reply = DataLocRangeReply {
+            regions: bincode::serialize(&regions).map_err(Error::from)?,
+            next_start_key,
+            stale: self.role == Role::ReadOnlyFollower,
+        };
         Ok(Response::new(reply))
     }
 
-    async fn get_data_location(&self, _request: Request<DataLocRequest>) -> RpcResult<DataLocReply> {
-        todo!()
+    async fn create_keyspace(
+        &self,
+        request: Request<CreateKeyspaceRequest>,
+    ) -> RpcResult<CreateKeyspaceReply> {
+        self.check_admin(&request)?;
+        let request = request.into_inner();
+        self.create_keyspace(
+            request.keyspace_id,
+            request.isolated_tso,
+            expected_version(request.expected_version),
+        )?;
+        Ok(Response::new(CreateKeyspaceReply {}))
+    }
+
+    async fn delete_keyspace(
+        &self,
+        request: Request<DeleteKeyspaceRequest>,
+    ) -> RpcResult<DeleteKeyspaceReply> {
+        self.check_admin(&request)?;
+        let request = request.into_inner();
+        self.delete_keyspace(request.keyspace_id, expected_version(request.expected_version))?;
+        Ok(Response::new(DeleteKeyspaceReply {}))
+    }
+
+    async fn get_region_by_id(
+        &self,
+        request: Request<GetRegionByIdRequest>,
+    ) -> RpcResult<GetRegionByIdReply> {
+        let request = request.into_inner();
+        let region = match request.epoch {
+            0 => self.get_region(request.region_id)?,
+            epoch => self.get_region_at_epoch(request.region_id, epoch)?,
+        };
+        let reply = GetRegionByIdReply { region: bincode::serialize(&region).map_err(Error::from)? };
+        Ok(Response::new(reply))
+    }
+
+    async fn alloc_id(&self, _: Request<AllocIdRequest>) -> RpcResult<AllocIdReply> {
+        Ok(Response::new(AllocIdReply { id: self.alloc_id()? }))
+    }
+
+    async fn register_store(&self, request: Request<RegisterStoreRequest>) -> RpcResult<RegisterStoreReply> {
+        let request = ValidatedRegisterStoreRequest::try_from(request.into_inner())?;
+        self.register_store(request.store_id, request.address, request.labels, request.weight)?;
+        Ok(Response::new(RegisterStoreReply {}))
+    }
+
+    async fn query_stores(&self, request: Request<QueryStoresRequest>) -> RpcResult<QueryStoresReply> {
+        let stores = self.query_stores(&request.into_inner().selector)?;
+        Ok(Response::new(QueryStoresReply { stores: bincode::serialize(&stores).map_err(Error::from)? }))
+    }
+
+    async fn store_heartbeat(
+        &self,
+        request: Request<StoreHeartbeatRequest>,
+    ) -> RpcResult<StoreHeartbeatReply> {
+        let request = request.into_inner();
+        self.store_heartbeat(request.store_id, request.capacity, request.used)?;
+        Ok(Response::new(StoreHeartbeatReply {}))
+    }
+
+    async fn batch_heartbeat(
+        &self,
+        request: Request<BatchHeartbeatRequest>,
+    ) -> RpcResult<BatchHeartbeatReply> {
+        let heartbeats: Vec<(u64, u64, u64)> = request
+            .into_inner()
+            .heartbeats
+            .into_iter()
+            .map(|heartbeat| (heartbeat.store_id, heartbeat.capacity, heartbeat.used))
+            .collect();
+        let stores = self
+            .batch_store_heartbeat(&heartbeats)?
+            .into_iter()
+            .map(|(store_id, ops)| Ok(StoreOperations { store_id, ops: bincode::serialize(&ops)? }))
+            .collect::<Result<_>>()?;
+        Ok(Response::new(BatchHeartbeatReply { stores }))
+    }
+
+    async fn set_store_state(&self, request: Request<SetStoreStateRequest>) -> RpcResult<SetStoreStateReply> {
+        self.check_admin(&request)?;
+        let request = request.into_inner();
+        let state = match placement_driver::StoreState::from_i32(request.state) {
+            Some(placement_driver::StoreState::Pending) => StoreState::Pending,
+            Some(placement_driver::StoreState::Up) => StoreState::Up,
+            Some(placement_driver::StoreState::Draining) => StoreState::Draining,
+            Some(placement_driver::StoreState::Down) => StoreState::Down,
+            Some(placement_driver::StoreState::Removed) => StoreState::Removed,
+            None => return Err(Error::Value(format!("Unknown store state {}", request.state)).into()),
+        };
+        let weight = request.weight.map(|weight| weight.weight);
+        let store = self.set_store_state(
+            request.store_id,
+            state,
+            weight,
+            expected_version(request.expected_version),
+        )?;
+        Ok(Response::new(SetStoreStateReply { store: bincode::serialize(&store).map_err(Error::from)? }))
+    }
+
+    async fn region_heartbeat(
+        &self,
+        request: Request<RegionHeartbeatRequest>,
+    ) -> RpcResult<RegionHeartbeatReply> {
+        let request = request.into_inner();
+        let mut region: RegionInfo = bincode::deserialize(&request.region).map_err(Error::from)?;
+        region.approximate_size = self.config.store.size_unit.to_bytes(request.approximate_size);
+        region.approximate_keys = request.approximate_keys;
+        let advice = self.region_heartbeat(
+            region,
+            request.read_qps,
+            request.write_qps,
+            request.median_key,
+            request.stale_versions,
+            request.sampled_keys,
+        )?;
+        Ok(Response::new(RegionHeartbeatReply { compact: advice.compact, split_keys: advice.split_keys }))
+    }
+
+    async fn confirm_transfer(
+        &self,
+        request: Request<ConfirmTransferRequest>,
+    ) -> RpcResult<ConfirmTransferReply> {
+        let request = request.into_inner();
+        let region = self.confirm_transfer(request.region_id, request.store_id)?;
+        Ok(Response::new(ConfirmTransferReply { region: bincode::serialize(&region).map_err(Error::from)? }))
+    }
+
+    type WatchTopologyStream = BoxStream<WatchTopologyReply>;
+
+    async fn watch_topology(
+        &self,
+        request: Request<WatchTopologyRequest>,
+    ) -> RpcResult<Self::WatchTopologyStream> {
+        let request = request.into_inner();
+        let events = self.watch_topology(request.keyspace_id, request.start_key, request.end_key)?;
+        // tonic::Status is large, but it's what the stream must yield.
+        #[allow(clippy::result_large_err)]
+        let stream = ReceiverStream::new(events).map(|event| {
+            let event = bincode::serialize(&event).map_err(Error::from)?;
+            Ok(WatchTopologyReply { event })
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    type WatchGcSafePointStream = BoxStream<WatchGcSafePointReply>;
+
+    async fn watch_gc_safe_point(
+        &self,
+        _request: Request<WatchGcSafePointRequest>,
+    ) -> RpcResult<Self::WatchGcSafePointStream> {
+        // The stream ends once the watchers are closed on shutdown.
+        let closed = self.watchers_closed.subscribe();
+        if *closed.borrow() {
+            return Err(Error::Unavailable("Server is shutting down".into()).into());
+        }
+        let closed = WatchStream::from_changes(closed).map(|_| None);
+        // tonic::Status is large, but it's what the stream must yield.
+        #[allow(clippy::result_large_err)]
+        let stream = WatchStream::new(self.watch_gc_safe_point())
+            .map(Some)
+            .merge(closed)
+            .map_while(|safe_point| Some(Ok(WatchGcSafePointReply { safe_point: safe_point? })));
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_cluster_status(
+        &self,
+        _request: Request<ClusterStatusRequest>,
+    ) -> RpcResult<ClusterStatusReply> {
+        let status = self.cluster_status()?;
+        let reply = ClusterStatusReply { status: bincode::serialize(&status).map_err(Error::from)? };
+        Ok(Response::new(reply))
+    }
+
+    async fn dump_topology(&self, request: Request<DumpTopologyRequest>) -> RpcResult<DumpTopologyReply> {
+        self.check_admin(&request)?;
+        let topology = self.dump_topology()?;
+        let dot = if request.into_inner().dot { topology.to_dot() } else { String::new() };
+        Ok(Response::new(DumpTopologyReply { json: topology.to_json()?, dot }))
+    }
+
+    async fn replay_topology(
+        &self,
+        request: Request<ReplayTopologyRequest>,
+    ) -> RpcResult<ReplayTopologyReply> {
+        self.check_admin(&request)?;
+        let topology = self.topology_at(request.into_inner().version)?;
+        Ok(Response::new(ReplayTopologyReply {
+            snapshot: snapshot::encode_as(self.config.persistence.format, &topology)?,
+        }))
+    }
+
+    async fn describe_store_keyspace(
+        &self,
+        request: Request<DescribeStoreKeyspaceRequest>,
+    ) -> RpcResult<DescribeStoreKeyspaceReply> {
+        let ranges = self.describe_store_keyspace(request.into_inner().store_id)?;
+        let reply = DescribeStoreKeyspaceReply { ranges: bincode::serialize(&ranges).map_err(Error::from)? };
+        Ok(Response::new(reply))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::{ADMIN_NONCE_KEY, ADMIN_TOKEN_KEY};
+    use crate::clock::ManualClock;
+    use crate::config::{ClientConfig, KeyspaceConfig, ServerConfig};
+    use crate::routing::DEFAULT_KEYSPACE;
+    use crate::snapshot::PersistenceFormat;
+    use crate::transport::{connect, spawn_serve, Address};
+    use crate::watch::WATCH_CHANNEL_CAPACITY;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    /// Creates a leader serving timestamps with the given configuration.
+    fn serving(config: Config) -> FeatherPD {
+        let pd = FeatherPD::from_config(&config).unwrap();
+        pd.finish_recovery(None).unwrap();
+        pd
+    }
+
+    /// Registers a store and brings it up.
+    fn add_store(pd: &FeatherPD, id: u64) {
+        pd.register_store(id, format!("store-{}", id), Labels::new(), None).unwrap();
+        pd.set_store_state(id, StoreState::Up, None, None).unwrap();
+        pd.store_heartbeat(id, 1000, 0).unwrap();
+    }
+
+    /// Creates a default keyspace region, spanning the whole keyspace.
+    fn region(id: u64, epoch: u64, stores: Vec<u64>, leader: u64) -> RegionInfo {
+        RegionInfo::new(id, DEFAULT_KEYSPACE, Vec::new(), Vec::new(), epoch, stores, leader).unwrap()
+    }
+
+    /// Applies a region heartbeat carrying no load.
+    fn heartbeat(pd: &FeatherPD, region: RegionInfo) {
+        pd.region_heartbeat(region, 0, 0, Vec::new(), 0, Vec::new()).unwrap();
+    }
+
+    /// Returns a fresh, empty scratch directory for a test.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("featherpd-server-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn ids_persist_next_to_the_tso_checkpoint_by_default() {
+        let dir = scratch_dir("ids");
+        let mut config = Config::default();
+        config.tso.checkpoint_path = Some(dir.join("tso"));
+        let first = FeatherPD::from_config(&config).unwrap().alloc_id().unwrap();
+        assert!(dir.join("tso.ids").exists());
+        assert!(FeatherPD::from_config(&config).unwrap().alloc_id().unwrap() > first);
+
+        config.id.checkpoint_path = Some(dir.join("ids"));
+        FeatherPD::from_config(&config).unwrap().alloc_id().unwrap();
+        assert!(dir.join("ids").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn scheduled_replicas_are_queued_once_and_dropped_once_added() {
+        let mut config = Config::default();
+        config.region.undersized_policy = UndersizedPolicy::Replicate;
+        let pd = serving(config);
+        add_store(&pd, 1);
+        add_store(&pd, 2);
+        pd.put_region(region(10, 1, vec![1], 1)).unwrap();
+        let scheduled = vec![ScheduleOp::AddReplica { region_id: 10, epoch: 1, store_id: 2 }];
+        assert_eq!(pd.pending_operations().unwrap(), scheduled);
+
+        // Scheduling again, e.g. on resume, doesn't queue the replica twice.
+        pd.set_scheduling_enabled(false).unwrap();
+        pd.set_scheduling_enabled(true).unwrap();
+        assert_eq!(pd.pending_operations().unwrap(), scheduled);
+        assert_eq!(pd.batch_store_heartbeat(&[(1, 1000, 0)]).unwrap(), vec![(1, scheduled)]);
+
+        // Once the replica is added, the operation is done.
+        heartbeat(&pd, region(10, 2, vec![1, 2], 1));
+        assert!(pd.pending_operations().unwrap().is_empty());
+        assert_eq!(pd.batch_store_heartbeat(&[(1, 1000, 0)]).unwrap(), vec![(1, Vec::new())]);
+    }
+
+    #[test]
+    fn operations_made_stale_by_an_epoch_change_are_dropped() {
+        let pd = FeatherPD::new().unwrap();
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        pd.put_region(region(10, 1, vec![1, 2], 1)).unwrap();
+        let op = ScheduleOp::AddReplica { region_id: 10, epoch: 1, store_id: 3 };
+        pd.inject_operation(op.clone(), None).unwrap();
+        pd.inject_operation(op.clone(), None).unwrap();
+        assert_eq!(pd.batch_store_heartbeat(&[(1, 1000, 0)]).unwrap(), vec![(1, vec![op])]);
+
+        heartbeat(&pd, region(10, 2, vec![1, 2], 2));
+        assert_eq!(pd.batch_store_heartbeat(&[(1, 1000, 0)]).unwrap(), vec![(1, Vec::new())]);
+        assert!(pd.pending_operations().unwrap().is_empty());
+    }
+
+    #[test]
+    fn confirmed_leader_transfers_leave_the_queue() {
+        let pd = FeatherPD::new().unwrap();
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        pd.put_region(region(10, 1, vec![1, 2, 3], 1)).unwrap();
+        let op = ScheduleOp::TransferLeader { region_id: 10, epoch: 1, to_store_id: 2 };
+        pd.inject_operation(op.clone(), None).unwrap();
+        assert_eq!(pd.pending_operations().unwrap(), vec![op]);
+        pd.confirm_transfer(10, 2).unwrap();
+        assert!(pd.pending_operations().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replicas_are_only_scheduled_on_stores_placement_would_pick() {
+        let mut config = Config::default();
+        config.region.undersized_policy = UndersizedPolicy::Replicate;
+        config.region.replicas = 3;
+        config.store.max_regions = 1;
+        let pd = serving(config);
+        for id in 1..=5 {
+            add_store(&pd, id);
+        }
+        pd.set_store_state(2, StoreState::Draining, None, None).unwrap();
+        pd.register_store(3, "store-3".to_string(), Labels::new(), Some(0)).unwrap();
+        // Store 4 is at the cap of one region.
+        pd.set_scheduling_enabled(false).unwrap();
+        let split = b"m".to_vec();
+        pd.put_region(
+            RegionInfo::new(20, DEFAULT_KEYSPACE, Vec::new(), split.clone(), 1, vec![4], 4).unwrap(),
+        )
+        .unwrap();
+        pd.put_region(RegionInfo::new(10, DEFAULT_KEYSPACE, split, Vec::new(), 1, vec![1], 1).unwrap())
+            .unwrap();
+        pd.set_scheduling_enabled(true).unwrap();
+
+        let targets: Vec<(u64, u64)> = pd
+            .pending_operations()
+            .unwrap()
+            .into_iter()
+            .filter_map(|op| match op {
+                ScheduleOp::AddReplica { region_id, store_id, .. } => Some((region_id, store_id)),
+                _ => None,
+            })
+            .collect();
+        // Region 20 takes store 5, leaving it at the cap too, so region 10
+        // has nowhere to go.
+        assert_eq!(targets, vec![(20, 5)]);
+    }
+
+    #[test]
+    fn new_regions_need_as_many_live_stores_as_replicas_unless_they_replicate() {
+        for policy in [UndersizedPolicy::Reject, UndersizedPolicy::Replicate] {
+            // With exactly as many live stores as replicas, the region is
+            // taken as it is under either policy.
+            let mut config = Config::default();
+            config.region.replicas = 3;
+            config.region.undersized_policy = policy;
+            let pd = serving(config.clone());
+            for id in 1..=3 {
+                add_store(&pd, id);
+            }
+            pd.put_region(region(10, 1, vec![1], 1)).unwrap();
+            assert!(pd.pending_operations().unwrap().is_empty(), "{:?}", policy);
+
+            // One live store short, it is refused or has its replicas queued.
+            let pd = serving(config);
+            for id in 1..=2 {
+                add_store(&pd, id);
+            }
+            let put = pd.put_region(region(10, 1, vec![1], 1));
+            match policy {
+                UndersizedPolicy::Reject => {
+                    assert!(matches!(put, Err(Error::NoAvailableStores(3))), "{:?}", put);
+                    assert!(pd.get_region(10).is_err());
+                    assert!(pd.pending_operations().unwrap().is_empty());
+                }
+                UndersizedPolicy::Replicate => {
+                    put.unwrap();
+                    assert_eq!(
+                        pd.pending_operations().unwrap(),
+                        vec![ScheduleOp::AddReplica { region_id: 10, epoch: 1, store_id: 2 }]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn replicas_on_removed_or_unknown_stores_are_violations() {
+        let pd = FeatherPD::new().unwrap();
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        pd.put_region(region(10, 1, vec![1, 2, 4], 1)).unwrap();
+        pd.set_store_state(2, StoreState::Down, None, None).unwrap();
+        pd.set_store_state(2, StoreState::Removed, None, None).unwrap();
+        assert_eq!(
+            pd.verify_consistency().unwrap().violations,
+            vec![
+                Violation::RemovedStore { region_id: 10, store_id: 2 },
+                Violation::UnknownStore { region_id: 10, store_id: 4 },
+            ]
+        );
+    }
+
+    #[test]
+    fn the_initial_region_is_created_once_and_serves_lookups() {
+        let mut config = Config::default();
+        config.cluster.create_initial_region = true;
+        let pd = serving(config);
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        let initial = pd.lookup(DEFAULT_KEYSPACE, b"any key").unwrap();
+        assert_eq!((initial.start_key.as_slice(), initial.end_key.as_slice()), (&b""[..], &b""[..]));
+        assert_eq!(initial.stores.len(), 3);
+
+        add_store(&pd, 4);
+        assert_eq!(pd.routing.lock().unwrap().region_count(), 1);
+    }
+
+    #[test]
+    fn a_restarted_leader_doesnt_create_the_initial_region_again() {
+        let dir = scratch_dir("bootstrap");
+        let mut config = Config::default();
+        config.cluster.create_initial_region = true;
+        config.tso.checkpoint_path = Some(dir.join("tso"));
+        let pd = serving(config.clone());
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        assert_eq!(pd.routing.lock().unwrap().region_count(), 1);
+        assert!(dir.join("tso.bootstrapped").exists());
+
+        // The restarted leader's routing table is empty until the stores
+        // report their regions.
+        let pd = serving(config);
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        assert_eq!(pd.routing.lock().unwrap().region_count(), 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// A checkpoint store whose writes hang for a while, like a stuck disk.
+    struct SlowCheckpoint(Duration);
+
+    impl CheckpointStore for SlowCheckpoint {
+        fn load(&self) -> Result<Option<Checkpoint>> {
+            Ok(None)
+        }
+
+        fn save(&self, _: &Checkpoint) -> Result<u64> {
+            std::thread::sleep(self.0);
+            Ok(0)
+        }
+    }
+
+    #[tokio::test]
+    async fn a_hung_checkpoint_write_times_the_request_out() {
+        let mut pd = FeatherPD::with_checkpoint(Arc::new(SlowCheckpoint(Duration::from_secs(1))), 10);
+        pd.config.server.default_rpc_timeout_ms = 50;
+        pd.recover().await.unwrap();
+        let path = scratch_dir("slow-tso").join("pd.sock");
+        let addr = Address::Unix(path);
+        let server = spawn_serve(pd, &addr, std::future::pending()).unwrap();
+        let mut client = connect(&addr, &ClientConfig::default()).await.unwrap();
+
+        // The single-threaded test runtime would be stuck in the write if the
+        // handler ran it inline, and couldn't time the request out.
+        let start = std::time::Instant::now();
+        let status = client.get_timestamp(TsoRequest { count: 1, ..Default::default() }).await.unwrap_err();
+        assert_eq!(Error::from(status), Error::Timeout);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        server.abort();
+    }
+
+    #[test]
+    fn the_first_timestamp_is_configurable() {
+        let mut config = Config::default();
+        let first_three = |pd: FeatherPD| (0..3).map(|_| pd.get_next_ts().unwrap()).collect::<Vec<u64>>();
+        assert_eq!(first_three(serving(config.clone())), vec![1, 2, 3]);
+
+        config.tso.first_ts = 0;
+        assert_eq!(first_three(serving(config.clone())), vec![0, 1, 2]);
+
+        config.tso.first_ts = 2;
+        assert!(matches!(FeatherPD::from_config(&config), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn put_regions_are_pushed_to_the_subscribers_covering_them() {
+        let pd = FeatherPD::new().unwrap();
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        let mut covering = pd.watch_topology(DEFAULT_KEYSPACE, b"a".to_vec(), b"m".to_vec()).unwrap();
+        let mut disjoint = pd.watch_topology(DEFAULT_KEYSPACE, b"x".to_vec(), Vec::new()).unwrap();
+        let mut other_keyspace = pd.watch_topology(DEFAULT_KEYSPACE + 1, Vec::new(), Vec::new()).unwrap();
+
+        let region =
+            RegionInfo::new(10, DEFAULT_KEYSPACE, Vec::new(), b"c".to_vec(), 1, vec![1, 2, 3], 1).unwrap();
+        pd.put_region(region.clone()).unwrap();
+        assert_eq!(covering.try_recv().unwrap(), TopologyEvent::RegionChanged(region));
+        assert!(covering.try_recv().is_err());
+        assert!(disjoint.try_recv().is_err());
+        assert!(other_keyspace.try_recv().is_err());
+    }
+
+    #[test]
+    fn slow_subscribers_are_told_to_resync_and_dropped() {
+        let pd = FeatherPD::new().unwrap();
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        let mut slow = pd.watch_topology(DEFAULT_KEYSPACE, Vec::new(), Vec::new()).unwrap();
+        let mut fast = pd.watch_topology(DEFAULT_KEYSPACE, Vec::new(), Vec::new()).unwrap();
+        for epoch in 1..=WATCH_CHANNEL_CAPACITY as u64 {
+            pd.put_region(region(10, epoch, vec![1, 2, 3], 1)).unwrap();
+            assert_eq!(
+                fast.try_recv().unwrap(),
+                TopologyEvent::RegionChanged(region(10, epoch, vec![1, 2, 3], 1))
+            );
+        }
+
+        // The last slot is kept for the resync event, after which the
+        // subscription ends.
+        for epoch in 1..WATCH_CHANNEL_CAPACITY as u64 {
+            assert_eq!(
+                slow.try_recv().unwrap(),
+                TopologyEvent::RegionChanged(region(10, epoch, vec![1, 2, 3], 1))
+            );
+        }
+        assert_eq!(slow.try_recv().unwrap(), TopologyEvent::Resync);
+        assert_eq!(slow.try_recv(), Err(mpsc::error::TryRecvError::Disconnected));
+        pd.put_region(region(10, WATCH_CHANNEL_CAPACITY as u64 + 1, vec![1, 2, 3], 1)).unwrap();
+        assert!(fast.try_recv().is_ok());
+        assert_eq!(slow.try_recv(), Err(mpsc::error::TryRecvError::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn leader_transfers_flip_the_leader_once_confirmed() {
+        let pd = FeatherPD::new().unwrap();
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        pd.put_region(region(10, 1, vec![1, 2, 3], 1)).unwrap();
+        let locate = || async {
+            let request = Request::new(DataLocRequest { key: b"k".to_vec(), ..Default::default() });
+            let reply = PlacementDriver::get_data_location(&pd, request).await.unwrap().into_inner();
+            let regions: Vec<RegionInfo> = bincode::deserialize(&reply.regions).unwrap();
+            (regions[0].leader, regions[0].epoch, reply.transferring)
+        };
+
+        let op = ScheduleOp::TransferLeader { region_id: 10, epoch: 1, to_store_id: 2 };
+        pd.inject_operation(op, None).unwrap();
+        assert_eq!(locate().await, (1, 1, true));
+
+        // Only the new leader's store can confirm.
+        assert!(matches!(pd.confirm_transfer(10, 3), Err(Error::Value(_))));
+        let region = pd.confirm_transfer(10, 2).unwrap();
+        assert_eq!((region.leader, region.epoch), (2, 2));
+        assert_eq!(locate().await, (2, 2, false));
+    }
+
+    #[test]
+    fn unconfirmed_leader_transfers_time_out() {
+        let clock = Arc::new(ManualClock::new(4_000_000_000_000));
+        let pd = FeatherPD::new().unwrap().with_clock(clock.clone());
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        pd.put_region(region(10, 1, vec![1, 2, 3], 1)).unwrap();
+        let op = ScheduleOp::TransferLeader { region_id: 10, epoch: 1, to_store_id: 2 };
+        pd.inject_operation(op, None).unwrap();
+        assert!(pd.is_transferring(10).unwrap());
+
+        clock.advance(pd.config.region.transfer_timeout_ms + 1);
+        assert!(!pd.is_transferring(10).unwrap());
+        assert!(matches!(pd.confirm_transfer(10, 2), Err(Error::Value(_))));
+        let region = pd.routing.lock().unwrap().get_region(10).unwrap();
+        assert_eq!((region.leader, region.epoch), (1, 1));
+    }
+
+    #[test]
+    fn followers_reject_snapshots_of_another_schema_version() {
+        let leader = FeatherPD::new().unwrap();
+        for id in 1..=3 {
+            add_store(&leader, id);
+        }
+        leader.put_region(region(10, 1, vec![1, 2, 3], 1)).unwrap();
+        let (version, mut bytes) = leader.snapshot().unwrap();
+
+        let follower = FeatherPD::new().unwrap().with_role(Role::ReadOnlyFollower);
+        bytes[4..6].copy_from_slice(&(snapshot::SNAPSHOT_VERSION - 1).to_be_bytes());
+        assert!(matches!(follower.apply_snapshot(version, &bytes), Err(Error::Internal(_))));
+        assert_eq!(follower.routing.lock().unwrap().region_count(), 0);
+
+        bytes[4..6].copy_from_slice(&snapshot::SNAPSHOT_VERSION.to_be_bytes());
+        follower.apply_snapshot(version, &bytes).unwrap();
+        assert_eq!(follower.routing.lock().unwrap().get_region(10).unwrap(), region(10, 1, vec![1, 2, 3], 1));
+    }
+
+    #[test]
+    fn isolated_keyspace_tsos_are_independent_and_recovered() {
+        let dir = scratch_dir("keyspace-tso");
+        let mut config = Config::default();
+        config.tso.checkpoint_path = Some(dir.join("tso"));
+        config.tso.window_size = 10;
+        let pd = serving(config.clone());
+        pd.create_keyspace(1, true, None).unwrap();
+        pd.create_keyspace(2, true, None).unwrap();
+        pd.create_keyspace(3, false, None).unwrap();
+
+        // Each isolated keyspace counts on its own, while the others share
+        // the global TSO.
+        let (first, _) = pd.get_keyspace_ts_batch(1, 25, false).unwrap();
+        assert_eq!(pd.get_keyspace_ts_batch(2, 1, false).unwrap(), (first, 1));
+        assert_eq!(pd.get_next_ts().unwrap(), first);
+        assert_eq!(pd.get_keyspace_ts_batch(3, 1, false).unwrap(), (first + 1, 1));
+        assert_eq!(pd.get_keyspace_ts_batch(1, 1, false).unwrap(), (first + 25, 1));
+
+        // Their watermarks are persisted to separate files next to the
+        // global one.
+        let watermark = |name: &str| FileCheckpoint::new(dir.join(name)).load().unwrap().unwrap().window_end;
+        let watermarks = (watermark("tso.keyspace-1"), watermark("tso.keyspace-2"));
+        assert!(watermarks.0 > first + 25 && watermarks.1 > first && watermarks.1 < watermarks.0);
+        assert!(!dir.join("tso.keyspace-3").exists());
+
+        // A restarted server resumes each keyspace above its own watermark.
+        pd.shutdown().unwrap();
+        let pd = serving(config);
+        pd.create_keyspace(1, true, None).unwrap();
+        pd.create_keyspace(2, true, None).unwrap();
+        assert_eq!(pd.get_keyspace_ts_batch(1, 1, false).unwrap(), (watermarks.0, 1));
+        assert_eq!(pd.get_keyspace_ts_batch(2, 1, false).unwrap(), (watermarks.1, 1));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn stream_replies_come_in_order_one_per_request_until_the_first_error() {
+        let pd = serving(Config::default());
+        let addr = Address::Unix(scratch_dir("allocate-stream").join("pd.sock"));
+        let server = spawn_serve(pd, &addr, std::future::pending()).unwrap();
+        let mut client = connect(&addr, &ClientConfig::default()).await.unwrap();
+
+        // The zero count is invalid, so the stream ends with its error and
+        // the request after it is never served.
+        let counts = [1, 3, 2, 0, 1];
+        let requests = counts.map(|count| TsoRequest { count, ..Default::default() });
+        let mut replies = client.allocate_stream(tokio_stream::iter(requests)).await.unwrap().into_inner();
+        let mut next_ts = None;
+        for count in &counts[..3] {
+            let reply = replies.message().await.unwrap().unwrap();
+            assert_eq!(reply.count, *count);
+            if let Some(next_ts) = next_ts {
+                assert_eq!(reply.timestamp, next_ts);
+            }
+            next_ts = Some(reply.timestamp + *count as u64);
+        }
+        let err = Error::from(replies.message().await.unwrap_err());
+        assert!(matches!(err, Error::Value(_)), "{:?}", err);
+        assert!(replies.message().await.unwrap().is_none());
+        server.abort();
+    }
+
+    #[test]
+    fn admin_operations_are_aborted_if_the_topology_changed_since_it_was_read() {
+        let pd = FeatherPD::new().unwrap();
+        for id in 1..=4 {
+            add_store(&pd, id);
+        }
+        let version = pd.topology_version().unwrap();
+        pd.set_store_state(4, StoreState::Draining, None, Some(version)).unwrap();
+        assert!(pd.topology_version().unwrap() > version);
+
+        // A second admin read the topology before the drain.
+        assert_eq!(pd.set_store_state(3, StoreState::Draining, None, Some(version)), Err(Error::Abort));
+        assert_eq!(pd.create_keyspace(1, false, Some(version)), Err(Error::Abort));
+        assert_eq!(pd.stores.lock().unwrap().get(3).unwrap().state, StoreState::Up);
+        assert_eq!(pd.routing.lock().unwrap().keyspace_ids(), vec![DEFAULT_KEYSPACE]);
+
+        // Without an expected version, the operation goes through.
+        pd.set_store_state(3, StoreState::Draining, None, None).unwrap();
+    }
+
+    #[test]
+    fn only_one_of_concurrent_modifications_at_the_same_version_wins() {
+        let pd = FeatherPD::new().unwrap();
+        let version = pd.topology_version().unwrap();
+        let barrier = Arc::new(std::sync::Barrier::new(8));
+        let threads: Vec<_> = (1..=8)
+            .map(|keyspace_id| {
+                let (pd, barrier) = (pd.clone(), barrier.clone());
+                std::thread::spawn(move || {
+                    barrier.wait();
+                    pd.create_keyspace(keyspace_id, false, Some(version))
+                })
+            })
+            .collect();
+        let results: Vec<Result<()>> = threads.into_iter().map(|thread| thread.join().unwrap()).collect();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(results.iter().all(|result| matches!(result, Ok(()) | Err(Error::Abort))));
+        assert_eq!(pd.routing.lock().unwrap().keyspace_ids().len(), 2);
+    }
+
+    #[test]
+    fn keyspace_prefixes_are_stripped_from_lookups_and_added_back_to_replies() {
+        let mut config = Config::default();
+        let keyspace =
+            KeyspaceConfig { id: DEFAULT_KEYSPACE, strip_prefix: "t1/".into(), ..Default::default() };
+        config.keyspaces = vec![keyspace];
+        let pd = serving(config);
+        let bounded = |id, start_key: &[u8], end_key: &[u8]| {
+            RegionInfo::new(id, DEFAULT_KEYSPACE, start_key.to_vec(), end_key.to_vec(), 1, vec![1], 1)
+                .unwrap()
+        };
+        let mut routing = pd.routing.lock().unwrap();
+        routing.put_region(bounded(1, b"", b"m")).unwrap();
+        routing.put_region(bounded(2, b"m", b"")).unwrap();
+        drop(routing);
+
+        let bounds = |region: RegionInfo| (region.id, region.start_key, region.end_key);
+        assert_eq!(bounds(pd.lookup(DEFAULT_KEYSPACE, b"t1/a").unwrap()), (1, Vec::new(), b"t1/m".to_vec()));
+        assert_eq!(bounds(pd.lookup(DEFAULT_KEYSPACE, b"t1/m").unwrap()), (2, b"t1/m".to_vec(), Vec::new()));
+        assert_eq!(pd.lookup(DEFAULT_KEYSPACE, b"t1/").unwrap().id, 1);
+
+        let (regions, next_start_key) = pd.scan(DEFAULT_KEYSPACE, b"t1/", b"", 1).unwrap();
+        assert_eq!(
+            regions.into_iter().map(bounds).collect::<Vec<_>>(),
+            vec![(1, Vec::new(), b"t1/m".to_vec())]
+        );
+        assert_eq!(next_start_key, b"t1/m".to_vec());
+        let (regions, next_start_key) = pd.scan(DEFAULT_KEYSPACE, &next_start_key, b"", 1).unwrap();
+        assert_eq!(
+            regions.into_iter().map(bounds).collect::<Vec<_>>(),
+            vec![(2, b"t1/m".to_vec(), Vec::new())]
+        );
+        assert!(next_start_key.is_empty());
+    }
+
+    #[test]
+    fn keys_without_the_keyspace_prefix_are_rejected() {
+        let mut config = Config::default();
+        let keyspace =
+            KeyspaceConfig { id: DEFAULT_KEYSPACE, strip_prefix: "t1/".into(), ..Default::default() };
+        config.keyspaces = vec![keyspace.clone()];
+        let pd = serving(config.clone());
+        pd.routing.lock().unwrap().put_region(region(1, 1, vec![1], 1)).unwrap();
+
+        assert!(matches!(pd.lookup(DEFAULT_KEYSPACE, b"t2/a"), Err(Error::Value(_))));
+        assert!(matches!(pd.lookup(DEFAULT_KEYSPACE, b"a"), Err(Error::Value(_))));
+        assert!(matches!(pd.scan(DEFAULT_KEYSPACE, b"t2/a", b"", 10), Err(Error::Value(_))));
+        assert!(matches!(pd.scan(DEFAULT_KEYSPACE, b"t1/a", b"t2/", 10), Err(Error::Value(_))));
+
+        config.keyspaces.push(keyspace);
+        assert!(matches!(FeatherPD::from_config(&config), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn snapshots_with_conflicting_regions_are_rejected() {
+        let bounded = |id, start_key: &[u8], end_key: &[u8]| {
+            RegionInfo::new(id, DEFAULT_KEYSPACE, start_key.to_vec(), end_key.to_vec(), 1, vec![1], 1)
+                .unwrap()
+        };
+        let encode = |regions: Vec<RegionInfo>| {
+            snapshot::encode(&Snapshot { keyspaces: vec![DEFAULT_KEYSPACE], regions, stores: Vec::new() })
+                .unwrap()
+        };
+        let follower = FeatherPD::new().unwrap().with_role(Role::ReadOnlyFollower);
+        follower.apply_snapshot(1, &encode(vec![bounded(1, b"", b"")])).unwrap();
+
+        let conflicting = encode(vec![
+            bounded(2, b"m", b""),
+            // A duplicate id.
+            bounded(2, b"x", b"y"),
+            // Starts inside region 2.
+            bounded(3, b"p", b"q"),
+            // Starts before region 2, but ends inside it.
+            bounded(4, b"a", b"n"),
+        ]);
+        let message = match follower.apply_snapshot(2, &conflicting) {
+            Err(Error::Internal(message)) => message,
+            result => panic!("unexpected result {:?}", result),
+        };
+        assert!(message.contains("duplicate region id 2"), "{}", message);
+        assert!(message.contains("regions 2 and 3 overlap"), "{}", message);
+        assert!(message.contains("regions 4 and 2 overlap"), "{}", message);
+
+        // The live state is untouched.
+        let routing = follower.routing.lock().unwrap();
+        assert_eq!(routing.region_count(), 1);
+        assert_eq!(routing.get_region(1).unwrap(), bounded(1, b"", b""));
+    }
+
+    #[test]
+    fn undrainable_stores_are_reported_as_stalled_past_the_drain_timeout() {
+        let clock = Arc::new(ManualClock::new(4_000_000_000_000));
+        let mut config = Config::default();
+        config.store.drain_timeout_ms = 1_000;
+        let pd = serving(config).with_clock(clock.clone());
+        for id in 1..=4 {
+            add_store(&pd, id);
+        }
+        // With 3 replicas on 3 stores, the replica on store 3 has nowhere to
+        // go once store 4 drains too. Store 4 holds no replicas.
+        pd.put_region(region(10, 1, vec![1, 2, 3], 1)).unwrap();
+        pd.set_store_state(3, StoreState::Draining, None, None).unwrap();
+        pd.set_store_state(4, StoreState::Draining, None, None).unwrap();
+
+        clock.advance(999);
+        pd.store_heartbeat(3, 1000, 0).unwrap();
+        assert!(pd.cluster_status().unwrap().stalled_drains.is_empty());
+
+        clock.advance(1);
+        pd.store_heartbeat(3, 1000, 0).unwrap();
+        pd.store_heartbeat(4, 1000, 0).unwrap();
+        assert_eq!(pd.cluster_status().unwrap().stalled_drains, vec![3]);
+        assert!(!pd.stores.lock().unwrap().report_drain_stall(3));
+
+        // The store keeps draining, and keeps its replica.
+        assert_eq!(pd.stores.lock().unwrap().get(3).unwrap().state, StoreState::Draining);
+        assert_eq!(pd.routing.lock().unwrap().get_region(10).unwrap().stores, vec![1, 2, 3]);
+
+        // Until an operator steps in.
+        pd.set_store_state(3, StoreState::Up, None, None).unwrap();
+        assert!(pd.cluster_status().unwrap().stalled_drains.is_empty());
+    }
+
+    #[test]
+    fn snapshots_round_trip_in_either_persistence_format() {
+        for format in [PersistenceFormat::Bincode, PersistenceFormat::Json] {
+            let mut config = Config::default();
+            config.persistence.format = format;
+            let leader = serving(config);
+            for id in 1..=3 {
+                add_store(&leader, id);
+            }
+            leader.put_region(region(10, 1, vec![1, 2, 3], 1)).unwrap();
+            let (version, bytes) = leader.snapshot().unwrap();
+            let magic = match format {
+                PersistenceFormat::Bincode => snapshot::SNAPSHOT_MAGIC,
+                PersistenceFormat::Json => snapshot::SNAPSHOT_JSON_MAGIC,
+            };
+            assert_eq!(bytes[..4], magic);
+
+            let follower = FeatherPD::new().unwrap().with_role(Role::ReadOnlyFollower);
+            follower.apply_snapshot(version, &bytes).unwrap();
+            let replicated: Snapshot = snapshot::decode(&follower.snapshot().unwrap().1).unwrap();
+            assert_eq!(replicated, snapshot::decode::<Snapshot>(&bytes).unwrap());
+            assert_eq!(replicated.regions, vec![region(10, 1, vec![1, 2, 3], 1)]);
+            assert_eq!(replicated.stores.len(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_below_the_gc_safe_point_are_rejected() {
+        let pd = FeatherPD::new().unwrap();
+        let ts: Vec<u64> = (0..10).map(|_| pd.get_next_ts().unwrap()).collect();
+        assert_eq!(pd.update_gc_safe_point(ts[5]).unwrap(), ts[5]);
+
+        let read = |read_ts| {
+            let request = Request::new(GetGcSafePointRequest { read_ts });
+            async { PlacementDriver::get_gc_safe_point(&pd, request).await.map(|reply| reply.into_inner()) }
+        };
+        let status = read(ts[4]).await.unwrap_err();
+        assert_eq!(Error::from(status), Error::Value("requested timestamp below GC safe point".into()));
+        assert_eq!(read(ts[5]).await.unwrap().safe_point, ts[5]);
+        assert_eq!(read(0).await.unwrap().safe_point, ts[5]);
+        assert!(pd.check_read_ts(ts[9]).is_ok());
+    }
+
+    #[test]
+    fn the_gc_safe_point_only_moves_forward_and_stays_below_the_next_timestamp() {
+        let pd = FeatherPD::new().unwrap();
+        let ts: Vec<u64> = (0..10).map(|_| pd.get_next_ts().unwrap()).collect();
+        pd.update_gc_safe_point(ts[5]).unwrap();
+        assert_eq!(pd.update_gc_safe_point(ts[2]).unwrap(), ts[5]);
+        let next_ts = pd.next_ts().unwrap();
+        assert!(matches!(pd.update_gc_safe_point(next_ts), Err(Error::Value(_))));
+        assert_eq!(pd.gc_safe_point().unwrap(), ts[5]);
+    }
+
+    #[test]
+    fn placements_are_reproducible_with_a_seed() {
+        let placements = |seed| {
+            let mut config = Config::default();
+            config.placement.seed = Some(seed);
+            let pd = serving(config);
+            for id in 1..=6 {
+                add_store(&pd, id);
+            }
+            let mut placements = Vec::new();
+            for _ in 0..10 {
+                let (stores, leader) = pd.place_region().unwrap();
+                pd.put_region(region(pd.alloc_id().unwrap(), 1, stores.clone(), leader)).unwrap();
+                placements.push((stores, leader));
+            }
+            placements
+        };
+        assert_eq!(placements(42), placements(42));
+        assert_ne!(placements(42), placements(43));
+    }
+
+    #[tokio::test]
+    async fn approximate_key_counts_propagate_from_heartbeats_to_lookups() {
+        let pd = FeatherPD::new().unwrap();
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        let report = |approximate_keys| {
+            let request = RegionHeartbeatRequest {
+                region: bincode::serialize(&region(10, 1, vec![1, 2, 3], 1)).unwrap(),
+                approximate_keys,
+                ..Default::default()
+            };
+            async { PlacementDriver::region_heartbeat(&pd, Request::new(request)).await.unwrap() }
+        };
+        let located = || async {
+            let request = Request::new(DataLocRequest { key: b"k".to_vec(), ..Default::default() });
+            let reply = PlacementDriver::get_data_location(&pd, request).await.unwrap().into_inner();
+            bincode::deserialize::<Vec<RegionInfo>>(&reply.regions).unwrap()[0].approximate_keys
+        };
+        let by_id = || async {
+            let request = Request::new(GetRegionByIdRequest { region_id: 10, epoch: 0 });
+            let reply = PlacementDriver::get_region_by_id(&pd, request).await.unwrap().into_inner();
+            bincode::deserialize::<RegionInfo>(&reply.region).unwrap().approximate_keys
+        };
+
+        report(42).await;
+        assert_eq!((located().await, by_id().await), (42, 42));
+        report(1_000).await;
+        assert_eq!((located().await, by_id().await), (1_000, 1_000));
+    }
+
+    #[test]
+    fn timestamps_are_refused_once_the_lease_expires_until_it_is_renewed() {
+        let clock = Arc::new(ManualClock::new(4_000_000_000_000));
+        let mut config = Config::default();
+        config.tso.lease_ms = 3_000;
+        let pd = FeatherPD::from_config(&config).unwrap().with_clock(clock.clone());
+        pd.finish_recovery(None).unwrap();
+        pd.get_next_ts().unwrap();
+
+        clock.advance(config.tso.lease_ms - 1);
+        let last = pd.get_next_ts().unwrap();
+        clock.advance(1);
+        assert_eq!(pd.get_next_ts(), Err(Error::NotLeader));
+        assert_eq!(pd.get_next_ts(), Err(Error::NotLeader));
+
+        // Renewing extends the lease from now, and serving resumes where it
+        // stopped.
+        assert_eq!(pd.renew_lease().unwrap(), clock.now_ms() + config.tso.lease_ms);
+        assert_eq!(pd.get_next_ts().unwrap(), last + 1);
+
+        // Followers have no lease to renew.
+        let follower = FeatherPD::from_config(&config).unwrap().with_role(Role::ReadOnlyFollower);
+        assert_eq!(follower.renew_lease(), Err(Error::NotLeader));
+    }
+
+    #[test]
+    fn replicas_behind_the_change_log_fall_back_to_a_full_snapshot() {
+        let mut config = Config::default();
+        config.changelog.max_entries = 3;
+        let leader = serving(config);
+        let follower = FeatherPD::new().unwrap().with_role(Role::ReadOnlyFollower);
+        let (version, bytes) = leader.snapshot().unwrap();
+        follower.apply_snapshot(version, &bytes).unwrap();
+
+        for keyspace_id in 1..=5 {
+            leader.create_keyspace(keyspace_id, false, None).unwrap();
+        }
+        assert_eq!(leader.cluster_status().unwrap().change_log_entries, 3);
+        assert_eq!(leader.snapshot_since(version), Err(Error::SnapshotRequired));
+        assert!(leader.snapshot_since(leader.topology_version().unwrap() - 3).is_ok());
+
+        let (version, bytes) = leader.snapshot().unwrap();
+        follower.apply_snapshot(version, &bytes).unwrap();
+        assert_eq!(follower.routing.lock().unwrap().keyspace_ids(), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn oversized_regions_get_split_keys_in_the_heartbeat_reply() {
+        let mut config = Config::default();
+        config.region.max_size = 100;
+        let pd = serving(config);
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        let report = |approximate_size, sampled_keys| {
+            let request = RegionHeartbeatRequest {
+                region: bincode::serialize(&region(10, 1, vec![1, 2, 3], 1)).unwrap(),
+                approximate_size,
+                sampled_keys,
+                ..Default::default()
+            };
+            async {
+                PlacementDriver::region_heartbeat(&pd, Request::new(request)).await.unwrap().into_inner()
+            }
+        };
+        let samples: Vec<Vec<u8>> = (0..100).map(|i| format!("k{:03}", i).into_bytes()).collect();
+
+        assert!(report(100, samples.clone()).await.split_keys.is_empty());
+        let split_keys = report(300, samples.clone()).await.split_keys;
+        assert_eq!(split_keys, vec![b"k033".to_vec(), b"k066".to_vec()]);
+        // Too few samples defer the split.
+        assert!(report(300, samples[..1].to_vec()).await.split_keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn data_locations_are_served_at_the_requested_consistency() {
+        use crate::proto::placement_driver::ConsistencyLevel as Level;
+        let locate = |pd: FeatherPD, level: Level, max_staleness_ms| async move {
+            let request = DataLocRequest {
+                key: b"k".to_vec(),
+                consistency: level as i32,
+                max_staleness_ms,
+                ..Default::default()
+            };
+            let reply = PlacementDriver::get_data_location(&pd, Request::new(request)).await;
+            reply.map(|reply| reply.into_inner().staleness_ms).map_err(Error::from)
+        };
+
+        let leader = FeatherPD::new().unwrap();
+        for id in 1..=3 {
+            add_store(&leader, id);
+        }
+        leader.put_region(region(10, 1, vec![1, 2, 3], 1)).unwrap();
+        for level in [Level::Strong, Level::BoundedStaleness, Level::Eventual] {
+            assert_eq!(locate(leader.clone(), level, 0).await, Ok(0));
+        }
+
+        // A follower never serves strong reads, and can't bound the staleness
+        // of a routing table it never synced.
+        let clock = Arc::new(ManualClock::new(4_000_000_000_000));
+        let follower = FeatherPD::new().unwrap().with_role(Role::ReadOnlyFollower).with_clock(clock.clone());
+        assert_eq!(locate(follower.clone(), Level::Strong, 0).await, Err(Error::NotLeader));
+        let never_synced = locate(follower.clone(), Level::BoundedStaleness, 1_000).await;
+        assert!(matches!(never_synced, Err(Error::Unavailable(_))));
+
+        let (version, bytes) = leader.snapshot().unwrap();
+        follower.apply_snapshot(version, &bytes).unwrap();
+        clock.advance(500);
+        assert_eq!(locate(follower.clone(), Level::Strong, 0).await, Err(Error::NotLeader));
+        assert_eq!(locate(follower.clone(), Level::BoundedStaleness, 1_000).await, Ok(500));
+        assert_eq!(locate(follower.clone(), Level::Eventual, 0).await, Ok(500));
+
+        clock.advance(1_000);
+        let too_stale = locate(follower.clone(), Level::BoundedStaleness, 1_000).await;
+        assert!(matches!(too_stale, Err(Error::Unavailable(_))));
+        assert_eq!(locate(follower, Level::Eventual, 0).await, Ok(1_500));
+    }
+
+    /// A checkpoint store which can be made read-only, like a disk that fills
+    /// up mid-run.
+    #[derive(Default)]
+    struct ReadOnlyCheckpoint {
+        read_only: std::sync::atomic::AtomicBool,
+        inner: MemoryCheckpoint,
+    }
+
+    impl CheckpointStore for ReadOnlyCheckpoint {
+        fn load(&self) -> Result<Option<Checkpoint>> {
+            self.inner.load()
+        }
+
+        fn save(&self, checkpoint: &Checkpoint) -> Result<u64> {
+            if self.read_only() {
+                return Err(Error::PersistenceReadOnly("read-only".into()));
+            }
+            self.inner.save(checkpoint)
+        }
+
+        fn read_only(&self) -> bool {
+            self.read_only.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn timestamps_stop_when_the_checkpoint_turns_read_only() {
+        let store = Arc::new(ReadOnlyCheckpoint::default());
+        let pd = FeatherPD::with_checkpoint(store.clone(), 10);
+        pd.recover().await.unwrap();
+        let last = pd.get_next_ts().unwrap();
+        assert!(!pd.cluster_status().unwrap().persistence_read_only);
+
+        // The current window is still persisted, but the next one can't be.
+        store.read_only.store(true, std::sync::atomic::Ordering::SeqCst);
+        let remaining = pd.tso.lock().unwrap().window_remaining();
+        let mut served = vec![last];
+        let err = loop {
+            match pd.get_next_ts() {
+                Ok(ts) => served.push(ts),
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, Error::PersistenceReadOnly(_)), "{:?}", err);
+        assert_eq!(served.len() as u64, remaining + 1, "{:?}", served);
+        assert!(served.windows(2).all(|w| w[0] < w[1]), "{:?}", served);
+        assert!(pd.cluster_status().unwrap().persistence_read_only);
+        assert_eq!(tonic::Status::from(err).code(), tonic::Code::Unavailable);
+    }
+
+    #[tokio::test]
+    async fn the_consistent_hash_resolver_follows_store_changes() {
+        use crate::config::ResolverKind;
+        let locate = |pd: FeatherPD| async move {
+            let request = DataLocRequest { key: b"k".to_vec(), ..Default::default() };
+            let reply =
+                PlacementDriver::get_data_location(&pd, Request::new(request)).await.map_err(Error::from)?;
+            let regions: Vec<RegionInfo> = bincode::deserialize(&reply.into_inner().regions)?;
+            let mut stores = regions[0].stores.clone();
+            stores.sort();
+            Ok::<_, Error>(stores)
+        };
+
+        let mut config = Config::default();
+        config.resolver.kind = ResolverKind::ConsistentHash;
+        let pd = serving(config.clone());
+        assert_eq!(locate(pd.clone()).await, Err(Error::NoAvailableStores(3)));
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        assert_eq!(locate(pd.clone()).await, Ok(vec![1, 2, 3]));
+        add_store(&pd, 4);
+        pd.set_store_state(2, StoreState::Down, None, None).unwrap();
+        assert_eq!(locate(pd.clone()).await, Ok(vec![1, 3, 4]));
+
+        // A follower builds the same ring from the replicated stores.
+        let follower = FeatherPD::from_config(&config).unwrap().with_role(Role::ReadOnlyFollower);
+        let (version, bytes) = pd.snapshot().unwrap();
+        follower.apply_snapshot(version, &bytes).unwrap();
+        assert_eq!(locate(follower).await, Ok(vec![1, 3, 4]));
+
+        config.resolver.virtual_nodes = 0;
+        assert!(matches!(FeatherPD::from_config(&config), Err(Error::Config(_))));
+    }
+
+    /// A resolver counting its lookups, which take a while like a slow
+    /// external service.
+    #[derive(Default)]
+    struct CountingResolver {
+        lookups: std::sync::atomic::AtomicUsize,
+    }
+
+    #[tonic::async_trait]
+    impl LocationResolver for CountingResolver {
+        async fn resolve(&self, keyspace_id: u32, key: &[u8]) -> Result<DataLocReply> {
+            self.lookups.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let region = RegionInfo::new(1, keyspace_id, key.to_vec(), Vec::new(), 1, vec![1], 1)?;
+            Ok(DataLocReply { regions: bincode::serialize(&vec![region])?, ..Default::default() })
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_identical_lookups_are_coalesced() {
+        let resolver = Arc::new(CountingResolver::default());
+        let pd = FeatherPD::new().unwrap().with_location_resolver(resolver.clone());
+        let lookup = |key: &[u8]| {
+            let (pd, key) = (pd.clone(), key.to_vec());
+            tokio::spawn(async move {
+                let request = DataLocRequest { key, ..Default::default() };
+                PlacementDriver::get_data_location(&pd, Request::new(request)).await.unwrap().into_inner()
+            })
+        };
+
+        let lookups: Vec<_> = (0..20).map(|_| lookup(b"hot")).collect();
+        let other = lookup(b"cold");
+        let mut replies = Vec::new();
+        for lookup in lookups {
+            replies.push(lookup.await.unwrap());
+        }
+        other.await.unwrap();
+        assert!(replies.iter().all(|reply| *reply == replies[0]));
+        assert_eq!(resolver.lookups.load(std::sync::atomic::Ordering::SeqCst), 2);
+
+        // A later lookup isn't served from a cache.
+        lookup(b"hot").await.unwrap();
+        assert_eq!(resolver.lookups.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn cluster_status_reports_tso_window_refills() {
+        let mut config = Config::default();
+        config.tso.window_size = 10;
+        let pd = serving(config);
+        pd.get_next_ts().unwrap();
+
+        // Running out the window doesn't refill it, the next timestamp does.
+        let refills = pd.cluster_status().unwrap().tso_window_refills;
+        for remaining in (0..pd.cluster_status().unwrap().tso_window_remaining).rev() {
+            pd.get_next_ts().unwrap();
+            let status = pd.cluster_status().unwrap();
+            assert_eq!((status.tso_window_refills, status.tso_window_remaining), (refills, remaining));
+        }
+        pd.get_next_ts().unwrap();
+        let status = pd.cluster_status().unwrap();
+        assert_eq!((status.tso_window_refills, status.tso_window_remaining), (refills + 1, 10));
+    }
+
+    #[test]
+    fn the_window_refill_counter_increments_once_per_refill() {
+        let mut config = Config::default();
+        config.tso.window_size = 10;
+        let pd = serving(config);
+        let start = pd.metrics().unwrap().tso_window_refills_total;
+
+        // The counter moves only when a timestamp finds the window used up.
+        for _ in 0..25 {
+            let before = pd.metrics().unwrap();
+            pd.get_next_ts().unwrap();
+            let after = pd.metrics().unwrap();
+            let refilled = before.tso_window_remaining == 0;
+            assert_eq!(after.tso_window_refills_total, before.tso_window_refills_total + refilled as u64);
+            assert_eq!(after, MetricsSnapshot::from(&pd.cluster_status().unwrap()));
+        }
+        let refills = pd.metrics().unwrap().tso_window_refills_total;
+        assert!(refills >= start + 2);
+        let text = pd.metrics().unwrap().to_prometheus();
+        assert!(text.contains(&format!("featherpd_tso_window_refills_total {}\n", refills)));
+    }
+
+    #[test]
+    fn a_zero_weight_store_gets_no_new_regions() {
+        let pd = FeatherPD::new().unwrap();
+        for id in 1..=4 {
+            add_store(&pd, id);
+        }
+        pd.set_store_state(2, StoreState::Up, Some(0), None).unwrap();
+        for _ in 0..20 {
+            let (stores, leader) = pd.place_region().unwrap();
+            assert!(!stores.contains(&2), "{:?}", stores);
+            pd.put_region(region(pd.alloc_id().unwrap(), 1, stores, leader)).unwrap();
+        }
+        let status = pd.cluster_status().unwrap();
+        assert_eq!(status.store_weights, vec![PinnedStoreWeight { store_id: 2, weight: 0 }]);
+
+        // Re-registering keeps the pinned weight, and invalid weights change
+        // nothing.
+        pd.register_store(2, "store-2".into(), Labels::new(), None).unwrap();
+        let result = pd.set_store_state(2, StoreState::Draining, Some(101), None);
+        assert!(matches!(result, Err(Error::Value(_))));
+        let result = pd.register_store(2, "store-2".into(), Labels::new(), Some(101));
+        assert!(matches!(result, Err(Error::Value(_))));
+        let store = pd.stores.lock().unwrap().get(2).unwrap();
+        assert_eq!((store.state, store.weight), (StoreState::Up, Some(0)));
+
+        // Pinned at the maximum weight, the store takes new regions again,
+        // ahead of stores with less free space.
+        for id in [1, 3, 4] {
+            pd.store_heartbeat(id, 1000, 500).unwrap();
+        }
+        pd.set_store_state(2, StoreState::Up, Some(100), None).unwrap();
+        assert_eq!(pd.place_replicas().unwrap()[0], 2);
+    }
+
+    #[tokio::test]
+    async fn no_operations_are_emitted_while_scheduling_is_paused() {
+        let mut config = Config::default();
+        config.region.undersized_policy = UndersizedPolicy::Replicate;
+        config.region.max_size = 100;
+        let pd = serving(config);
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        let request = |enabled| Request::new(SetSchedulingEnabledRequest { enabled });
+        PlacementDriver::set_scheduling_enabled(&pd, request(false)).await.unwrap();
+        assert!(pd.cluster_status().unwrap().scheduling_paused);
+
+        // An undersized, oversized region gets neither replicas nor splits.
+        let mut undersized = region(10, 1, vec![1], 1);
+        undersized.approximate_size = 300;
+        pd.put_region(undersized.clone()).unwrap();
+        let samples: Vec<Vec<u8>> = (0..100).map(|i| format!("k{:03}", i).into_bytes()).collect();
+        let advise = || pd.region_heartbeat(undersized.clone(), 0, 0, Vec::new(), 0, samples.clone());
+        assert!(advise().unwrap().split_keys.is_empty());
+        assert!(pd.pending_operations().unwrap().is_empty());
+
+        // Injected operations are queued but not handed out, while
+        // heartbeats still update the stores.
+        let op = ScheduleOp::AddReplica { region_id: 10, epoch: 1, store_id: 2 };
+        pd.inject_operation(op.clone(), None).unwrap();
+        assert_eq!(pd.pending_operations().unwrap(), vec![op.clone()]);
+        let replies = pd.batch_store_heartbeat(&[(1, 1000, 500)]).unwrap();
+        assert_eq!(replies, vec![(1, vec![])]);
+        assert_eq!(pd.stores.lock().unwrap().get(1).unwrap().used, 500);
+
+        // Resuming schedules the missing replica once, and hands out both.
+        PlacementDriver::set_scheduling_enabled(&pd, request(true)).await.unwrap();
+        assert!(!pd.cluster_status().unwrap().scheduling_paused);
+        let ops = pd.batch_store_heartbeat(&[(1, 1000, 500)]).unwrap().remove(0).1;
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0], op);
+        assert!(matches!(ops[1], ScheduleOp::AddReplica { region_id: 10, store_id: 3, .. }), "{:?}", ops);
+        assert_eq!(advise().unwrap().split_keys.len(), 2);
+    }
+
+    /// A resolver failing lookups with a settable error, like one whose
+    /// backing store has a hiccup.
+    #[derive(Default)]
+    struct FlakyResolver {
+        error: Mutex<Option<Error>>,
     }
-}
\ No newline at end of file
+
+    #[tonic::async_trait]
+    impl LocationResolver for FlakyResolver {
+        async fn resolve(&self, keyspace_id: u32, key: &[u8]) -> Result<DataLocReply> {
+            if let Some(error) = self.error.lock()?.clone() {
+                return Err(error);
+            }
+            let region = RegionInfo::new(1, keyspace_id, key.to_vec(), Vec::new(), 1, vec![1], 1)?;
+            Ok(DataLocReply { regions: bincode::serialize(&vec![region])?, ..Default::default() })
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_lookups_fall_back_to_stale_locations_if_enabled() {
+        let locate = |pd: FeatherPD, key: &[u8]| {
+            let request = DataLocRequest { key: key.to_vec(), ..Default::default() };
+            async move {
+                let reply = PlacementDriver::get_data_location(&pd, Request::new(request)).await;
+                reply.map(|reply| reply.into_inner()).map_err(Error::from)
+            }
+        };
+        let server = |serve_stale_on_error| {
+            let mut config = Config::default();
+            config.dataloc.serve_stale_on_error = serve_stale_on_error;
+            let resolver = Arc::new(FlakyResolver::default());
+            let clock = Arc::new(ManualClock::new(4_000_000_000_000));
+            let pd = serving(config).with_location_resolver(resolver.clone()).with_clock(clock.clone());
+            (pd, resolver, clock)
+        };
+
+        let (pd, resolver, clock) = server(true);
+        let fresh = locate(pd.clone(), b"k").await.unwrap();
+        assert!(!fresh.stale);
+        clock.advance(700);
+        *resolver.error.lock().unwrap() = Some(Error::Unavailable("backend hiccup".into()));
+        let stale = locate(pd.clone(), b"k").await.unwrap();
+        assert_eq!(stale, DataLocReply { stale: true, staleness_ms: 700, ..fresh.clone() });
+
+        // Keys never located, and errors that aren't transient, still fail.
+        let never_located = locate(pd.clone(), b"other").await;
+        assert!(matches!(never_located, Err(Error::Unavailable(_))));
+        *resolver.error.lock().unwrap() = Some(Error::NotFound("gone".into()));
+        assert!(matches!(locate(pd, b"k").await, Err(Error::NotFound(_))));
+
+        // Strict by default.
+        let (pd, resolver, _) = server(false);
+        locate(pd.clone(), b"k").await.unwrap();
+        *resolver.error.lock().unwrap() = Some(Error::Unavailable("backend hiccup".into()));
+        assert!(matches!(locate(pd, b"k").await, Err(Error::Unavailable(_))));
+
+        let mut config = Config::default();
+        config.dataloc.serve_stale_on_error = true;
+        config.dataloc.stale_cache_capacity = 0;
+        assert!(matches!(FeatherPD::from_config(&config), Err(Error::Config(_))));
+    }
+
+    #[test]
+    fn concurrent_lookups_never_see_a_region_missing_their_key() {
+        use rand::seq::SliceRandom;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let pd = FeatherPD::new().unwrap();
+        for id in 1..=3 {
+            add_store(&pd, id);
+        }
+        pd.put_region(region(pd.alloc_id().unwrap(), 1, vec![1, 2, 3], 1)).unwrap();
+        let keys: Vec<Vec<u8>> = (0..2_000).map(|i| format!("key{:04}", i).into_bytes()).collect();
+        let done = Arc::new(AtomicBool::new(false));
+
+        let lookups: Vec<_> = (0..4)
+            .map(|_| {
+                let (pd, keys, done) = (pd.clone(), keys.clone(), done.clone());
+                std::thread::spawn(move || {
+                    let mut rng = rand::thread_rng();
+                    let mut count = 0;
+                    while !done.load(Ordering::SeqCst) {
+                        let key = keys.choose(&mut rng).unwrap();
+                        let region = pd.lookup(DEFAULT_KEYSPACE, key).unwrap();
+                        assert!(region.contains(key), "region {:?} doesn't contain {:?}", region, key);
+                        count += 1;
+                    }
+                    count
+                })
+            })
+            .collect();
+
+        let mut split_keys = keys.clone();
+        split_keys.shuffle(&mut rand::thread_rng());
+        for key in &split_keys {
+            let region = pd.lookup(DEFAULT_KEYSPACE, key).unwrap();
+            if region.start_key != *key {
+                pd.split_region(region.id, key, pd.alloc_id().unwrap()).unwrap();
+            }
+        }
+        done.store(true, Ordering::SeqCst);
+        for lookup in lookups {
+            assert!(lookup.join().unwrap() > 0);
+        }
+        assert_eq!(pd.routing.lock().unwrap().region_count(), keys.len() + 1);
+    }
+
+    /// A checkpoint store whose loads wait to be let through, like a slow
+    /// disk, and which holds a watermark from a previous run.
+    struct GatedCheckpoint {
+        gate: Mutex<std::sync::mpsc::Receiver<()>>,
+        inner: MemoryCheckpoint,
+    }
+
+    impl CheckpointStore for GatedCheckpoint {
+        fn load(&self) -> Result<Option<Checkpoint>> {
+            self.gate.lock()?.recv().map_err(|err| Error::Internal(err.to_string()))?;
+            self.inner.load()
+        }
+
+        fn save(&self, checkpoint: &Checkpoint) -> Result<u64> {
+            self.inner.save(checkpoint)
+        }
+    }
+
+    #[tokio::test]
+    async fn timestamps_are_refused_until_the_watermark_is_recovered() {
+        let (open, gate) = std::sync::mpsc::channel();
+        let inner = MemoryCheckpoint::new();
+        inner.save(&Checkpoint { window_end: 500, last_allocated: Some(499) }).unwrap();
+        let store = Arc::new(GatedCheckpoint { gate: Mutex::new(gate), inner });
+        let pd = FeatherPD::with_checkpoint(store, 10);
+        let recovery = tokio::spawn({
+            let pd = pd.clone();
+            async move { pd.recover().await }
+        });
+
+        for _ in 0..3 {
+            assert!(matches!(pd.get_next_ts(), Err(Error::Unavailable(_))));
+            assert_eq!(pd.serving_state().unwrap(), ServingState::Bootstrapping);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        open.send(()).unwrap();
+        recovery.await.unwrap().unwrap();
+        assert_eq!(pd.serving_state().unwrap(), ServingState::Serving);
+        assert!(pd.get_next_ts().unwrap() >= 500);
+    }
+
+    #[tokio::test]
+    async fn a_replayed_admin_request_is_rejected() {
+        let pd = FeatherPD::new().unwrap().with_admin_token("secret");
+        let request = || {
+            let mut request = Request::new(AdvanceTimestampRequest { target: 1_000 });
+            request.metadata_mut().insert(ADMIN_TOKEN_KEY, "secret".parse().unwrap());
+            request.metadata_mut().insert(ADMIN_NONCE_KEY, "nonce-1".parse().unwrap());
+            request
+        };
+        PlacementDriver::advance_timestamp(&pd, request()).await.unwrap();
+        let status = PlacementDriver::advance_timestamp(&pd, request()).await.unwrap_err();
+        assert_eq!(Error::from(status), Error::Abort);
+    }
+
+    #[tokio::test]
+    async fn range_replies_above_the_default_grpc_limit_get_through() {
+        const DEFAULT_GRPC_LIMIT: usize = 4 * 1024 * 1024;
+        let serve = |max_message_size| {
+            let mut config = Config::default();
+            config.server.max_message_size = max_message_size;
+            let pd = serving(config);
+            // 600 regions bounded by 8000-byte keys, about 9.6 MB in total.
+            let key = |i: u32| {
+                let mut key = i.to_be_bytes().to_vec();
+                key.resize(8_000, b'k');
+                key
+            };
+            let mut routing = pd.routing.lock().unwrap();
+            for i in 0..600 {
+                let start_key = if i == 0 { Vec::new() } else { key(i) };
+                let end_key = if i == 599 { Vec::new() } else { key(i + 1) };
+                let region =
+                    RegionInfo::new(i as u64 + 1, DEFAULT_KEYSPACE, start_key, end_key, 1, vec![1], 1);
+                routing.put_region(region.unwrap()).unwrap();
+            }
+            drop(routing);
+            let addr = Address::Unix(scratch_dir(&format!("large-{}", max_message_size)).join("pd.sock"));
+            let server = spawn_serve(pd, &addr, std::future::pending()).unwrap();
+            (addr, server)
+        };
+        let scan = |addr: Address, max_message_size| async move {
+            let mut client = connect(&addr, &ClientConfig { max_message_size }).await.unwrap();
+            let request = DataLocRangeRequest { limit: 1_000, ..Default::default() };
+            client.get_data_location_range(request).await.map(|reply| reply.into_inner())
+        };
+
+        let (addr, server) = serve(ServerConfig::default().max_message_size);
+        let reply = scan(addr.clone(), ClientConfig::default().max_message_size).await.unwrap();
+        assert!(reply.regions.len() > DEFAULT_GRPC_LIMIT);
+        assert_eq!(bincode::deserialize::<Vec<RegionInfo>>(&reply.regions).unwrap().len(), 600);
+
+        // The client rejects the reply at the default limit.
+        assert!(scan(addr, DEFAULT_GRPC_LIMIT).await.is_err());
+        server.abort();
+
+        // And so does a server configured with it.
+        let (addr, server) = serve(DEFAULT_GRPC_LIMIT);
+        assert!(scan(addr, ClientConfig::default().max_message_size).await.is_err());
+        server.abort();
+    }
+}
@@ -0,0 +1,191 @@
+use log::{error, info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::error::{Error, Result};
+use crate::server::FeatherPD;
+use crate::transport::{spawn_serve, Address};
+
+/// The default time the server is given to finish the requests in flight on
+/// shutdown, in milliseconds.
+pub const DEFAULT_DRAIN_TIMEOUT_MS: u64 = 10_000;
+
+/// Coordinates a PD's shutdown, stopping it and its background tasks in a
+/// safe order. See `shutdown()`.
+pub struct Shutdown {
+    /// The PD being served.
+    pd: FeatherPD,
+    /// Cancelled to stop the server accepting connections and requests.
+    serving: CancellationToken,
+    /// Cancelled to stop the background tasks.
+    tasks: CancellationToken,
+    /// The server task, if serving.
+    server: Option<JoinHandle<Result<()>>>,
+    /// The background tasks, with their names.
+    handles: Vec<(&'static str, JoinHandle<Result<()>>)>,
+    /// The time the server is given to finish the requests in flight.
+    drain_timeout: Duration,
+}
+
+impl Shutdown {
+    /// Creates a coordinator for the given PD, with nothing running yet.
+    pub fn new(pd: FeatherPD) -> Self {
+        Self {
+            pd,
+            serving: CancellationToken::new(),
+            tasks: CancellationToken::new(),
+            server: None,
+            handles: Vec::new(),
+            drain_timeout: Duration::from_millis(DEFAULT_DRAIN_TIMEOUT_MS),
+        }
+    }
+
+    /// Sets the time the server is given to finish the requests in flight,
+    /// e.g. client-driven allocation streams, before it is aborted.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Returns the PD, e.g. to start background tasks for it.
+    pub fn pd(&self) -> Arc<FeatherPD> {
+        Arc::new(self.pd.clone())
+    }
+
+    /// Starts serving the PD on the given address until shutdown.
+    pub fn serve(&mut self, addr: &Address) -> Result<()> {
+        let signal = self.serving.clone().cancelled_owned();
+        self.server = Some(spawn_serve(self.pd.clone(), addr, signal)?);
+        Ok(())
+    }
+
+    /// Returns the token background tasks must return on once cancelled, e.g.
+    /// for `lease::start()` and `gossip::start()`.
+    pub fn task_token(&self) -> CancellationToken {
+        self.tasks.child_token()
+    }
+
+    /// Registers a background task to wait for on shutdown. It must return
+    /// once the `task_token()` it was given is cancelled.
+    pub fn add_task(&mut self, name: &'static str, handle: JoinHandle<Result<()>>) {
+        self.handles.push((name, handle));
+    }
+
+    /// Shuts the PD down, in order:
+    ///
+    /// 1. Stops accepting requests: timestamps are refused from now on, and
+    ///    the server stops accepting connections and requests.
+    /// 2. Persists the final state of the TSOs, now that no more timestamps
+    ///    are handed out, so the checkpoints record the true last allocated
+    ///    timestamps.
+    /// 3. Stops the background tasks, e.g. lease renewal and gossip, and
+    ///    waits for them.
+    /// 4. Closes the watchers, so subscribers see every change up to the
+    ///    shutdown followed by a clean `TopologyEvent::ShuttingDown`.
+    /// 5. Waits for the server to finish the requests in flight, which the
+    ///    closed watch streams no longer hold up, or aborts it after the drain
+    ///    timeout.
+    ///
+    /// A failing step doesn't stop the later ones, so nothing is left
+    /// running. Returns the first error, if any.
+    pub async fn shutdown(self) -> Result<()> {
+        info!("Shutting down: no longer accepting requests");
+        let mut result = self.pd.begin_shutdown();
+        self.serving.cancel();
+
+        info!("Shutting down: persisting the TSO state");
+        result = result.and(self.pd.shutdown());
+
+        info!("Shutting down: stopping {} background tasks", self.handles.len());
+        self.tasks.cancel();
+        for (name, handle) in self.handles {
+            result = result.and(join(name, handle).await);
+        }
+
+        info!("Shutting down: closing watchers");
+        result = result.and(self.pd.close_watchers());
+
+        if let Some(mut server) = self.server {
+            let drained = match tokio::time::timeout(self.drain_timeout, &mut server).await {
+                Ok(joined) => joined.map_err(Error::from).and_then(|result| result),
+                Err(_) => {
+                    warn!("Server didn't drain within {:?}, aborting it", self.drain_timeout);
+                    server.abort();
+                    Ok(())
+                }
+            };
+            if let Err(err) = &drained {
+                error!("Server failed during shutdown: {}", err);
+            }
+            result = result.and(drained);
+        }
+        info!("Shut down");
+        result
+    }
+}
+
+/// Waits for a background task, logging its failure.
+async fn join(name: &str, handle: JoinHandle<Result<()>>) -> Result<()> {
+    let result = handle.await.map_err(Error::from).and_then(|result| result);
+    if let Err(err) = &result {
+        error!("Background task {} failed: {}", name, err);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::{CheckpointStore, FileCheckpoint};
+    use crate::config::{ClientConfig, Config};
+    use crate::proto::placement_driver::TsoRequest;
+    use crate::transport::connect;
+    use crate::{gossip, lease};
+
+    #[tokio::test]
+    async fn stops_the_server_and_its_tasks_and_persists_the_final_watermark() {
+        let dir = std::env::temp_dir().join(format!("featherpd-shutdown-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = Config::default();
+        config.tso.checkpoint_path = Some(dir.join("tso"));
+        config.tso.lease_ms = 2_000;
+        config.gossip.listen_addr = Some("127.0.0.1:0".parse().unwrap());
+        config.gossip.interval_ms = 10;
+        let pd = FeatherPD::from_config(&config).unwrap();
+        pd.recover().await.unwrap();
+
+        let mut shutdown = Shutdown::new(pd).with_drain_timeout(Duration::from_secs(5));
+        let addr = Address::Unix(dir.join("pd.sock"));
+        shutdown.serve(&addr).unwrap();
+        let lease = lease::start(shutdown.pd(), &config.tso, shutdown.task_token()).unwrap();
+        let gossip =
+            gossip::start(shutdown.pd(), &config.gossip, shutdown.task_token()).await.unwrap().unwrap();
+        let tasks = [lease.abort_handle(), gossip.abort_handle()];
+        shutdown.add_task("lease", lease);
+        shutdown.add_task("gossip", gossip);
+
+        let mut client = connect(&addr, &ClientConfig::default()).await.unwrap();
+        let mut last = 0;
+        for _ in 0..3 {
+            let request = TsoRequest { count: 1, ..Default::default() };
+            last = client.get_timestamp(request).await.unwrap().into_inner().timestamp;
+        }
+
+        let pd = shutdown.pd();
+        tokio::time::timeout(Duration::from_secs(5), shutdown.shutdown()).await.unwrap().unwrap();
+        assert!(tasks.iter().all(|task| task.is_finished()));
+        assert!(matches!(pd.get_next_ts(), Err(Error::Unavailable(_))));
+        let checkpoint = FileCheckpoint::new(dir.join("tso"))
+            .with_format(config.persistence.format)
+            .load()
+            .unwrap()
+            .unwrap();
+        assert_eq!(checkpoint.last_allocated, Some(last));
+        assert!(checkpoint.window_end > last);
+        assert!(connect(&addr, &ClientConfig::default()).await.is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
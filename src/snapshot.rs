@@ -0,0 +1,276 @@
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::error::{Error, Result};
+use crate::routing::RegionInfo;
+use crate::store::StoreInfo;
+
+/// The magic number prefixing bincode-encoded snapshots and change sets.
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"FPDS";
+
+/// The magic number prefixing JSON-encoded snapshots and change sets.
+pub const SNAPSHOT_JSON_MAGIC: [u8; 4] = *b"FPDJ";
+
+/// The schema version of encoded snapshots and change sets. Must be bumped
+/// whenever the layout of `Snapshot` or `Change`, or of anything they
+/// contain, changes, since bincode would silently misparse it.
+pub const SNAPSHOT_VERSION: u16 = 3;
+
+/// The default number of changes retained for incremental snapshots.
+pub const DEFAULT_CHANGE_LOG_CAPACITY: usize = 10_000;
+
+/// The default age after which changes are no longer retained for
+/// incremental snapshots, in milliseconds.
+pub const DEFAULT_CHANGE_LOG_MAX_AGE_MS: u64 = 60 * 60 * 1000;
+
+/// The serialization format of persisted state, i.e. snapshots, change sets
+/// and TSO checkpoints. Decoding detects the format, so it can be changed
+/// without migrating existing state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub enum PersistenceFormat {
+    /// Compact but opaque binary encoding. The default.
+    #[default]
+    Bincode,
+    /// JSON. Several times larger and slower to encode, but human-readable,
+    /// e.g. for debugging.
+    Json,
+}
+
+/// A full snapshot of the PD's routing and store state.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// The ids of all keyspaces, in ascending order.
+    pub keyspaces: Vec<u32>,
+    /// All regions, by ascending id.
+    pub regions: Vec<RegionInfo>,
+    /// All stores, by ascending id.
+    pub stores: Vec<StoreInfo>,
+}
+
+/// A change to the routing or store state, as replayed by a PD replica.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Change {
+    /// A keyspace was created.
+    CreateKeyspace(u32),
+    /// A keyspace was deleted, along with all of its regions.
+    DeleteKeyspace(u32),
+    /// A region was inserted or replaced.
+    PutRegion(RegionInfo),
+    /// A store was registered or re-registered.
+    PutStore(StoreInfo),
+}
+
+/// A bounded log of recent changes, each tagged with a monotonically
+/// increasing version, from which incremental snapshots are built. It is
+/// bounded both by the number of changes and by their age, whichever evicts
+/// first.
+pub struct ChangeLog {
+    /// The version of the latest change, or 0 if none.
+    version: u64,
+    /// The retained changes with the time they were appended at, oldest
+    /// first.
+    changes: VecDeque<(u64, u64, Change)>,
+    /// The maximum number of changes retained.
+    capacity: usize,
+    /// The maximum age of retained changes in milliseconds, or 0 for no
+    /// limit.
+    max_age_ms: u64,
+}
+
+impl ChangeLog {
+    /// Creates an empty change log retaining up to `capacity` changes, none
+    /// older than `max_age_ms` unless it is 0.
+    pub fn new(capacity: usize, max_age_ms: u64) -> Self {
+        Self { version: 0, changes: VecDeque::new(), capacity, max_age_ms }
+    }
+
+    /// Returns the number of retained changes.
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Returns true if no changes are retained.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Returns the version of the latest change.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Appends a change at the given time, dropping the oldest ones if the
+    /// log is full or they have expired.
+    pub fn append(&mut self, change: Change, now_ms: u64) {
+        self.version += 1;
+        self.changes.push_back((self.version, now_ms, change));
+        if self.changes.len() > self.capacity {
+            self.changes.pop_front();
+        }
+        if self.max_age_ms > 0 {
+            let min_ms = now_ms.saturating_sub(self.max_age_ms);
+            while self.changes.front().is_some_and(|(_, at_ms, _)| *at_ms < min_ms) {
+                self.changes.pop_front();
+            }
+        }
+    }
+
+    /// Returns the changes after the given version, oldest first. Returns
+    /// `Error::SnapshotRequired` if some of them are no longer retained, in
+    /// which case the caller must fall back to a full snapshot.
+    pub fn since(&self, version: u64) -> Result<Vec<Change>> {
+        if version > self.version {
+            return Err(Error::Value(format!(
+                "Version {} is ahead of current version {}",
+                version, self.version
+            )));
+        }
+        let oldest = self.changes.front().map_or(self.version + 1, |(version, _, _)| *version);
+        if version + 1 < oldest {
+            return Err(Error::SnapshotRequired);
+        }
+        Ok(self
+            .changes
+            .iter()
+            .filter(|(v, _, _)| *v > version)
+            .map(|(_, _, change)| change.clone())
+            .collect())
+    }
+
+    /// Returns a region as last recorded at the given epoch, e.g. to debug
+    /// historical routing. Returns `Error::NotFound` if no change of the
+    /// region at that epoch is retained.
+    pub fn region_at_epoch(&self, region_id: u64, epoch: u64) -> Result<RegionInfo> {
+        self.changes
+            .iter()
+            .rev()
+            .find_map(|(_, _, change)| match change {
+                Change::PutRegion(region) if region.id == region_id && region.epoch == epoch => Some(region),
+                _ => None,
+            })
+            .cloned()
+            .ok_or_else(|| {
+                Error::NotFound(format!("Region {} at epoch {} is no longer retained", region_id, epoch))
+            })
+    }
+}
+
+/// Encodes a snapshot or change set with bincode, prefixed by the magic
+/// number and the schema version.
+pub fn encode<T: serde::Serialize>(value: &T) -> Result<Vec<u8>> {
+    encode_as(PersistenceFormat::Bincode, value)
+}
+
+/// Encodes a snapshot or change set in the given format, prefixed by the
+/// format's magic number and the schema version.
+pub fn encode_as<T: serde::Serialize>(format: PersistenceFormat, value: &T) -> Result<Vec<u8>> {
+    let magic = match format {
+        PersistenceFormat::Bincode => SNAPSHOT_MAGIC,
+        PersistenceFormat::Json => SNAPSHOT_JSON_MAGIC,
+    };
+    let mut bytes = Vec::from(magic);
+    bytes.extend(SNAPSHOT_VERSION.to_be_bytes());
+    match format {
+        PersistenceFormat::Bincode => bytes.extend(bincode::serialize(value)?),
+        PersistenceFormat::Json => serde_json::to_writer(&mut bytes, value)?,
+    }
+    Ok(bytes)
+}
+
+/// Decodes a snapshot or change set encoded by `encode()` or `encode_as()`,
+/// in either format. Returns `Error::Internal` if the magic number is
+/// missing or the schema version differs, rather than misparsing it, e.g.
+/// when it was produced by another FeatherPD version during a rolling
+/// upgrade.
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    if bytes.len() < 6 || (bytes[..4] != SNAPSHOT_MAGIC && bytes[..4] != SNAPSHOT_JSON_MAGIC) {
+        return Err(Error::Internal("Not a FeatherPD snapshot".into()));
+    }
+    let version = u16::from_be_bytes(bytes[4..6].try_into()?);
+    if version != SNAPSHOT_VERSION {
+        return Err(Error::Internal(format!(
+            "Unsupported snapshot schema version {}, expected {}",
+            version, SNAPSHOT_VERSION
+        )));
+    }
+    if bytes[..4] == SNAPSHOT_JSON_MAGIC {
+        return Ok(serde_json::from_slice(&bytes[6..])?);
+    }
+    Ok(bincode::deserialize(&bytes[6..])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> Snapshot {
+        let region = RegionInfo::new(1, 0, Vec::new(), Vec::new(), 1, vec![1], 1).unwrap();
+        Snapshot { keyspaces: vec![0], regions: vec![region], stores: Vec::new() }
+    }
+
+    #[test]
+    fn snapshots_round_trip_in_either_format() {
+        for format in [PersistenceFormat::Bincode, PersistenceFormat::Json] {
+            let bytes = encode_as(format, &snapshot()).unwrap();
+            assert_eq!(&bytes[4..6], &SNAPSHOT_VERSION.to_be_bytes());
+            assert_eq!(decode::<Snapshot>(&bytes).unwrap(), snapshot());
+        }
+    }
+
+    #[test]
+    fn snapshots_of_another_schema_version_are_rejected() {
+        for version in [SNAPSHOT_VERSION - 1, SNAPSHOT_VERSION + 1] {
+            let mut bytes = encode(&snapshot()).unwrap();
+            bytes[4..6].copy_from_slice(&version.to_be_bytes());
+            assert!(matches!(decode::<Snapshot>(&bytes), Err(Error::Internal(_))));
+        }
+    }
+
+    #[test]
+    fn unversioned_snapshots_are_rejected() {
+        // A raw bincode snapshot, as written before snapshots were versioned.
+        let raw = bincode::serialize(&snapshot()).unwrap();
+        assert!(matches!(decode::<Snapshot>(&raw), Err(Error::Internal(_))));
+        assert!(matches!(decode::<Snapshot>(&SNAPSHOT_MAGIC), Err(Error::Internal(_))));
+        assert!(matches!(decode::<Snapshot>(&[]), Err(Error::Internal(_))));
+    }
+
+    #[test]
+    fn changes_beyond_the_capacity_require_a_full_snapshot() {
+        let mut log = ChangeLog::new(3, 0);
+        for keyspace_id in 1..=5 {
+            log.append(Change::CreateKeyspace(keyspace_id), 0);
+        }
+        assert_eq!((log.version(), log.len()), (5, 3));
+        assert_eq!(log.since(0), Err(Error::SnapshotRequired));
+        assert_eq!(log.since(1), Err(Error::SnapshotRequired));
+        assert_eq!(
+            log.since(2).unwrap(),
+            vec![Change::CreateKeyspace(3), Change::CreateKeyspace(4), Change::CreateKeyspace(5)]
+        );
+        assert_eq!(log.since(5).unwrap(), Vec::new());
+        assert!(matches!(log.since(6), Err(Error::Value(_))));
+    }
+
+    #[test]
+    fn changes_beyond_the_max_age_require_a_full_snapshot() {
+        let mut log = ChangeLog::new(100, 1_000);
+        log.append(Change::CreateKeyspace(1), 0);
+        log.append(Change::CreateKeyspace(2), 500);
+        log.append(Change::CreateKeyspace(3), 1_000);
+        assert_eq!(log.len(), 3);
+
+        // The first change is now over a second old.
+        log.append(Change::CreateKeyspace(4), 1_001);
+        assert_eq!(log.len(), 3);
+        assert_eq!(log.since(0), Err(Error::SnapshotRequired));
+        assert_eq!(log.since(1).unwrap().len(), 3);
+
+        // Without a max age, changes are only evicted by count.
+        let mut log = ChangeLog::new(100, 0);
+        log.append(Change::CreateKeyspace(1), 0);
+        log.append(Change::CreateKeyspace(2), u64::MAX);
+        assert_eq!(log.since(0).unwrap().len(), 2);
+    }
+}
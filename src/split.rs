@@ -0,0 +1,109 @@
+use std::cmp::Ordering;
+
+use crate::comparator::{compare_keys, KeyComparator};
+use crate::routing::RegionInfo;
+
+/// Computes the keys to split an oversized region at, so that each resulting
+/// region holds about `target_size` bytes. The split keys are picked among
+/// keys sampled by the store, which are assumed to be spread evenly across
+/// the region's data, e.g. one per fixed number of bytes. Samples that don't
+/// lie strictly inside the region are ignored, with keys ordered by the
+/// given comparator.
+///
+/// Returns an empty list if the region is within the target size, and None
+/// if too few distinct keys were sampled to split it evenly, in which case
+/// the split should be deferred until more are reported.
+pub fn split_keys(
+    region: &RegionInfo,
+    samples: Vec<Vec<u8>>,
+    target_size: u64,
+    comparator: &dyn KeyComparator,
+) -> Option<Vec<Vec<u8>>> {
+    if target_size == 0 || region.approximate_size <= target_size {
+        return Some(Vec::new());
+    }
+    let pieces = region.approximate_size.div_ceil(target_size).min(usize::MAX as u64) as usize;
+    let mut samples: Vec<Vec<u8>> = samples
+        .into_iter()
+        .filter(|key| {
+            compare_keys(comparator, key, &region.start_key) == Ordering::Greater
+                && region.contains_by(key, comparator)
+        })
+        .collect();
+    samples.sort_by(|a, b| compare_keys(comparator, a, b));
+    samples.dedup_by(|a, b| compare_keys(comparator, a, b) == Ordering::Equal);
+    if samples.len() < pieces - 1 {
+        return None;
+    }
+    // The i-th split key is the sample at the i/pieces quantile. Since there
+    // are at least pieces - 1 distinct samples, the indexes are distinct.
+    Some((1..pieces).map(|i| samples[i * samples.len() / pieces].clone()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::Lexicographic;
+    use crate::routing::DEFAULT_KEYSPACE;
+
+    /// Returns a region over [start_key, end_key) of the given size.
+    fn region(start_key: &[u8], end_key: &[u8], approximate_size: u64) -> RegionInfo {
+        let region =
+            RegionInfo::new(1, DEFAULT_KEYSPACE, start_key.to_vec(), end_key.to_vec(), 1, vec![1], 1);
+        RegionInfo { approximate_size, ..region.unwrap() }
+    }
+
+    /// Returns the keys "k000" to "k099", or those in the given range.
+    fn keys(range: std::ops::Range<u32>) -> Vec<Vec<u8>> {
+        range.map(|i| format!("k{:03}", i).into_bytes()).collect()
+    }
+
+    fn split(region: &RegionInfo, samples: Vec<Vec<u8>>, target_size: u64) -> Option<Vec<Vec<u8>>> {
+        split_keys(region, samples, target_size, &Lexicographic)
+    }
+
+    #[test]
+    fn regions_within_the_target_size_are_not_split() {
+        assert_eq!(split(&region(b"", b"", 100), keys(0..100), 100), Some(Vec::new()));
+        assert_eq!(split(&region(b"", b"", 100), Vec::new(), 0), Some(Vec::new()));
+    }
+
+    #[test]
+    fn evenly_spread_samples_split_at_even_quantiles() {
+        assert_eq!(split(&region(b"", b"", 101), keys(0..100), 100), Some(keys(50..51)));
+        assert_eq!(
+            split(&region(b"", b"", 400), keys(0..100), 100),
+            Some(vec![b"k025".to_vec(), b"k050".to_vec(), b"k075".to_vec()])
+        );
+    }
+
+    #[test]
+    fn skewed_samples_split_where_the_data_is() {
+        // Most of the data, and so most samples, is in the last tenth.
+        let mut samples = keys(0..10);
+        samples
+            .extend(keys(90..100).into_iter().flat_map(|key| (0..9).map(move |i| [&key[..], &[i]].concat())));
+        let split_keys = split(&region(b"", b"", 200), samples, 100).unwrap();
+        assert_eq!(split_keys.len(), 1);
+        assert!(split_keys[0].as_slice() > b"k090".as_slice(), "{:?}", split_keys);
+    }
+
+    #[test]
+    fn duplicate_and_out_of_range_samples_are_ignored() {
+        let region = region(b"k010", b"k020", 200);
+        let mut samples = keys(0..30);
+        samples.extend(keys(11..20));
+        // Only k011 to k019 lie strictly inside the region.
+        assert_eq!(split(&region, samples, 100), Some(keys(15..16)));
+    }
+
+    #[test]
+    fn splits_are_deferred_with_too_few_distinct_samples() {
+        // 10 pieces need 9 distinct samples.
+        let region = region(b"", b"", 1_000);
+        assert_eq!(split(&region, keys(0..8), 100), None);
+        assert_eq!(split(&region, vec![b"k".to_vec(); 100], 100), None);
+        assert_eq!(split(&region, keys(0..8).into_iter().chain(keys(0..8)).collect(), 100), None);
+        assert_eq!(split(&region, keys(0..9), 100), Some(keys(0..9)));
+    }
+}
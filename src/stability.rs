@@ -0,0 +1,53 @@
+use std::collections::VecDeque;
+
+/// The period over which topology changes are counted, in milliseconds.
+pub const ACTIVITY_WINDOW_MS: u64 = 60_000;
+
+/// The routing cache TTL suggested while the topology is quiescent, in
+/// milliseconds.
+pub const MAX_CACHE_TTL_MS: u64 = 60_000;
+
+/// The shortest routing cache TTL suggested, in milliseconds.
+pub const MIN_CACHE_TTL_MS: u64 = 1_000;
+
+/// Tracks recent topology changes, i.e. splits, merges and leader transfers,
+/// to suggest how long clients may cache routing information: the more
+/// changes, the sooner cached routes go stale.
+pub struct TopologyActivity {
+    /// The times of recent topology changes, in milliseconds, oldest first.
+    changes: VecDeque<u64>,
+}
+
+impl TopologyActivity {
+    /// Creates a new, quiescent activity tracker.
+    pub fn new() -> Self {
+        Self { changes: VecDeque::new() }
+    }
+
+    /// Records a topology change at the given time.
+    pub fn record(&mut self, now_ms: u64) {
+        self.expire(now_ms);
+        self.changes.push_back(now_ms);
+    }
+
+    /// Returns the suggested routing cache TTL in milliseconds. It is
+    /// MAX_CACHE_TTL_MS without recent changes, and shrinks with each change
+    /// in the activity window, down to MIN_CACHE_TTL_MS.
+    pub fn cache_ttl_ms(&mut self, now_ms: u64) -> u64 {
+        self.expire(now_ms);
+        (MAX_CACHE_TTL_MS / (1 + self.changes.len() as u64)).max(MIN_CACHE_TTL_MS)
+    }
+
+    /// Forgets changes older than the activity window.
+    fn expire(&mut self, now_ms: u64) {
+        while self.changes.front().is_some_and(|&at| at.saturating_add(ACTIVITY_WINDOW_MS) <= now_ms) {
+            self.changes.pop_front();
+        }
+    }
+}
+
+impl Default for TopologyActivity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
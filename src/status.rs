@@ -0,0 +1,116 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::breaker::BreakerState;
+use crate::compaction::CompactionCandidate;
+use crate::hotspot::Hotspot;
+use crate::store::{SpaceLevel, StoreTombstone};
+
+/// A point-in-time overview of the cluster, for operators and tooling.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClusterStatus {
+    /// The persisted TSO watermark, which recovery resumes from.
+    pub tso_window_end: u64,
+    /// The last timestamp actually handed out, if any. The gap up to the
+    /// window end is skipped on restart.
+    pub tso_last_allocated: Option<u64>,
+    /// The number of TSO windows persisted since startup because allocation
+    /// exhausted the previous one, each costing a checkpoint write and its
+    /// sync. The refill rate is the allocation rate over `tso.window_size`:
+    /// if refills are frequent enough to show up in allocation latency,
+    /// raise the window size, at the cost of skipping more timestamps on
+    /// restart.
+    pub tso_window_refills: u64,
+    /// The number of timestamps left in the current TSO window, i.e. at most
+    /// how many would be skipped if the server restarted now.
+    pub tso_window_remaining: u64,
+    /// The topology version, for compare-and-swap admin operations.
+    pub topology_version: u64,
+    /// The number of changes retained for incremental snapshots.
+    pub change_log_entries: usize,
+    /// The GC safe point, or 0 if none was set.
+    pub gc_safe_point: u64,
+    /// The state of the checkpoint store's circuit breaker, if any. While
+    /// open, allocations needing a new window fail fast.
+    pub persistence_breaker: Option<BreakerState>,
+    /// Whether a TSO checkpoint store turned out to be read-only, e.g.
+    /// because its file system is read-only or full. Unlike an open circuit
+    /// breaker, this lasts until the server is restarted, and timestamps
+    /// needing a new window are refused until then.
+    pub persistence_read_only: bool,
+    /// Regions currently flagged as hot, hottest first.
+    pub hotspots: Vec<Hotspot>,
+    /// The ids of regions larger than the configured maximum region size,
+    /// which should be split. Empty while the region count cap is reached.
+    pub oversized_regions: Vec<u64>,
+    /// The number of regions, across keyspaces.
+    pub region_count: usize,
+    /// The configured maximum number of regions, or 0 for no limit.
+    pub max_region_count: usize,
+    /// The number of regions each registered store holds a replica of, by
+    /// store id.
+    pub store_regions: Vec<StoreRegionCount>,
+    /// The placement weights pinned by operators, by store id. Other stores
+    /// are weighted by their free space.
+    pub store_weights: Vec<PinnedStoreWeight>,
+    /// The configured maximum number of regions per store, or 0 for no
+    /// limit.
+    pub max_regions_per_store: usize,
+    /// Stores above a space threshold, by store id. Stores at the critical
+    /// level should have their regions evacuated.
+    pub space_alerts: Vec<SpaceAlert>,
+    /// The tombstones of recently removed stores, whose ids can't be reused
+    /// yet, by store id.
+    pub store_tombstones: Vec<StoreTombstone>,
+    /// The ids of stores whose drain has stalled: they are past the drain
+    /// timeout but still hold replicas, and need an operator to intervene.
+    pub stalled_drains: Vec<u64>,
+    /// Regions currently recommended for compaction, most stale versions
+    /// first.
+    pub compaction_candidates: Vec<CompactionCandidate>,
+    /// Regions whose leader is pinned to a store, by region id.
+    pub pinned_leaders: Vec<PinnedLeader>,
+    /// Whether scheduling is paused, e.g. for a bulk import. No replicas,
+    /// splits, or queued operations are handed out until it resumes.
+    pub scheduling_paused: bool,
+    /// The number of region heartbeats that skipped reconciliation because
+    /// they were identical to the region's previous one.
+    pub skipped_reconciliations: u64,
+}
+
+/// A store whose used space is above a configured threshold.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SpaceAlert {
+    /// The store id.
+    pub store_id: u64,
+    /// The fraction of the store's capacity in use.
+    pub used_ratio: f64,
+    /// The threshold level reached.
+    pub level: SpaceLevel,
+}
+
+/// The number of regions a store holds a replica of.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoreRegionCount {
+    /// The store id.
+    pub store_id: u64,
+    /// The number of regions the store holds a replica of.
+    pub region_count: usize,
+}
+
+/// A store placement weight pinned by an operator.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PinnedStoreWeight {
+    /// The store id.
+    pub store_id: u64,
+    /// The pinned weight. 0 means no new regions are placed on the store.
+    pub weight: u32,
+}
+
+/// A region whose leader is pinned to a store.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PinnedLeader {
+    /// The region id.
+    pub region_id: u64,
+    /// The id of the store the leader is pinned to.
+    pub store_id: u64,
+}
@@ -0,0 +1,590 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::config::StoreConfig;
+use crate::error::{Error, Result};
+use crate::labels::{LabelSelector, Labels};
+
+/// The default time the id of a removed store stays reserved, in
+/// milliseconds.
+pub const DEFAULT_TOMBSTONE_RETENTION_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// The default time a store may take to drain before its drain is
+/// considered stalled, in milliseconds.
+pub const DEFAULT_DRAIN_TIMEOUT_MS: u64 = 60 * 60 * 1000;
+
+/// The state of a store. Stores move through their lifecycle via
+/// `StoreInfo::transition()`, which rejects illegal transitions.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StoreState {
+    /// The store is known but not yet serving.
+    Pending,
+    /// The store is live and serving.
+    Up,
+    /// The store is live, but its regions are being moved off it ahead of
+    /// its removal. No new regions are placed on it.
+    Draining,
+    /// The store is not live.
+    Down,
+    /// The store was permanently removed from the cluster, and may never
+    /// come back. Its id may only be reused by a new store once its
+    /// tombstone expires, see `StoreRegistry::register()`.
+    Removed,
+}
+
+impl StoreState {
+    /// Returns true if a store may move from this state to the given one.
+    /// Staying in the same state is always allowed, except for leaving
+    /// `Removed`.
+    pub fn can_transition_to(self, to: StoreState) -> bool {
+        use StoreState::*;
+        match (self, to) {
+            (from, to) if from == to => true,
+            (Pending, Up | Removed) => true,
+            (Up, Draining | Down) => true,
+            (Draining, Up | Down | Removed) => true,
+            (Down, Up | Draining | Removed) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the store is live, i.e. up or draining.
+    pub fn is_live(self) -> bool {
+        matches!(self, StoreState::Up | StoreState::Draining)
+    }
+}
+
+/// How full a store is, relative to the configured space thresholds.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Serialize, Deserialize)]
+pub enum SpaceLevel {
+    /// Below the high-water mark.
+    Normal,
+    /// Above the high-water mark: no new regions are placed on the store.
+    High,
+    /// Above the critical mark: regions should be evacuated from the store.
+    Critical,
+}
+
+/// The highest manual placement weight of a store, which is also the
+/// capacity-derived weight of an empty store.
+pub const MAX_STORE_WEIGHT: u32 = 100;
+
+/// Metadata of a store, i.e. a storage node holding region replicas.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoreInfo {
+    /// The store id.
+    pub id: u64,
+    /// The address clients reach the store at.
+    pub address: String,
+    /// The store state.
+    pub state: StoreState,
+    /// The store labels, e.g. its zone or disk type.
+    pub labels: Labels,
+    /// The disk capacity in bytes, as last reported. 0 if unknown.
+    pub capacity: u64,
+    /// The used disk space in bytes, as last reported.
+    pub used: u64,
+    /// The time of the last registration or heartbeat, in milliseconds.
+    pub last_heartbeat_ms: u64,
+    /// The placement weight pinned by an operator, from 0 to
+    /// `MAX_STORE_WEIGHT`, e.g. to favor newer hardware. Overrides the
+    /// capacity-derived weight if set. A store with weight 0 gets no new
+    /// regions, like a soft drain.
+    pub weight: Option<u32>,
+}
+
+impl StoreInfo {
+    /// Returns the fraction of the capacity in use, or 0 if unknown.
+    pub fn used_ratio(&self) -> f64 {
+        if self.capacity == 0 {
+            return 0.0;
+        }
+        self.used as f64 / self.capacity as f64
+    }
+
+    /// Returns how full the store is.
+    pub fn space_level(&self, config: &StoreConfig) -> SpaceLevel {
+        let ratio = self.used_ratio();
+        if ratio >= config.space_critical_ratio {
+            SpaceLevel::Critical
+        } else if ratio >= config.space_high_ratio {
+            SpaceLevel::High
+        } else {
+            SpaceLevel::Normal
+        }
+    }
+
+    /// Moves the store to the given state. Returns `Error::Value` for an
+    /// illegal transition, e.g. resurrecting a removed store.
+    pub fn transition(&mut self, to: StoreState) -> Result<()> {
+        if !self.state.can_transition_to(to) {
+            return Err(Error::Value(format!(
+                "Store {} cannot transition from {:?} to {:?}",
+                self.id, self.state, to
+            )));
+        }
+        self.state = to;
+        Ok(())
+    }
+
+    /// Returns the weight new regions are placed by, heaviest first: the
+    /// manual weight if set, and otherwise the free fraction of the capacity
+    /// scaled to `MAX_STORE_WEIGHT`.
+    pub fn placement_weight(&self) -> f64 {
+        match self.weight {
+            Some(weight) => weight as f64,
+            None => (1.0 - self.used_ratio()) * MAX_STORE_WEIGHT as f64,
+        }
+    }
+
+    /// Returns true if new regions may be placed on the store, i.e. if it is
+    /// up, below the space high-water mark, and not weighted 0.
+    pub fn accepts_new_regions(&self, config: &StoreConfig) -> bool {
+        self.state == StoreState::Up
+            && self.space_level(config) == SpaceLevel::Normal
+            && self.weight != Some(0)
+    }
+}
+
+/// The tombstone of a removed store, reserving its id until it expires.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoreTombstone {
+    /// The removed store's id.
+    pub store_id: u64,
+    /// The time the tombstone expires at, in milliseconds.
+    pub expires_at_ms: u64,
+}
+
+/// The registry of all known stores.
+#[derive(Default)]
+pub struct StoreRegistry {
+    stores: HashMap<u64, StoreInfo>,
+    /// The expiry times of the tombstones of removed stores, by store id.
+    tombstones: HashMap<u64, u64>,
+    /// The times draining stores started draining, by store id.
+    draining_since: HashMap<u64, u64>,
+    /// The draining stores whose stalled drain was already reported.
+    stalls_reported: HashSet<u64>,
+}
+
+impl StoreRegistry {
+    /// Creates a new, empty store registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a store, bringing it up. Re-registering an existing id, e.g.
+    /// when a store restarts, is allowed and updates its address, though a
+    /// draining store keeps draining, and a removed store is rejected.
+    /// Once a removed store's tombstone expires, its id may be registered
+    /// again by a new store. Until then, in-flight requests from the old
+    /// store can't be mistaken for the new one's. Registering an address
+    /// already used by a different live store is rejected, since routing
+    /// can't tell the two apart.
+    pub fn register(&mut self, id: u64, address: String, labels: Labels, now_ms: u64) -> Result<StoreInfo> {
+        if let Some(&expires_at_ms) = self.tombstones.get(&id) {
+            if now_ms < expires_at_ms {
+                return Err(Error::Value(format!(
+                    "Store {} was removed, and its id can't be reused until {}",
+                    id, expires_at_ms
+                )));
+            }
+            self.tombstones.remove(&id);
+            self.stores.remove(&id);
+        }
+        if let Some(other) =
+            self.stores.values().find(|s| s.id != id && s.address == address && s.state.is_live())
+        {
+            return Err(Error::Value(format!("Address {} is already used by store {}", address, other.id)));
+        }
+        let store = self.stores.entry(id).or_insert_with(|| StoreInfo {
+            id,
+            address: String::new(),
+            state: StoreState::Pending,
+            labels: Labels::new(),
+            capacity: 0,
+            used: 0,
+            last_heartbeat_ms: now_ms,
+            weight: None,
+        });
+        if store.state != StoreState::Draining {
+            store.transition(StoreState::Up)?;
+        }
+        store.address = address;
+        store.labels = labels;
+        store.last_heartbeat_ms = now_ms;
+        Ok(store.clone())
+    }
+
+    /// Inserts or replaces a store as is, e.g. when replaying replicated
+    /// changes on a follower.
+    pub fn put(&mut self, store: StoreInfo) {
+        self.stores.insert(store.id, store);
+    }
+
+    /// Returns the live stores whose labels match the selector, by ascending
+    /// id.
+    pub fn query(&self, selector: &LabelSelector) -> Vec<StoreInfo> {
+        let mut stores: Vec<StoreInfo> = self
+            .stores
+            .values()
+            .filter(|store| store.state.is_live() && selector.matches(&store.labels))
+            .cloned()
+            .collect();
+        stores.sort_by_key(|store| store.id);
+        stores
+    }
+
+    /// Picks stores to place the given number of replicas of a new region on,
+    /// see `placement_candidates()`. Returns `Error::NoAvailableStores` if
+    /// there aren't enough of them, which is usually temporary, e.g. while
+    /// stores are down or nearly full.
+    pub fn place(
+        &self,
+        replicas: u32,
+        config: &StoreConfig,
+        region_counts: &HashMap<u64, usize>,
+        rng: &mut impl Rng,
+    ) -> Result<Vec<u64>> {
+        let candidates = self.placement_candidates(config, region_counts, rng);
+        if candidates.len() < replicas as usize {
+            return Err(Error::NoAvailableStores(replicas));
+        }
+        Ok(candidates.into_iter().take(replicas as usize).collect())
+    }
+
+    /// Returns the stores new replicas may be placed on, heaviest first, see
+    /// `StoreInfo::placement_weight()`: those that accept new regions, and
+    /// aren't at the `max_regions` cap given their current region counts.
+    /// Stores of equal weight come in an order drawn from the given RNG, so
+    /// ties don't always go to the same stores. See `placement.seed`.
+    pub fn placement_candidates(
+        &self,
+        config: &StoreConfig,
+        region_counts: &HashMap<u64, usize>,
+        rng: &mut impl Rng,
+    ) -> Vec<u64> {
+        let below_cap = |store: &StoreInfo| {
+            config.max_regions == 0 || region_counts.get(&store.id).copied().unwrap_or(0) < config.max_regions
+        };
+        let mut candidates: Vec<&StoreInfo> = self
+            .stores
+            .values()
+            .filter(|store| store.accepts_new_regions(config) && below_cap(store))
+            .collect();
+        // Shuffle from a fixed order, since the map's isn't, then sort stably
+        // so the shuffle only decides between equal weights.
+        candidates.sort_by_key(|store| store.id);
+        candidates.shuffle(rng);
+        candidates.sort_by(|a, b| b.placement_weight().total_cmp(&a.placement_weight()));
+        candidates.into_iter().map(|store| store.id).collect()
+    }
+
+    /// Applies a store heartbeat, updating its space usage. Returns the store
+    /// as it was before and after the heartbeat.
+    pub fn heartbeat(
+        &mut self,
+        id: u64,
+        capacity: u64,
+        used: u64,
+        now_ms: u64,
+    ) -> Result<(StoreInfo, StoreInfo)> {
+        let store =
+            self.stores.get_mut(&id).ok_or_else(|| Error::NotFound(format!("Store {} not found", id)))?;
+        let before = store.clone();
+        store.capacity = capacity;
+        store.used = used;
+        store.last_heartbeat_ms = now_ms;
+        Ok((before, store.clone()))
+    }
+
+    /// Moves a store to the given state, returning the updated store. See
+    /// `StoreInfo::transition()`. Removing a store leaves a tombstone for
+    /// `tombstone_retention_ms`, and draining one starts its drain timeout.
+    pub fn set_state(
+        &mut self,
+        id: u64,
+        state: StoreState,
+        now_ms: u64,
+        config: &StoreConfig,
+    ) -> Result<StoreInfo> {
+        let store =
+            self.stores.get_mut(&id).ok_or_else(|| Error::NotFound(format!("Store {} not found", id)))?;
+        let before = store.state;
+        store.transition(state)?;
+        let store = store.clone();
+        if before != StoreState::Removed && state == StoreState::Removed {
+            self.tombstones.insert(id, now_ms.saturating_add(config.tombstone_retention_ms));
+        }
+        if before != StoreState::Draining && state == StoreState::Draining {
+            self.draining_since.insert(id, now_ms);
+        } else if state != StoreState::Draining {
+            self.draining_since.remove(&id);
+            self.stalls_reported.remove(&id);
+        }
+        Ok(store)
+    }
+
+    /// Returns the draining stores past their `drain_timeout_ms`, by
+    /// ascending id. These have stalled if they still hold any replicas.
+    pub fn overdue_drains(&self, now_ms: u64, config: &StoreConfig) -> Vec<u64> {
+        if config.drain_timeout_ms == 0 {
+            return Vec::new();
+        }
+        let mut ids: Vec<u64> = self
+            .draining_since
+            .iter()
+            .filter(|(_, &since_ms)| now_ms >= since_ms.saturating_add(config.drain_timeout_ms))
+            .map(|(&id, _)| id)
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Records that a store's stalled drain was reported, returning false if
+    /// it already was.
+    pub fn report_drain_stall(&mut self, id: u64) -> bool {
+        self.stalls_reported.insert(id)
+    }
+
+    /// Returns the unexpired tombstones, by ascending store id.
+    pub fn tombstones(&self, now_ms: u64) -> Vec<StoreTombstone> {
+        let mut tombstones: Vec<StoreTombstone> = self
+            .tombstones
+            .iter()
+            .filter(|(_, &expires_at_ms)| now_ms < expires_at_ms)
+            .map(|(&store_id, &expires_at_ms)| StoreTombstone { store_id, expires_at_ms })
+            .collect();
+        tombstones.sort_by_key(|tombstone| tombstone.store_id);
+        tombstones
+    }
+
+    /// Fetches a store by id.
+    pub fn get(&self, id: u64) -> Result<StoreInfo> {
+        self.stores.get(&id).cloned().ok_or_else(|| Error::NotFound(format!("Store {} not found", id)))
+    }
+
+    /// Pins the placement weight of a store. Returns `Error::Value` if it is
+    /// above `MAX_STORE_WEIGHT`.
+    pub fn set_weight(&mut self, id: u64, weight: u32) -> Result<StoreInfo> {
+        check_weight(weight)?;
+        let store =
+            self.stores.get_mut(&id).ok_or_else(|| Error::NotFound(format!("Store {} not found", id)))?;
+        store.weight = Some(weight);
+        Ok(store.clone())
+    }
+
+    /// Iterates over all stores.
+    pub fn stores(&self) -> impl Iterator<Item = &StoreInfo> {
+        self.stores.values()
+    }
+}
+
+/// Checks that a store placement weight is at most `MAX_STORE_WEIGHT`.
+pub fn check_weight(weight: u32) -> Result<()> {
+    if weight > MAX_STORE_WEIGHT {
+        return Err(Error::Value(format!(
+            "Store weight {} is above the maximum {}",
+            weight, MAX_STORE_WEIGHT
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn only_legal_state_transitions_are_allowed() {
+        use StoreState::*;
+        let legal = [
+            (Pending, Up),
+            (Pending, Removed),
+            (Up, Draining),
+            (Up, Down),
+            (Draining, Up),
+            (Draining, Down),
+            (Draining, Removed),
+            (Down, Up),
+            (Down, Draining),
+            (Down, Removed),
+        ];
+        let states = [Pending, Up, Draining, Down, Removed];
+        for from in states {
+            for to in states {
+                let mut store = StoreInfo {
+                    id: 1,
+                    address: "10.0.0.1:20160".into(),
+                    state: from,
+                    labels: Labels::new(),
+                    capacity: 0,
+                    used: 0,
+                    last_heartbeat_ms: 0,
+                    weight: None,
+                };
+                let allowed = from == to || legal.contains(&(from, to));
+                assert_eq!(from.can_transition_to(to), allowed, "{:?} -> {:?}", from, to);
+                match store.transition(to) {
+                    Ok(()) => assert!(allowed && store.state == to, "{:?} -> {:?}", from, to),
+                    Err(Error::Value(_)) => {
+                        assert!(!allowed && store.state == from, "{:?} -> {:?}", from, to)
+                    }
+                    Err(err) => panic!("unexpected error {}", err),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn a_removed_store_isnt_resurrected() {
+        let mut registry = StoreRegistry::new();
+        let config = StoreConfig::default();
+        registry.register(1, "10.0.0.1:20160".into(), Labels::new(), 0).unwrap();
+        registry.set_state(1, StoreState::Down, 0, &config).unwrap();
+        registry.set_state(1, StoreState::Removed, 0, &config).unwrap();
+
+        assert!(matches!(registry.set_state(1, StoreState::Up, 0, &config), Err(Error::Value(_))));
+        let result = registry.register(1, "10.0.0.1:20160".into(), Labels::new(), 1);
+        assert!(matches!(result, Err(Error::Value(_))));
+        assert_eq!(registry.get(1).unwrap().state, StoreState::Removed);
+
+        // Once the tombstone expires, the id is free for a new store.
+        let expired = config.tombstone_retention_ms;
+        let store = registry.register(1, "10.0.0.2:20160".into(), Labels::new(), expired).unwrap();
+        assert_eq!(store.state, StoreState::Up);
+    }
+
+    #[test]
+    fn registering_a_live_stores_address_under_another_id_is_rejected() {
+        let mut registry = StoreRegistry::new();
+        registry.register(1, "10.0.0.1:20160".into(), Labels::new(), 0).unwrap();
+
+        let result = registry.register(2, "10.0.0.1:20160".into(), Labels::new(), 0);
+        assert!(matches!(result, Err(Error::Value(_))));
+        assert!(matches!(registry.get(2), Err(Error::NotFound(_))));
+        assert_eq!(registry.get(1).unwrap().address, "10.0.0.1:20160");
+    }
+
+    #[test]
+    fn a_restarting_store_can_re_register_under_its_id() {
+        let mut registry = StoreRegistry::new();
+        registry.register(1, "10.0.0.1:20160".into(), Labels::new(), 0).unwrap();
+
+        // Same id, same address.
+        let store = registry.register(1, "10.0.0.1:20160".into(), Labels::new(), 10).unwrap();
+        assert_eq!(store.state, StoreState::Up);
+        assert_eq!(store.last_heartbeat_ms, 10);
+
+        // Same id, new address, which frees the old one.
+        let store = registry.register(1, "10.0.0.9:20160".into(), Labels::new(), 20).unwrap();
+        assert_eq!(store.address, "10.0.0.9:20160");
+        registry.register(2, "10.0.0.1:20160".into(), Labels::new(), 20).unwrap();
+        assert_eq!(registry.stores().count(), 2);
+    }
+
+    #[test]
+    fn placement_needs_enough_stores_accepting_new_regions() {
+        let rng = &mut StdRng::seed_from_u64(0);
+        let mut registry = StoreRegistry::new();
+        let config = StoreConfig::default();
+        for id in 1..=3 {
+            registry.register(id, format!("10.0.0.{}:20160", id), Labels::new(), 0).unwrap();
+        }
+        assert_eq!(registry.place(3, &config, &HashMap::new(), rng).unwrap().len(), 3);
+
+        registry.set_state(3, StoreState::Draining, 0, &config).unwrap();
+        assert_eq!(registry.place(3, &config, &HashMap::new(), rng), Err(Error::NoAvailableStores(3)));
+        let mut stores = registry.place(2, &config, &HashMap::new(), rng).unwrap();
+        stores.sort_unstable();
+        assert_eq!(stores, vec![1, 2]);
+    }
+
+    #[test]
+    fn the_address_of_a_down_store_can_be_reused() {
+        let mut registry = StoreRegistry::new();
+        let config = StoreConfig::default();
+        registry.register(1, "10.0.0.1:20160".into(), Labels::new(), 0).unwrap();
+        registry.set_state(1, StoreState::Down, 0, &config).unwrap();
+
+        let store = registry.register(2, "10.0.0.1:20160".into(), Labels::new(), 0).unwrap();
+        assert_eq!(store.address, "10.0.0.1:20160");
+        assert_eq!(registry.get(1).unwrap().state, StoreState::Down);
+    }
+
+    #[test]
+    fn drains_are_overdue_past_the_drain_timeout_unless_disabled() {
+        let mut registry = StoreRegistry::new();
+        let mut config = StoreConfig { drain_timeout_ms: 100, ..StoreConfig::default() };
+        registry.register(1, "10.0.0.1:20160".into(), Labels::new(), 0).unwrap();
+        registry.register(2, "10.0.0.2:20160".into(), Labels::new(), 0).unwrap();
+        registry.set_state(1, StoreState::Draining, 0, &config).unwrap();
+        registry.set_state(2, StoreState::Draining, 50, &config).unwrap();
+
+        assert!(registry.overdue_drains(99, &config).is_empty());
+        assert_eq!(registry.overdue_drains(100, &config), vec![1]);
+        assert_eq!(registry.overdue_drains(150, &config), vec![1, 2]);
+
+        // Leaving the draining state resets the timeout.
+        registry.set_state(1, StoreState::Up, 150, &config).unwrap();
+        registry.set_state(1, StoreState::Draining, 150, &config).unwrap();
+        assert_eq!(registry.overdue_drains(150, &config), vec![2]);
+
+        config.drain_timeout_ms = 0;
+        assert!(registry.overdue_drains(u64::MAX, &config).is_empty());
+    }
+
+    #[test]
+    fn equally_weighted_stores_are_ordered_by_the_rng() {
+        let mut registry = StoreRegistry::new();
+        let config = StoreConfig::default();
+        for id in 1..=5 {
+            registry.register(id, format!("10.0.0.{}:20160", id), Labels::new(), 0).unwrap();
+            registry.heartbeat(id, 1_000, if id == 5 { 500 } else { 0 }, 0).unwrap();
+        }
+        let candidates =
+            |seed| registry.placement_candidates(&config, &HashMap::new(), &mut StdRng::seed_from_u64(seed));
+
+        // The same seed always gives the same order, whatever order the
+        // stores are kept in.
+        assert_eq!(candidates(7), candidates(7));
+        let orders: HashSet<Vec<u64>> = (0..50).map(candidates).collect();
+        assert!(orders.len() > 1, "{:?}", orders);
+        // Seeds only decide ties: the fuller store always comes last.
+        assert!(orders.iter().all(|order| order.len() == 5 && order[4] == 5), "{:?}", orders);
+    }
+
+    #[test]
+    fn pinned_weights_override_free_space() {
+        let rng = &mut StdRng::seed_from_u64(0);
+        let mut registry = StoreRegistry::new();
+        let config = StoreConfig::default();
+        for id in 1..=4 {
+            registry.register(id, format!("10.0.0.{}:20160", id), Labels::new(), 0).unwrap();
+        }
+        // Store 1 is the emptiest, store 4 the fullest.
+        for id in 1..=4 {
+            registry.heartbeat(id, 1_000, id * 100, 0).unwrap();
+        }
+        assert_eq!(registry.placement_candidates(&config, &HashMap::new(), rng), vec![1, 2, 3, 4]);
+
+        registry.set_weight(4, MAX_STORE_WEIGHT).unwrap();
+        registry.set_weight(1, 0).unwrap();
+        assert_eq!(registry.placement_candidates(&config, &HashMap::new(), rng), vec![4, 2, 3]);
+        assert_eq!(registry.place(3, &config, &HashMap::new(), rng).unwrap(), vec![4, 2, 3]);
+        assert_eq!(registry.place(4, &config, &HashMap::new(), rng), Err(Error::NoAvailableStores(4)));
+    }
+
+    #[test]
+    fn weights_above_the_maximum_are_rejected() {
+        let mut registry = StoreRegistry::new();
+        registry.register(1, "10.0.0.1:20160".into(), Labels::new(), 0).unwrap();
+        assert!(matches!(registry.set_weight(1, MAX_STORE_WEIGHT + 1), Err(Error::Value(_))));
+        assert_eq!(registry.get(1).unwrap().weight, None);
+        assert!(matches!(registry.set_weight(2, 0), Err(Error::NotFound(_))));
+    }
+}
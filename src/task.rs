@@ -0,0 +1,48 @@
+use std::future::Future;
+use tokio::task::JoinHandle;
+
+/// Starts serving the PD's tasks to tokio-console, with the `tokio-console`
+/// feature, by installing the console subscriber as the global tracing
+/// subscriber. The binary running the PD should call this once, at startup.
+/// Otherwise, this does nothing.
+pub fn init_console() {
+    #[cfg(feature = "tokio-console")]
+    console_subscriber::init();
+}
+
+/// Spawns a named task. With the `tokio-console` feature, and when built with
+/// `RUSTFLAGS="--cfg tokio_unstable"`, the name identifies the task in
+/// tokio-console, e.g. to find the one that is stuck when the PD becomes
+/// unresponsive. Otherwise, this is a plain `tokio::spawn()`.
+pub fn spawn<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "tokio-console", tokio_unstable))]
+    {
+        tokio::task::Builder::new().name(name).spawn(future).expect("spawning a task can't fail")
+    }
+    #[cfg(not(all(feature = "tokio-console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::spawn(future)
+    }
+}
+
+/// Spawns a named blocking task, like `spawn()`.
+pub fn spawn_blocking<F, R>(name: &str, f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    #[cfg(all(feature = "tokio-console", tokio_unstable))]
+    {
+        tokio::task::Builder::new().name(name).spawn_blocking(f).expect("spawning a task can't fail")
+    }
+    #[cfg(not(all(feature = "tokio-console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::task::spawn_blocking(f)
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use tonic::codegen::BoxStream;
+use tonic::{Request, Response, Streaming};
+
+use crate::consistency::ConsistencyReport;
+use crate::error::{Error, Result, RpcResult};
+use crate::proto::placement_driver::{
+    AdvanceTimestampReply, AdvanceTimestampRequest, AllocIdReply, AllocIdRequest, BatchHeartbeatReply,
+    BatchHeartbeatRequest, ClusterStatusReply, ClusterStatusRequest, ConfirmTransferReply,
+    ConfirmTransferRequest, CreateKeyspaceReply, CreateKeyspaceRequest, DataLocRangeReply,
+    DataLocRangeRequest, DataLocReply, DataLocRequest, DeleteKeyspaceReply, DeleteKeyspaceRequest,
+    DescribeStoreKeyspaceReply, DescribeStoreKeyspaceRequest, DumpTopologyReply, DumpTopologyRequest,
+    FlushReply, FlushRequest, GetGcSafePointReply, GetGcSafePointRequest, GetRegionByIdReply,
+    GetRegionByIdRequest, InjectOperationReply, InjectOperationRequest, PinLeaderReply, PinLeaderRequest,
+    PlacementDriver, QueryStoresReply, QueryStoresRequest, RegionHeartbeatReply, RegionHeartbeatRequest,
+    RegisterStoreReply, RegisterStoreRequest, ReplayTopologyReply, ReplayTopologyRequest,
+    SetSchedulingEnabledReply, SetSchedulingEnabledRequest, SetStoreStateReply, SetStoreStateRequest,
+    StoreHeartbeatReply, StoreHeartbeatRequest, TsoReply, TsoRequest, UnpinLeaderReply, UnpinLeaderRequest,
+    UpdateGcSafePointReply, UpdateGcSafePointRequest, VerifyConsistencyReply, VerifyConsistencyRequest,
+    WatchGcSafePointReply, WatchGcSafePointRequest, WatchTopologyReply, WatchTopologyRequest,
+};
+use crate::routing::{RangeDescription, RegionInfo};
+use crate::status::ClusterStatus;
+use crate::store::StoreInfo;
+
+/// A scriptable fake PD for downstream tests, e.g. of a toyDB client, which
+/// can be served with `PlacementDriverServer::new()` like the real one.
+///
+/// Timestamps, data locations and ids are served from canned responses, in
+/// order, and calls beyond them fail with `Error::Internal`. Errors can be
+/// injected for any RPC by its method name, e.g. `get_timestamp`, and are
+/// returned before any canned response. All other RPCs succeed with empty
+/// replies.
+#[derive(Default)]
+pub struct MockPd {
+    timestamps: Mutex<VecDeque<u64>>,
+    locations: Mutex<VecDeque<RegionInfo>>,
+    ids: Mutex<VecDeque<u64>>,
+    errors: Mutex<HashMap<String, VecDeque<Error>>>,
+}
+
+impl MockPd {
+    /// Creates a mock PD without any canned responses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues timestamps returned by successive `get_timestamp` calls.
+    pub fn expect_get_timestamp_returns(mut self, timestamps: Vec<u64>) -> Self {
+        self.timestamps.get_mut().unwrap_or_else(|err| err.into_inner()).extend(timestamps);
+        self
+    }
+
+    /// Queues regions returned by successive `get_data_location` calls,
+    /// regardless of the requested key.
+    pub fn expect_get_data_location_returns(mut self, regions: Vec<RegionInfo>) -> Self {
+        self.locations.get_mut().unwrap_or_else(|err| err.into_inner()).extend(regions);
+        self
+    }
+
+    /// Queues ids returned by successive `alloc_id` calls.
+    pub fn expect_alloc_id_returns(mut self, ids: Vec<u64>) -> Self {
+        self.ids.get_mut().unwrap_or_else(|err| err.into_inner()).extend(ids);
+        self
+    }
+
+    /// Queues an error returned by the next call to the given RPC method,
+    /// e.g. `get_timestamp`. Several errors for a method are returned in
+    /// order.
+    pub fn expect_error(mut self, method: &str, err: Error) -> Self {
+        self.errors
+            .get_mut()
+            .unwrap_or_else(|err| err.into_inner())
+            .entry(method.to_string())
+            .or_default()
+            .push_back(err);
+        self
+    }
+
+    /// Returns the next injected error for a method, if any.
+    fn check_error(&self, method: &str) -> Result<()> {
+        match self.errors.lock()?.get_mut(method).and_then(|errors| errors.pop_front()) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Pops the next canned response for a method.
+    fn next<T>(&self, method: &str, queue: &Mutex<VecDeque<T>>) -> Result<T> {
+        self.check_error(method)?;
+        queue
+            .lock()?
+            .pop_front()
+            .ok_or_else(|| Error::Internal(format!("MockPd: no canned {} response", method)))
+    }
+}
+
+#[tonic::async_trait]
+impl PlacementDriver for MockPd {
+    async fn get_timestamp(&self, _: Request<TsoRequest>) -> RpcResult<TsoReply> {
+        let timestamp = self.next("get_timestamp", &self.timestamps)?;
+        Ok(Response::new(TsoReply { timestamp, server_time_ms: 0, count: 1 }))
+    }
+
+    type AllocateStreamStream = BoxStream<TsoReply>;
+
+    async fn allocate_stream(
+        &self,
+        _: Request<Streaming<TsoRequest>>,
+    ) -> RpcResult<Self::AllocateStreamStream> {
+        self.check_error("allocate_stream")?;
+        Ok(Response::new(Box::pin(tokio_stream::empty())))
+    }
+
+    async fn advance_timestamp(
+        &self,
+        _: Request<AdvanceTimestampRequest>,
+    ) -> RpcResult<AdvanceTimestampReply> {
+        self.check_error("advance_timestamp")?;
+        Ok(Response::new(AdvanceTimestampReply {}))
+    }
+
+    async fn flush(&self, _: Request<FlushRequest>) -> RpcResult<FlushReply> {
+        self.check_error("flush")?;
+        Ok(Response::new(FlushReply { bytes_written: 0, watermark: 0 }))
+    }
+
+    async fn update_gc_safe_point(
+        &self,
+        _: Request<UpdateGcSafePointRequest>,
+    ) -> RpcResult<UpdateGcSafePointReply> {
+        self.check_error("update_gc_safe_point")?;
+        Ok(Response::new(UpdateGcSafePointReply { safe_point: 0 }))
+    }
+
+    async fn get_gc_safe_point(&self, _: Request<GetGcSafePointRequest>) -> RpcResult<GetGcSafePointReply> {
+        self.check_error("get_gc_safe_point")?;
+        Ok(Response::new(GetGcSafePointReply { safe_point: 0 }))
+    }
+
+    async fn inject_operation(&self, _: Request<InjectOperationRequest>) -> RpcResult<InjectOperationReply> {
+        self.check_error("inject_operation")?;
+        Ok(Response::new(InjectOperationReply {}))
+    }
+
+    async fn pin_leader(&self, _: Request<PinLeaderRequest>) -> RpcResult<PinLeaderReply> {
+        self.check_error("pin_leader")?;
+        Ok(Response::new(PinLeaderReply {}))
+    }
+
+    async fn unpin_leader(&self, _: Request<UnpinLeaderRequest>) -> RpcResult<UnpinLeaderReply> {
+        self.check_error("unpin_leader")?;
+        Ok(Response::new(UnpinLeaderReply {}))
+    }
+
+    async fn set_scheduling_enabled(
+        &self,
+        _: Request<SetSchedulingEnabledRequest>,
+    ) -> RpcResult<SetSchedulingEnabledReply> {
+        self.check_error("set_scheduling_enabled")?;
+        Ok(Response::new(SetSchedulingEnabledReply {}))
+    }
+
+    async fn verify_consistency(
+        &self,
+        _: Request<VerifyConsistencyRequest>,
+    ) -> RpcResult<VerifyConsistencyReply> {
+        self.check_error("verify_consistency")?;
+        let report = bincode::serialize(&ConsistencyReport::default()).map_err(Error::from)?;
+        Ok(Response::new(VerifyConsistencyReply { report }))
+    }
+
+    async fn get_data_location(&self, _: Request<DataLocRequest>) -> RpcResult<DataLocReply> {
+        let region = self.next("get_data_location", &self.locations)?;
+        let regions = bincode::serialize(&vec![region]).map_err(Error::from)?;
+        Ok(Response::new(DataLocReply {
+            regions,
+            cache_ttl_ms: 0,
+            stale: false,
+            transferring: false,
+            staleness_ms: 0,
+        }))
+    }
+
+    async fn get_data_location_range(&self, _: Request<DataLocRangeRequest>) -> RpcResult<DataLocRangeReply> {
+        self.check_error("get_data_location_range")?;
+        let regions = bincode::serialize(&Vec::<RegionInfo>::new()).map_err(Error::from)?;
+        Ok(Response::new(DataLocRangeReply { regions, next_start_key: Vec::new(), stale: false }))
+    }
+
+    async fn create_keyspace(&self, _: Request<CreateKeyspaceRequest>) -> RpcResult<CreateKeyspaceReply> {
+        self.check_error("create_keyspace")?;
+        Ok(Response::new(CreateKeyspaceReply {}))
+    }
+
+    async fn delete_keyspace(&self, _: Request<DeleteKeyspaceRequest>) -> RpcResult<DeleteKeyspaceReply> {
+        self.check_error("delete_keyspace")?;
+        Ok(Response::new(DeleteKeyspaceReply {}))
+    }
+
+    async fn get_region_by_id(
+        &self,
+        request: Request<GetRegionByIdRequest>,
+    ) -> RpcResult<GetRegionByIdReply> {
+        self.check_error("get_region_by_id")?;
+        Err(Error::NotFound(format!("Region {} not found", request.into_inner().region_id)).into())
+    }
+
+    async fn alloc_id(&self, _: Request<AllocIdRequest>) -> RpcResult<AllocIdReply> {
+        let id = self.next("alloc_id", &self.ids)?;
+        Ok(Response::new(AllocIdReply { id }))
+    }
+
+    async fn register_store(&self, _: Request<RegisterStoreRequest>) -> RpcResult<RegisterStoreReply> {
+        self.check_error("register_store")?;
+        Ok(Response::new(RegisterStoreReply {}))
+    }
+
+    async fn query_stores(&self, _: Request<QueryStoresRequest>) -> RpcResult<QueryStoresReply> {
+        self.check_error("query_stores")?;
+        let stores = bincode::serialize(&Vec::<StoreInfo>::new()).map_err(Error::from)?;
+        Ok(Response::new(QueryStoresReply { stores }))
+    }
+
+    async fn store_heartbeat(&self, _: Request<StoreHeartbeatRequest>) -> RpcResult<StoreHeartbeatReply> {
+        self.check_error("store_heartbeat")?;
+        Ok(Response::new(StoreHeartbeatReply {}))
+    }
+
+    async fn confirm_transfer(&self, _: Request<ConfirmTransferRequest>) -> RpcResult<ConfirmTransferReply> {
+        self.check_error("confirm_transfer")?;
+        Ok(Response::new(ConfirmTransferReply { region: Vec::new() }))
+    }
+
+    async fn dump_topology(&self, _: Request<DumpTopologyRequest>) -> RpcResult<DumpTopologyReply> {
+        self.check_error("dump_topology")?;
+        Ok(Response::new(DumpTopologyReply { json: String::new(), dot: String::new() }))
+    }
+
+    async fn replay_topology(&self, _: Request<ReplayTopologyRequest>) -> RpcResult<ReplayTopologyReply> {
+        self.check_error("replay_topology")?;
+        Ok(Response::new(ReplayTopologyReply { snapshot: Vec::new() }))
+    }
+
+    async fn batch_heartbeat(&self, _: Request<BatchHeartbeatRequest>) -> RpcResult<BatchHeartbeatReply> {
+        self.check_error("batch_heartbeat")?;
+        Ok(Response::new(BatchHeartbeatReply { stores: Vec::new() }))
+    }
+
+    async fn set_store_state(&self, _: Request<SetStoreStateRequest>) -> RpcResult<SetStoreStateReply> {
+        self.check_error("set_store_state")?;
+        Ok(Response::new(SetStoreStateReply { store: Vec::new() }))
+    }
+
+    async fn region_heartbeat(&self, _: Request<RegionHeartbeatRequest>) -> RpcResult<RegionHeartbeatReply> {
+        self.check_error("region_heartbeat")?;
+        Ok(Response::new(RegionHeartbeatReply { compact: false, split_keys: Vec::new() }))
+    }
+
+    type WatchTopologyStream = BoxStream<WatchTopologyReply>;
+
+    async fn watch_topology(&self, _: Request<WatchTopologyRequest>) -> RpcResult<Self::WatchTopologyStream> {
+        self.check_error("watch_topology")?;
+        Ok(Response::new(Box::pin(tokio_stream::empty())))
+    }
+
+    type WatchGcSafePointStream = BoxStream<WatchGcSafePointReply>;
+
+    async fn watch_gc_safe_point(
+        &self,
+        _: Request<WatchGcSafePointRequest>,
+    ) -> RpcResult<Self::WatchGcSafePointStream> {
+        self.check_error("watch_gc_safe_point")?;
+        Ok(Response::new(Box::pin(tokio_stream::empty())))
+    }
+
+    async fn get_cluster_status(&self, _: Request<ClusterStatusRequest>) -> RpcResult<ClusterStatusReply> {
+        self.check_error("get_cluster_status")?;
+        let status = bincode::serialize(&ClusterStatus::default()).map_err(Error::from)?;
+        Ok(Response::new(ClusterStatusReply { status }))
+    }
+
+    async fn describe_store_keyspace(
+        &self,
+        _: Request<DescribeStoreKeyspaceRequest>,
+    ) -> RpcResult<DescribeStoreKeyspaceReply> {
+        self.check_error("describe_store_keyspace")?;
+        let ranges = bincode::serialize(&Vec::<RangeDescription>::new()).map_err(Error::from)?;
+        Ok(Response::new(DescribeStoreKeyspaceReply { ranges }))
+    }
+}
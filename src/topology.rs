@@ -0,0 +1,134 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::Write;
+
+use crate::error::Result;
+use crate::labels::Labels;
+use crate::routing::RegionInfo;
+use crate::store::{StoreInfo, StoreState};
+
+/// The version of the topology dump schema. It is bumped on any change that
+/// could break consumers, i.e. anything but adding fields.
+pub const TOPOLOGY_SCHEMA_VERSION: u32 = 1;
+
+/// A dump of the cluster topology, for visualization and external tooling.
+/// Its JSON form is a stable schema: fields are only ever added, and any
+/// other change bumps `schema_version`. Keys are hex-encoded, with the empty
+/// string as the unbounded sentinel.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TopologyDump {
+    /// The schema version, see `TOPOLOGY_SCHEMA_VERSION`.
+    pub schema_version: u32,
+    /// The topology version the dump was taken at.
+    pub topology_version: u64,
+    /// The ids of all keyspaces, in ascending order.
+    pub keyspaces: Vec<u32>,
+    /// All stores, by ascending id.
+    pub stores: Vec<TopologyStore>,
+    /// All regions, by ascending id.
+    pub regions: Vec<TopologyRegion>,
+}
+
+/// A store in a topology dump.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TopologyStore {
+    /// The store id.
+    pub id: u64,
+    /// The address clients reach the store at.
+    pub address: String,
+    /// The store state, e.g. "Up" or "Draining".
+    pub state: StoreState,
+    /// The store labels.
+    pub labels: Labels,
+}
+
+/// A region in a topology dump.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TopologyRegion {
+    /// The region id.
+    pub id: u64,
+    /// The keyspace the region belongs to.
+    pub keyspace_id: u32,
+    /// The hex-encoded inclusive start key, empty if unbounded.
+    pub start_key: String,
+    /// The hex-encoded exclusive end key, empty if unbounded.
+    pub end_key: String,
+    /// The region epoch.
+    pub epoch: u64,
+    /// The ids of the stores holding a replica.
+    pub stores: Vec<u64>,
+    /// The id of the store holding the leader replica.
+    pub leader: u64,
+}
+
+impl From<&StoreInfo> for TopologyStore {
+    fn from(store: &StoreInfo) -> Self {
+        Self {
+            id: store.id,
+            address: store.address.clone(),
+            state: store.state,
+            labels: store.labels.clone(),
+        }
+    }
+}
+
+impl From<&RegionInfo> for TopologyRegion {
+    fn from(region: &RegionInfo) -> Self {
+        Self {
+            id: region.id,
+            keyspace_id: region.keyspace_id,
+            start_key: hex(&region.start_key),
+            end_key: hex(&region.end_key),
+            epoch: region.epoch,
+            stores: region.stores.clone(),
+            leader: region.leader,
+        }
+    }
+}
+
+impl TopologyDump {
+    /// Serializes the dump as JSON.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Renders the dump as a GraphViz DOT digraph, with a node per store and
+    /// region, and an edge from each region to each store holding one of its
+    /// replicas. Edges to leaders are bold.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph topology {\n    rankdir=LR;\n");
+        // Writing to a String can't fail.
+        for store in &self.stores {
+            let _ = writeln!(
+                dot,
+                "    store{} [shape=box, label=\"store {}\\n{}\\n{:?}\"];",
+                store.id,
+                store.id,
+                escape(&store.address),
+                store.state
+            );
+        }
+        for region in &self.regions {
+            let _ = writeln!(
+                dot,
+                "    region{} [shape=ellipse, label=\"region {} (keyspace {})\\n[{}, {})\\nepoch {}\"];",
+                region.id, region.id, region.keyspace_id, region.start_key, region.end_key, region.epoch
+            );
+            for store_id in &region.stores {
+                let style = if *store_id == region.leader { " [style=bold]" } else { "" };
+                let _ = writeln!(dot, "    region{} -> store{}{};", region.id, store_id, style);
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Hex-encodes a key.
+fn hex(key: &[u8]) -> String {
+    key.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Escapes a string for a DOT label.
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
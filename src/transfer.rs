@@ -0,0 +1,119 @@
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::error::{Error, Result};
+
+/// The default time a leader transfer may wait for the new leader to confirm
+/// before it is aborted, in milliseconds.
+pub const DEFAULT_TRANSFER_TIMEOUT_MS: u64 = 10_000;
+
+/// A leader transfer waiting for the new leader's store to confirm it is
+/// ready to take over.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingTransfer {
+    /// The region id.
+    pub region_id: u64,
+    /// The region epoch the transfer was started at.
+    pub epoch: u64,
+    /// The store currently holding the leader replica.
+    pub from_store_id: u64,
+    /// The store taking over the leader replica.
+    pub to_store_id: u64,
+    /// The time after which the transfer is aborted, in milliseconds.
+    pub deadline_ms: u64,
+}
+
+/// Tracks two-phase leader transfers. A transfer is first marked pending,
+/// during which lookups keep returning the old leader flagged as
+/// transferring. Once the new leader's store confirms, the leader is flipped
+/// and the epoch bumped in one step, so clients never see a leader that
+/// isn't ready. Unconfirmed transfers are aborted at their deadline.
+#[derive(Default)]
+pub struct TransferTracker {
+    /// The pending transfers, by region id.
+    transfers: HashMap<u64, PendingTransfer>,
+}
+
+impl TransferTracker {
+    /// Creates a tracker without any pending transfers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks a transfer pending. Only one transfer per region may be pending
+    /// at a time, though an expired one is replaced.
+    pub fn begin(&mut self, transfer: PendingTransfer, now_ms: u64) -> Result<()> {
+        if let Some(pending) = self.get(transfer.region_id, now_ms) {
+            return Err(Error::Value(format!(
+                "Region {} is already transferring its leader to store {}",
+                pending.region_id, pending.to_store_id
+            )));
+        }
+        self.transfers.insert(transfer.region_id, transfer);
+        Ok(())
+    }
+
+    /// Completes a pending transfer confirmed by the given store, returning
+    /// it. Fails if no transfer to that store is pending, or if it expired,
+    /// in which case it is aborted.
+    pub fn confirm(&mut self, region_id: u64, store_id: u64, now_ms: u64) -> Result<PendingTransfer> {
+        let transfer = match self.transfers.get(&region_id) {
+            Some(transfer) if transfer.to_store_id == store_id => transfer.clone(),
+            _ => {
+                return Err(Error::Value(format!(
+                    "No pending leader transfer of region {} to store {}",
+                    region_id, store_id
+                )))
+            }
+        };
+        self.transfers.remove(&region_id);
+        if now_ms > transfer.deadline_ms {
+            return Err(Error::Value(format!(
+                "Leader transfer of region {} to store {} timed out and was aborted",
+                region_id, store_id
+            )));
+        }
+        Ok(transfer)
+    }
+
+    /// Returns the unexpired pending transfer of a region, if any.
+    pub fn get(&self, region_id: u64, now_ms: u64) -> Option<&PendingTransfer> {
+        self.transfers.get(&region_id).filter(|transfer| now_ms <= transfer.deadline_ms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(region_id: u64, to_store_id: u64, deadline_ms: u64) -> PendingTransfer {
+        PendingTransfer { region_id, epoch: 1, from_store_id: 1, to_store_id, deadline_ms }
+    }
+
+    #[test]
+    fn a_confirmed_transfer_completes_once() {
+        let mut tracker = TransferTracker::new();
+        tracker.begin(transfer(10, 2, 100), 0).unwrap();
+        assert!(matches!(tracker.begin(transfer(10, 3, 100), 0), Err(Error::Value(_))));
+        assert_eq!(tracker.get(10, 50), Some(&transfer(10, 2, 100)));
+
+        // Only the new leader's store can confirm.
+        assert!(matches!(tracker.confirm(10, 3, 50), Err(Error::Value(_))));
+        assert_eq!(tracker.confirm(10, 2, 50).unwrap(), transfer(10, 2, 100));
+        assert_eq!(tracker.get(10, 50), None);
+        assert!(matches!(tracker.confirm(10, 2, 50), Err(Error::Value(_))));
+    }
+
+    #[test]
+    fn an_unconfirmed_transfer_is_aborted_at_its_deadline() {
+        let mut tracker = TransferTracker::new();
+        tracker.begin(transfer(10, 2, 100), 0).unwrap();
+        assert!(tracker.get(10, 100).is_some());
+        assert_eq!(tracker.get(10, 101), None);
+
+        // An expired transfer is replaced by a new one.
+        tracker.begin(transfer(10, 3, 300), 101).unwrap();
+        assert!(matches!(tracker.confirm(10, 3, 301), Err(Error::Value(_))));
+        assert_eq!(tracker.get(10, 0), None);
+    }
+}
@@ -0,0 +1,126 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::{Channel, Endpoint, Server, Uri};
+
+use crate::config::ClientConfig;
+use crate::error::{Error, Result};
+use crate::limit::{InFlightLimitLayer, TimeoutLayer};
+use crate::proto::placement_driver::{PlacementDriverClient, PlacementDriverServer};
+use crate::server::FeatherPD;
+use crate::task;
+
+/// The scheme prefix of UNIX domain socket addresses.
+const UNIX_SCHEME: &str = "unix:";
+
+/// An address to serve on or connect to: either a TCP socket address such as
+/// `127.0.0.1:2379`, or a UNIX domain socket path such as `unix:/tmp/pd.sock`.
+/// UNIX domain sockets avoid TCP overhead when the PD and its stores share a
+/// host.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    /// A TCP socket address.
+    Tcp(SocketAddr),
+    /// A UNIX domain socket path.
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix(UNIX_SCHEME) {
+            Some("") => Err(Error::Parse(format!("Missing socket path in address {}", s))),
+            Some(path) => Ok(Address::Unix(path.into())),
+            None => Ok(Address::Tcp(s.parse()?)),
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_SCHEME, path.display()),
+        }
+    }
+}
+
+/// Serves the PD on the given address until the server fails, applying the
+/// message size, concurrency and timeout limits of the `server`
+/// configuration. For UNIX domain sockets, a stale socket file left behind by a previous run is removed
+/// first. Requests over a UNIX domain socket carry no client address, so
+/// `client_addr()` returns None for them. With the `reflection` feature, the
+/// PD also serves gRPC server reflection, e.g. for grpcurl.
+pub async fn serve(pd: FeatherPD, addr: &Address) -> Result<()> {
+    spawn_serve(pd, addr, std::future::pending())?.await?
+}
+
+/// Like `serve()`, but serves from a spawned task, returning its handle. Once
+/// `signal` completes, the server stops accepting connections and requests,
+/// and the task returns when those in flight are done. See
+/// `shutdown::Shutdown` for shutting down the whole PD.
+pub fn spawn_serve(
+    pd: FeatherPD,
+    addr: &Address,
+    signal: impl Future<Output = ()> + Send + 'static,
+) -> Result<JoinHandle<Result<()>>> {
+    let config = pd.config().server.clone();
+    let service = PlacementDriverServer::new(pd)
+        .max_decoding_message_size(config.max_message_size)
+        .max_encoding_message_size(config.max_message_size);
+    let timeout = match config.default_rpc_timeout_ms {
+        0 => None,
+        timeout_ms => Some(TimeoutLayer::new(Duration::from_millis(timeout_ms))),
+    };
+    let router = Server::builder()
+        .max_concurrent_streams(config.max_concurrent_streams)
+        .layer(InFlightLimitLayer::new(config.max_in_flight))
+        .layer(tower::util::option_layer(timeout))
+        .add_service(service);
+    #[cfg(feature = "reflection")]
+    let router = router.add_service(
+        tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(crate::proto::placement_driver::FILE_DESCRIPTOR_SET)
+            .build()
+            .map_err(|err| Error::Internal(format!("Failed to build the reflection service: {}", err)))?,
+    );
+    let serving = match addr {
+        Address::Tcp(addr) => {
+            let serving = router.serve_with_shutdown(*addr, signal);
+            task::spawn("featherpd-serve", async move { Ok(serving.await?) })
+        }
+        Address::Unix(path) => {
+            match std::fs::remove_file(path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            let incoming = UnixListenerStream::new(UnixListener::bind(path)?);
+            let serving = router.serve_with_incoming_shutdown(incoming, signal);
+            task::spawn("featherpd-serve", async move { Ok(serving.await?) })
+        }
+    };
+    Ok(serving)
+}
+
+/// Connects a client to a PD serving on the given address, limiting message
+/// sizes to `client.max_message_size`.
+pub async fn connect(addr: &Address, config: &ClientConfig) -> Result<PlacementDriverClient<Channel>> {
+    let channel = match addr {
+        Address::Tcp(addr) => Endpoint::try_from(format!("http://{}", addr))?.connect().await?,
+        Address::Unix(path) => {
+            let path = path.clone();
+            // The URI is required by the endpoint, but ignored by the connector.
+            Endpoint::try_from("http://[::]:0")?
+                .connect_with_connector(tower::service_fn(move |_: Uri| UnixStream::connect(path.clone())))
+                .await?
+        }
+    };
+    Ok(PlacementDriverClient::new(channel)
+        .max_decoding_message_size(config.max_message_size)
+        .max_encoding_message_size(config.max_message_size))
+}
@@ -0,0 +1,336 @@
+use log::warn;
+use std::sync::Arc;
+
+use crate::audit::AuditLog;
+use crate::checked::add_or_err;
+use crate::checkpoint::{Checkpoint, CheckpointStore};
+use crate::error::{Error, Result};
+
+/// The default number of timestamps reserved by each persisted window.
+pub const DEFAULT_WINDOW_SIZE: u64 = 1000;
+
+/// The largest timestamp ever handed out. Window ends saturate at u64::MAX,
+/// just above it, so they never overflow.
+pub const MAX_TIMESTAMP: u64 = u64::MAX - 1;
+
+/// The default first timestamp handed out by a fresh oracle.
+pub const DEFAULT_FIRST_TS: u64 = 1;
+
+/// A timestamp oracle handing out strictly increasing timestamps. To avoid a
+/// checkpoint write per timestamp, it reserves windows of timestamps ahead of
+/// time and only persists the end of each window.
+pub struct TimestampOracle {
+    /// The next timestamp to be assigned.
+    next_ts: u64,
+    /// The end of the reserved window (exclusive), as persisted.
+    window_end: u64,
+    /// The number of timestamps reserved per window.
+    window_size: u64,
+    /// The last timestamp handed out, if any. After recovery, this is the
+    /// last allocated timestamp recorded by the previous run.
+    last_allocated: Option<u64>,
+    /// The durable store for the window end.
+    checkpoint: Arc<dyn CheckpointStore>,
+    /// The audit log of issued windows, if enabled.
+    audit_log: Option<Arc<AuditLog>>,
+    /// The number of windows persisted because allocation exhausted the
+    /// previous one, since the oracle was created.
+    window_refills: u64,
+}
+
+impl TimestampOracle {
+    /// Creates a new timestamp oracle. It must be recovered before use.
+    pub fn new(checkpoint: Arc<dyn CheckpointStore>, window_size: u64) -> Self {
+        Self {
+            next_ts: DEFAULT_FIRST_TS,
+            window_end: DEFAULT_FIRST_TS,
+            window_size,
+            last_allocated: None,
+            checkpoint,
+            audit_log: None,
+            window_refills: 0,
+        }
+    }
+
+    /// Sets the first timestamp handed out when no checkpoint was ever
+    /// persisted, i.e. 0 or 1. Recovery from a checkpoint ignores it.
+    pub fn with_first_ts(mut self, first_ts: u64) -> Self {
+        self.next_ts = first_ts;
+        self.window_end = first_ts;
+        self
+    }
+
+    /// Records every issued window in the given audit log.
+    pub fn with_audit_log(mut self, audit_log: Arc<AuditLog>) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Returns the audit log, if enabled.
+    pub fn audit_log(&self) -> Option<Arc<AuditLog>> {
+        self.audit_log.clone()
+    }
+
+    /// Records an issued window in the audit log, if enabled. The window is
+    /// already persisted, so a failure to audit it is only logged.
+    fn audit(&self, window_start: u64, window_end: u64) {
+        if let Some(audit_log) = &self.audit_log {
+            if let Err(err) = audit_log.record(window_start, window_end) {
+                warn!("Failed to audit TSO window [{}, {}): {}", window_start, window_end, err);
+            }
+        }
+    }
+
+    /// Resumes allocation from a checkpoint loaded from the checkpoint store.
+    /// Everything below the window end may have been handed out already, so
+    /// allocation never resumes below it, whatever the last allocated value.
+    pub fn recover(&mut self, checkpoint: Option<Checkpoint>) {
+        if let Some(checkpoint) = checkpoint {
+            self.next_ts = self.next_ts.max(checkpoint.window_end);
+            self.last_allocated = checkpoint.last_allocated;
+        }
+        self.window_end = self.next_ts;
+    }
+
+    /// Returns the checkpoint store of the oracle.
+    pub fn checkpoint(&self) -> Arc<dyn CheckpointStore> {
+        self.checkpoint.clone()
+    }
+
+    /// Returns the next timestamp to be handed out.
+    pub fn next_ts(&self) -> u64 {
+        self.next_ts
+    }
+
+    /// Returns the end of the persisted window.
+    pub fn window_end(&self) -> u64 {
+        self.window_end
+    }
+
+    /// Returns the number of windows persisted because allocation exhausted
+    /// the previous one. Manual advances aren't counted.
+    pub fn window_refills(&self) -> u64 {
+        self.window_refills
+    }
+
+    /// Returns the number of timestamps left in the persisted window.
+    pub fn window_remaining(&self) -> u64 {
+        self.window_end.saturating_sub(self.next_ts)
+    }
+
+    /// Returns the last timestamp handed out, if any.
+    pub fn last_allocated(&self) -> Option<u64> {
+        self.last_allocated
+    }
+
+    /// Persists the current window end and last allocated timestamp, e.g.
+    /// on shutdown, so operators can audit the gap left by the window.
+    /// Returns the number of bytes written.
+    pub fn persist(&self) -> Result<u64> {
+        self.checkpoint.save(&Checkpoint { window_end: self.window_end, last_allocated: self.last_allocated })
+    }
+
+    /// Jumps the oracle forward so that the next timestamp handed out is
+    /// `target`, immediately persisting a new window starting there. The
+    /// oracle never moves backward: a target below the next timestamp is
+    /// rejected.
+    pub fn advance_to(&mut self, target: u64) -> Result<()> {
+        if target < self.next_ts {
+            return Err(Error::Value(format!(
+                "Cannot move the TSO backward from {} to {}",
+                self.next_ts, target
+            )));
+        }
+        if target > MAX_TIMESTAMP {
+            return Err(Error::Value(format!("Cannot move the TSO past {}", MAX_TIMESTAMP)));
+        }
+        let window_end = target.saturating_add(self.window_size);
+        self.checkpoint.save(&Checkpoint { window_end, last_allocated: self.last_allocated })?;
+        self.audit(target, window_end);
+        self.next_ts = target;
+        self.window_end = window_end;
+        Ok(())
+    }
+
+    /// Moves the oracle forward so that the next timestamp is at least
+    /// `target`, if it isn't already. Returns true if it moved.
+    pub fn bump_to(&mut self, target: u64) -> Result<bool> {
+        let target = target.min(MAX_TIMESTAMP);
+        if target <= self.next_ts {
+            return Ok(false);
+        }
+        self.advance_to(target)?;
+        Ok(true)
+    }
+
+    /// Allocates the next timestamp, persisting a new window first if the
+    /// current one is exhausted. This returns the pre-increment value of the
+    /// next timestamp, so a fresh oracle starts the sequence at its first
+    /// timestamp, e.g. 1, 2, 3 by default, or 0, 1, 2 with a first timestamp
+    /// of 0.
+    pub fn get_next_ts(&mut self) -> Result<u64> {
+        self.get_next_ts_batch(1, false).map(|(ts, _)| ts)
+    }
+
+    /// Allocates a batch of consecutive timestamps, persisting a new window
+    /// first if the batch doesn't fit in the current one. Returns the first
+    /// timestamp and the number of timestamps served.
+    ///
+    /// If the full batch would go past MAX_TIMESTAMP, it is rejected with
+    /// `Error::Value`, unless `partial_ok` is set, in which case as many
+    /// timestamps as remain are served. Once none remain, even a partial
+    /// batch is rejected.
+    pub fn get_next_ts_batch(&mut self, count: u64, partial_ok: bool) -> Result<(u64, u64)> {
+        if count == 0 {
+            return Err(Error::Value("Cannot allocate an empty batch of timestamps".into()));
+        }
+        let remaining = (MAX_TIMESTAMP + 1).saturating_sub(self.next_ts);
+        let count = match count {
+            count if count <= remaining => count,
+            _ if partial_ok && remaining > 0 => remaining,
+            count => {
+                return Err(Error::Value(format!(
+                    "Cannot allocate {} timestamps, only {} remain before overflow",
+                    count, remaining
+                )))
+            }
+        };
+        let end = add_or_err(self.next_ts, count)?;
+        if end > self.window_end {
+            let window_end = end.saturating_add(self.window_size);
+            self.checkpoint.save(&Checkpoint { window_end, last_allocated: self.last_allocated })?;
+            self.audit(self.next_ts, window_end);
+            self.window_end = window_end;
+            self.window_refills += 1;
+        }
+        let ts = self.next_ts;
+        // Every timestamp handed out must be above all previous ones, across
+        // restarts too, since recovery restores the last allocated one.
+        debug_assert!(
+            self.last_allocated.is_none_or(|last| ts > last),
+            "timestamp {} allocated after {:?}",
+            ts,
+            self.last_allocated
+        );
+        self.next_ts = end;
+        self.last_allocated = Some(end - 1);
+        Ok((ts, count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::MemoryCheckpoint;
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    /// Returns a recovered oracle over an in-memory checkpoint store.
+    fn oracle(checkpoint: Arc<MemoryCheckpoint>) -> TimestampOracle {
+        let mut tso = TimestampOracle::new(checkpoint.clone(), DEFAULT_WINDOW_SIZE);
+        tso.recover(checkpoint.load().unwrap());
+        tso
+    }
+
+    #[test]
+    fn a_million_concurrent_timestamps_are_unique_and_increase_per_thread() {
+        const THREADS: usize = 8;
+        const PER_THREAD: usize = 125_000;
+        let tso = Arc::new(Mutex::new(oracle(Arc::new(MemoryCheckpoint::new()))));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let tso = tso.clone();
+                std::thread::spawn(move || {
+                    (0..PER_THREAD).map(|_| tso.lock().unwrap().get_next_ts().unwrap()).collect::<Vec<u64>>()
+                })
+            })
+            .collect();
+        let mut seen = HashSet::with_capacity(THREADS * PER_THREAD);
+        for thread in threads {
+            let timestamps = thread.join().unwrap();
+            assert!(timestamps.windows(2).all(|pair| pair[0] < pair[1]));
+            seen.extend(timestamps);
+        }
+        assert_eq!(seen.len(), THREADS * PER_THREAD);
+    }
+
+    #[test]
+    fn a_restarted_oracle_resumes_above_the_last_timestamp() {
+        let checkpoint = Arc::new(MemoryCheckpoint::new());
+        let mut tso = oracle(checkpoint.clone());
+        let last = (0..10).map(|_| tso.get_next_ts().unwrap()).last().unwrap();
+        assert!(oracle(checkpoint).get_next_ts().unwrap() > last);
+    }
+
+    #[test]
+    fn a_fresh_oracle_starts_at_its_first_timestamp() {
+        let first_three = |first_ts| {
+            let checkpoint = Arc::new(MemoryCheckpoint::new());
+            let mut tso = TimestampOracle::new(checkpoint.clone(), 2).with_first_ts(first_ts);
+            tso.recover(checkpoint.load().unwrap());
+            (0..3).map(|_| tso.get_next_ts().unwrap()).collect::<Vec<u64>>()
+        };
+        assert_eq!(first_three(DEFAULT_FIRST_TS), vec![1, 2, 3]);
+        assert_eq!(first_three(1), vec![1, 2, 3]);
+        assert_eq!(first_three(0), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn recovery_from_a_checkpoint_ignores_the_first_timestamp() {
+        let checkpoint = Arc::new(MemoryCheckpoint::new());
+        let last = oracle(checkpoint.clone()).get_next_ts().unwrap();
+        let mut tso = TimestampOracle::new(checkpoint.clone(), DEFAULT_WINDOW_SIZE).with_first_ts(0);
+        tso.recover(checkpoint.load().unwrap());
+        assert!(tso.get_next_ts().unwrap() > last);
+    }
+
+    #[test]
+    fn batches_at_the_top_of_the_range_dont_overflow() {
+        let mut tso = oracle(Arc::new(MemoryCheckpoint::new()));
+        tso.advance_to(MAX_TIMESTAMP - 1).unwrap();
+        assert_eq!(tso.get_next_ts_batch(5, true).unwrap(), (MAX_TIMESTAMP - 1, 2));
+        assert!(matches!(tso.get_next_ts_batch(1, true), Err(Error::Value(_))));
+        assert!(matches!(tso.get_next_ts(), Err(Error::Value(_))));
+    }
+
+    #[test]
+    fn whole_batches_past_the_top_of_the_range_are_refused_without_allocating() {
+        let mut tso = oracle(Arc::new(MemoryCheckpoint::new()));
+        tso.advance_to(MAX_TIMESTAMP - 1).unwrap();
+        assert!(matches!(tso.get_next_ts_batch(5, false), Err(Error::Value(_))));
+        assert_eq!(tso.next_ts(), MAX_TIMESTAMP - 1);
+        assert_eq!(tso.get_next_ts_batch(2, false).unwrap(), (MAX_TIMESTAMP - 1, 2));
+    }
+
+    #[test]
+    fn window_refills_are_counted_once_each() {
+        let checkpoint = Arc::new(MemoryCheckpoint::new());
+        let mut tso = TimestampOracle::new(checkpoint.clone(), 10);
+        tso.recover(None);
+        assert_eq!((tso.window_refills(), tso.window_remaining()), (0, 0));
+
+        // Each refill persists a new window, and only refills do.
+        let mut persisted = Vec::new();
+        for _ in 0..25 {
+            let refills = tso.window_refills();
+            tso.get_next_ts().unwrap();
+            let window_end = checkpoint.load().unwrap().unwrap().window_end;
+            if persisted.last() != Some(&window_end) {
+                persisted.push(window_end);
+                assert_eq!(tso.window_refills(), refills + 1);
+            } else {
+                assert_eq!(tso.window_refills(), refills);
+            }
+            assert_eq!(tso.window_remaining(), window_end - tso.next_ts());
+        }
+        assert_eq!(tso.window_refills(), 3);
+        assert_eq!(persisted.len(), 3);
+
+        // A batch overrunning the window refills it once, and manual
+        // advances don't count.
+        tso.get_next_ts_batch(100, false).unwrap();
+        assert_eq!(tso.window_refills(), 4);
+        tso.advance_to(10_000).unwrap();
+        assert_eq!((tso.window_refills(), tso.window_remaining()), (4, 10));
+    }
+}
@@ -0,0 +1,261 @@
+use crate::admission::Priority;
+use crate::error::{Error, Result};
+use crate::labels::{parse_label, Labels};
+use crate::proto::placement_driver::{
+    self, DataLocRangeRequest, DataLocRequest, RegisterStoreRequest, TsoRequest,
+};
+use crate::server::ConsistencyLevel;
+use crate::store::check_weight;
+
+/// The maximum length of a key, in bytes.
+pub const MAX_KEY_LEN: usize = 8 * 1024;
+
+/// The maximum number of regions returned by a single range lookup page.
+pub const MAX_RANGE_LIMIT: u32 = 10_000;
+
+/// A timestamp request that has passed validation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidatedTsoRequest {
+    /// The request priority, which only matters under overload.
+    pub priority: Priority,
+    /// The number of timestamps requested, at least 1.
+    pub count: u64,
+    /// Whether a partial batch may be served near overflow.
+    pub partial_ok: bool,
+    /// The keyspace to allocate from.
+    pub keyspace_id: u32,
+}
+
+impl TryFrom<TsoRequest> for ValidatedTsoRequest {
+    type Error = Error;
+
+    fn try_from(request: TsoRequest) -> Result<Self> {
+        let priority = match placement_driver::Priority::from_i32(request.priority) {
+            Some(placement_driver::Priority::Normal) => Priority::Normal,
+            Some(placement_driver::Priority::High) => Priority::High,
+            None => return Err(Error::Value(format!("Unknown priority {}", request.priority))),
+        };
+        // An unset count is rejected rather than taken as 1, so a client bug
+        // dropping the count doesn't go unnoticed.
+        if request.count == 0 {
+            return Err(Error::Value("Timestamp count must be at least 1".into()));
+        }
+        Ok(Self {
+            priority,
+            count: request.count as u64,
+            partial_ok: request.partial_ok,
+            keyspace_id: request.keyspace_id,
+        })
+    }
+}
+
+/// A data location request that has passed validation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidatedDataLocRequest {
+    /// The keyspace to look the key up in.
+    pub keyspace_id: u32,
+    /// The key to locate, non-empty and at most MAX_KEY_LEN bytes.
+    pub key: Vec<u8>,
+    /// How fresh the routing must be.
+    pub consistency: ConsistencyLevel,
+}
+
+impl TryFrom<DataLocRequest> for ValidatedDataLocRequest {
+    type Error = Error;
+
+    fn try_from(request: DataLocRequest) -> Result<Self> {
+        validate_key(&request.key)?;
+        let consistency = match placement_driver::ConsistencyLevel::from_i32(request.consistency) {
+            Some(placement_driver::ConsistencyLevel::Eventual) => ConsistencyLevel::Eventual,
+            Some(placement_driver::ConsistencyLevel::BoundedStaleness) => {
+                ConsistencyLevel::BoundedStaleness(request.max_staleness_ms)
+            }
+            Some(placement_driver::ConsistencyLevel::Strong) => ConsistencyLevel::Strong,
+            None => return Err(Error::Value(format!("Unknown consistency level {}", request.consistency))),
+        };
+        Ok(Self { keyspace_id: request.keyspace_id, key: request.key, consistency })
+    }
+}
+
+/// A range data location request that has passed validation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidatedDataLocRangeRequest {
+    /// The keyspace to scan.
+    pub keyspace_id: u32,
+    /// The inclusive start key, or the continuation token of a previous page.
+    /// Empty means the start of the keyspace.
+    pub start_key: Vec<u8>,
+    /// The exclusive end key. Empty means unbounded.
+    pub end_key: Vec<u8>,
+    /// The maximum number of regions to return, at least 1.
+    pub limit: usize,
+}
+
+impl TryFrom<DataLocRangeRequest> for ValidatedDataLocRangeRequest {
+    type Error = Error;
+
+    fn try_from(request: DataLocRangeRequest) -> Result<Self> {
+        if !request.start_key.is_empty() {
+            validate_key(&request.start_key)?;
+        }
+        if !request.end_key.is_empty() {
+            validate_key(&request.end_key)?;
+        }
+        if request.limit == 0 || request.limit > MAX_RANGE_LIMIT {
+            return Err(Error::Value(format!(
+                "Range limit must be between 1 and {}, got {}",
+                MAX_RANGE_LIMIT, request.limit
+            )));
+        }
+        Ok(Self {
+            keyspace_id: request.keyspace_id,
+            start_key: request.start_key,
+            end_key: request.end_key,
+            limit: request.limit as usize,
+        })
+    }
+}
+
+/// A store registration request that has passed validation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValidatedRegisterStoreRequest {
+    /// The store id, non-zero.
+    pub store_id: u64,
+    /// The store address, non-empty.
+    pub address: String,
+    /// The store labels, with keys and values valid in label selectors.
+    pub labels: Labels,
+    /// The placement weight to pin, if any, at most `MAX_STORE_WEIGHT`.
+    pub weight: Option<u32>,
+}
+
+impl TryFrom<RegisterStoreRequest> for ValidatedRegisterStoreRequest {
+    type Error = Error;
+
+    fn try_from(request: RegisterStoreRequest) -> Result<Self> {
+        if request.store_id == 0 {
+            return Err(Error::Value("Store id must be non-zero".into()));
+        }
+        if request.address.is_empty() {
+            return Err(Error::Value("Store address must not be empty".into()));
+        }
+        for (key, value) in &request.labels {
+            parse_label(key)?;
+            parse_label(value)?;
+        }
+        let weight = request.weight.map(|weight| weight.weight);
+        if let Some(weight) = weight {
+            check_weight(weight)?;
+        }
+        Ok(Self {
+            store_id: request.store_id,
+            address: request.address,
+            labels: request.labels.into_iter().collect(),
+            weight,
+        })
+    }
+}
+
+/// Checks that a key is non-empty and within the maximum key length.
+pub fn validate_key(key: &[u8]) -> Result<()> {
+    if key.is_empty() {
+        return Err(Error::Value("Key must not be empty".into()));
+    }
+    if key.len() > MAX_KEY_LEN {
+        return Err(Error::Value(format!(
+            "Key of {} bytes exceeds the maximum of {} bytes",
+            key.len(),
+            MAX_KEY_LEN
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MAX_STORE_WEIGHT;
+
+    #[test]
+    fn register_store_requests_are_validated() {
+        let request =
+            |store_id, address: &str, label: (&str, &str), weight: Option<u32>| RegisterStoreRequest {
+                store_id,
+                address: address.into(),
+                labels: [(label.0.to_string(), label.1.to_string())].into_iter().collect(),
+                weight: weight.map(|weight| placement_driver::StoreWeight { weight }),
+            };
+        let ok = |request| ValidatedRegisterStoreRequest::try_from(request);
+        let rejected =
+            |request| matches!(ValidatedRegisterStoreRequest::try_from(request), Err(Error::Value(_)));
+
+        let validated = ok(request(1, "10.0.0.1:20160", ("zone", "z1"), Some(5))).unwrap();
+        assert_eq!(validated.store_id, 1);
+        assert_eq!(validated.address, "10.0.0.1:20160");
+        assert_eq!(validated.labels.get("zone").map(String::as_str), Some("z1"));
+        assert_eq!(validated.weight, Some(5));
+
+        assert!(rejected(request(0, "10.0.0.1:20160", ("zone", "z1"), None)));
+        assert!(rejected(request(1, "", ("zone", "z1"), None)));
+        assert!(matches!(ok(request(1, "10.0.0.1:20160", ("zone", "z 1"), None)), Err(Error::Parse(_))));
+        assert!(matches!(ok(request(1, "10.0.0.1:20160", ("", "z1"), None)), Err(Error::Parse(_))));
+        assert!(rejected(request(1, "10.0.0.1:20160", ("zone", "z1"), Some(MAX_STORE_WEIGHT + 1))));
+    }
+
+    #[test]
+    fn tso_requests_need_a_positive_count() {
+        let request = TsoRequest { count: 0, ..Default::default() };
+        assert!(matches!(ValidatedTsoRequest::try_from(request), Err(Error::Value(_))));
+
+        let request = TsoRequest { count: 3, partial_ok: true, keyspace_id: 7, ..Default::default() };
+        assert_eq!(
+            ValidatedTsoRequest::try_from(request).unwrap(),
+            ValidatedTsoRequest { priority: Priority::Normal, count: 3, partial_ok: true, keyspace_id: 7 }
+        );
+    }
+
+    #[test]
+    fn tso_requests_with_unknown_priorities_are_rejected() {
+        let request = TsoRequest { count: 1, priority: 7, ..Default::default() };
+        assert!(matches!(ValidatedTsoRequest::try_from(request), Err(Error::Value(_))));
+    }
+
+    #[test]
+    fn data_location_requests_need_a_valid_key_and_consistency() {
+        let request = |key: &[u8], consistency| DataLocRequest {
+            key: key.to_vec(),
+            consistency,
+            max_staleness_ms: 100,
+            ..Default::default()
+        };
+        assert!(matches!(ValidatedDataLocRequest::try_from(request(b"", 0)), Err(Error::Value(_))));
+        let long = vec![b'k'; MAX_KEY_LEN + 1];
+        assert!(matches!(ValidatedDataLocRequest::try_from(request(&long, 0)), Err(Error::Value(_))));
+        assert!(matches!(ValidatedDataLocRequest::try_from(request(b"k", 9)), Err(Error::Value(_))));
+
+        let bounded = placement_driver::ConsistencyLevel::BoundedStaleness as i32;
+        assert_eq!(
+            ValidatedDataLocRequest::try_from(request(b"k", bounded)).unwrap(),
+            ValidatedDataLocRequest {
+                keyspace_id: 0,
+                key: b"k".to_vec(),
+                consistency: ConsistencyLevel::BoundedStaleness(100),
+            }
+        );
+    }
+
+    #[test]
+    fn range_requests_need_a_limit_in_range() {
+        let request = |limit| DataLocRangeRequest { limit, ..Default::default() };
+        assert!(matches!(ValidatedDataLocRangeRequest::try_from(request(0)), Err(Error::Value(_))));
+        assert!(matches!(
+            ValidatedDataLocRangeRequest::try_from(request(MAX_RANGE_LIMIT + 1)),
+            Err(Error::Value(_))
+        ));
+        assert_eq!(ValidatedDataLocRangeRequest::try_from(request(MAX_RANGE_LIMIT)).unwrap().limit, 10_000);
+
+        let bad_end =
+            DataLocRangeRequest { end_key: vec![b'k'; MAX_KEY_LEN + 1], limit: 1, ..Default::default() };
+        assert!(matches!(ValidatedDataLocRangeRequest::try_from(bad_end), Err(Error::Value(_))));
+    }
+}
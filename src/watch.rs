@@ -0,0 +1,97 @@
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::comparator::{key_before, KeyComparator};
+use crate::routing::RegionInfo;
+
+/// The number of events buffered per subscriber before it is considered too
+/// slow and dropped.
+pub const WATCH_CHANNEL_CAPACITY: usize = 256;
+
+/// A topology change pushed to subscribers.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TopologyEvent {
+    /// A region's epoch or leader changed, e.g. due to a split or a leader
+    /// transfer. Carries the new region.
+    RegionChanged(RegionInfo),
+    /// The subscriber fell behind and was dropped. It must refetch the
+    /// ranges it cares about and subscribe again.
+    Resync,
+    /// The PD is shutting down, and this is the subscription's last event.
+    /// The subscriber must subscribe again once a PD is back.
+    ShuttingDown,
+}
+
+/// A subscription to the topology changes of a key range.
+struct Subscriber {
+    keyspace_id: u32,
+    start_key: Vec<u8>,
+    end_key: Vec<u8>,
+    tx: mpsc::Sender<TopologyEvent>,
+}
+
+impl Subscriber {
+    /// Returns true if the region overlaps the subscribed range.
+    fn overlaps(&self, region: &RegionInfo, comparator: &dyn KeyComparator) -> bool {
+        region.keyspace_id == self.keyspace_id
+            && key_before(comparator, &region.start_key, &self.end_key)
+            && key_before(comparator, &self.start_key, &region.end_key)
+    }
+}
+
+/// Fans out topology changes to subscribers. Each subscriber has a bounded
+/// channel; a subscriber that lets it back up is sent a final `Resync` event
+/// and dropped, so a slow client can't make the PD buffer without bound.
+#[derive(Default)]
+pub struct TopologyWatchers {
+    subscribers: Vec<Subscriber>,
+}
+
+impl TopologyWatchers {
+    /// Creates a set of watchers without any subscribers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes to changes of regions overlapping [start_key, end_key) in a
+    /// keyspace, where an empty end key means unbounded.
+    pub fn subscribe(
+        &mut self,
+        keyspace_id: u32,
+        start_key: Vec<u8>,
+        end_key: Vec<u8>,
+    ) -> mpsc::Receiver<TopologyEvent> {
+        let (tx, rx) = mpsc::channel(WATCH_CHANNEL_CAPACITY);
+        self.subscribers.push(Subscriber { keyspace_id, start_key, end_key, tx });
+        rx
+    }
+
+    /// Sends every subscriber a final `ShuttingDown` event and drops it, ending
+    /// its subscription. A subscriber backed up to its last slot gets the
+    /// event in that slot, which `notify()` keeps free.
+    pub fn close(&mut self) {
+        for subscriber in self.subscribers.drain(..) {
+            let _ = subscriber.tx.try_send(TopologyEvent::ShuttingDown);
+        }
+    }
+
+    /// Notifies the subscribers of a changed region, dropping those that have
+    /// gone away or fallen behind. Keys are ordered by the given comparator.
+    pub fn notify(&mut self, region: &RegionInfo, comparator: &dyn KeyComparator) {
+        self.subscribers.retain(|subscriber| {
+            if !subscriber.overlaps(region, comparator) {
+                return !subscriber.tx.is_closed();
+            }
+            // Keep the last slot free for the resync event.
+            if subscriber.tx.capacity() <= 1 {
+                let _ = subscriber.tx.try_send(TopologyEvent::Resync);
+                return false;
+            }
+            match subscriber.tx.try_send(TopologyEvent::RegionChanged(region.clone())) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) | Err(TrySendError::Closed(_)) => false,
+            }
+        });
+    }
+}
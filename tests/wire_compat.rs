@@ -0,0 +1,210 @@
+//! Replays recorded gRPC wire captures against a fresh PD, to catch
+//! accidental changes to the wire format: the protobuf messages, the bincode
+//! payloads they carry, and the `[Tag] message` convention of error statuses.
+//!
+//! The captures are in `tests/fixtures/wire_captures.txt`. To regenerate
+//! them after an intentional wire format change, see
+//! `tests/fixtures/README.md`.
+
+use prost::Message;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tonic::{Request, Status};
+
+use featherpd::clock::ManualClock;
+use featherpd::error::{Error, RpcResult};
+use featherpd::proto::placement_driver::{
+    AllocIdRequest, DataLocRequest, GetRegionByIdRequest, PlacementDriver, QueryStoresRequest,
+    RegionHeartbeatRequest, RegisterStoreRequest, SetStoreStateRequest, StoreState, TsoRequest,
+};
+use featherpd::routing::{RegionInfo, DEFAULT_KEYSPACE};
+use featherpd::server::FeatherPD;
+
+/// The environment variable that makes the test rewrite the captures from
+/// the current server instead of checking them.
+const REGENERATE_VAR: &str = "FEATHERPD_REGENERATE_WIRE_CAPTURES";
+
+/// The wall-clock time of the replayed PD, far enough in the future that
+/// it's past the leader grace period.
+const NOW_MS: u64 = 4_000_000_000_000;
+
+/// An encoded reply, or the code and message of an error status.
+type Response = std::result::Result<Vec<u8>, (i32, String)>;
+
+/// A recorded RPC: the request, and the response to it.
+#[derive(Debug, PartialEq)]
+struct Capture {
+    method: String,
+    request: Vec<u8>,
+    response: Response,
+}
+
+/// Returns the requests of the recorded session, in order.
+fn session() -> Vec<(&'static str, Vec<u8>)> {
+    let tso = |count, priority| TsoRequest { count, priority, ..Default::default() }.encode_to_vec();
+    let register = |store_id: u64, labels: &[(&str, &str)]| {
+        RegisterStoreRequest {
+            store_id,
+            address: format!("10.0.0.{}:20160", store_id),
+            labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            weight: None,
+        }
+        .encode_to_vec()
+    };
+    let up = |store_id| {
+        SetStoreStateRequest { store_id, state: StoreState::Up as i32, ..Default::default() }.encode_to_vec()
+    };
+    let locate = |key: &[u8]| DataLocRequest { key: key.to_vec(), ..Default::default() }.encode_to_vec();
+    let region_by_id = |region_id| GetRegionByIdRequest { region_id, epoch: 0 }.encode_to_vec();
+    let region = RegionInfo::new(10, DEFAULT_KEYSPACE, Vec::new(), Vec::new(), 1, vec![1, 2, 3], 1).unwrap();
+    let heartbeat = RegionHeartbeatRequest {
+        region: bincode::serialize(&region).unwrap(),
+        approximate_size: 1,
+        ..Default::default()
+    };
+    vec![
+        ("get_timestamp", tso(1, 0)),
+        ("get_timestamp", tso(3, 1)),
+        ("get_timestamp", tso(0, 0)),
+        ("get_timestamp", tso(1, 7)),
+        ("alloc_id", AllocIdRequest {}.encode_to_vec()),
+        ("register_store", register(1, &[("zone", "z1")])),
+        ("register_store", register(2, &[])),
+        ("register_store", register(3, &[])),
+        ("set_store_state", up(1)),
+        ("set_store_state", up(2)),
+        ("set_store_state", up(3)),
+        ("set_store_state", up(9)),
+        ("query_stores", QueryStoresRequest { selector: String::new() }.encode_to_vec()),
+        ("query_stores", QueryStoresRequest { selector: "zone=".into() }.encode_to_vec()),
+        ("get_data_location", locate(b"a")),
+        ("get_data_location", locate(b"")),
+        ("region_heartbeat", heartbeat.encode_to_vec()),
+        ("get_data_location", locate(b"a")),
+        ("get_region_by_id", region_by_id(10)),
+        ("get_region_by_id", region_by_id(99)),
+    ]
+}
+
+/// Sends an encoded request to the PD, returning the response.
+async fn call(pd: &FeatherPD, method: &str, request: &[u8]) -> Response {
+    fn decode<T: Message + Default>(request: &[u8]) -> Request<T> {
+        Request::new(T::decode(request).expect("undecodable request"))
+    }
+    fn encode<T: Message>(reply: RpcResult<T>) -> Response {
+        reply
+            .map(|reply| reply.into_inner().encode_to_vec())
+            .map_err(|status| (status.code() as i32, status.message().to_string()))
+    }
+    match method {
+        "get_timestamp" => encode(PlacementDriver::get_timestamp(pd, decode(request)).await),
+        "alloc_id" => encode(PlacementDriver::alloc_id(pd, decode(request)).await),
+        "register_store" => encode(PlacementDriver::register_store(pd, decode(request)).await),
+        "set_store_state" => encode(PlacementDriver::set_store_state(pd, decode(request)).await),
+        "query_stores" => encode(PlacementDriver::query_stores(pd, decode(request)).await),
+        "get_data_location" => encode(PlacementDriver::get_data_location(pd, decode(request)).await),
+        "region_heartbeat" => encode(PlacementDriver::region_heartbeat(pd, decode(request)).await),
+        "get_region_by_id" => encode(PlacementDriver::get_region_by_id(pd, decode(request)).await),
+        method => panic!("no replay support for method {}", method),
+    }
+}
+
+/// Returns a fresh PD with a fixed clock.
+fn pd() -> FeatherPD {
+    FeatherPD::new().unwrap().with_clock(Arc::new(ManualClock::new(NOW_MS)))
+}
+
+/// Returns the path of the captures file.
+fn captures_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/wire_captures.txt")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "-".into();
+    }
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Vec<u8> {
+    if hex == "-" {
+        return Vec::new();
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+}
+
+/// Parses the captures file. Each line is either
+/// `<method> <request> ok <reply>` or
+/// `<method> <request> err <code> <message>`, with hex-encoded messages, `-`
+/// for empty ones. Blank lines and `#` comments are skipped.
+fn parse_captures(text: &str) -> Vec<Capture> {
+    let mut captures = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+        let mut fields = line.splitn(5, ' ');
+        let mut next = || fields.next().unwrap_or_else(|| panic!("truncated capture {:?}", line));
+        let (method, request) = (next().to_string(), from_hex(next()));
+        let response = match next() {
+            "ok" => Ok(from_hex(next())),
+            "err" => Err((next().parse().unwrap(), next().to_string())),
+            kind => panic!("unknown capture kind {:?}", kind),
+        };
+        captures.push(Capture { method, request, response });
+    }
+    captures
+}
+
+/// Formats captures as the captures file.
+fn format_captures(captures: &[Capture]) -> String {
+    let mut text = String::from(
+        "# gRPC wire captures, replayed in order against a fresh PD by tests/wire_compat.rs.\n\
+         # Generated, see tests/fixtures/README.md before changing.\n",
+    );
+    for capture in captures {
+        let response = match &capture.response {
+            Ok(reply) => format!("ok {}", to_hex(reply)),
+            Err((code, message)) => format!("err {} {}", code, message),
+        };
+        text += &format!("{} {} {}\n", capture.method, to_hex(&capture.request), response);
+    }
+    text
+}
+
+/// Records the session against the current server.
+async fn record() -> Vec<Capture> {
+    let pd = pd();
+    let mut captures = Vec::new();
+    for (method, request) in session() {
+        let response = call(&pd, method, &request).await;
+        captures.push(Capture { method: method.to_string(), request, response });
+    }
+    captures
+}
+
+#[tokio::test]
+async fn replays_recorded_wire_captures() {
+    if std::env::var_os(REGENERATE_VAR).is_some() {
+        std::fs::write(captures_path(), format_captures(&record().await)).unwrap();
+    }
+    let recorded = parse_captures(&std::fs::read_to_string(captures_path()).unwrap());
+
+    // The requests are still encoded as recorded.
+    let requests: Vec<(&str, Vec<u8>)> =
+        recorded.iter().map(|capture| (capture.method.as_str(), capture.request.clone())).collect();
+    assert_eq!(requests, session(), "requests no longer encode as recorded");
+
+    // The recorded requests still get the recorded responses.
+    let pd = pd();
+    let mut tags = HashMap::new();
+    for (i, capture) in recorded.iter().enumerate() {
+        let response = call(&pd, &capture.method, &capture.request).await;
+        assert_eq!(response, capture.response, "capture {} ({}) got a different response", i, capture.method);
+        if let Err((code, message)) = &capture.response {
+            // The message still parses back into the error it came from.
+            let err = Error::from(Status::new((*code).into(), message.as_str()));
+            assert_eq!(Status::from(err.clone()).message(), message);
+            tags.insert(message.split(' ').next().unwrap().to_string(), err);
+        }
+    }
+    assert!(tags.len() > 1, "the captures should cover several error types");
+}